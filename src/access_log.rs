@@ -0,0 +1,201 @@
+//! Access log of TCP relay connections written by a separate thread.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+use failure::{Error, format_err};
+
+/// Number of entries waiting to be written. Entries are dropped when the
+/// queue is full so that connections never wait for the file.
+const QUEUE_SIZE: usize = 1024;
+/// Interval of time for flushing written entries to the file.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Message to the writer thread.
+enum Message {
+    Entry(String),
+    Stop,
+}
+
+/// How a connection of the TCP relay ended.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Outcome {
+    /// The connection was closed without errors.
+    Ok,
+    /// The connection was closed because of an error.
+    Error,
+    /// The connection was closed before running the relay protocol for the
+    /// reason, e.g. `ban-list`.
+    Rejected(&'static str),
+}
+
+/// Closed connection of the TCP relay.
+pub struct Entry {
+    /// Time when the connection was closed.
+    pub closed: DateTime<Local>,
+    /// Address of the client, the proxied one if PROXY protocol is used.
+    pub client: SocketAddr,
+    /// Name of the listener that accepted the connection.
+    pub listener: String,
+    /// Time since the connection was accepted.
+    pub duration: Duration,
+    /// Number of bytes received from the client.
+    pub received: u64,
+    /// Number of bytes sent to the client.
+    pub sent: u64,
+    /// How the connection ended.
+    pub outcome: Outcome,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} client={} listener={} duration={:.3} received={} sent={} result=",
+            self.closed.format("%Y-%m-%dT%H:%M:%S%.3f%:z"),
+            self.client,
+            self.listener,
+            self.duration.as_secs_f64(),
+            self.received,
+            self.sent,
+        )?;
+        match self.outcome {
+            Outcome::Ok => write!(f, "ok"),
+            Outcome::Error => write!(f, "error"),
+            Outcome::Rejected(reason) => write!(f, "rejected reason={}", reason),
+        }
+    }
+}
+
+/// Sender of entries to the access log. Clones share the same file.
+#[derive(Clone)]
+pub struct AccessLog {
+    tx: SyncSender<Message>,
+}
+
+impl AccessLog {
+    /// Queue the entry to be written without waiting for the file.
+    pub fn record(&self, entry: &Entry) {
+        match self.tx.try_send(Message::Entry(entry.to_string())) {
+            Ok(()) | Err(TrySendError::Disconnected(_)) => { },
+            Err(TrySendError::Full(_)) => debug!("Access log queue is full, dropping entry of {}", entry.client),
+        }
+    }
+}
+
+/// Thread writing the access log. Queued entries are written and flushed
+/// when it's dropped.
+pub struct AccessLogWriter {
+    tx: SyncSender<Message>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for AccessLogWriter {
+    fn drop(&mut self) {
+        let _ = self.tx.send(Message::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Open the access log file for appending and start the writer thread.
+pub fn open(path: &Path) -> Result<(AccessLog, AccessLogWriter), Error> {
+    let file = OpenOptions::new().create(true).append(true).open(path)
+        .map_err(|e| format_err!("Failed to open access log '{}': {}", path.display(), e))?;
+
+    let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+    let path = path.to_owned();
+    let thread = thread::Builder::new()
+        .name("access-log".to_owned())
+        .spawn(move || write_entries(rx, BufWriter::new(file), &path))
+        .map_err(|e| format_err!("Failed to start access log thread: {}", e))?;
+
+    Ok((AccessLog { tx: tx.clone() }, AccessLogWriter { tx, thread: Some(thread) }))
+}
+
+/// Write received entries to the file flushing it at least once per
+/// `FLUSH_INTERVAL` while there are unflushed entries.
+fn write_entries(rx: Receiver<Message>, mut file: BufWriter<File>, path: &Path) {
+    let mut flushed = Instant::now();
+    let mut dirty = false;
+    loop {
+        match rx.recv_timeout(FLUSH_INTERVAL) {
+            Ok(Message::Entry(line)) => {
+                if let Err(e) = writeln!(file, "{}", line) {
+                    error!("Failed to write access log '{}': {}", path.display(), e);
+                }
+                dirty = true;
+            },
+            Ok(Message::Stop) | Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => { },
+        }
+
+        if dirty && flushed.elapsed() >= FLUSH_INTERVAL {
+            if let Err(e) = file.flush() {
+                error!("Failed to flush access log '{}': {}", path.display(), e);
+            }
+            flushed = Instant::now();
+            dirty = false;
+        }
+    }
+
+    if let Err(e) = file.flush() {
+        error!("Failed to flush access log '{}': {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+    use tempfile::TempDir;
+
+    fn entry(outcome: Outcome) -> Entry {
+        Entry {
+            closed: Local.ymd(2020, 1, 2).and_hms_milli(3, 4, 5, 6),
+            client: "1.2.3.4:33445".parse().unwrap(),
+            listener: "0.0.0.0:33445".to_owned(),
+            duration: Duration::from_millis(12345),
+            received: 100,
+            sent: 200,
+            outcome,
+        }
+    }
+
+    #[test]
+    fn format_entry() {
+        let line = entry(Outcome::Ok).to_string();
+        assert!(line.starts_with("2020-01-02T03:04:05.006"));
+        assert!(line.ends_with(" client=1.2.3.4:33445 listener=0.0.0.0:33445 duration=12.345 received=100 sent=200 result=ok"));
+        assert!(entry(Outcome::Error).to_string().ends_with(" result=error"));
+        assert!(entry(Outcome::Rejected("ban-list")).to_string().ends_with(" sent=200 result=rejected reason=ban-list"));
+    }
+
+    #[test]
+    fn entries_are_written_on_drop() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("access.log");
+        std::fs::write(&path, "previous\n").unwrap();
+
+        let (access_log, writer) = open(&path).unwrap();
+        access_log.record(&entry(Outcome::Ok));
+        access_log.clone().record(&entry(Outcome::Error));
+        drop(writer);
+        // entries recorded after the writer is stopped are ignored
+        access_log.record(&entry(Outcome::Ok));
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines = content.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "previous");
+        assert!(lines[1].ends_with(" result=ok"));
+        assert!(lines[2].ends_with(" result=error"));
+    }
+}
@@ -0,0 +1,110 @@
+//! Re-reading the bootstrap file of the running node.
+
+use std::time::Duration;
+
+use futures::{Stream, StreamExt};
+use tox::core::dht::server::{Server as UdpServer};
+
+use crate::node_config::{AddrOrder, NodeConfig, load_bootstrap_file, select_new_bootstrap_nodes};
+
+/// Re-read the bootstrap file each time `reloads` yields and ping nodes from
+/// it that are not known yet since the initial bootstrap list can't be
+/// changed after the DHT server is started. Nothing else is reloaded.
+/// Completes when `reloads` ends.
+pub async fn reload_bootstrap_file<S>(config: &NodeConfig, bootstrap_file: &str, udp_server: &UdpServer, mut reloads: S)
+    where S: Stream<Item = ()> + Unpin
+{
+    // nodes from the bootstrap file are already added to configured nodes
+    let mut known_nodes = config.bootstrap_nodes.clone();
+
+    while reloads.next().await.is_some() {
+        let nodes = match load_bootstrap_file(bootstrap_file) {
+            Ok(nodes) => nodes,
+            Err(e) => {
+                warn!("Failed to reload bootstrap file: {}", e);
+                continue;
+            },
+        };
+
+        let new_nodes = select_new_bootstrap_nodes(&mut known_nodes, nodes);
+        for node in &new_nodes {
+            for node in node.resolve_with_timeout(Duration::from_secs(config.dns_timeout), config.dns_retries, AddrOrder::new(config)).await {
+                info!("Adding bootstrap node {:?}", node.saddr);
+                if let Err(e) = udp_server.ping_node(&node).await {
+                    warn!("Failed to ping bootstrap node: {}", e);
+                }
+            }
+        }
+        info!("Reloaded bootstrap file '{}', added {} new nodes", bootstrap_file, new_nodes.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::net::SocketAddr;
+
+    use futures::FutureExt;
+    use futures::channel::mpsc;
+    use tox::crypto::*;
+    use tox::packet::dht::Packet;
+
+    use crate::node_config::try_parse_config;
+
+    /// Addresses of nodes pinged so far.
+    fn pinged(rx: &mut mpsc::Receiver<(Packet, SocketAddr)>) -> Vec<SocketAddr> {
+        let mut addrs = Vec::new();
+        while let Ok(Some((packet, addr))) = rx.try_next() {
+            assert!(matches!(packet, Packet::NodesRequest(_)));
+            addrs.push(addr);
+        }
+        addrs
+    }
+
+    #[test]
+    fn new_nodes_are_pinged() {
+        crypto_init().unwrap();
+        let dir = tempfile::TempDir::new().unwrap();
+        let bootstrap_file = dir.path().join("nodes.txt");
+        let bootstrap_file = bootstrap_file.to_str().unwrap();
+        let node = |port: u16| format!("{} 127.0.0.1:{}\n", hex::encode(gen_keypair().0).to_uppercase(), port);
+        let known_pk = hex::encode(gen_keypair().0).to_uppercase();
+        let known = format!("{} 127.0.0.1:33445\n", known_pk);
+        std::fs::write(bootstrap_file, &known).unwrap();
+
+        let mut yaml = tempfile::Builder::new().suffix(".yml").tempfile().unwrap();
+        write!(yaml, "udp-address: 127.0.0.1:0\nsecret-key-file: ./key\nbootstrap-nodes:\n  - pk: {}\n    addr: 127.0.0.1:33445\n", known_pk).unwrap();
+        let config = try_parse_config(&[yaml.path().to_str().unwrap()]).unwrap();
+
+        let (tx, mut rx) = mpsc::channel(32);
+        let (pk, sk) = gen_keypair();
+        let udp_server = UdpServer::new(tx, pk, sk);
+        let (reload_tx, reload_rx) = mpsc::unbounded();
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let reload = reload_bootstrap_file(&config, bootstrap_file, &udp_server, reload_rx).fuse();
+            futures::pin_mut!(reload);
+
+            // the node from the config is known already
+            std::fs::write(bootstrap_file, format!("{}{}", known, node(33446))).unwrap();
+            reload_tx.unbounded_send(()).unwrap();
+            let reloaded = tokio::time::delay_for(Duration::from_millis(100));
+            futures::select! {
+                () = reload => panic!("Reloading stopped"),
+                () = reloaded.fuse() => { },
+            }
+            assert_eq!(pinged(&mut rx), vec!["127.0.0.1:33446".parse().unwrap()]);
+
+            // nodes are pinged only when they are added
+            let mut file = std::fs::OpenOptions::new().append(true).open(bootstrap_file).unwrap();
+            file.write_all(node(33447).as_bytes()).unwrap();
+            reload_tx.unbounded_send(()).unwrap();
+            reload_tx.close_channel();
+            reload.await;
+            assert_eq!(pinged(&mut rx), vec!["127.0.0.1:33447".parse().unwrap()]);
+        });
+    }
+}
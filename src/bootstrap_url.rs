@@ -0,0 +1,33 @@
+//! Fetching the list of bootstrap nodes from an HTTP URL.
+
+use std::time::Duration;
+
+use failure::{Error, format_err};
+use hyper::{Client, Uri};
+
+use crate::node_config::{BootstrapNode, parse_bootstrap_json};
+
+/// Fetch a JSON list of bootstrap nodes from the URL. Only plain HTTP is
+/// supported. The whole request including reading the body is bounded by
+/// `timeout`.
+pub async fn fetch_bootstrap_nodes(url: &str, timeout: Duration) -> Result<Vec<BootstrapNode>, Error> {
+    let uri: Uri = url.parse()?;
+    if uri.scheme_str() != Some("http") {
+        return Err(format_err!("only http URLs are supported"));
+    }
+
+    let fetch = async {
+        let response = Client::new().get(uri).await?;
+        if !response.status().is_success() {
+            return Err(format_err!("server responded with {}", response.status()));
+        }
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        Ok(String::from_utf8(body.to_vec())?)
+    };
+
+    let text = tokio::time::timeout(timeout, fetch)
+        .await
+        .map_err(|_| format_err!("timed out after {} seconds", timeout.as_secs()))??;
+
+    parse_bootstrap_json(&text)
+}
@@ -0,0 +1,526 @@
+//! Reading legacy `tox-bootstrapd` config files.
+//!
+//! `tox-bootstrapd` uses libconfig syntax. Only the subset needed by its
+//! config is supported: scalars, arrays, lists and groups, comments, string
+//! escapes including `\xNN` bytes and adjacent string concatenation.
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::Ipv6Addr;
+
+use config::{ConfigError, Source, Value};
+
+/// Defaults of `tox-bootstrapd` for keys missing in its config.
+const DEFAULT_PORT: i64 = 33445;
+const DEFAULT_TCP_RELAY_PORTS: [i64; 3] = [443, 3389, 33445];
+const DEFAULT_MOTD: &str = "tox-bootstrapd";
+
+/// Settings of `tox-bootstrapd` that are mapped to config keys of the node.
+const SUPPORTED_SETTINGS: [&str; 11] = [
+    "port", "keys_file_path", "pid_file_path", "enable_ipv6", "enable_ipv4_fallback", "enable_lan_discovery",
+    "enable_tcp_relay", "tcp_relay_ports", "enable_motd", "motd", "bootstrap_nodes",
+];
+
+/// Value of a libconfig setting. Arrays and lists are not distinguished.
+#[derive(Clone, PartialEq, Debug)]
+enum Setting {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    List(Vec<Setting>),
+    Group(Vec<(String, Setting)>),
+}
+
+impl Setting {
+    /// Name of the type for error messages.
+    fn kind(&self) -> &'static str {
+        match self {
+            Setting::Bool(_) => "boolean",
+            Setting::Int(_) => "integer",
+            Setting::Float(_) => "float",
+            Setting::Str(_) => "string",
+            Setting::List(_) => "list",
+            Setting::Group(_) => "group",
+        }
+    }
+
+    /// Convert to a config value that came from `origin`.
+    fn into_value(self, origin: &String) -> Value {
+        match self {
+            Setting::Bool(value) => Value::new(Some(origin), value),
+            Setting::Int(value) => Value::new(Some(origin), value),
+            Setting::Float(value) => Value::new(Some(origin), value),
+            Setting::Str(value) => Value::new(Some(origin), value),
+            Setting::List(values) => Value::new(Some(origin), values
+                .into_iter()
+                .map(|value| value.into_value(origin))
+                .collect::<Vec<_>>()),
+            Setting::Group(settings) => Value::new(Some(origin), settings
+                .into_iter()
+                .map(|(name, value)| (name, value.into_value(origin)))
+                .collect::<HashMap<_, _>>()),
+        }
+    }
+}
+
+/// Recursive descent parser of libconfig text.
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Self {
+        Parser {
+            chars: text.chars().peekable(),
+            line: 1,
+        }
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("line {}: {}", self.line, message)
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(self.error(&format!("expected '{}' but found '{}'", expected, c))),
+            None => Err(self.error(&format!("expected '{}' but found end of file", expected))),
+        }
+    }
+
+    /// Skip whitespaces and `#`, `//` and `/* */` comments.
+    fn skip_whitespaces(&mut self) -> Result<(), String> {
+        loop {
+            match self.chars.peek() {
+                Some(c) if c.is_whitespace() => { self.next(); },
+                Some('#') => self.skip_line(),
+                Some('/') => {
+                    self.next();
+                    match self.next() {
+                        Some('/') => self.skip_line(),
+                        Some('*') => loop {
+                            match self.next() {
+                                Some('*') if self.chars.peek() == Some(&'/') => {
+                                    self.next();
+                                    break
+                                },
+                                Some(_) => { },
+                                None => return Err(self.error("unterminated comment")),
+                            }
+                        },
+                        _ => return Err(self.error("unexpected '/'")),
+                    }
+                },
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn skip_line(&mut self) {
+        while let Some(c) = self.next() {
+            if c == '\n' {
+                break
+            }
+        }
+    }
+
+    /// Parse settings until the end of the text or the closing brace of a
+    /// group. Settings may be terminated by `;` or `,`.
+    fn settings(&mut self, group: bool) -> Result<Vec<(String, Setting)>, String> {
+        let mut settings = Vec::new();
+        loop {
+            self.skip_whitespaces()?;
+            match self.chars.peek() {
+                None if group => return Err(self.error("unterminated group")),
+                None => return Ok(settings),
+                Some('}') if group => return Ok(settings),
+                _ => { },
+            }
+
+            let name = self.name()?;
+            self.skip_whitespaces()?;
+            match self.next() {
+                Some('=') | Some(':') => { },
+                _ => return Err(self.error(&format!("expected '=' or ':' after '{}'", name))),
+            }
+            let value = self.value()?;
+            self.skip_whitespaces()?;
+            if let Some(';') | Some(',') = self.chars.peek() {
+                self.next();
+            }
+            settings.push((name, value));
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let mut name = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '*' {
+                name.push(c);
+                self.next();
+            } else {
+                break
+            }
+        }
+        if name.is_empty() || !name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '*') {
+            return Err(self.error("expected setting name"))
+        }
+        Ok(name)
+    }
+
+    fn value(&mut self) -> Result<Setting, String> {
+        self.skip_whitespaces()?;
+        match self.chars.peek() {
+            Some('"') => self.string(),
+            Some('[') => self.list('[', ']'),
+            Some('(') => self.list('(', ')'),
+            Some('{') => {
+                self.next();
+                let settings = self.settings(true)?;
+                self.expect('}')?;
+                Ok(Setting::Group(settings))
+            },
+            Some(_) => self.scalar(),
+            None => Err(self.error("expected value but found end of file")),
+        }
+    }
+
+    /// Parse a string. Adjacent strings are concatenated. `\xNN` escapes are
+    /// bytes so the resulting string must be valid UTF-8.
+    fn string(&mut self) -> Result<Setting, String> {
+        let mut result = Vec::new();
+        let push = |result: &mut Vec<u8>, c: char| result.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+        while let Some('"') = self.chars.peek() {
+            self.next();
+            loop {
+                match self.next() {
+                    Some('"') => break,
+                    Some('\\') => match self.next() {
+                        Some('n') => push(&mut result, '\n'),
+                        Some('r') => push(&mut result, '\r'),
+                        Some('t') => push(&mut result, '\t'),
+                        Some('f') => push(&mut result, '\x0c'),
+                        Some(c @ '"') | Some(c @ '\\') => push(&mut result, c),
+                        Some('x') => {
+                            let digits = (self.next(), self.next());
+                            match digits {
+                                (Some(high), Some(low)) if high.is_ascii_hexdigit() && low.is_ascii_hexdigit() =>
+                                    result.push((high.to_digit(16).unwrap() * 16 + low.to_digit(16).unwrap()) as u8),
+                                _ => return Err(self.error("invalid escape sequence")),
+                            }
+                        },
+                        _ => return Err(self.error("invalid escape sequence")),
+                    },
+                    Some(c) => push(&mut result, c),
+                    None => return Err(self.error("unterminated string")),
+                }
+            }
+            self.skip_whitespaces()?;
+        }
+        String::from_utf8(result)
+            .map(Setting::Str)
+            .map_err(|_| self.error("string is not valid UTF-8"))
+    }
+
+    fn list(&mut self, open: char, close: char) -> Result<Setting, String> {
+        self.expect(open)?;
+        let mut values = Vec::new();
+        loop {
+            self.skip_whitespaces()?;
+            if self.chars.peek() == Some(&close) {
+                self.next();
+                return Ok(Setting::List(values))
+            }
+            values.push(self.value()?);
+            self.skip_whitespaces()?;
+            match self.next() {
+                Some(',') => { },
+                Some(c) if c == close => return Ok(Setting::List(values)),
+                _ => return Err(self.error(&format!("expected ',' or '{}'", close))),
+            }
+        }
+    }
+
+    /// Parse a boolean, an integer or a float.
+    fn scalar(&mut self) -> Result<Setting, String> {
+        let mut token = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '+' || c == '.' {
+                token.push(c);
+                self.next();
+            } else {
+                break
+            }
+        }
+
+        let lowercase = token.to_lowercase();
+        let number = lowercase.trim_end_matches('l');
+        if lowercase == "true" {
+            Ok(Setting::Bool(true))
+        } else if lowercase == "false" {
+            Ok(Setting::Bool(false))
+        } else if let Some(hex) = number.strip_prefix("0x") {
+            i64::from_str_radix(hex, 16).map(Setting::Int)
+                .map_err(|_| self.error(&format!("invalid number '{}'", token)))
+        } else if let Ok(int) = number.parse() {
+            Ok(Setting::Int(int))
+        } else if let Ok(float) = lowercase.parse() {
+            Ok(Setting::Float(float))
+        } else {
+            Err(self.error(&format!("invalid value '{}'", token)))
+        }
+    }
+}
+
+/// Parse top level settings of libconfig text.
+fn parse_settings(text: &str) -> Result<Vec<(String, Setting)>, String> {
+    Parser::new(text).settings(false)
+}
+
+/// Top level settings of `tox-bootstrapd` config by name.
+struct Settings {
+    settings: HashMap<String, Setting>,
+}
+
+impl Settings {
+    fn get_bool(&self, name: &str, default: bool) -> Result<bool, String> {
+        match self.settings.get(name) {
+            Some(Setting::Bool(value)) => Ok(*value),
+            Some(value) => Err(format!("'{}' must be a boolean, not {}", name, value.kind())),
+            None => Ok(default),
+        }
+    }
+
+    fn get_str(&self, name: &str) -> Result<Option<&str>, String> {
+        match self.settings.get(name) {
+            Some(Setting::Str(value)) => Ok(Some(value)),
+            Some(value) => Err(format!("'{}' must be a string, not {}", name, value.kind())),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Port of the setting described by `name` for error messages.
+fn to_port(name: &str, value: &Setting) -> Result<u16, String> {
+    match *value {
+        Setting::Int(port) if port > 0 && port <= i64::from(u16::MAX) => Ok(port as u16),
+        Setting::Int(port) => Err(format!("{} has invalid port {}", name, port)),
+        ref value => Err(format!("{} must be an integer, not {}", name, value.kind())),
+    }
+}
+
+/// Host and port joined into an address. IPv6 addresses are put in brackets.
+fn join_host_port(host: &str, port: u16) -> String {
+    if host.parse::<Ipv6Addr>().is_ok() {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Convert a bootstrap node group with `address`, `port` and `public_key` to
+/// the compact format `<pk>@<host>:<port>`.
+fn bootstrap_node(index: usize, node: &Setting) -> Result<String, String> {
+    let fields = match node {
+        Setting::Group(fields) => fields,
+        value => return Err(format!("bootstrap node #{} must be a group, not {}", index + 1, value.kind())),
+    };
+    let field = |name: &str| fields.iter().find(|(field, _)| field == name).map(|(_, value)| value);
+
+    let (address, port, public_key) = match (field("address"), field("port"), field("public_key")) {
+        (Some(Setting::Str(address)), Some(port), Some(Setting::Str(public_key))) => (address, port, public_key),
+        _ => return Err(format!("bootstrap node #{} must have string 'address', 'port' and string 'public_key'", index + 1)),
+    };
+    let port = to_port(&format!("port of bootstrap node #{}", index + 1), port)?;
+
+    Ok(format!("{}@{}", public_key, join_host_port(address, port)))
+}
+
+/// Map supported `tox-bootstrapd` settings to config keys of the node.
+/// Missing settings get defaults of `tox-bootstrapd`.
+fn node_values(settings: Vec<(String, Setting)>) -> Result<Vec<(&'static str, Value)>, String> {
+    let settings = Settings { settings: settings.into_iter().collect() };
+
+    let mut values: Vec<(&'static str, Value)> = Vec::new();
+
+    let enable_ipv6 = settings.get_bool("enable_ipv6", true)?;
+    let enable_ipv4_fallback = settings.get_bool("enable_ipv4_fallback", true)?;
+    let host = if enable_ipv6 { "::" } else { "0.0.0.0" };
+    let ip_mode = match (enable_ipv6, enable_ipv4_fallback) {
+        (false, _) => "ipv4",
+        (true, false) => "ipv6",
+        (true, true) => "dual",
+    };
+    values.push(("ip-mode", ip_mode.into()));
+
+    let port = to_port("'port'", settings.settings.get("port").unwrap_or(&Setting::Int(DEFAULT_PORT)))?;
+    values.push(("udp-address", join_host_port(host, port).into()));
+
+    if settings.get_bool("enable_tcp_relay", true)? {
+        let default_ports = Setting::List(DEFAULT_TCP_RELAY_PORTS.iter().map(|&port| Setting::Int(port)).collect());
+        let tcp_addrs = match settings.settings.get("tcp_relay_ports").unwrap_or(&default_ports) {
+            Setting::List(ports) => ports
+                .iter()
+                .map(|port| to_port("'tcp_relay_ports'", port).map(|port| join_host_port(host, port)))
+                .collect::<Result<Vec<_>, _>>()?,
+            value => return Err(format!("'tcp_relay_ports' must be a list, not {}", value.kind())),
+        };
+        values.push(("tcp-addresses", tcp_addrs.into()));
+    }
+
+    match settings.get_str("keys_file_path")? {
+        Some(keys_file_path) => values.push(("keys-file", keys_file_path.into())),
+        None => return Err("'keys_file_path' is not set".to_owned()),
+    }
+
+    if let Some(pid_file_path) = settings.get_str("pid_file_path")? {
+        values.push(("pid-file", pid_file_path.into()));
+    }
+
+    values.push(("lan-discovery", settings.get_bool("enable_lan_discovery", true)?.into()));
+
+    let motd = if settings.get_bool("enable_motd", true)? {
+        settings.get_str("motd")?.unwrap_or(DEFAULT_MOTD)
+    } else {
+        ""
+    };
+    values.push(("motd", motd.into()));
+
+    let bootstrap_nodes = match settings.settings.get("bootstrap_nodes") {
+        Some(Setting::List(nodes)) => nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| bootstrap_node(index, node))
+            .collect::<Result<Vec<_>, _>>()?,
+        Some(value) => return Err(format!("'bootstrap_nodes' must be a list, not {}", value.kind())),
+        None => Vec::new(),
+    };
+    values.push(("bootstrap-nodes", bootstrap_nodes.into()));
+
+    Ok(values)
+}
+
+/// `tox-bootstrapd` config file as a source of config keys of the node.
+/// Unsupported settings keep their names so that they end up among unused
+/// keys of the config and are reported once the logger is initialized.
+#[derive(Clone, Debug)]
+pub struct BootstrapdFile {
+    path: String,
+}
+
+impl BootstrapdFile {
+    pub fn new(path: &str) -> Self {
+        BootstrapdFile {
+            path: path.to_owned(),
+        }
+    }
+}
+
+impl Source for BootstrapdFile {
+    fn clone_into_box(&self) -> Box<dyn Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<HashMap<String, Value>, ConfigError> {
+        let text = fs::read_to_string(&self.path)
+            .map_err(|e| ConfigError::Message(format!("Can't read tox-bootstrapd config {}: {}", self.path, e)))?;
+        let invalid = |e| ConfigError::Message(format!("Invalid tox-bootstrapd config {}: {}", self.path, e));
+        let (supported, unsupported): (Vec<_>, Vec<_>) = parse_settings(&text)
+            .map_err(invalid)?
+            .into_iter()
+            .partition(|(name, _)| SUPPORTED_SETTINGS.contains(&name.as_str()));
+        let values = node_values(supported).map_err(invalid)?;
+
+        Ok(values
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), Value::new(Some(&self.path), value.kind)))
+            .chain(unsupported.into_iter().map(|(name, setting)| (name, setting.into_value(&self.path))))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_libconfig() {
+        let settings = parse_settings(r#"
+            // comment
+            port = 33445; # comment
+            /* multi-line
+               comment */
+            enable_ipv6 : false
+            motd = "tox-" "bootstrapd\n";
+            utf8 = "\xc3\xa9\x41";
+            hex = 0x1Fl;
+            float = 1.5;
+            ports = [443, 3389];
+            nodes = (
+                { address = "::1", port = 33445 },
+            );
+        "#).unwrap();
+        assert_eq!(settings, vec![
+            ("port".to_owned(), Setting::Int(33445)),
+            ("enable_ipv6".to_owned(), Setting::Bool(false)),
+            ("motd".to_owned(), Setting::Str("tox-bootstrapd\n".to_owned())),
+            ("utf8".to_owned(), Setting::Str("\u{e9}A".to_owned())),
+            ("hex".to_owned(), Setting::Int(31)),
+            ("float".to_owned(), Setting::Float(1.5)),
+            ("ports".to_owned(), Setting::List(vec![Setting::Int(443), Setting::Int(3389)])),
+            ("nodes".to_owned(), Setting::List(vec![Setting::Group(vec![
+                ("address".to_owned(), Setting::Str("::1".to_owned())),
+                ("port".to_owned(), Setting::Int(33445)),
+            ])])),
+        ]);
+    }
+
+    #[test]
+    fn parse_libconfig_errors() {
+        assert_eq!(parse_settings("port = 33445\nmotd = \"abc").unwrap_err(), "line 2: unterminated string");
+        assert!(parse_settings("port 33445").is_err());
+        assert!(parse_settings("nodes = ({ port = 1 )").is_err());
+        assert!(parse_settings("port = abc").is_err());
+        assert_eq!(parse_settings("motd = \"\\x4\"").unwrap_err(), "line 1: invalid escape sequence");
+        assert_eq!(parse_settings("motd = \"\\xff\"").unwrap_err(), "line 1: string is not valid UTF-8");
+    }
+
+    #[test]
+    fn bootstrapd_defaults() {
+        let values = node_values(parse_settings("keys_file_path = \"keys\"").unwrap()).unwrap();
+        let values = values.into_iter().collect::<HashMap<_, _>>();
+        assert_eq!(values["ip-mode"], "dual".into());
+        assert_eq!(values["udp-address"], "[::]:33445".into());
+        assert_eq!(values["tcp-addresses"], vec!["[::]:443", "[::]:3389", "[::]:33445"].into());
+        assert_eq!(values["lan-discovery"], true.into());
+        assert_eq!(values["motd"], "tox-bootstrapd".into());
+        assert!(!values.contains_key("pid-file"));
+    }
+
+    #[test]
+    fn bootstrapd_missing_keys_file() {
+        assert_eq!(node_values(parse_settings("port = 33445").unwrap()).unwrap_err(), "'keys_file_path' is not set");
+    }
+
+    #[test]
+    fn bootstrapd_invalid_values() {
+        let error = node_values(parse_settings("keys_file_path = \"keys\"\nport = 70000").unwrap()).unwrap_err();
+        assert_eq!(error, "'port' has invalid port 70000");
+        let error = node_values(parse_settings("keys_file_path = \"keys\"\nenable_lan_discovery = 1").unwrap()).unwrap_err();
+        assert_eq!(error, "'enable_lan_discovery' must be a boolean, not integer");
+        let error = node_values(parse_settings("keys_file_path = \"keys\"\nbootstrap_nodes = ({ address = \"::1\" })").unwrap()).unwrap_err();
+        assert_eq!(error, "bootstrap node #1 must have string 'address', 'port' and string 'public_key'");
+        let error = node_values(parse_settings("keys_file_path = \"keys\"\nbootstrap_nodes = ({ address = \"::1\", port = 0, public_key = \"\" })").unwrap()).unwrap_err();
+        assert_eq!(error, "port of bootstrap node #1 has invalid port 0");
+    }
+}
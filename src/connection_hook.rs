@@ -0,0 +1,242 @@
+//! External command deciding whether TCP connections from an IP are accepted.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use failure::{Error, format_err};
+use futures::FutureExt;
+use futures::channel::oneshot;
+use futures::future::Shared;
+use tokio::process::Command;
+
+use crate::node_config::HookPolicy;
+
+/// Number of cached decisions after which expired ones are evicted.
+const MAX_CACHED_DECISIONS: usize = 4096;
+
+/// Decision of the command for an IP.
+enum Decision {
+    /// The command is running, the receiver resolves with its decision.
+    Pending(Shared<oneshot::Receiver<bool>>),
+    /// The decision and the time it was made.
+    Made(bool, Instant),
+}
+
+/// How to get the decision for a new connection.
+enum Lookup {
+    /// Use the cached decision.
+    Cached(bool),
+    /// Wait for the command run for another connection from the same IP.
+    Wait(Shared<oneshot::Receiver<bool>>),
+    /// Run the command and send the decision to the waiting connections.
+    Run(oneshot::Sender<bool>),
+}
+
+/// Command that is run with the IP of a new TCP connection as an argument.
+/// Zero exit status accepts the connection, non-zero status rejects it.
+/// Decisions are cached per IP so that the command is not run for every
+/// connection, concurrent connections from the same IP wait for a single
+/// run of the command.
+pub struct ConnectionHook {
+    /// Shell command to run.
+    command: String,
+    /// Time after which the command is killed and considered failed.
+    timeout: Duration,
+    /// Time to keep decisions for an IP.
+    cache_ttl: Duration,
+    /// Decision used when the command fails to run or times out.
+    on_failure: HookPolicy,
+    /// Recent and pending decisions keyed by IP.
+    cache: Mutex<HashMap<IpAddr, Decision>>,
+}
+
+impl ConnectionHook {
+    /// Create new hook running `command`.
+    pub fn new(command: String, timeout: Duration, cache_ttl: Duration, on_failure: HookPolicy) -> Self {
+        ConnectionHook {
+            command,
+            timeout,
+            cache_ttl,
+            on_failure,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check whether a connection from the IP should be accepted. The command
+    /// is run only when there is neither cached nor pending decision for the
+    /// IP.
+    pub async fn accepts(&self, ip: IpAddr) -> bool {
+        let decision = match self.lookup(ip, Instant::now()) {
+            Lookup::Cached(accepted) => return accepted,
+            // the command can't be cancelled so the receiver fails only if
+            // the runtime is shutting down
+            Lookup::Wait(decision) => return decision.await.unwrap_or(self.on_failure == HookPolicy::Allow),
+            Lookup::Run(decision) => decision,
+        };
+
+        let accepted = match self.run(ip).await {
+            Ok(true) => true,
+            Ok(false) => {
+                debug!("Rejecting TCP connection from {} by connection hook", ip);
+                false
+            },
+            Err(e) => {
+                let accepted = self.on_failure == HookPolicy::Allow;
+                warn!("Connection hook failed for {}: {}. {} the connection", ip, e, if accepted { "Accepting" } else { "Rejecting" });
+                accepted
+            },
+        };
+
+        self.insert(ip, Decision::Made(accepted, Instant::now()));
+        let _ = decision.send(accepted);
+        accepted
+    }
+
+    /// Get unexpired or pending decision for the IP. If there is none the
+    /// decision is marked as pending and the caller should run the command.
+    fn lookup(&self, ip: IpAddr, now: Instant) -> Lookup {
+        {
+            let cache = self.cache.lock().expect("Failed to lock connection hook cache");
+            match cache.get(&ip) {
+                Some(&Decision::Made(accepted, time)) if now.duration_since(time) < self.cache_ttl =>
+                    return Lookup::Cached(accepted),
+                Some(Decision::Pending(decision)) => return Lookup::Wait(decision.clone()),
+                _ => { },
+            }
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.insert(ip, Decision::Pending(rx.shared()));
+        Lookup::Run(tx)
+    }
+
+    /// Remember the decision for the IP. Expired decisions are evicted when
+    /// the cache grows too large.
+    fn insert(&self, ip: IpAddr, decision: Decision) {
+        let mut cache = self.cache.lock().expect("Failed to lock connection hook cache");
+        if cache.len() >= MAX_CACHED_DECISIONS {
+            let cache_ttl = self.cache_ttl;
+            let now = Instant::now();
+            cache.retain(|_, decision| match *decision {
+                Decision::Pending(_) => true,
+                Decision::Made(_, time) => now.duration_since(time) < cache_ttl,
+            });
+        }
+        cache.insert(ip, decision);
+    }
+
+    /// Run the command with the IP and return whether it exited successfully.
+    /// It's an error if the command can't be started, takes longer than the
+    /// timeout or is killed by a signal.
+    async fn run(&self, ip: IpAddr) -> Result<bool, Error> {
+        let child = shell_command(&self.command, ip)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format_err!("Failed to run '{}': {}", self.command, e))?;
+        let status = tokio::time::timeout(self.timeout, child)
+            .await
+            .map_err(|_| format_err!("Timed out after {:?}", self.timeout))??;
+        match status.code() {
+            Some(code) => Ok(code == 0),
+            None => Err(format_err!("Killed by a signal")),
+        }
+    }
+}
+
+/// Command running `command` by the shell with the IP appended as the last
+/// argument.
+#[cfg(unix)]
+fn shell_command(command: &str, ip: IpAddr) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c")
+        .arg(format!("{} \"$1\"", command))
+        .arg("sh")
+        .arg(ip.to_string());
+    shell
+}
+
+/// Command running `command` by the shell with the IP appended as the last
+/// argument.
+#[cfg(not(unix))]
+fn shell_command(command: &str, ip: IpAddr) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C")
+        .arg(format!("{} {}", command, ip));
+    shell
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hook(command: &str, on_failure: HookPolicy) -> ConnectionHook {
+        ConnectionHook::new(command.to_owned(), Duration::from_secs(5), Duration::from_secs(60), on_failure)
+    }
+
+    fn accepts(hook: &ConnectionHook, ip: &str) -> bool {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(hook.accepts(ip.parse().unwrap()))
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exit_status_decides() {
+        assert!(accepts(&hook("test 1.2.3.4 =", HookPolicy::Deny), "1.2.3.4"));
+        assert!(!accepts(&hook("test 5.6.7.8 =", HookPolicy::Allow), "1.2.3.4"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failure_policy() {
+        let timeout = |on_failure| ConnectionHook::new("sleep 5 #".to_owned(), Duration::from_millis(50), Duration::from_secs(60), on_failure);
+        assert!(accepts(&timeout(HookPolicy::Allow), "1.2.3.4"));
+        assert!(!accepts(&timeout(HookPolicy::Deny), "1.2.3.4"));
+        assert!(accepts(&hook("kill -9 $$ #", HookPolicy::Allow), "1.2.3.4"));
+        assert!(!accepts(&hook("kill -9 $$ #", HookPolicy::Deny), "1.2.3.4"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn decisions_are_cached() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let runs = dir.path().join("runs");
+        let hook = hook(&format!("echo >> {} #", runs.display()), HookPolicy::Deny);
+
+        assert!(accepts(&hook, "1.2.3.4"));
+        assert!(accepts(&hook, "1.2.3.4"));
+        assert!(accepts(&hook, "5.6.7.8"));
+        assert_eq!(std::fs::read_to_string(&runs).unwrap().lines().count(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn concurrent_connections_share_decision() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let runs = dir.path().join("runs");
+        let hook = hook(&format!("sleep 0.2; echo >> {} #", runs.display()), HookPolicy::Deny);
+        let ip = "1.2.3.4".parse().unwrap();
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let decisions = runtime.block_on(futures::future::join_all((0..5).map(|_| hook.accepts(ip))));
+        assert_eq!(decisions, vec![true; 5]);
+        assert_eq!(std::fs::read_to_string(&runs).unwrap().lines().count(), 1);
+    }
+
+    #[test]
+    fn cached_decisions_expire() {
+        let hook = hook("false", HookPolicy::Deny);
+        let ip = "1.2.3.4".parse().unwrap();
+        let now = Instant::now();
+
+        hook.insert(ip, Decision::Made(true, now));
+        assert!(matches!(hook.lookup(ip, now + Duration::from_secs(59)), Lookup::Cached(true)));
+        assert!(matches!(hook.lookup(ip, now + Duration::from_secs(60)), Lookup::Run(_)));
+        // the expired decision is replaced with the pending one
+        assert!(matches!(hook.lookup(ip, now + Duration::from_secs(60)), Lookup::Wait(_)));
+    }
+}
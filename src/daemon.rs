@@ -0,0 +1,52 @@
+//! Detaching the node from the terminal to run in background.
+
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use failure::{Error, format_err};
+
+/// Fork the process. The parent exits immediately and the child continues.
+fn fork_and_exit_parent() -> Result<(), Error> {
+    // Safe because the process is still single threaded so the child gets a
+    // consistent copy of its state
+    match unsafe { libc::fork() } {
+        -1 => Err(format_err!("Failed to fork: {}", io::Error::last_os_error())),
+        0 => Ok(()),
+        // Exit without running destructors and flushing buffers since they
+        // belong to the child now
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+/// Detach the process from the terminal: fork, start a new session, fork
+/// again so the process can't acquire a controlling terminal and redirect
+/// standard streams to `/dev/null`. The working directory is not changed so
+/// relative paths from the config remain valid.
+///
+/// Must be called before any threads are spawned, i.e. before the runtime is
+/// created.
+pub fn daemonize() -> Result<(), Error> {
+    fork_and_exit_parent()?;
+
+    // Safe because setsid has no memory effects
+    if unsafe { libc::setsid() } == -1 {
+        return Err(format_err!("Failed to create a new session: {}", io::Error::last_os_error()));
+    }
+
+    fork_and_exit_parent()?;
+
+    let dev_null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .map_err(|e| format_err!("Failed to open /dev/null: {}", e))?;
+    for fd in &[libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        // Safe because both descriptors are valid
+        if unsafe { libc::dup2(dev_null.as_raw_fd(), *fd) } == -1 {
+            return Err(format_err!("Failed to redirect standard streams: {}", io::Error::last_os_error()));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,150 @@
+//! Checking that the node can run with the config without starting it.
+
+use std::fs::File;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+
+use failure::{Error, format_err};
+use itertools::Itertools;
+
+use crate::error::NodeError;
+use crate::keys::{load_secret_key_file, try_load_keys};
+use crate::node_config::NodeConfig;
+
+/// Check that the address can be bound with the socket type. The socket is
+/// closed right away and TCP sockets are not put to the listening state.
+fn probe_bind(addr: SocketAddr, ty: socket2::Type) -> Result<(), Error> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), ty, None)?;
+    socket.bind(&addr.into()).map_err(|e| crate::bind_error(if ty == socket2::Type::DGRAM { "UDP" } else { "TCP" }, addr, e))?;
+    Ok(())
+}
+
+/// Check that keys are readable, addresses can be bound and bootstrap nodes
+/// can be resolved printing the result of each check. Failed checks are
+/// returned as errors of the class they would stop the node with.
+fn check(config: &NodeConfig) -> Vec<NodeError> {
+    let mut errors = Vec::new();
+
+    if config.sk.is_some() {
+        println!("DHT secret key: OK");
+    } else if let Some(ref sk_file) = config.sk_file {
+        match load_secret_key_file(sk_file) {
+            Ok(sk) => println!("Secret key file '{}': OK, DHT public key: {}",
+                sk_file, hex::encode(sk.public_key().as_ref()).to_uppercase()),
+            Err(e) => errors.push(NodeError::Keys(e)),
+        }
+    } else if !config.keys_files.is_empty() {
+        for keys_file in &config.keys_files {
+            match File::open(keys_file) {
+                Ok(file) => match try_load_keys(file, config.keys_format) {
+                    Ok((pk, _)) => println!("Keys file '{}': OK, DHT public key: {}",
+                        keys_file, hex::encode(pk.as_ref()).to_uppercase()),
+                    Err(e) => errors.push(NodeError::Keys(format_err!("Keys file '{}': {}", keys_file, e))),
+                },
+                Err(ref e) if e.kind() == ErrorKind::NotFound =>
+                    println!("Keys file '{}': not found, new keys will be generated", keys_file),
+                Err(e) => errors.push(NodeError::Keys(format_err!("Keys file '{}': {}", keys_file, e))),
+            }
+        }
+    } else {
+        errors.push(NodeError::Config(format_err!("Neither secret key nor keys file is specified")));
+    }
+
+    if config.tcp_addrs.is_empty() && config.udp_addrs.is_empty() {
+        errors.push(NodeError::Config(format_err!("Both TCP addresses and UDP addresses are not defined")));
+    }
+
+    for udp_addr in config.udp_addrs.iter().filter(|udp_addr| !config.ip_mode.allows(udp_addr)) {
+        errors.push(NodeError::Config(format_err!("UDP address {} doesn't match IP mode {}", udp_addr, config.ip_mode)));
+    }
+
+    let udp_addrs = config.udp_addrs.iter().map(|&addr| ("UDP", addr, socket2::Type::DGRAM));
+    let tcp_addrs = config.tcp_addrs.iter().map(|addr| ("TCP", addr.addr, socket2::Type::STREAM));
+    let metrics_addrs = config.metrics_addr.iter().map(|&addr| ("Metrics", addr, socket2::Type::STREAM));
+    let health_addrs = config.health_addr.iter().map(|&addr| ("Health check", addr, socket2::Type::STREAM));
+    for (name, addr, ty) in udp_addrs.chain(tcp_addrs).chain(metrics_addrs).chain(health_addrs) {
+        match probe_bind(addr, ty) {
+            Ok(()) => println!("{} address {}: OK", name, addr),
+            Err(e) => errors.push(NodeError::Bind(format_err!("{} address {}: can't bind: {}", name, addr, e))),
+        }
+    }
+
+    for node in &config.bootstrap_nodes {
+        let resolved = node.resolve().map(|node| node.saddr).collect::<Vec<_>>();
+        if resolved.is_empty() {
+            println!("Bootstrap node {}: unresolvable", node.addr);
+        } else {
+            println!("Bootstrap node {}: resolvable to {}", node.addr, resolved.iter().format(", "));
+        }
+    }
+
+    errors
+}
+
+/// Check that the node can run with the config without starting it. Exit
+/// with code 0 if the config is valid and with the exit code of the first
+/// failed check otherwise.
+pub fn dry_run(config: &NodeConfig) -> ! {
+    let errors = check(config);
+    if let Some(first) = errors.first() {
+        let exit_code = first.exit_code();
+        for error in errors {
+            eprintln!("Error: {}", error);
+        }
+        std::process::exit(exit_code)
+    }
+
+    println!("Config is valid");
+    std::process::exit(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use crate::error::{EXIT_BIND, EXIT_CONFIG, EXIT_KEYS};
+    use crate::node_config::try_parse_config;
+
+    fn config(content: &str) -> NodeConfig {
+        let mut file = tempfile::Builder::new().suffix(".yml").tempfile().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        try_parse_config(&[file.path().to_str().unwrap()]).unwrap()
+    }
+
+    fn exit_codes(config: &NodeConfig) -> Vec<i32> {
+        check(config).iter().map(NodeError::exit_code).collect()
+    }
+
+    #[test]
+    fn valid_config() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = config(&format!("udp-address: 127.0.0.1:0\nkeys-file: {}\n", dir.path().join("keys").display()));
+        assert!(check(&config).is_empty());
+    }
+
+    #[test]
+    fn invalid_keys_are_keys_errors() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let keys_file = dir.path().join("keys");
+        std::fs::write(&keys_file, b"invalid").unwrap();
+        let config = config(&format!("udp-address: 127.0.0.1:0\nkeys-file: {}\n", keys_file.display()));
+        assert_eq!(exit_codes(&config), vec![EXIT_KEYS]);
+    }
+
+    #[test]
+    fn ip_mode_mismatch_is_config_error() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = config(&format!("udp-address: 127.0.0.1:0\nip-mode: ipv6\nkeys-file: {}\n", dir.path().join("keys").display()));
+        assert_eq!(exit_codes(&config), vec![EXIT_CONFIG]);
+    }
+
+    #[test]
+    fn busy_address_is_bind_error() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let config = config(&format!("udp-address: {}\nkeys-file: {}\n", socket.local_addr().unwrap(), dir.path().join("keys").display()));
+        assert_eq!(exit_codes(&config), vec![EXIT_BIND]);
+    }
+}
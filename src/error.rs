@@ -0,0 +1,98 @@
+//! Errors that stop the node and exit codes they are reported with.
+
+use std::fmt;
+
+use failure::Error;
+
+/// Exit code of invalid arguments or config file.
+pub const EXIT_CONFIG: i32 = 2;
+/// Exit code of a failure to bind an address.
+pub const EXIT_BIND: i32 = 3;
+/// Exit code of invalid or inaccessible DHT keys.
+pub const EXIT_KEYS: i32 = 4;
+/// Exit code of a failure of the running node.
+pub const EXIT_RUNTIME: i32 = 5;
+
+/// Error that stops the node. Its class determines the exit code of the
+/// process so that supervisors can tell failures apart.
+#[derive(Debug)]
+pub enum NodeError {
+    /// Invalid arguments or config file.
+    Config(Error),
+    /// Failure to bind a UDP or TCP address.
+    Bind(Error),
+    /// Invalid, unreadable or unwritable DHT keys.
+    Keys(Error),
+    /// Failure of the running node.
+    Runtime(Error),
+}
+
+impl NodeError {
+    /// Classify an error of the running node. Bind errors are recognized by
+    /// `BindError` they are made of, all other errors are runtime ones.
+    pub fn from_runtime(error: Error) -> Self {
+        if error.downcast_ref::<BindError>().is_some() {
+            NodeError::Bind(error)
+        } else {
+            NodeError::Runtime(error)
+        }
+    }
+
+    /// Exit code of the process for the error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            NodeError::Config(_) => EXIT_CONFIG,
+            NodeError::Bind(_) => EXIT_BIND,
+            NodeError::Keys(_) => EXIT_KEYS,
+            NodeError::Runtime(_) => EXIT_RUNTIME,
+        }
+    }
+}
+
+impl fmt::Display for NodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NodeError::Config(e) | NodeError::Bind(e) | NodeError::Keys(e) | NodeError::Runtime(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Exit after a command that doesn't run the node. The error is printed to
+/// stderr and the exit code depends on its class.
+pub fn exit_command(result: Result<(), NodeError>) -> ! {
+    match result {
+        Ok(()) => std::process::exit(0),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(e.exit_code())
+        },
+    }
+}
+
+/// Failure to bind a socket to an address. It's a separate type so that bind
+/// errors can be told apart from other errors of the running node.
+#[derive(Debug)]
+pub struct BindError(pub String);
+
+impl fmt::Display for BindError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for BindError { }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use failure::format_err;
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(NodeError::Config(format_err!("config")).exit_code(), 2);
+        assert_eq!(NodeError::Keys(format_err!("keys")).exit_code(), 4);
+        assert_eq!(NodeError::from_runtime(BindError("bind".to_owned()).into()).exit_code(), 3);
+        assert_eq!(NodeError::from_runtime(format_err!("runtime")).exit_code(), 5);
+    }
+}
@@ -0,0 +1,60 @@
+//! Health check endpoint for load balancers.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use failure::Error;
+use futures::StreamExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+
+use crate::error::BindError;
+
+/// Response to a health check.
+fn response(uptime: Duration, bootstrapped: bool) -> String {
+    format!("OK\nuptime: {}\nbootstrapped: {}\n", uptime.as_secs(), bootstrapped)
+}
+
+/// Run TCP server that responds with `OK`, uptime in seconds and whether the
+/// DHT server is bootstrapped to every connection and closes it. The response
+/// doesn't depend on the request so any plain TCP probe works.
+pub async fn run_health(addr: SocketAddr, start: Instant, bootstrapped: Arc<AtomicBool>) -> Result<(), Error> {
+    let mut listener = TcpListener::bind(&addr).await
+        .map_err(|e| BindError(format!("Failed to bind health check endpoint to {}: {}", addr, e)))?;
+
+    info!("Running health check endpoint on {}", addr);
+
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to accept health check connection: {}", e);
+                continue;
+            },
+        };
+
+        let response = response(start.elapsed(), bootstrapped.load(Ordering::Relaxed));
+        tokio::spawn(async move {
+            let res = stream.write_all(response.as_bytes()).await;
+            if let Err(e) = res {
+                debug!("Failed to respond to health check: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_format() {
+        assert_eq!(response(Duration::from_secs(42), true), "OK\nuptime: 42\nbootstrapped: true\n");
+        assert_eq!(response(Duration::from_secs(0), false), "OK\nuptime: 0\nbootstrapped: false\n");
+    }
+}
@@ -0,0 +1,314 @@
+//! Filtering of incoming traffic by source IP networks.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Maximum number of banned IPs whose drops are remembered as logged. Drops
+/// from other banned IPs are not logged after it's reached.
+const MAX_LOGGED_IPS: usize = 4096;
+
+/// Loopback networks.
+const LOOPBACK_NETWORKS: [&str; 2] = ["127.0.0.0/8", "::1"];
+/// Networks that are not routed in the Internet: RFC1918 and link-local IPv4,
+/// unique local (ULA) and link-local IPv6.
+const PRIVATE_NETWORKS: [&str; 6] = ["10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16", "169.254.0.0/16", "fc00::/7", "fe80::/10"];
+
+/// IPv4-mapped IPv6 addresses are matched as IPv4 addresses since IPv4 peers
+/// are seen this way by IPv6 sockets in dual mode.
+pub fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => match ip.segments() {
+            [0, 0, 0, 0, 0, 0xffff, ..] => ip.to_ipv4().map_or(IpAddr::V6(ip), IpAddr::V4),
+            _ => IpAddr::V6(ip),
+        },
+        ip => ip,
+    }
+}
+
+/// Keep only the first `prefix` bits of the IP as `u128`.
+fn network_bits(ip: IpAddr, prefix: u8) -> u128 {
+    let (bits, len) = match ip {
+        IpAddr::V4(ip) => (u128::from(u32::from(ip)), 32),
+        IpAddr::V6(ip) => (u128::from(ip), 128),
+    };
+    let host_bits = u32::from(len - prefix);
+    bits.checked_shr(host_bits).unwrap_or(0)
+}
+
+/// IP network in CIDR notation, e.g. `10.0.0.0/8`. A bare IP address is a
+/// network of a single address.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Cidr {
+    /// Address of the network
+    pub ip: IpAddr,
+    /// Number of leading bits of the network address
+    pub prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(2, '/');
+        let ip = parts.next().unwrap_or_default();
+        let prefix = parts.next();
+        let parsed_ip: IpAddr = ip.parse().map_err(|_| format!("'{}' is not a valid IP address", ip))?;
+        let ip = canonical(parsed_ip);
+        // prefix of IPv4-mapped network is counted from the start of IPv6
+        // address
+        let (min_prefix, max_prefix) = match (parsed_ip, ip) {
+            (IpAddr::V6(_), IpAddr::V4(_)) => (96, 128),
+            (IpAddr::V4(_), _) => (0, 32),
+            _ => (0, 128),
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>()
+                .ok()
+                .filter(|&prefix| prefix >= min_prefix && prefix <= max_prefix)
+                .ok_or_else(|| format!("'{}' is not a valid prefix length for {}", prefix, parsed_ip))?,
+            None => max_prefix,
+        };
+
+        Ok(Cidr { ip, prefix: prefix - min_prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.ip, self.prefix)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        let s = String::deserialize(deserializer)?;
+
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        serializer.collect_str(self)
+    }
+}
+
+/// Set of IP networks. Networks are grouped by prefix length so a lookup
+/// takes one hash set lookup per distinct prefix length.
+#[derive(Clone, Default, Debug)]
+pub struct IpNetworks {
+    v4: HashMap<u8, HashSet<u128>>,
+    v6: HashMap<u8, HashSet<u128>>,
+}
+
+impl IpNetworks {
+    pub fn new(networks: &[Cidr]) -> Self {
+        let mut result = IpNetworks::default();
+        for network in networks {
+            let by_prefix = if network.ip.is_ipv4() { &mut result.v4 } else { &mut result.v6 };
+            by_prefix
+                .entry(network.prefix)
+                .or_default()
+                .insert(network_bits(network.ip, network.prefix));
+        }
+        result
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_empty() && self.v6.is_empty()
+    }
+
+    /// Check whether the IP address belongs to any of the networks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = canonical(ip);
+        let by_prefix = if ip.is_ipv4() { &self.v4 } else { &self.v6 };
+        by_prefix
+            .iter()
+            .any(|(&prefix, networks)| networks.contains(&network_bits(ip, prefix)))
+    }
+}
+
+/// Scope where an IP address is reachable from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AddrScope {
+    /// Reachable from the Internet, including unspecified addresses that
+    /// stand for all interfaces.
+    Public,
+    /// Reachable only from the same host.
+    Loopback,
+    /// Reachable only from the local network.
+    Private,
+}
+
+/// Classify the IP address by where it's reachable from.
+pub fn addr_scope(ip: IpAddr) -> AddrScope {
+    let networks = |cidrs: &[&str]| IpNetworks::new(&cidrs.iter().map(|cidr| cidr.parse().expect("Invalid network")).collect::<Vec<_>>());
+    if networks(&LOOPBACK_NETWORKS).contains(ip) {
+        AddrScope::Loopback
+    } else if networks(&PRIVATE_NETWORKS).contains(ip) {
+        AddrScope::Private
+    } else {
+        AddrScope::Public
+    }
+}
+
+/// List of banned IP networks. Traffic from them is dropped, the first drop
+/// from each banned IP is logged.
+pub struct BanList {
+    networks: IpNetworks,
+    logged: HashSet<IpAddr>,
+}
+
+impl BanList {
+    pub fn new(networks: &[Cidr]) -> Self {
+        BanList {
+            networks: IpNetworks::new(networks),
+            logged: HashSet::new(),
+        }
+    }
+
+    /// Check whether the IP is banned. `what` describes the dropped traffic
+    /// in the log, e.g. "UDP packets".
+    pub fn is_banned(&mut self, ip: IpAddr, what: &str) -> bool {
+        if self.networks.is_empty() || !self.networks.contains(ip) {
+            return false;
+        }
+
+        if self.logged.len() < MAX_LOGGED_IPS && self.logged.insert(ip) {
+            info!("Dropping {} from banned IP {}", what, ip);
+        }
+
+        true
+    }
+}
+
+/// Filter of incoming TCP connections. Connections from banned networks are
+/// dropped, other connections are accepted only from allowed networks unless
+/// the allow list is empty.
+pub struct TcpFilter {
+    ban_list: BanList,
+    allow_list: IpNetworks,
+}
+
+impl TcpFilter {
+    pub fn new(ban_list: &[Cidr], allow_list: &[Cidr]) -> Self {
+        TcpFilter {
+            ban_list: BanList::new(ban_list),
+            allow_list: IpNetworks::new(allow_list),
+        }
+    }
+
+    /// Check whether a connection from the IP should be rejected. Returns
+    /// the list that rejects it, `ban-list` or `allow-list`.
+    pub fn rejects(&mut self, ip: IpAddr) -> Option<&'static str> {
+        if self.ban_list.is_banned(ip, "TCP connections") {
+            return Some("ban-list");
+        }
+
+        if !self.allow_list.is_empty() && !self.allow_list.contains(ip) {
+            debug!("Rejecting TCP connection from {} not in the allow list", ip);
+            return Some("allow-list");
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn networks(cidrs: &[&str]) -> IpNetworks {
+        IpNetworks::new(&cidrs.iter().map(|cidr| cidr.parse().unwrap()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn parse_cidr() {
+        assert_eq!("10.0.0.0/8".parse(), Ok(Cidr { ip: "10.0.0.0".parse().unwrap(), prefix: 8 }));
+        assert_eq!("1.2.3.4".parse(), Ok(Cidr { ip: "1.2.3.4".parse().unwrap(), prefix: 32 }));
+        assert_eq!("2001:db8::/32".parse(), Ok(Cidr { ip: "2001:db8::".parse().unwrap(), prefix: 32 }));
+        assert_eq!("::ffff:1.2.3.0/120".parse(), Ok(Cidr { ip: "1.2.3.0".parse().unwrap(), prefix: 24 }));
+        assert_eq!("::ffff:1.2.3.4".parse(), Ok(Cidr { ip: "1.2.3.4".parse().unwrap(), prefix: 32 }));
+        assert!("::ffff:1.2.3.4/32".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert_eq!("10.0.0.0/8".parse::<Cidr>().unwrap().to_string(), "10.0.0.0/8");
+    }
+
+    #[test]
+    fn contains_network() {
+        let networks = networks(&["10.0.0.0/8"]);
+        assert!(networks.contains("10.0.0.1".parse().unwrap()));
+        assert!(networks.contains("10.255.255.255".parse().unwrap()));
+        assert!(networks.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!networks.contains("11.0.0.1".parse().unwrap()));
+        assert!(!networks.contains("::a00:1".parse().unwrap()));
+    }
+
+    #[test]
+    fn contains_single_address() {
+        let networks = networks(&["1.2.3.4/32", "2001:db8::/32"]);
+        assert!(networks.contains("1.2.3.4".parse().unwrap()));
+        assert!(!networks.contains("1.2.3.5".parse().unwrap()));
+        assert!(networks.contains("2001:db8::1".parse().unwrap()));
+        assert!(!networks.contains("2001:db9::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn contains_everything() {
+        let networks = networks(&["0.0.0.0/0"]);
+        assert!(networks.contains("1.2.3.4".parse().unwrap()));
+        assert!(!networks.contains("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ban_list() {
+        let mut ban_list = BanList::new(&["10.0.0.0/8".parse().unwrap()]);
+        assert!(ban_list.is_banned("10.0.0.1".parse().unwrap(), "UDP packets"));
+        assert!(ban_list.is_banned("10.0.0.1".parse().unwrap(), "UDP packets"));
+        assert!(!ban_list.is_banned("1.2.3.4".parse().unwrap(), "UDP packets"));
+        assert_eq!(ban_list.logged.len(), 1);
+    }
+
+    #[test]
+    fn tcp_filter_allow_list() {
+        let mut filter = TcpFilter::new(&[], &["192.168.0.0/16".parse().unwrap()]);
+        assert_eq!(filter.rejects("192.168.1.2".parse().unwrap()), None);
+        assert_eq!(filter.rejects("10.0.0.1".parse().unwrap()), Some("allow-list"));
+
+        let mut filter = TcpFilter::new(&[], &[]);
+        assert_eq!(filter.rejects("10.0.0.1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn tcp_filter_deny_takes_precedence() {
+        let mut filter = TcpFilter::new(&["192.168.1.0/24".parse().unwrap()], &["192.168.0.0/16".parse().unwrap()]);
+        assert_eq!(filter.rejects("192.168.2.1".parse().unwrap()), None);
+        assert_eq!(filter.rejects("192.168.1.1".parse().unwrap()), Some("ban-list"));
+    }
+
+    #[test]
+    fn scope_of_addresses() {
+        for (ip, scope) in &[
+            ("127.0.0.1", AddrScope::Loopback),
+            ("::1", AddrScope::Loopback),
+            ("::ffff:127.0.0.1", AddrScope::Loopback),
+            ("10.1.2.3", AddrScope::Private),
+            ("172.31.0.1", AddrScope::Private),
+            ("192.168.1.1", AddrScope::Private),
+            ("169.254.0.1", AddrScope::Private),
+            ("fd00::1", AddrScope::Private),
+            ("fe80::1", AddrScope::Private),
+            ("0.0.0.0", AddrScope::Public),
+            ("::", AddrScope::Public),
+            ("172.32.0.1", AddrScope::Public),
+            ("2001:db8::1", AddrScope::Public),
+        ] {
+            assert_eq!(addr_scope(ip.parse().unwrap()), *scope, "{}", ip);
+        }
+    }
+}
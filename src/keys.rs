@@ -0,0 +1,446 @@
+//! Loading, generating and saving DHT keys.
+
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+
+use failure::{Error, format_err};
+use tox::crypto::*;
+
+use crate::node_config::KeysFormat;
+
+/// Save DHT keys to a file in the specified format.
+pub fn save_keys(keys_file: &str, pk: PublicKey, sk: &SecretKey, format: KeysFormat) -> Result<(), Error> {
+    #[cfg(unix)]
+    use std::os::unix::fs::OpenOptionsExt;
+
+    #[cfg(not(unix))]
+    let file = File::create(keys_file);
+
+    #[cfg(unix)]
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(keys_file);
+
+    let mut file = file
+        .map_err(|e| format_err!("Failed to create the keys file '{}': {}", keys_file, e))?;
+
+    let res = match format {
+        KeysFormat::Raw => {
+            file.write_all(pk.as_ref())
+                .and_then(|()| file.write_all(&sk[0..SECRETKEYBYTES]))
+        },
+        KeysFormat::Base64 => {
+            writeln!(file, "{}", base64::encode(pk.as_ref()))
+                .and_then(|()| writeln!(file, "{}", base64::encode(&sk[0..SECRETKEYBYTES])))
+        },
+        KeysFormat::Libsodium => {
+            file.write_all(&sk[0..SECRETKEYBYTES])
+                .and_then(|()| file.write_all(pk.as_ref()))
+        },
+    };
+    res.map_err(|e| format_err!("Failed to save keys to the keys file '{}': {}", keys_file, e))
+}
+
+/// Load DHT keys from a file. Binary keys are read only in the layout of the
+/// format since raw and libsodium files have the same length, base64 keys are
+/// recognized whatever the format is.
+pub fn try_load_keys(mut file: File, format: KeysFormat) -> Result<(PublicKey, SecretKey), Error> {
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)
+        .map_err(|e| format_err!("Failed to read keys from the keys file: {}", e))?;
+
+    let binary_keys = match format {
+        KeysFormat::Libsodium => parse_libsodium_keys(&buf),
+        KeysFormat::Raw | KeysFormat::Base64 => parse_raw_keys(&buf),
+    };
+    binary_keys.or_else(|binary_error| {
+        if is_base64_text(&buf) {
+            parse_base64_keys(&buf)
+        } else {
+            Err(binary_error)
+        }
+    })
+}
+
+/// Check if the buffer contains only base64 symbols and whitespaces.
+fn is_base64_text(buf: &[u8]) -> bool {
+    !buf.is_empty() && buf.iter().all(|&b|
+        b.is_ascii_alphanumeric() || b == b'+' || b == b'/' || b == b'=' || b.is_ascii_whitespace()
+    )
+}
+
+/// Check that the public key corresponds to the secret key.
+pub fn validate_keys(pk: &PublicKey, sk: &SecretKey) -> Result<(), Error> {
+    if *pk != sk.public_key() {
+        return Err(format_err!("The loaded public key does not correspond to the loaded secret key"));
+    }
+    Ok(())
+}
+
+/// Parse keys stored sequentially as raw bytes.
+fn parse_raw_keys(buf: &[u8]) -> Result<(PublicKey, SecretKey), Error> {
+    if buf.len() < PUBLICKEYBYTES + SECRETKEYBYTES {
+        return Err(format_err!("Failed to read keys from the keys file: file is too short, {} bytes instead of at least {}",
+            buf.len(), PUBLICKEYBYTES + SECRETKEYBYTES));
+    }
+    let pk = PublicKey::from_slice(&buf[..PUBLICKEYBYTES])
+        .ok_or_else(|| format_err!("Failed to read public key from the keys file"))?;
+    let sk = SecretKey::from_slice(&buf[PUBLICKEYBYTES..PUBLICKEYBYTES + SECRETKEYBYTES])
+        .ok_or_else(|| format_err!("Failed to read secret key from the keys file"))?;
+    validate_keys(&pk, &sk)?;
+    Ok((pk, sk))
+}
+
+/// Parse keys stored sequentially as raw bytes with the 32 bytes `crypto_box`
+/// secret key followed by the 32 bytes public key, the opposite of the raw
+/// format.
+fn parse_libsodium_keys(buf: &[u8]) -> Result<(PublicKey, SecretKey), Error> {
+    if buf.len() != SECRETKEYBYTES + PUBLICKEYBYTES {
+        return Err(format_err!("Failed to read libsodium keys from the keys file: {} bytes instead of {}",
+            buf.len(), SECRETKEYBYTES + PUBLICKEYBYTES));
+    }
+    let sk = SecretKey::from_slice(&buf[..SECRETKEYBYTES])
+        .ok_or_else(|| format_err!("Failed to read secret key from the keys file"))?;
+    let pk = PublicKey::from_slice(&buf[SECRETKEYBYTES..])
+        .ok_or_else(|| format_err!("Failed to read public key from the keys file"))?;
+    validate_keys(&pk, &sk)?;
+    Ok((pk, sk))
+}
+
+/// Parse keys stored as two lines of standard base64.
+fn parse_base64_keys(buf: &[u8]) -> Result<(PublicKey, SecretKey), Error> {
+    let text = std::str::from_utf8(buf)
+        .map_err(|e| format_err!("Failed to read base64 keys from the keys file: {}", e))?;
+    let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+
+    let pk_bytes = lines.next()
+        .ok_or_else(|| format_err!("Failed to read public key from the keys file: missing line"))
+        .and_then(|line| base64::decode(line)
+            .map_err(|e| format_err!("Failed to decode base64 public key from the keys file: {}", e)))?;
+    let sk_bytes = lines.next()
+        .ok_or_else(|| format_err!("Failed to read secret key from the keys file: missing line"))
+        .and_then(|line| base64::decode(line)
+            .map_err(|e| format_err!("Failed to decode base64 secret key from the keys file: {}", e)))?;
+
+    let pk = PublicKey::from_slice(&pk_bytes)
+        .ok_or_else(|| format_err!("Failed to read public key from the keys file: invalid length"))?;
+    let sk = SecretKey::from_slice(&sk_bytes)
+        .ok_or_else(|| format_err!("Failed to read secret key from the keys file: invalid length"))?;
+    validate_keys(&pk, &sk)?;
+    Ok((pk, sk))
+}
+
+/// Check the keys file without generating new keys. Unlike `try_load_keys`
+/// the raw format requires the exact length of the file so that trailing
+/// garbage is reported too.
+pub fn check_keys_file(keys_file: &str, format: KeysFormat) -> Result<PublicKey, Error> {
+    let buf = std::fs::read(keys_file)
+        .map_err(|e| format_err!("Failed to read the keys file '{}': {}", keys_file, e))?;
+
+    if !is_base64_text(&buf) && buf.len() != PUBLICKEYBYTES + SECRETKEYBYTES {
+        return Err(format_err!(
+            "Invalid length of the keys file '{}': {} bytes instead of {}",
+            keys_file, buf.len(), PUBLICKEYBYTES + SECRETKEYBYTES
+        ));
+    }
+
+    let file = File::open(keys_file)
+        .map_err(|e| format_err!("Can't open keys file '{}': {}", keys_file, e))?;
+    let (pk, _sk) = try_load_keys(file, format)
+        .map_err(|e| format_err!("Invalid keys file '{}': {}", keys_file, e))?;
+    Ok(pk)
+}
+
+/// Load DHT secret key stored as a hex string from a file. Surrounding
+/// whitespaces are ignored.
+pub fn load_secret_key_file(sk_file: &str) -> Result<SecretKey, Error> {
+    let sk_hex = std::fs::read_to_string(sk_file)
+        .map_err(|e| format_err!("Failed to read the secret key file '{}': {}", sk_file, e))?;
+    let sk_bytes: [u8; SECRETKEYBYTES] = hex::FromHex::from_hex(sk_hex.trim())
+        .map_err(|e| format_err!("Invalid DHT secret key in the file '{}': {}", sk_file, e))?;
+    SecretKey::from_slice(&sk_bytes)
+        .ok_or_else(|| format_err!("Invalid DHT secret key in the file '{}'", sk_file))
+}
+
+/// Load DHT keys from a binary file or generate and save them if file does not
+/// exist.
+pub fn load_or_gen_keys(keys_file: &str, format: KeysFormat) -> Result<(PublicKey, SecretKey), Error> {
+    match File::open(keys_file) {
+        Ok(file) => try_load_keys(file, format)
+            .map_err(|e| format_err!("Invalid keys file '{}': {}", keys_file, e)),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {
+            info!("Generating new DHT keys and storing them to '{}'", keys_file);
+            let (pk, sk) = gen_keypair();
+            save_keys(keys_file, pk, &sk, format)?;
+            Ok((pk, sk))
+        },
+        Err(e) => Err(format_err!("Failed to read the keys file '{}': {}", keys_file, e)),
+    }
+}
+
+/// Load DHT keys from the first keys file with the expected public key. If
+/// there is no expected public key or none of the files contains it then keys
+/// are loaded from the first file or generated and saved there in the
+/// specified format.
+pub fn select_keys(keys_files: &[String], expected_pk: Option<PublicKey>, format: KeysFormat) -> Result<(PublicKey, SecretKey), Error> {
+    let first_keys_file = keys_files.first().ok_or_else(|| format_err!("No keys files specified"))?;
+
+    if let Some(expected_pk) = expected_pk {
+        for keys_file in keys_files {
+            let keys = File::open(keys_file)
+                .map_err(Error::from)
+                .and_then(|file| try_load_keys(file, format));
+            match keys {
+                Ok((pk, sk)) if pk == expected_pk => {
+                    info!("Using DHT keys from '{}'", keys_file);
+                    return Ok((pk, sk))
+                },
+                Ok(_) => debug!("Keys file '{}' doesn't contain the expected public key", keys_file),
+                Err(e) => warn!("Skipping keys file '{}': {}", keys_file, e),
+            }
+        }
+
+        warn!("None of the keys files contains the expected public key, using '{}'", first_keys_file);
+    }
+
+    load_or_gen_keys(first_keys_file, format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    fn gen_keys_file(dir: &TempDir, name: &str) -> (String, PublicKey) {
+        let path = dir.path().join(name).to_str().unwrap().to_owned();
+        let (pk, sk) = gen_keypair();
+        save_keys(&path, pk, &sk, KeysFormat::Raw).unwrap();
+        (path, pk)
+    }
+
+    #[test]
+    fn load_secret_key_file_with_trailing_newline() {
+        crypto_init().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret_key").to_str().unwrap().to_owned();
+        let (_pk, sk) = gen_keypair();
+        std::fs::write(&path, format!("{}\n", hex::encode(&sk[..]))).unwrap();
+
+        assert_eq!(load_secret_key_file(&path).unwrap(), sk);
+    }
+
+    #[test]
+    fn load_secret_key_file_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret_key").to_str().unwrap().to_owned();
+        std::fs::write(&path, "not a key\n").unwrap();
+
+        assert!(load_secret_key_file(&path).is_err());
+        assert!(load_secret_key_file(dir.path().join("missing").to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn select_keys_by_pk() {
+        crypto_init().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let (path_1, _pk_1) = gen_keys_file(&dir, "keys_1");
+        let (path_2, pk_2) = gen_keys_file(&dir, "keys_2");
+        let (path_3, _pk_3) = gen_keys_file(&dir, "keys_3");
+
+        let (pk, sk) = select_keys(&[path_1, path_2, path_3], Some(pk_2), KeysFormat::Raw).unwrap();
+        assert_eq!(pk, pk_2);
+        assert_eq!(sk.public_key(), pk_2);
+    }
+
+    #[test]
+    fn select_keys_falls_back_to_first_file() {
+        crypto_init().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let (path_1, pk_1) = gen_keys_file(&dir, "keys_1");
+        let (path_2, _pk_2) = gen_keys_file(&dir, "keys_2");
+        let (unknown_pk, _) = gen_keypair();
+
+        let (pk, _sk) = select_keys(&[path_1.clone(), path_2.clone()], Some(unknown_pk), KeysFormat::Raw).unwrap();
+        assert_eq!(pk, pk_1);
+        let (pk, _sk) = select_keys(&[path_1, path_2], None, KeysFormat::Raw).unwrap();
+        assert_eq!(pk, pk_1);
+    }
+
+    #[test]
+    fn select_keys_unwritable_file() {
+        crypto_init().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing").join("keys").to_str().unwrap().to_owned();
+
+        let error = select_keys(&[path], None, KeysFormat::Raw).unwrap_err();
+        assert!(error.to_string().contains("Failed to create the keys file"), "{}", error);
+    }
+
+    #[test]
+    fn select_keys_generates_missing_file() {
+        crypto_init().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys").to_str().unwrap().to_owned();
+
+        let (pk, _sk) = select_keys(std::slice::from_ref(&path), None, KeysFormat::Raw).unwrap();
+        let (loaded_pk, _sk) = try_load_keys(File::open(path).unwrap(), KeysFormat::Raw).unwrap();
+        assert_eq!(loaded_pk, pk);
+    }
+
+    fn round_trip(format: KeysFormat) {
+        crypto_init().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys").to_str().unwrap().to_owned();
+        let (pk, sk) = gen_keypair();
+        save_keys(&path, pk, &sk, format).unwrap();
+
+        let (loaded_pk, loaded_sk) = try_load_keys(File::open(&path).unwrap(), format).unwrap();
+        assert_eq!(loaded_pk, pk);
+        assert_eq!(loaded_sk, sk);
+    }
+
+    #[test]
+    fn round_trip_raw() {
+        round_trip(KeysFormat::Raw);
+    }
+
+    #[test]
+    fn round_trip_base64() {
+        round_trip(KeysFormat::Base64);
+    }
+
+    #[test]
+    fn round_trip_libsodium() {
+        round_trip(KeysFormat::Libsodium);
+    }
+
+    #[test]
+    fn libsodium_keys_layout() {
+        crypto_init().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys").to_str().unwrap().to_owned();
+        let (pk, sk) = gen_keypair();
+        save_keys(&path, pk, &sk, KeysFormat::Libsodium).unwrap();
+
+        let content = std::fs::read(&path).unwrap();
+        assert_eq!(&content[..SECRETKEYBYTES], &sk[..]);
+        assert_eq!(&content[SECRETKEYBYTES..], pk.as_ref());
+        assert_eq!(check_keys_file(&path, KeysFormat::Libsodium).unwrap(), pk);
+    }
+
+    #[test]
+    fn binary_layout_is_not_guessed() {
+        crypto_init().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys").to_str().unwrap().to_owned();
+        let (pk, sk) = gen_keypair();
+
+        save_keys(&path, pk, &sk, KeysFormat::Libsodium).unwrap();
+        assert!(try_load_keys(File::open(&path).unwrap(), KeysFormat::Raw).is_err());
+        let error = load_or_gen_keys(&path, KeysFormat::Raw).unwrap_err();
+        assert!(error.to_string().contains(&format!("Invalid keys file '{}'", path)), "{}", error);
+
+        save_keys(&path, pk, &sk, KeysFormat::Raw).unwrap();
+        assert!(try_load_keys(File::open(&path).unwrap(), KeysFormat::Libsodium).is_err());
+        let error = check_keys_file(&path, KeysFormat::Libsodium).unwrap_err();
+        assert!(error.to_string().contains(&format!("Invalid keys file '{}'", path)), "{}", error);
+
+        // base64 keys are recognized with any format
+        save_keys(&path, pk, &sk, KeysFormat::Base64).unwrap();
+        assert_eq!(try_load_keys(File::open(&path).unwrap(), KeysFormat::Libsodium).unwrap().0, pk);
+    }
+
+    #[test]
+    fn libsodium_keys_wrong_length() {
+        crypto_init().unwrap();
+        let (pk, sk) = gen_keypair();
+        let mut content = sk[..].to_vec();
+        content.extend_from_slice(pk.as_ref());
+        assert_eq!(parse_libsodium_keys(&content).unwrap().0, pk);
+
+        let error = parse_libsodium_keys(&content[..63]).unwrap_err();
+        assert!(error.to_string().contains("63 bytes instead of 64"), "{}", error);
+        content.push(0);
+        let error = parse_libsodium_keys(&content).unwrap_err();
+        assert!(error.to_string().contains("65 bytes instead of 64"), "{}", error);
+    }
+
+    #[test]
+    fn load_keys_truncated_base64() {
+        crypto_init().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys").to_str().unwrap().to_owned();
+        let (pk, sk) = gen_keypair();
+        save_keys(&path, pk, &sk, KeysFormat::Base64).unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, &content[..content.len() - 10]).unwrap();
+
+        let error = try_load_keys(File::open(&path).unwrap(), KeysFormat::Base64).unwrap_err();
+        assert!(error.to_string().contains("secret key"), "{}", error);
+    }
+
+    #[test]
+    fn load_keys_mismatched_pk() {
+        crypto_init().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys").to_str().unwrap().to_owned();
+        let (pk, _sk) = gen_keypair();
+        let (_pk, sk) = gen_keypair();
+        save_keys(&path, pk, &sk, KeysFormat::Raw).unwrap();
+
+        assert!(try_load_keys(File::open(&path).unwrap(), KeysFormat::Raw).is_err());
+    }
+
+    #[test]
+    fn check_keys_file_valid() {
+        crypto_init().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let (path, pk) = gen_keys_file(&dir, "keys");
+        assert_eq!(check_keys_file(&path, KeysFormat::Raw).unwrap(), pk);
+
+        let path = dir.path().join("keys_base64").to_str().unwrap().to_owned();
+        let (pk, sk) = gen_keypair();
+        save_keys(&path, pk, &sk, KeysFormat::Base64).unwrap();
+        assert_eq!(check_keys_file(&path, KeysFormat::Raw).unwrap(), pk);
+    }
+
+    #[test]
+    fn check_keys_file_corrupted() {
+        crypto_init().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let (path, _pk) = gen_keys_file(&dir, "keys");
+        let mut content = std::fs::read(&path).unwrap();
+
+        // flipped bit of the public key
+        content[0] ^= 1;
+        std::fs::write(&path, &content).unwrap();
+        let error = check_keys_file(&path, KeysFormat::Raw).unwrap_err();
+        assert!(error.to_string().contains("does not correspond"), "{}", error);
+
+        // trailing garbage
+        content[0] ^= 1;
+        content.push(0);
+        std::fs::write(&path, &content).unwrap();
+        let error = check_keys_file(&path, KeysFormat::Raw).unwrap_err();
+        assert!(error.to_string().contains("65 bytes instead of 64"), "{}", error);
+
+        // missing file
+        let missing = dir.path().join("missing").to_str().unwrap().to_owned();
+        let error = check_keys_file(&missing, KeysFormat::Raw).unwrap_err();
+        assert!(error.to_string().contains(&missing), "{}", error);
+    }
+
+    #[test]
+    fn validate_keys_pair() {
+        crypto_init().unwrap();
+        let (pk, sk) = gen_keypair();
+        let (other_pk, _sk) = gen_keypair();
+        assert!(validate_keys(&pk, &sk).is_ok());
+        assert!(validate_keys(&other_pk, &sk).is_err());
+    }
+}
@@ -0,0 +1,421 @@
+//! Logger backends configuration.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Result as IoResult, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local, Utc};
+use env_logger::{Builder, Env};
+use env_logger::filter::{self, Filter};
+use env_logger::fmt::Formatter;
+use futures::{future, StreamExt};
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde_json::json;
+
+use crate::node_config::LogFormat;
+
+/// Maximum number of distinct messages tracked by `LogThrottle`. Messages
+/// beyond it are logged without throttling.
+const MAX_THROTTLED_MESSAGES: usize = 1024;
+
+/// Create `env_logger` builder. The log level is taken from `RUST_LOG`
+/// environment variable with `info` by default unless `log_level` is
+/// specified.
+pub fn env_logger_builder(log_level: Option<LevelFilter>) -> Builder {
+    let env = Env::default()
+        .filter_or("RUST_LOG", "info");
+    let mut builder = Builder::from_env(env);
+    if let Some(log_level) = log_level {
+        builder.filter_level(log_level);
+    }
+    builder
+}
+
+/// Serialize the log record to a single line JSON object.
+fn json_record(timestamp: DateTime<Utc>, record: &Record) -> String {
+    json!({
+        "timestamp": timestamp.to_rfc3339(),
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    }).to_string()
+}
+
+/// Format for `env_logger` that writes one JSON object per record.
+pub fn format_json(buf: &mut Formatter, record: &Record) -> IoResult<()> {
+    writeln!(buf, "{}", json_record(Utc::now(), record))
+}
+
+/// Format for `env_logger` that writes only local time, level and message.
+/// The level is colored when colors are enabled for the target.
+pub fn format_compact(buf: &mut Formatter, record: &Record) -> IoResult<()> {
+    writeln!(buf, "{} {:<5} {}", Local::now().format("%H:%M:%S"), buf.default_styled_level(record.level()), record.args())
+}
+
+/// Log file that is rotated when writing to it would exceed `max_size` bytes.
+/// Rotated files get suffixes from `.1` for the newest one to `.<keep>`,
+/// older ones are removed.
+pub struct RotatingFile {
+    path: PathBuf,
+    /// Size after which the file is rotated. 0 disables rotation.
+    max_size: u64,
+    /// Number of rotated files to keep.
+    keep: usize,
+    file: File,
+    /// Current size of the file.
+    size: u64,
+}
+
+impl RotatingFile {
+    /// Open the file for appending creating it if it doesn't exist.
+    pub fn open(path: PathBuf, max_size: u64, keep: usize) -> IoResult<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    /// Path of the rotated file with the number.
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    /// Shift rotated files by one dropping the oldest, move the current file
+    /// to `.1` and start a new one.
+    fn rotate(&mut self) -> IoResult<()> {
+        if self.keep > 0 {
+            let _ = fs::remove_file(self.rotated_path(self.keep));
+            for n in (1..self.keep).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    /// Write the buffer to the file rotating it first if the buffer doesn't
+    /// fit so that records are not split between files.
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        if self.max_size > 0 && self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.file.flush()
+    }
+}
+
+/// Format the record as a single line in the layout chosen by `format`. The
+/// full layout is the same as the default one of `env_logger`.
+fn format_line(format: LogFormat, record: &Record) -> String {
+    match format {
+        LogFormat::Compact => format!("{} {:<5} {}\n", Local::now().format("%H:%M:%S"), record.level(), record.args()),
+        LogFormat::Full => format!("[{} {:<5} {}] {}\n", Utc::now().format("%Y-%m-%dT%H:%M:%SZ"), record.level(), record.target(), record.args()),
+        LogFormat::Json => format!("{}\n", json_record(Utc::now(), record)),
+    }
+}
+
+/// `log` backend writing records to a rotating file. Records are filtered
+/// like by `env_logger` and flushed one by one so that they are not lost on
+/// crash.
+pub struct FileLogger {
+    filter: Filter,
+    format: LogFormat,
+    file: Mutex<RotatingFile>,
+}
+
+impl FileLogger {
+    /// Create new logger. The log level is taken from `RUST_LOG` environment
+    /// variable with `info` by default unless `log_level` is specified.
+    pub fn new(file: RotatingFile, log_level: Option<LevelFilter>, format: LogFormat) -> Self {
+        let mut builder = filter::Builder::new();
+        builder.parse(&std::env::var("RUST_LOG").unwrap_or_else(|_| "info".to_owned()));
+        if let Some(log_level) = log_level {
+            builder.filter_level(log_level);
+        }
+        FileLogger {
+            filter: builder.build(),
+            format,
+            file: Mutex::new(file),
+        }
+    }
+
+    /// Set the logger as the global one.
+    pub fn init(self) -> Result<(), SetLoggerError> {
+        log::set_max_level(self.filter.filter());
+        log::set_boxed_logger(Box::new(self))
+    }
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+
+        let line = format_line(self.format, record);
+        let mut file = self.file.lock().expect("Failed to lock log file");
+        // there is nowhere to report failures to write logs
+        let _ = file.write_all(line.as_bytes()).and_then(|()| file.flush());
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().expect("Failed to lock log file").flush();
+    }
+}
+
+/// Repetitions of a throttled message within the current window.
+struct Repeats {
+    level: Level,
+    /// Time when the message was logged and the window started.
+    started: Instant,
+    /// Number of repetitions that were not logged.
+    suppressed: u64,
+}
+
+/// Logger of repeated messages, e.g. errors of handling packets under attack.
+/// The first occurrence of a message is logged immediately, identical
+/// messages within the next `window` are only counted and reported with a
+/// single line when the window ends.
+pub struct LogThrottle {
+    window: Duration,
+    messages: Mutex<HashMap<String, Repeats>>,
+}
+
+impl LogThrottle {
+    /// Create new throttle. Zero `window` disables throttling.
+    pub fn new(window: Duration) -> Self {
+        LogThrottle {
+            window,
+            messages: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Log the message unless it was already logged within the window.
+    pub fn log(&self, level: Level, message: String) {
+        for (level, line) in self.check(level, message, Instant::now()) {
+            log!(level, "{}", line);
+        }
+    }
+
+    /// Get lines to log for the message. It's empty when the message is only
+    /// counted. When the message repeats after its window has ended the
+    /// summary of the previous window is logged before it.
+    fn check(&self, level: Level, message: String, now: Instant) -> Vec<(Level, String)> {
+        if self.window == Duration::from_secs(0) {
+            return vec![(level, message)];
+        }
+
+        let mut messages = self.messages.lock().expect("Failed to lock log throttle");
+        let full = messages.len() >= MAX_THROTTLED_MESSAGES;
+        let mut lines = Vec::new();
+        match messages.get_mut(&message) {
+            Some(repeats) if now.duration_since(repeats.started) < self.window => {
+                repeats.suppressed += 1;
+                return lines;
+            },
+            Some(repeats) => {
+                if repeats.suppressed > 0 {
+                    lines.push((repeats.level, self.summary(&message, repeats.suppressed)));
+                }
+                *repeats = Repeats { level, started: now, suppressed: 0 };
+            },
+            None if !full => {
+                messages.insert(message.clone(), Repeats { level, started: now, suppressed: 0 });
+            },
+            None => { },
+        }
+        lines.push((level, message));
+        lines
+    }
+
+    /// Summary line of the message with the number of its occurrences
+    /// within the window including the logged one.
+    fn summary(&self, message: &str, suppressed: u64) -> String {
+        format!("{} ({} occurrences in the last {} seconds)", message, suppressed + 1, self.window.as_secs())
+    }
+
+    /// Forget messages whose window has ended and return summaries of their
+    /// suppressed repetitions.
+    fn flush(&self, now: Instant) -> Vec<(Level, String)> {
+        let mut messages = self.messages.lock().expect("Failed to lock log throttle");
+        let mut summaries = Vec::new();
+        let window = self.window;
+        messages.retain(|message, repeats| {
+            if now.duration_since(repeats.started) < window {
+                return true;
+            }
+            if repeats.suppressed > 0 {
+                summaries.push((repeats.level, self.summary(message, repeats.suppressed)));
+            }
+            false
+        });
+        summaries
+    }
+
+    /// Log summaries of suppressed messages when their windows end. Never
+    /// completes.
+    pub async fn run(&self) {
+        if self.window == Duration::from_secs(0) {
+            return future::pending().await;
+        }
+
+        let mut wakeups = tokio::time::interval(self.window);
+        while wakeups.next().await.is_some() {
+            for (level, summary) in self.flush(Instant::now()) {
+                log!(level, "{}", summary);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use log::Level;
+    use serde_json::Value;
+
+    #[test]
+    fn json_record_is_valid_json() {
+        let record = Record::builder()
+            .args(format_args!("Running \"DHT\" server"))
+            .level(Level::Info)
+            .target("tox_node")
+            .build();
+        let line = json_record(Utc::now(), &record);
+        assert!(!line.contains('\n'));
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["level"], "INFO");
+        assert_eq!(value["target"], "tox_node");
+        assert_eq!(value["message"], "Running \"DHT\" server");
+        assert!(value["timestamp"].is_string());
+    }
+
+    #[test]
+    fn rotating_file_rotates() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("tox-node.log");
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+
+        for line in &["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(dir.path().join("tox-node.log.1")).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(dir.path().join("tox-node.log.2")).unwrap(), "second\n");
+        assert!(!dir.path().join("tox-node.log.3").exists());
+    }
+
+    #[test]
+    fn rotating_file_appends() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("tox-node.log");
+        fs::write(&path, "old\n").unwrap();
+
+        let mut file = RotatingFile::open(path.clone(), 0, 2).unwrap();
+        file.write_all(b"new\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old\nnew\n");
+        assert!(!dir.path().join("tox-node.log.1").exists());
+    }
+
+    #[test]
+    fn rotating_file_keeps_none() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("tox-node.log");
+        let mut file = RotatingFile::open(path.clone(), 10, 0).unwrap();
+
+        file.write_all(b"first\n").unwrap();
+        file.write_all(b"second\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second\n");
+        assert!(!dir.path().join("tox-node.log.1").exists());
+    }
+
+    #[test]
+    fn full_line_format() {
+        let record = Record::builder()
+            .args(format_args!("Running DHT server"))
+            .level(Level::Info)
+            .target("tox_node")
+            .build();
+        let line = format_line(LogFormat::Full, &record);
+        assert!(line.ends_with(" INFO  tox_node] Running DHT server\n"), "{}", line);
+    }
+
+    #[test]
+    fn throttle_logs_first_occurrence() {
+        let throttle = LogThrottle::new(Duration::from_secs(10));
+        let now = Instant::now();
+        assert_eq!(throttle.check(Level::Error, "error".to_owned(), now), vec![(Level::Error, "error".to_owned())]);
+        assert_eq!(throttle.check(Level::Warn, "other".to_owned(), now), vec![(Level::Warn, "other".to_owned())]);
+        assert!(throttle.check(Level::Error, "error".to_owned(), now + Duration::from_secs(1)).is_empty());
+        assert!(throttle.check(Level::Error, "error".to_owned(), now + Duration::from_secs(2)).is_empty());
+    }
+
+    #[test]
+    fn throttle_summarizes_repeats() {
+        let throttle = LogThrottle::new(Duration::from_secs(10));
+        let now = Instant::now();
+        throttle.check(Level::Error, "error".to_owned(), now);
+        throttle.check(Level::Error, "error".to_owned(), now + Duration::from_secs(1));
+        throttle.check(Level::Error, "error".to_owned(), now + Duration::from_secs(2));
+        throttle.check(Level::Warn, "other".to_owned(), now);
+
+        assert!(throttle.flush(now + Duration::from_secs(9)).is_empty());
+        assert_eq!(
+            throttle.flush(now + Duration::from_secs(10)),
+            vec![(Level::Error, "error (3 occurrences in the last 10 seconds)".to_owned())]
+        );
+        // the window starts again with the next occurrence
+        assert_eq!(throttle.check(Level::Error, "error".to_owned(), now + Duration::from_secs(11)), vec![(Level::Error, "error".to_owned())]);
+    }
+
+    #[test]
+    fn throttle_summarizes_before_next_window() {
+        let throttle = LogThrottle::new(Duration::from_secs(10));
+        let now = Instant::now();
+        throttle.check(Level::Error, "error".to_owned(), now);
+        throttle.check(Level::Error, "error".to_owned(), now + Duration::from_secs(1));
+
+        assert_eq!(throttle.check(Level::Error, "error".to_owned(), now + Duration::from_secs(10)), vec![
+            (Level::Error, "error (2 occurrences in the last 10 seconds)".to_owned()),
+            (Level::Error, "error".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn throttle_disabled() {
+        let throttle = LogThrottle::new(Duration::from_secs(0));
+        let now = Instant::now();
+        assert_eq!(throttle.check(Level::Error, "error".to_owned(), now).len(), 1);
+        assert_eq!(throttle.check(Level::Error, "error".to_owned(), now).len(), 1);
+    }
+}
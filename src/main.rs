@@ -7,35 +7,102 @@ extern crate log;
 
 mod node_config;
 mod motd;
+mod metrics;
+mod keys;
+mod udp;
+mod rate_limit;
+mod ip_filter;
+mod logger;
+mod health;
+mod bootstrap_url;
+mod pid_file;
+mod proxy_protocol;
+mod nodes_cache;
+mod error;
+mod connection_hook;
+mod ping;
+mod relay;
+mod onion_errors;
+mod self_test;
+mod access_log;
+mod bootstrapd;
+mod bootstrap_reload;
+mod dry_run;
+mod restart;
+#[cfg(unix)]
+mod unix;
+#[cfg(unix)]
+mod daemon;
 
-use std::fs::File;
-use std::io::{ErrorKind, Read, Write};
+use std::collections::HashSet;
+use std::io::ErrorKind;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-use failure::Error;
-use futures::{channel::mpsc, StreamExt};
+use chrono::{DateTime, Local};
+use failure::{Error, format_err};
+use futures::{channel::{mpsc, oneshot}, SinkExt, StreamExt};
 use futures::{future, Future, TryFutureExt, FutureExt};
 use itertools::Itertools;
-use tokio::net::{TcpListener, UdpSocket};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use tokio::runtime;
 use tox::crypto::*;
-use tox::core::dht::server::{Server as UdpServer};
-use tox::core::dht::server_ext::dht_run_socket;
+use tox::core::dht::server::{Server as UdpServer, MAX_TO_PING, NODES_REQ_INTERVAL, PING_TIMEOUT, TIME_TO_PING};
 use tox::core::dht::lan_discovery::LanDiscoverySender;
+use tox::packet::dht::Packet;
 use tox::packet::onion::InnerOnionResponse;
 use tox::packet::relay::OnionRequest;
-use tox::core::relay::server::{Server as TcpServer, tcp_run};
-use tox::core::stats::Stats;
+use tox::core::relay::server::Server as TcpServer;
 #[cfg(unix)]
 use syslog::Facility;
 
 use crate::node_config::*;
-use crate::motd::{Motd, Counters};
+use crate::motd::{Motd, Counters, format_uptime};
+use crate::metrics::{Metrics, run_metrics, run_stats_logging};
+use crate::keys::*;
+use crate::udp::{BootstrapRequester, dht_run_sockets};
+use crate::health::run_health;
+use crate::bootstrap_url::fetch_bootstrap_nodes;
+use crate::ip_filter::{AddrScope, BanList, TcpFilter, addr_scope};
+use crate::pid_file::{PidFile, check_pid_file};
+use crate::nodes_cache::{load_nodes_cache, run_nodes_cache};
+use crate::error::{BindError, NodeError, exit_command};
+use crate::connection_hook::ConnectionHook;
+use crate::logger::LogThrottle;
+use crate::onion_errors::{OnionErrors, TcpClients};
+use crate::relay::Activity;
+use crate::access_log::{AccessLog, Outcome};
+use crate::dry_run::dry_run;
+use crate::bootstrap_reload::reload_bootstrap_file;
+use crate::restart::{Backoff, run_restartable};
 
 /// Channel size for onion messages between UDP and TCP relay.
 const ONION_CHANNEL_SIZE: usize = 32;
 /// Channel size for DHT packets.
 const DHT_CHANNEL_SIZE: usize = 32;
+/// Interval of time for updating the number of known DHT nodes.
+const DHT_NODES_POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// Interval of time for TCP ping sender.
+const TCP_PING_INTERVAL: Duration = Duration::from_secs(1);
+/// Delay before the first retry to bind a TCP listener. It's doubled after
+/// each failed attempt.
+const BIND_RETRY_DELAY: Duration = Duration::from_millis(500);
+/// Maximum delay between attempts to bind a TCP listener.
+const BIND_RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+/// Timeout for fetching the list of bootstrap nodes from `bootstrap-url`.
+const BOOTSTRAP_URL_TIMEOUT: Duration = Duration::from_secs(10);
+/// Interval of time for checking the number of TCP connections while draining.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Maximum delay between restarts of DHT server.
+const UDP_RESTART_MAX_DELAY: Duration = Duration::from_secs(30);
+/// DHT server that worked for this interval of time before failing is
+/// restarted as if it failed for the first time.
+const UDP_RESTART_RESET_INTERVAL: Duration = Duration::from_secs(60);
+/// Number of threads per CPU core above which a warning is logged.
+const MAX_THREADS_PER_CORE: usize = 4;
 
 /// Get version in format 3AAABBBCCC, where A B and C are major, minor and patch
 /// versions of node. `tox-bootstrapd` uses similar scheme but with leading 1.
@@ -51,79 +118,407 @@ fn version() -> u32 {
     3_000_000_000 + major * 1_000_000 + minor * 1000 + patch
 }
 
-/// Bind a UDP listener to the socket address.
-async fn bind_socket(addr: SocketAddr) -> UdpSocket {
-    let socket = UdpSocket::bind(&addr).await.expect("Failed to bind UDP socket");
-    socket.set_broadcast(true).expect("set_broadcast call failed");
-    if addr.is_ipv6() {
-        socket.set_multicast_loop_v6(true).expect("set_multicast_loop_v6 call failed");
+/// Add addresses of the listen interfaces to UDP and TCP addresses.
+fn add_interfaces_addrs(config: &mut NodeConfig) {
+    if config.listen_interfaces.is_empty() {
+        return;
     }
-    socket
+
+    let interfaces = get_if_addrs::get_if_addrs().unwrap_or_else(|e| {
+        warn!("Failed to get addresses of network interfaces: {}", e);
+        Vec::new()
+    });
+    let (udp_addrs, tcp_addrs) = interfaces_addrs(&config.listen_interfaces, &interfaces);
+    config.udp_addrs.extend(udp_addrs);
+    config.tcp_addrs.extend(tcp_addrs.into_iter().map(TcpAddress::from));
 }
 
-/// Save DHT keys to a binary file.
-fn save_keys(keys_file: &str, pk: PublicKey, sk: &SecretKey) {
-    #[cfg(unix)]
-    use std::os::unix::fs::OpenOptionsExt;
+/// Make an error of binding a socket to the address. When the permission is
+/// denied the error contains a hint how to use a privileged port.
+fn bind_error(protocol: &str, addr: SocketAddr, e: std::io::Error) -> Error {
+    let message = if e.kind() == ErrorKind::PermissionDenied {
+        format!("Failed to bind {} socket to {}: {}. Ports below 1024 require \
+            CAP_NET_BIND_SERVICE capability, grant it with \
+            `setcap cap_net_bind_service=+ep <path to tox-node>` or use a port \
+            above 1023 and redirect the privileged port to it", protocol, addr, e)
+    } else {
+        format!("Failed to bind {} socket to {}: {}", protocol, addr, e)
+    };
+    BindError(message).into()
+}
 
-    #[cfg(not(unix))]
-    let mut file = File::create(keys_file).expect("Failed to create the keys file");
+/// Bind a UDP listener to the socket address. IPv6 socket accepts only IPv6
+/// packets when `only_v6` is set so that it can share the port with IPv4
+/// socket. Buffer sizes are left to OS defaults unless specified. Broadcast is
+/// needed only for LAN discovery so it's enabled only with `lan_discovery`
+/// since some restricted environments disallow it. IPv6 multicast loop is
+/// enabled only with `multicast_loop`, otherwise our own LAN discovery packets
+/// are delivered back to the host.
+fn bind_socket(
+    addr: SocketAddr,
+    only_v6: bool,
+    recv_buffer: Option<usize>,
+    send_buffer: Option<usize>,
+    lan_discovery: bool,
+    multicast_loop: bool,
+) -> Result<UdpSocket, Error> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::DGRAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    if let Some(recv_buffer) = recv_buffer {
+        socket.set_recv_buffer_size(recv_buffer)?;
+        let size = socket.recv_buffer_size()?;
+        info!("UDP receive buffer size of {} is {} bytes ({} requested)", addr, size, recv_buffer);
+    }
+    if let Some(send_buffer) = send_buffer {
+        socket.set_send_buffer_size(send_buffer)?;
+        let size = socket.send_buffer_size()?;
+        info!("UDP send buffer size of {} is {} bytes ({} requested)", addr, size, send_buffer);
+    }
+    socket.bind(&addr.into()).map_err(|e| bind_error("UDP", addr, e))?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket.into())?;
+    if lan_discovery {
+        socket.set_broadcast(true)?;
+    }
+    if addr.is_ipv6() {
+        if multicast_loop {
+            socket.set_multicast_loop_v6(true)?;
+        } else if let Err(e) = socket.set_multicast_loop_v6(false) {
+            // the OS default is kept if it can't be disabled
+            debug!("Failed to disable IPv6 multicast loop of {}: {}", addr, e);
+        }
+    }
+    Ok(socket)
+}
 
-    #[cfg(unix)]
-    let mut file = std::fs::OpenOptions::new()
-        .create(true)
-        .write(true)
-        .mode(0o600)
-        .open(keys_file)
-        .expect("Failed to create the keys file");
-
-    file.write_all(pk.as_ref()).expect("Failed to save public key to the keys file");
-    file.write_all(&sk[0..SECRETKEYBYTES]).expect("Failed to save secret key to the keys file");
-}
-
-/// Load DHT keys from a binary file.
-fn load_keys(mut file: File) -> (PublicKey, SecretKey) {
-    let mut buf = [0; PUBLICKEYBYTES + SECRETKEYBYTES];
-    file.read_exact(&mut buf).expect("Failed to read keys from the keys file");
-    let pk = PublicKey::from_slice(&buf[..PUBLICKEYBYTES]).expect("Failed to read public key from the keys file");
-    let sk = SecretKey::from_slice(&buf[PUBLICKEYBYTES..]).expect("Failed to read secret key from the keys file");
-    assert!(pk == sk.public_key(), "The loaded public key does not correspond to the loaded secret key");
-    (pk, sk)
-}
-
-/// Load DHT keys from a binary file or generate and save them if file does not
-/// exist.
-fn load_or_gen_keys(keys_file: &str) -> (PublicKey, SecretKey) {
-    match File::open(keys_file) {
-        Ok(file) => load_keys(file),
-        Err(ref e) if e.kind() == ErrorKind::NotFound => {
-            info!("Generating new DHT keys and storing them to '{}'", keys_file);
-            let (pk, sk) = gen_keypair();
-            save_keys(keys_file, pk, &sk);
-            (pk, sk)
-        },
-        Err(e) => panic!("Failed to read the keys file: {}", e)
+/// Warn when the number of threads greatly exceeds the number of CPU cores
+/// since extra threads only add context switches.
+fn check_threads(threads: Threads) {
+    let cores = num_cpus::get();
+    if let Threads::N(n) = threads {
+        if usize::from(n) > cores * MAX_THREADS_PER_CORE {
+            warn!("{} threads greatly exceed {} available CPU cores, consider using 'auto'", n, cores);
+        }
     }
 }
 
 /// Run a future with the runtime specified by config.
-fn run<F>(future: F, threads: Threads)
+fn run<F>(future: F, threads: Threads) -> Result<(), Error>
     where F: Future<Output = Result<(), Error>> + Send + 'static
 {
-    if threads == Threads::N(1) {
-        let mut runtime = runtime::Runtime::new().expect("Failed to create runtime");
-        runtime.block_on(future).expect("Execution was terminated with error");
-    } else {
-        let mut builder = runtime::Builder::new();
-        match threads {
-            Threads::N(n) => { builder.core_threads(n as usize); },
-            Threads::Auto => { }, // builder will detect number of cores automatically
+    let mut builder = runtime::Builder::new();
+    builder.enable_all();
+    match threads {
+        // a single thread runs everything on the current one
+        Threads::N(1) => { builder.basic_scheduler(); },
+        Threads::N(n) => { builder.threaded_scheduler().core_threads(n as usize); },
+        Threads::Auto => { builder.threaded_scheduler(); }, // builder will detect number of cores automatically
+    }
+    let mut runtime = builder.build()
+        .map_err(|e| format_err!("Failed to create runtime: {}", e))?;
+    runtime.block_on(future)
+}
+
+/// Wait for a signal to stop the node: SIGTERM or SIGINT on unix and ctrl-c on
+/// other platforms.
+#[cfg(unix)]
+async fn shutdown_signal() -> Result<(), Error> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+
+    futures::select! {
+        _ = terminate.recv().fuse() => {},
+        _ = interrupt.recv().fuse() => {},
+    }
+
+    Ok(())
+}
+
+/// Wait for a signal to stop the node: SIGTERM or SIGINT on unix and ctrl-c on
+/// other platforms.
+#[cfg(not(unix))]
+async fn shutdown_signal() -> Result<(), Error> {
+    tokio::signal::ctrl_c().await?;
+
+    Ok(())
+}
+
+/// Stream of signals to restart the TCP relay: SIGUSR2 on unix.
+#[cfg(unix)]
+fn tcp_restart_signals() -> Result<impl futures::Stream<Item = ()> + Unpin, Error> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    Ok(signal(SignalKind::user_defined2())?)
+}
+
+/// Stream of signals to restart the TCP relay. Signals are not supported on
+/// this platform so the stream never yields.
+#[cfg(not(unix))]
+fn tcp_restart_signals() -> Result<impl futures::Stream<Item = ()> + Unpin, Error> {
+    Ok(futures::stream::pending())
+}
+
+/// Reload config files on SIGHUP. Only MOTD and bootstrap nodes are applied to
+/// the running node, other changes require restart. Newly added bootstrap
+/// nodes are pinged since the initial bootstrap list can't be changed after
+/// the DHT server is started.
+#[cfg(unix)]
+async fn run_config_reload(config: &NodeConfig, udp_server: &UdpServer, motd: &RwLock<Motd>) -> Result<(), Error> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    if config.config_paths.is_empty() {
+        // Nothing to reload if the node was configured via CLI
+        return future::pending().await
+    }
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut current = config.clone();
+
+    while hangup.recv().await.is_some() {
+        info!("Reloading config files '{}'", config.config_paths.iter().format("', '"));
+
+        let mut new = match try_parse_config_as(&config.config_paths, config.config_format) {
+            Ok(new) => new,
+            Err(e) => {
+                error!("Failed to reload config, keeping the old one: {}", e);
+                continue;
+            },
+        };
+
+        add_interfaces_addrs(&mut new);
+
+        if new.udp_addrs != current.udp_addrs || new.tcp_addrs != current.tcp_addrs {
+            warn!("Changes of UDP or TCP addresses require restart and are ignored");
         }
-        let mut runtime = builder
-            .build()
-            .expect("Failed to create runtime");
-        runtime.block_on(future).expect("Execution was terminated with error");
+
+        if new.motd != current.motd {
+            info!("Updating MOTD");
+            motd.write()
+                .expect("Failed to lock MOTD")
+                .set_template(new.motd.clone());
+            current.motd = new.motd;
+        }
+
+        if new.motd_ipv6 != current.motd_ipv6 {
+            info!("Updating MOTD for IPv6 clients");
+            motd.write()
+                .expect("Failed to lock MOTD")
+                .set_template_ipv6(new.motd_ipv6.clone());
+            current.motd_ipv6 = new.motd_ipv6;
+        }
+
+        for node in new.bootstrap_nodes.iter().filter(|&node| !current.bootstrap_nodes.contains(node)) {
+            for node in node.resolve_with_timeout(Duration::from_secs(config.dns_timeout), config.dns_retries, AddrOrder::new(config)).await {
+                info!("Adding bootstrap node {:?}", node.saddr);
+                if let Err(e) = udp_server.ping_node(&node).await {
+                    warn!("Failed to ping bootstrap node: {}", e);
+                }
+            }
+        }
+        current.bootstrap_nodes = new.bootstrap_nodes;
+    }
+
+    Ok(())
+}
+
+/// Reload config files on SIGHUP. Signals are not supported on this platform
+/// so this future never completes.
+#[cfg(not(unix))]
+async fn run_config_reload(_config: &NodeConfig, _udp_server: &UdpServer, _motd: &RwLock<Motd>) -> Result<(), Error> {
+    future::pending().await
+}
+
+/// Re-read the bootstrap file on `bootstrap-reload-signal`. Never completes if
+/// there is no bootstrap file or the signal is not set.
+#[cfg(unix)]
+async fn run_bootstrap_file_reload(config: &NodeConfig, udp_server: &UdpServer) -> Result<(), Error> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let (bootstrap_file, reload_signal) = match (&config.bootstrap_file, config.bootstrap_reload_signal) {
+        (Some(bootstrap_file), Some(reload_signal)) => (bootstrap_file, reload_signal),
+        _ => return future::pending().await,
+    };
+
+    let reloads = signal(SignalKind::from_raw(reload_signal))
+        .map_err(|e| format_err!("Failed to handle signal {} to reload bootstrap file: {}", reload_signal, e))?;
+    reload_bootstrap_file(config, bootstrap_file, udp_server, reloads).await;
+
+    Ok(())
+}
+
+/// Re-read the bootstrap file on `bootstrap-reload-signal`. Signals are not
+/// supported on this platform so this future never completes.
+#[cfg(not(unix))]
+async fn run_bootstrap_file_reload(_config: &NodeConfig, _udp_server: &UdpServer) -> Result<(), Error> {
+    future::pending().await
+}
+
+/// Node details logged on SIGUSR1.
+struct Status {
+    start_date: DateTime<Local>,
+    dht_pk: PublicKey,
+    udp_addrs: Vec<SocketAddr>,
+    tcp_addrs: Vec<TcpAddress>,
+    tcp_unix_path: Option<String>,
+    metrics: Metrics,
+}
+
+impl Status {
+    /// Log the status snapshot: start date, uptime, version, public key,
+    /// addresses and counters.
+    fn log(&self) {
+        info!("Status: started at {}, uptime {}",
+            self.start_date.format("%c"), format_uptime(Local::now() - self.start_date));
+        info!("Status: version {} (bootstrap info version {}), DHT public key {}",
+            env!("CARGO_PKG_VERSION"), version(), hex::encode(self.dht_pk.as_ref()).to_uppercase());
+        info!("Status: UDP addresses: [{}], TCP addresses: [{}]{}",
+            self.udp_addrs.iter().format(", "),
+            self.tcp_addrs.iter().format(", "),
+            self.tcp_unix_path.as_ref().map_or(String::new(), |path| format!(", Unix socket: '{}'", path)));
+        info!("Status: UDP packets in/out: {}/{}, TCP packets in/out: {}/{}, TCP connections: {}",
+            self.metrics.udp.counters.incoming(),
+            self.metrics.udp.counters.outgoing(),
+            self.metrics.tcp.counters.incoming(),
+            self.metrics.tcp.counters.outgoing(),
+            self.metrics.tcp_connections.load(Ordering::Relaxed));
+        info!("Status: DHT nodes: {}, bootstrapped: {}, onion requests/responses/dropped: {}/{}/{}",
+            self.metrics.dht_nodes.load(Ordering::Relaxed),
+            self.metrics.bootstrapped.load(Ordering::Relaxed),
+            self.metrics.onion_requests.load(Ordering::Relaxed),
+            self.metrics.onion_responses.load(Ordering::Relaxed),
+            self.metrics.onion_dropped.load(Ordering::Relaxed));
+    }
+}
+
+/// Log the node status on SIGUSR1.
+#[cfg(unix)]
+async fn run_status_dump(status: Status) -> Result<(), Error> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut user_defined1 = signal(SignalKind::user_defined1())?;
+    while user_defined1.recv().await.is_some() {
+        status.log();
+    }
+
+    Ok(())
+}
+
+/// Log the node status on SIGUSR1. Signals are not supported on this platform
+/// so this future never completes.
+#[cfg(not(unix))]
+async fn run_status_dump(_status: Status) -> Result<(), Error> {
+    future::pending().await
+}
+
+/// Resolve bootstrap nodes every `bootstrap_refresh` seconds to follow changes
+/// of dynamic DNS names. Addresses that are not in `known_addrs` are pinged
+/// since the initial bootstrap list can't be changed after the DHT server is
+/// started. Never completes if the refresh is disabled.
+async fn run_bootstrap_refresh(config: &NodeConfig, udp_server: &UdpServer, mut known_addrs: HashSet<SocketAddr>) -> Result<(), Error> {
+    if config.bootstrap_refresh == 0 {
+        return future::pending().await
+    }
+
+    let interval = Duration::from_secs(config.bootstrap_refresh);
+    let mut wakeups = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    while wakeups.next().await.is_some() {
+        for node in &config.bootstrap_nodes {
+            for node in node.resolve_with_timeout(Duration::from_secs(config.dns_timeout), config.dns_retries, AddrOrder::new(config)).await {
+                if known_addrs.insert(node.saddr) {
+                    info!("Adding resolved bootstrap node {:?}", node.saddr);
+                    if let Err(e) = udp_server.ping_node(&node).await {
+                        warn!("Failed to ping bootstrap node: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Ping the closest DHT nodes every `dht_ping_interval` seconds in addition to
+/// the pings sent by the DHT server itself. The DHT server doesn't allow to
+/// change its own timings so this is the only way to ping nodes more often.
+/// Never completes if the interval is not set.
+async fn run_dht_pings(config: &NodeConfig, udp_server: &UdpServer, dht_pk: PublicKey) -> Result<(), Error> {
+    info!("DHT timings: ping interval {}s, ping timeout {}s, nodes request interval {}s, extra ping interval {}",
+        TIME_TO_PING.as_secs(),
+        PING_TIMEOUT.as_secs(),
+        NODES_REQ_INTERVAL.as_secs(),
+        if config.dht_ping_interval == 0 { "disabled".to_owned() } else { format!("{}s", config.dht_ping_interval) },
+    );
+
+    if config.dht_ping_interval == 0 {
+        return future::pending().await
+    }
+
+    let mut wakeups = tokio::time::interval(Duration::from_secs(config.dht_ping_interval));
+    while wakeups.next().await.is_some() {
+        let nodes = udp_server.get_closest(&dht_pk, MAX_TO_PING, false).await;
+        for node in nodes.iter() {
+            if let Err(e) = udp_server.ping_node(node).await {
+                debug!("Failed to ping DHT node {:?}: {}", node.saddr, e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Run LAN discovery counting sent packets. Packets are passed to the DHT
+/// sockets through an intermediate channel since `LanDiscoverySender` doesn't
+/// report them. The number of packets sent during the last `log_interval` is
+/// logged at debug level unless the interval is 0.
+async fn run_lan_discovery(
+    mut tx: mpsc::Sender<(Packet, SocketAddr)>,
+    dht_pk: PublicKey,
+    ipv6_enabled: bool,
+    log_interval: u64,
+    sent: Arc<AtomicU64>,
+) -> Result<(), Error> {
+    let (lan_tx, mut lan_rx) = mpsc::channel(DHT_CHANNEL_SIZE);
+
+    let sender_future = async move {
+        LanDiscoverySender::new(lan_tx, dht_pk, ipv6_enabled)
+            .run()
+            .map_err(Error::from)
+            .await
+    };
+
+    let sent_c = sent.clone();
+    let forward_future = async move {
+        while let Some(packet) = lan_rx.next().await {
+            sent_c.fetch_add(1, Ordering::Relaxed);
+            tx.send(packet).await
+                .map_err(|e| format_err!("Failed to send LAN discovery packet: {}", e))?;
+        }
+        Ok(())
+    };
+
+    let log_future = async move {
+        if log_interval == 0 {
+            return future::pending().await
+        }
+
+        let interval = Duration::from_secs(log_interval);
+        let mut wakeups = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        let mut last = 0;
+        while wakeups.next().await.is_some() {
+            let current = sent.load(Ordering::Relaxed);
+            debug!("Sent {} LAN discovery packets in the last {}s", current - last, log_interval);
+            last = current;
+        }
+        Ok(())
     };
+
+    futures::try_join!(sender_future, forward_future, log_future)?;
+
+    Ok(())
 }
 
 /// Onion sink and stream for TCP.
@@ -132,6 +527,8 @@ struct TcpOnion {
     tx: mpsc::Sender<(OnionRequest, SocketAddr)>,
     /// Stream of onion packets from TCP to UDP.
     rx: mpsc::Receiver<(InnerOnionResponse, SocketAddr)>,
+    /// Connections of TCP clients onion packets belong to.
+    clients: TcpClients,
 }
 
 /// Onion sink and stream for UDP.
@@ -140,192 +537,1189 @@ struct UdpOnion {
     tx: mpsc::Sender<(InnerOnionResponse, SocketAddr)>,
     /// Stream of onion packets from TCP to UDP.
     rx: mpsc::Receiver<(OnionRequest, SocketAddr)>,
+    /// Connections of TCP clients onion packets belong to.
+    clients: TcpClients,
+}
+
+/// Drop all onion packets of the stream since the server they are destined to
+/// is disabled. Packets are counted in `dropped`, the first one is logged as a
+/// warning so that operators know onion forwarding doesn't work.
+async fn drop_onion_packets<T>(rx: &mut mpsc::Receiver<T>, dropped: &AtomicU64, disabled: &str) {
+    let mut warned = false;
+    while rx.next().await.is_some() {
+        dropped.fetch_add(1, Ordering::Relaxed);
+        if !warned {
+            warned = true;
+            warn!("Onion forwarding is unavailable because {} is disabled, dropping onion packets", disabled);
+        }
+    }
+}
+
+/// Forward onion packets sent by one server to the bounded queue of the other
+/// one. Packets are dropped when the queue is full so that a slow receiver
+/// neither stalls the sender nor makes the queue grow.
+async fn forward_onion<T>(mut rx: mpsc::Receiver<T>, mut tx: mpsc::Sender<T>, direction: &'static str, dropped: Arc<AtomicU64>) {
+    let mut dropping = false;
+    while let Some(packet) = rx.next().await {
+        match tx.try_send(packet) {
+            Ok(()) => dropping = false,
+            Err(e) if e.is_full() => {
+                dropped.fetch_add(1, Ordering::Relaxed);
+                if !dropping {
+                    warn!("Onion queue {} is full, dropping packets", direction);
+                    dropping = true;
+                }
+            },
+            // the receiving server is stopped
+            Err(_) => return,
+        }
+    }
 }
 
-/// Create onion streams for TCP and UDP servers communication.
-fn create_onion_streams() -> (TcpOnion, UdpOnion) {
-    let (udp_onion_tx, udp_onion_rx) = mpsc::channel(ONION_CHANNEL_SIZE);
-    let (tcp_onion_tx, tcp_onion_rx) = mpsc::channel(ONION_CHANNEL_SIZE);
+/// Create onion streams for TCP and UDP servers communication. Packets are
+/// queued up to `onion_buffer` in each direction, the returned future forwards
+/// them to the queues counting dropped ones and should be spawned.
+fn create_onion_streams(onion_buffer: usize, dropped: Arc<AtomicU64>) -> (TcpOnion, UdpOnion, impl Future<Output = ()>) {
+    let (udp_onion_tx, udp_onion_forward_rx) = mpsc::channel(ONION_CHANNEL_SIZE);
+    let (udp_onion_forward_tx, udp_onion_rx) = mpsc::channel(onion_buffer);
+    let (tcp_onion_tx, tcp_onion_forward_rx) = mpsc::channel(ONION_CHANNEL_SIZE);
+    let (tcp_onion_forward_tx, tcp_onion_rx) = mpsc::channel(onion_buffer);
+    let clients = TcpClients::new();
     let tcp_onion = TcpOnion {
         tx: tcp_onion_tx,
         rx: udp_onion_rx,
+        clients: clients.clone(),
     };
     let udp_onion = UdpOnion {
         tx: udp_onion_tx,
         rx: tcp_onion_rx,
+        clients,
+    };
+    let forwarding = future::join(
+        forward_onion(tcp_onion_forward_rx, tcp_onion_forward_tx, "from TCP to UDP", dropped.clone()),
+        forward_onion(udp_onion_forward_rx, udp_onion_forward_tx, "from UDP to TCP", dropped),
+    ).map(drop);
+    (tcp_onion, udp_onion, forwarding)
+}
+
+/// TCP relay server with the signal to close its connections. Connections run
+/// in spawned tasks so they would outlive the relay when it's restarted
+/// otherwise.
+#[derive(Clone)]
+struct TcpRelay {
+    server: TcpServer,
+    /// Resolves when the relay is stopped, i.e. when the sender is dropped.
+    stopped: future::Shared<oneshot::Receiver<()>>,
+    /// Logger of errors of connections and onion responses.
+    log_throttle: Arc<LogThrottle>,
+    /// Connections that can be closed because of onion errors.
+    clients: TcpClients,
+    /// Log of closed connections of TCP listeners.
+    access_log: Option<AccessLog>,
+    /// Command that decides whether to accept connections of all TCP
+    /// listeners.
+    hook: Option<Arc<ConnectionHook>>,
+}
+
+impl TcpRelay {
+    /// Run the connection of the client with the address the relay knows it
+    /// by. The connection is made by `connection` from the future that
+    /// resolves when the relay is stopped or the client is disconnected so
+    /// that the connection can shut the client down.
+    async fn serve<F, C>(&self, addr: SocketAddr, connection: F) -> Result<(), Error>
+        where F: FnOnce(future::BoxFuture<'static, ()>) -> C, C: Future<Output = Result<(), Error>>
+    {
+        // the relay requires unique addresses and with PROXY protocol they
+        // come from the load balancer
+        let disconnected = self.clients.register(addr)
+            .ok_or_else(|| format_err!("TCP client with address {} is already connected", addr))?;
+        let stopped = self.stopped.clone();
+        let close = async move {
+            futures::select! {
+                _ = stopped.fuse() => { },
+                res = disconnected.fuse() => if res.is_ok() {
+                    debug!("Disconnecting TCP client {} because of onion errors", addr);
+                },
+            }
+        };
+        let res = connection(close.boxed()).await;
+        self.clients.unregister(addr);
+        res
+    }
+
+    /// Record the connection of the client accepted by the listener at
+    /// `accepted` in the access log if it's enabled.
+    fn log_access(&self, client: SocketAddr, listener: &str, accepted: Instant, (received, sent): (u64, u64), outcome: Outcome) {
+        if let Some(ref access_log) = self.access_log {
+            access_log.record(&access_log::Entry {
+                closed: Local::now(),
+                client,
+                listener: listener.to_owned(),
+                duration: accepted.elapsed(),
+                received,
+                sent,
+                outcome,
+            });
+        }
+    }
+}
+
+/// Limit of TCP connections shared by all listeners of the relay.
+struct MaxConnections {
+    /// Maximum number of connections. No limit if `None`.
+    limit: Option<usize>,
+    /// Number of active connections.
+    count: AtomicUsize,
+    /// Whether the limit is reached. Used to log when the state changes.
+    reached: AtomicBool,
+}
+
+impl MaxConnections {
+    fn new(limit: Option<usize>) -> Self {
+        MaxConnections {
+            limit,
+            count: AtomicUsize::new(0),
+            reached: AtomicBool::new(false),
+        }
+    }
+
+    /// Count a new connection if the limit is not reached.
+    fn acquire(&self) -> bool {
+        let limit = if let Some(limit) = self.limit {
+            limit
+        } else {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            return true
+        };
+
+        let res = self.count.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count|
+            if count < limit { Some(count + 1) } else { None }
+        );
+
+        if res.is_err() && !self.reached.swap(true, Ordering::SeqCst) {
+            warn!("TCP relay has reached the limit of {} connections, new connections are refused", limit);
+        }
+
+        res.is_ok()
+    }
+
+    /// Uncount a closed connection.
+    fn release(&self) {
+        let count = self.count.fetch_sub(1, Ordering::SeqCst) - 1;
+        if let Some(limit) = self.limit {
+            if count < limit && self.reached.swap(false, Ordering::SeqCst) {
+                warn!("TCP relay has dropped below the limit of {} connections, accepting new connections", limit);
+            }
+        }
+    }
+}
+
+/// Connections of a TCP listener counted in its own limit, the limit of the
+/// relay and metrics. Clones share the same counters.
+#[derive(Clone)]
+struct ListenerConnections {
+    /// Listener the connections belong to.
+    addr: TcpAddress,
+    /// Maximum number of connections of the listener.
+    limit: usize,
+    /// Number of active connections of the listener.
+    count: Arc<AtomicUsize>,
+    /// Limit shared by all listeners of the relay.
+    max_connections: Arc<MaxConnections>,
+    metrics: Metrics,
+    /// Number of active connections of the listener in metrics.
+    listener_connections: Arc<AtomicU64>,
+}
+
+impl ListenerConnections {
+    fn new(addr: &TcpAddress, limit: usize, max_connections: &Arc<MaxConnections>, metrics: &Metrics) -> Self {
+        ListenerConnections {
+            addr: addr.clone(),
+            limit,
+            count: Arc::new(AtomicUsize::new(0)),
+            max_connections: max_connections.clone(),
+            metrics: metrics.clone(),
+            listener_connections: metrics.tcp_listener_connections(&addr.name()),
+        }
+    }
+
+    /// Count a new connection if neither the limit of the listener nor the
+    /// limit of the relay is reached.
+    fn acquire(&self) -> bool {
+        if self.count.load(Ordering::SeqCst) >= self.limit {
+            trace!("Tcp server on {} has reached the limit of {} connections", self.addr, self.limit);
+            return false
+        }
+
+        if !self.max_connections.acquire() {
+            return false
+        }
+
+        self.count.fetch_add(1, Ordering::SeqCst);
+        self.metrics.tcp_connections.fetch_add(1, Ordering::Relaxed);
+        self.listener_connections.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Uncount a closed connection.
+    fn release(&self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+        self.max_connections.release();
+        self.metrics.tcp_connections.fetch_sub(1, Ordering::Relaxed);
+        self.listener_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Get the address of the client from PROXY protocol header and check that
+/// it's accepted by the filter. An error means that the connection should be
+/// closed, it contains the address of the client, if it's known, and the
+/// reason for the access log.
+async fn accept_proxied(stream: &mut TcpStream, peer_addr: SocketAddr, filter: &Mutex<TcpFilter>) -> Result<SocketAddr, (SocketAddr, &'static str)> {
+    let header = tokio::time::timeout(proxy_protocol::HEADER_TIMEOUT, proxy_protocol::read_header(stream))
+        .await
+        .map_err(|_| format_err!("Timed out"))
+        .and_then(|res| res);
+    let client_addr = match header {
+        Ok(client_addr) => client_addr.unwrap_or(peer_addr),
+        Err(e) => {
+            debug!("Rejecting TCP connection from {} without valid PROXY protocol header: {}", peer_addr, e);
+            return Err((peer_addr, "proxy-header"))
+        },
     };
-    (tcp_onion, udp_onion)
+
+    trace!("TCP connection from {} is proxied for {}", peer_addr, client_addr);
+
+    match filter.lock().expect("Failed to lock TCP filter").rejects(client_addr.ip()) {
+        Some(reason) => Err((client_addr, reason)),
+        None => Ok(client_addr),
+    }
 }
 
-async fn run_tcp(config: &NodeConfig, dht_sk: SecretKey, mut tcp_onion: TcpOnion, stats: Stats) -> Result<(), Error> {
-    if config.tcp_addrs.is_empty() {
+/// Accept connections from the TCP listener and run the relay protocol on
+/// them. Connections from banned IPs or IPs not in the allow list are dropped
+/// right away. With PROXY protocol the client address is read from the header
+/// first and connections without a valid header are dropped. Up to
+/// `tcp-connections-limit` connections can wait for the header, they are
+/// counted as connections of the listener only when the header is read. Then
+/// the connection hook, if any, decides whether the client is accepted. The
+/// relay knows the client by the address from the header. Active connections
+/// are counted in `metrics`. Connections without traffic for
+/// `tcp-idle-timeout` seconds are closed unless it's 0. Both closed and
+/// rejected connections are recorded in the access log.
+async fn run_tcp_listener(
+    relay: &TcpRelay,
+    mut listener: TcpListener,
+    addr: &TcpAddress,
+    config: &NodeConfig,
+    dht_sk: SecretKey,
+    max_connections: &Arc<MaxConnections>,
+    metrics: &Metrics,
+) -> Result<(), Error> {
+    let connections_limit = config.tcp_connections_limit;
+    let proxy_protocol = config.proxy_protocol;
+    let idle_timeout = Duration::from_secs(config.tcp_idle_timeout);
+    let filter = Arc::new(Mutex::new(TcpFilter::new(&config.ban_list, &config.tcp_allow_list)));
+    let hook = relay.hook.clone();
+    let connections = ListenerConnections::new(addr, connections_limit, max_connections, metrics);
+    let pending_headers = Arc::new(AtomicUsize::new(0));
+    let name = addr.name();
+
+    loop {
+        let (mut stream, peer_addr) = listener.accept().await?;
+        let accepted = Instant::now();
+
+        if proxy_protocol {
+            // the address of a proxied client is known only after the
+            // header is read so it's checked in the connection task
+            if pending_headers.load(Ordering::SeqCst) >= connections_limit {
+                trace!("Tcp server on {} has reached the limit of {} connections waiting for PROXY protocol header", addr, connections_limit);
+                relay.log_access(peer_addr, &name, accepted, (0, 0), Outcome::Rejected("limit"));
+                continue;
+            }
+            pending_headers.fetch_add(1, Ordering::SeqCst);
+        } else {
+            if let Some(reason) = filter.lock().expect("Failed to lock TCP filter").rejects(peer_addr.ip()) {
+                relay.log_access(peer_addr, &name, accepted, (0, 0), Outcome::Rejected(reason));
+                continue;
+            }
+
+            if !connections.acquire() {
+                relay.log_access(peer_addr, &name, accepted, (0, 0), Outcome::Rejected("limit"));
+                continue;
+            }
+        }
+
+        let connections = connections.clone();
+        let pending_headers = pending_headers.clone();
+        let addr = addr.clone();
+        let name = name.clone();
+        let filter = filter.clone();
+        let hook = hook.clone();
+        let relay = relay.clone();
+        let dht_sk = dht_sk.clone();
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let client_addr = if proxy_protocol {
+                let client_addr = accept_proxied(&mut stream, peer_addr, &filter).await;
+                pending_headers.fetch_sub(1, Ordering::SeqCst);
+                match client_addr {
+                    Ok(client_addr) if connections.acquire() => client_addr,
+                    Ok(client_addr) => return relay.log_access(client_addr, &name, accepted, (0, 0), Outcome::Rejected("limit")),
+                    Err((client_addr, reason)) => return relay.log_access(client_addr, &name, accepted, (0, 0), Outcome::Rejected(reason)),
+                }
+            } else {
+                peer_addr
+            };
+
+            let accepted_by_hook = match hook {
+                Some(hook) => hook.accepts(client_addr.ip()).await,
+                None => true,
+            };
+
+            if !accepted_by_hook {
+                relay.log_access(client_addr, &name, accepted, (0, 0), Outcome::Rejected("hook"));
+            } else {
+                debug!("A new TCP client connected from {}", client_addr);
+
+                let activity = Activity::new(idle_timeout);
+                let res = relay.serve(client_addr, |close|
+                    relay::run_connection(&relay.server, stream, dht_sk, metrics.tcp.clone(), client_addr, activity.clone(), close)
+                ).await;
+
+                if let Err(ref e) = res {
+                    relay.log_throttle.log(log::Level::Error, format!("Error while running tcp connection from {} on {}: {:?}", client_addr.ip(), addr, e))
+                }
+
+                let outcome = if res.is_err() { Outcome::Error } else { Outcome::Ok };
+                relay.log_access(client_addr, &name, accepted, activity.bytes(), outcome);
+            }
+
+            connections.release();
+        });
+    }
+}
+
+/// Maximum length of the queue of pending TCP connections allowed by the OS.
+/// Larger backlogs are silently capped to it.
+#[cfg(target_os = "linux")]
+fn max_tcp_backlog() -> Option<u32> {
+    std::fs::read_to_string("/proc/sys/net/core/somaxconn").ok()?.trim().parse().ok()
+}
+
+/// Maximum length of the queue of pending TCP connections allowed by the OS.
+/// It's unknown on this platform.
+#[cfg(not(target_os = "linux"))]
+fn max_tcp_backlog() -> Option<u32> {
+    None
+}
+
+/// Bind a TCP listener to the address with the backlog. The default backlog
+/// of `TcpListener::bind` is used if it's not specified.
+async fn bind_tcp(addr: SocketAddr, backlog: Option<u32>) -> std::io::Result<TcpListener> {
+    let backlog = if let Some(backlog) = backlog {
+        backlog
+    } else {
+        return TcpListener::bind(addr).await
+    };
+
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::STREAM, None)?;
+    // the same as `TcpListener::bind` does to rebind the port of a restarted
+    // node right away
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog.min(i32::MAX as u32) as i32)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Bind a TCP listener to the address retrying up to `retries` times with
+/// exponential backoff, e.g. while the port is still held by the previous
+/// instance of the node during a restart.
+async fn bind_tcp_listener(addr: &TcpAddress, retries: u32, backlog: Option<u32>) -> Result<TcpListener, Error> {
+    let mut delay = BIND_RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        match bind_tcp(addr.addr, backlog).await {
+            Ok(listener) => {
+                if let Some(backlog) = backlog {
+                    match max_tcp_backlog() {
+                        Some(max_backlog) if max_backlog < backlog =>
+                            warn!("TCP listener on {} uses backlog {} capped by net.core.somaxconn ({} requested)", addr, max_backlog, backlog),
+                        _ => info!("TCP listener on {} uses backlog {}", addr, backlog),
+                    }
+                }
+                return Ok(listener)
+            },
+            // retrying won't help if the port is privileged
+            Err(e) if attempt < retries && e.kind() != ErrorKind::PermissionDenied => {
+                attempt += 1;
+                warn!("Failed to bind TCP listener to {}: {}. Retrying in {:?} (attempt {} of {})", addr, e, delay, attempt, retries);
+                tokio::time::delay_for(delay).await;
+                delay = (delay * 2).min(BIND_RETRY_MAX_DELAY);
+            },
+            Err(e) => return Err(bind_error("TCP", addr.addr, e)),
+        }
+    }
+}
+
+/// Run TCP relay on Unix domain socket. Connected clients get unique ports
+/// since the relay identifies them by IP and port, ports are reused only after
+/// their clients disconnect. Connections without traffic for
+/// `tcp-idle-timeout` seconds are closed unless it's 0. Both closed and
+/// rejected connections are recorded in the access log with the listener
+/// `unix:<path>`.
+#[cfg(unix)]
+async fn run_unix_listener(
+    relay: &TcpRelay,
+    mut listener: tokio::net::UnixListener,
+    config: &NodeConfig,
+    dht_sk: SecretKey,
+    max_connections: &Arc<MaxConnections>,
+    metrics: &Metrics,
+) -> Result<(), Error> {
+    let connections_limit = config.tcp_connections_limit;
+    let idle_timeout = Duration::from_secs(config.tcp_idle_timeout);
+    let name = format!("unix:{}", config.tcp_unix_path.as_deref().unwrap_or_default());
+    let connections_count = Arc::new(AtomicUsize::new(0));
+    let client_ports = unix::ClientPorts::default();
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let accepted = Instant::now();
+        let port = match client_ports.take() {
+            Some(port) => port,
+            None => {
+                trace!("All ports for Unix socket clients are in use");
+                relay.log_access(SocketAddr::new(unix::UNIX_CLIENT_IP, 0), &name, accepted, (0, 0), Outcome::Rejected("limit"));
+                continue;
+            },
+        };
+        let addr = SocketAddr::new(unix::UNIX_CLIENT_IP, port);
+
+        if connections_count.load(Ordering::SeqCst) >= connections_limit {
+            trace!("Tcp server has reached the limit of {} connections", connections_limit);
+            relay.log_access(addr, &name, accepted, (0, 0), Outcome::Rejected("limit"));
+            client_ports.release(port);
+            continue;
+        }
+
+        if !max_connections.acquire() {
+            relay.log_access(addr, &name, accepted, (0, 0), Outcome::Rejected("limit"));
+            client_ports.release(port);
+            continue;
+        }
+
+        connections_count.fetch_add(1, Ordering::SeqCst);
+        metrics.tcp_connections.fetch_add(1, Ordering::Relaxed);
+
+        let connections_count = connections_count.clone();
+        let max_connections = max_connections.clone();
+        let relay = relay.clone();
+        let dht_sk = dht_sk.clone();
+        let metrics = metrics.clone();
+        let name = name.clone();
+        let client_ports = client_ports.clone();
+        tokio::spawn(async move {
+            let activity = Activity::new(idle_timeout);
+            let res = relay.serve(addr, |close|
+                unix::unix_run_connection(&relay.server, stream, dht_sk, metrics.tcp.clone(), port, activity.clone(), close)
+            ).await;
+
+            if let Err(ref e) = res {
+                relay.log_throttle.log(log::Level::Error, format!("Error while running unix connection: {}", e))
+            }
+
+            let outcome = if res.is_err() { Outcome::Error } else { Outcome::Ok };
+            relay.log_access(addr, &name, accepted, activity.bytes(), outcome);
+
+            client_ports.release(port);
+            connections_count.fetch_sub(1, Ordering::SeqCst);
+            max_connections.release();
+            metrics.tcp_connections.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
+
+/// Wait until all TCP connections are closed or the timeout expires. The
+/// number of remaining connections is logged whenever it changes.
+async fn drain_tcp_connections(timeout: Duration, metrics: &Metrics) {
+    let deadline = Instant::now() + timeout;
+    let mut last_connections = None;
+    let mut wakeups = tokio::time::interval(DRAIN_POLL_INTERVAL);
+    while wakeups.next().await.is_some() {
+        let connections = metrics.tcp_connections.load(Ordering::Relaxed);
+        if connections == 0 {
+            info!("All TCP connections are closed");
+            return;
+        }
+
+        if Instant::now() >= deadline {
+            info!("Drain timeout expired, closing {} TCP connections", connections);
+            return;
+        }
+
+        if last_connections != Some(connections) {
+            info!("Draining TCP relay: {} connections left", connections);
+            last_connections = Some(connections);
+        }
+    }
+}
+
+/// Run TCP relay. If TCP addresses are not specified only onion packets from
+/// the DHT server are dropped. If onion forwarding is disabled the onion sink
+/// is not passed to the relay but it's kept alive by the caller so that
+/// `run_udp` keeps draining its onion stream like when TCP is disabled. The
+/// onion streams are borrowed so that the relay can be restarted.
+///
+/// When `shutdown` resolves new connections are no longer accepted while the
+/// existing ones are served for up to `drain-timeout` seconds. Connections
+/// are closed when the relay stops.
+///
+/// `ready` is taken and notified when all listeners are bound. Closed and
+/// rejected connections are recorded in `access_log` if it's set.
+async fn run_tcp(
+    config: &NodeConfig,
+    dht_sk: SecretKey,
+    tcp_onion: &mut TcpOnion,
+    metrics: Metrics,
+    shutdown: future::Shared<oneshot::Receiver<()>>,
+    ready: &mut Option<oneshot::Sender<()>>,
+    access_log: Option<AccessLog>,
+) -> Result<(), Error> {
+    let mut notify_ready = || if let Some(ready) = ready.take() {
+        let _ = ready.send(());
+    };
+
+    if config.tcp_addrs.is_empty() && config.tcp_unix_path.is_none() {
+        notify_ready();
+
         // If TCP address is not specified don't start TCP server and only drop
         // all onion packets from DHT server
-        while tcp_onion.rx.next().await.is_some() {}
+        drop_onion_packets(&mut tcp_onion.rx, &metrics.onion_dropped_tcp_disabled, "the TCP relay").await;
 
         return Ok(())
     }
 
-    let onion_tx = tcp_onion.tx;
-    let mut onion_rx = tcp_onion.rx;
+    let onion_rx = &mut tcp_onion.rx;
+
+    for addr in &config.tcp_addrs {
+        check_public_addr("TCP", addr.addr, config.lan_discovery_enabled);
+    }
 
     let mut tcp_server = TcpServer::new();
-    tcp_server.set_udp_onion_sink(onion_tx);
+    if !config.no_onion {
+        tcp_server.set_udp_onion_sink(tcp_onion.tx.clone());
+    }
 
-    let tcp_server_c = tcp_server.clone();
-    let tcp_server_futures = config.tcp_addrs.iter().map(move |&addr| {
-        let tcp_server_c = tcp_server_c.clone();
-        let stats = stats.clone();
+    // connections are closed when the sender is dropped with the relay
+    let (_stopped_tx, stopped_rx) = oneshot::channel::<()>();
+    let relay = TcpRelay {
+        server: tcp_server.clone(),
+        stopped: stopped_rx.shared(),
+        log_throttle: Arc::new(LogThrottle::new(Duration::from_secs(config.log_throttle_secs))),
+        clients: tcp_onion.clients.clone(),
+        access_log,
+        hook: config.connection_hook.clone().map(|command| Arc::new(ConnectionHook::new(
+            command,
+            Duration::from_secs(config.connection_hook_timeout),
+            Duration::from_secs(config.connection_hook_cache),
+            config.connection_hook_failure,
+        ))),
+    };
+    let log_throttle = relay.log_throttle.clone();
+    let onion_errors = OnionErrors::new(config.onion_error_policy, tcp_onion.clients.clone());
+    let relay_c = relay.clone();
+    let metrics_c = metrics.clone();
+    let max_connections = Arc::new(MaxConnections::new(config.tcp_max_connections));
+    #[cfg(unix)]
+    let unix_listener = config.tcp_unix_path
+        .as_ref()
+        .map(|path| unix::bind_unix_listener(path.as_ref()))
+        .transpose()?;
+    #[cfg(unix)]
+    let unix_server_future = unix_listener.map(|(listener, socket_file)| {
+        let relay = relay.clone();
+        let metrics = metrics.clone();
+        let max_connections = max_connections.clone();
         let dht_sk = dht_sk.clone();
         async move {
-            let listener = TcpListener::bind(&addr).await.expect("Failed to bind TCP listener");
-            tcp_run(&tcp_server_c, listener, dht_sk, stats.clone(), config.tcp_connections_limit)
-                .await
-                .map_err(Error::from)
+            // the socket file is removed when the relay stops
+            let _socket_file = socket_file;
+            run_unix_listener(&relay, listener, config, dht_sk, &max_connections, &metrics).await
         }.boxed()
     });
+    #[cfg(not(unix))]
+    let unix_server_future = None;
+
+    // all listeners are bound before accepting connections so that readiness
+    // is notified only when the relay is reachable on all addresses
+    let bind_listeners = future::try_join_all(config.tcp_addrs.iter().map(|addr|
+        bind_tcp_listener(addr, config.bind_retry, config.tcp_backlog)
+    ));
+    let listeners = futures::select! {
+        res = bind_listeners.fuse() => res?,
+        _ = shutdown.clone().fuse() => return Ok(()),
+    };
+    notify_ready();
 
+    let tcp_server_futures = config.tcp_addrs.iter().zip(listeners).map(move |(addr, listener)| {
+        let relay = relay_c.clone();
+        let metrics = metrics_c.clone();
+        let max_connections = max_connections.clone();
+        let dht_sk = dht_sk.clone();
+        async move {
+            run_tcp_listener(&relay, listener, addr, config, dht_sk, &max_connections, &metrics).await
+        }.boxed()
+    }).chain(unix_server_future);
+
+    let drain_timeout = Duration::from_secs(config.drain_timeout);
     let tcp_server_future = async {
-        future::select_all(tcp_server_futures)
-            .await
-            .0
+        futures::select! {
+            res = future::select_all(tcp_server_futures).fuse() => res.0,
+            _ = shutdown.fuse() => {
+                if drain_timeout > Duration::from_secs(0) {
+                    info!("Stopped accepting TCP connections, draining for up to {} seconds", drain_timeout.as_secs());
+                    drain_tcp_connections(drain_timeout, &metrics).await;
+                }
+                Ok(())
+            },
+        }
+    };
+
+    let tcp_ping_future = async {
+        let mut wakeups = tokio::time::interval(TCP_PING_INTERVAL);
+        while wakeups.next().await.is_some() {
+            trace!("Tcp server ping sender wake up");
+            tcp_server.send_pings().await?;
+        }
+
+        Ok(())
     };
 
-    // let tcp_onion_rx = tcp_onion.rx.clone()
     let tcp_onion_future = async {
+        if config.no_onion {
+            // If onion forwarding is disabled only drop all onion packets
+            // from DHT server
+            while onion_rx.next().await.is_some() {}
+
+            return Ok(())
+        }
+
         while let Some((onion_response, addr)) = onion_rx.next().await {
+            metrics.onion_responses.fetch_add(1, Ordering::Relaxed);
             let res = tcp_server
                 .handle_udp_onion_response(addr.ip(), addr.port(), onion_response)
                 .await;
 
             if let Err(err) = res {
-                warn!("Failed to handle UDP onion response: {:?}", err);
+                let message = format!("Failed to handle UDP onion response: {:?}", err);
+                onion_errors.handle(addr, message, &metrics.onion_response_errors, &log_throttle);
             }
         }
 
         Ok(())
     };
 
-    info!("Running TCP relay on {}", config.tcp_addrs.iter().format(","));
-
-    futures::try_join!(tcp_server_future, tcp_onion_future)?;
+    if !config.tcp_addrs.is_empty() {
+        info!("Running TCP relay on {}", config.tcp_addrs.iter().format(","));
+    }
+    if let Some(ref tcp_unix_path) = config.tcp_unix_path {
+        info!("Running TCP relay on Unix socket '{}'", tcp_unix_path);
+    }
 
-    Ok(())
+    // TCP relay stops when listeners fail or when draining is finished,
+    // pings and onion responses are handled until then
+    futures::select! {
+        res = tcp_server_future.fuse() => res,
+        res = future::try_join3(tcp_ping_future, tcp_onion_future, log_throttle.run().map(Ok)).fuse() => res.map(drop),
+    }
 }
 
-async fn run_udp(config: &NodeConfig, dht_pk: PublicKey, dht_sk: &SecretKey, mut udp_onion: UdpOnion, tcp_stats: Stats) -> Result<(), Error> {
-    let udp_addr = if let Some(udp_addr) = config.udp_addr {
-        udp_addr
-    } else {
+/// Run DHT server. If UDP addresses are not specified only onion packets from
+/// the TCP relay are dropped. If onion forwarding is disabled the onion sink
+/// is not passed to the DHT server but kept alive until the server stops so
+/// that `run_tcp` keeps draining its onion stream like when UDP is disabled.
+///
+/// `ready` is notified when UDP sockets are bound.
+async fn run_udp(
+    config: &NodeConfig,
+    dht_pk: PublicKey,
+    dht_sk: &SecretKey,
+    mut udp_onion: UdpOnion,
+    metrics: Metrics,
+    ready: oneshot::Sender<()>,
+) -> Result<(), Error> {
+    if config.udp_addrs.is_empty() {
+        let _ = ready.send(());
+
         // If UDP address is not specified don't start DHT server and only drop
         // all onion packets from TCP server
-        while udp_onion.rx.next().await.is_some() {}
+        drop_onion_packets(&mut udp_onion.rx, &metrics.onion_dropped_udp_disabled, "the DHT server").await;
 
         return Ok(())
-    };
+    }
+
+    if let Some(udp_addr) = config.udp_addrs.iter().find(|udp_addr| !config.ip_mode.allows(udp_addr)) {
+        return Err(format_err!("UDP address {} doesn't match IP mode {}", udp_addr, config.ip_mode))
+    }
+
+    for &udp_addr in &config.udp_addrs {
+        check_public_addr("UDP", udp_addr, config.lan_discovery_enabled);
+    }
+
+    let sockets = bind_udp_sockets(config)?;
+    let _ = ready.send(());
 
-    let socket = bind_socket(udp_addr).await;
-    let udp_stats = Stats::new();
+    if config.no_v4_mapped && config.ip_mode == IpMode::Dual && config.udp_addrs.iter().all(|udp_addr| udp_addr.is_ipv6()) {
+        warn!("IPv4-mapped addresses are disabled and there is no IPv4 UDP address, packets to IPv4 nodes will be dropped");
+    }
+
+    let ipv6_enabled = match config.ip_mode {
+        IpMode::Dual => config.udp_addrs.iter().any(|udp_addr| udp_addr.is_ipv6()),
+        IpMode::Ipv4 => false,
+        IpMode::Ipv6 => true,
+    };
 
     // Create a channel for server to communicate with network
     let (tx, rx) = mpsc::channel(DHT_CHANNEL_SIZE);
 
     let tx_clone = tx.clone();
+    let lan_discovery_sent = metrics.lan_discovery_sent.clone();
     let lan_discovery_future = async move {
         if config.lan_discovery_enabled {
-            LanDiscoverySender::new(tx_clone, dht_pk, udp_addr.is_ipv6())
-                .run()
-                .map_err(Error::from)
-                .await
+            run_lan_discovery(tx_clone, dht_pk, ipv6_enabled, config.lan_discovery_log_interval, lan_discovery_sent).await
         }
         else { Ok(()) }
     };
 
     let (onion_tx, mut onion_rx) = (udp_onion.tx, udp_onion.rx);
+    let onion_errors = OnionErrors::new(config.onion_error_policy, udp_onion.clients);
 
     let mut udp_server = UdpServer::new(tx, dht_pk, dht_sk.clone());
-    let counters = Counters::new(tcp_stats, udp_stats.clone());
-    let motd = Motd::new(config.motd.clone(), counters);
-    udp_server.set_bootstrap_info(version(), Box::new(move |_| motd.format().as_bytes().to_owned()));
+    let counters = Counters::new(metrics.tcp.clone(), metrics.udp.clone());
+    let mut motd = Motd::new(config.motd.clone(), counters, dht_pk, version(), Duration::from_secs(config.motd_cache_ttl));
+    motd.set_template_ipv6(config.motd_ipv6.clone());
+    let motd = Arc::new(RwLock::new(motd));
+    let motd_c = motd.clone();
+    let requester = BootstrapRequester::new();
+    let requester_c = requester.clone();
+    let bootstrap_stats = config.bootstrap_stats;
+    let dht_nodes = metrics.dht_nodes.clone();
+    let bootstrap_version = if let Some(advertise_version) = config.advertise_version {
+        info!("Advertising version {} in bootstrap info instead of {}", advertise_version, version());
+        advertise_version
+    } else {
+        version()
+    };
+    // the DHT server doesn't pass the requester address to the callback so
+    // it's taken from `requester` set by `dht_run_sockets`
+    udp_server.set_bootstrap_info(bootstrap_version, Box::new(move |_| {
+        let motd = motd_c.read().expect("Failed to lock MOTD");
+        let ipv6 = requester_c.is_ipv6();
+        if bootstrap_stats {
+            motd.format_with_stats(bootstrap_version, dht_nodes.load(Ordering::Relaxed) as u32, ipv6)
+        } else {
+            motd.formatted(ipv6).into_bytes()
+        }
+    }));
     udp_server.enable_lan_discovery(config.lan_discovery_enabled);
-    udp_server.set_tcp_onion_sink(onion_tx);
-    udp_server.enable_ipv6_mode(udp_addr.is_ipv6());
+    let _onion_tx = if config.no_onion {
+        Some(onion_tx)
+    } else {
+        udp_server.set_tcp_onion_sink(onion_tx);
+        None
+    };
+    udp_server.enable_ipv6_mode(ipv6_enabled);
+
+    let log_throttle = LogThrottle::new(Duration::from_secs(config.log_throttle_secs));
+    let log_throttle_c = &log_throttle;
 
     let udp_server_c = udp_server.clone();
+    let onion_requests = metrics.onion_requests.clone();
+    let onion_request_errors = metrics.onion_request_errors.clone();
+    let no_onion = config.no_onion;
     let udp_onion_future = async move {
+        if no_onion {
+            // If onion forwarding is disabled only drop all onion packets
+            // from TCP server
+            while onion_rx.next().await.is_some() {}
+
+            return Ok(())
+        }
+
         while let Some((onion_request, addr)) = onion_rx.next().await {
+            onion_requests.fetch_add(1, Ordering::Relaxed);
             let res = udp_server_c
                 .handle_tcp_onion_request(onion_request, addr)
                 .await;
 
             if let Err(err) = res {
-                warn!("Failed to handle TCP onion request: {:?}", err);
+                let message = format!("Failed to handle TCP onion request: {:?}", err);
+                onion_errors.handle(addr, message, &onion_request_errors, log_throttle_c);
             }
         }
 
         Ok(())
     };
 
-    if config.bootstrap_nodes.is_empty() {
+    let mut bootstrap_nodes = config.bootstrap_nodes.clone();
+    if let Some(ref bootstrap_url) = config.bootstrap_url {
+        match fetch_bootstrap_nodes(bootstrap_url, BOOTSTRAP_URL_TIMEOUT).await {
+            Ok(nodes) => {
+                info!("Fetched {} bootstrap nodes from '{}'", nodes.len(), bootstrap_url);
+                bootstrap_nodes.extend(nodes);
+            },
+            Err(e) => warn!("Failed to fetch bootstrap nodes from '{}', using configured ones: {}", bootstrap_url, e),
+        }
+    }
+
+    let cached_nodes = config.nodes_cache
+        .as_ref()
+        .map(|nodes_cache| load_nodes_cache(Path::new(nodes_cache)))
+        .unwrap_or_default();
+    if !cached_nodes.is_empty() {
+        info!("Loaded {} bootstrap nodes from nodes cache", cached_nodes.len());
+    }
+
+    if bootstrap_nodes.is_empty() && cached_nodes.is_empty() {
         warn!("No bootstrap nodes!");
     }
 
-    for node in config.bootstrap_nodes.iter().flat_map(|node| node.resolve()) {
+    let mut resolved_nodes = Vec::new();
+    for node in &bootstrap_nodes {
+        let resolved = node.resolve_with_timeout(Duration::from_secs(config.dns_timeout), config.dns_retries, AddrOrder::new(config)).await;
+        if resolved.is_empty() {
+            metrics.bootstrap_resolve_failures.fetch_add(1, Ordering::Relaxed);
+        }
+        resolved_nodes.extend(resolved);
+    }
+    // cached nodes go last so that duplicates of configured nodes are
+    // removed from them
+    let (unique_nodes, duplicates) = dedup_bootstrap_nodes(resolved_nodes.into_iter().chain(cached_nodes));
+    if duplicates > 0 {
+        debug!("Removed {} duplicate bootstrap nodes", duplicates);
+    }
+
+    let mut bootstrap_addrs = HashSet::new();
+    for node in unique_nodes {
+        bootstrap_addrs.insert(node.saddr);
         udp_server.add_initial_bootstrap(node);
     }
 
-    info!("Running DHT server on {}", udp_addr);
+    info!("Running DHT server on {}", config.udp_addrs.iter().format(","));
+
+    let udp_server_future = run_dht_sockets(config, &udp_server, sockets, rx, &metrics, &requester, &log_throttle);
+
+    let config_reload_future = run_config_reload(config, &udp_server, &motd);
 
-    let udp_server_future = dht_run_socket(&udp_server, socket, rx, udp_stats).map_err(Error::from);
+    let bootstrap_file_reload_future = run_bootstrap_file_reload(config, &udp_server);
 
-    futures::try_join!(udp_server_future, lan_discovery_future, udp_onion_future)?;
+    let bootstrap_refresh_future = run_bootstrap_refresh(config, &udp_server, bootstrap_addrs);
+
+    let dht_pings_future = run_dht_pings(config, &udp_server, dht_pk);
+
+    let nodes_cache_future = async {
+        if let Some(ref nodes_cache) = config.nodes_cache {
+            run_nodes_cache(Path::new(nodes_cache), &udp_server, dht_pk).await
+        } else {
+            future::pending().await
+        }
+    };
+
+    let dht_nodes_future = async {
+        let mut wakeups = tokio::time::interval(DHT_NODES_POLL_INTERVAL);
+        let mut nodes_threshold = config.dht_nodes_threshold.map(NodesThreshold::new);
+        while wakeups.next().await.is_some() {
+            if let Some(ref mut nodes_threshold) = nodes_threshold {
+                // `get_closest` returns at most 255 nodes so all of them
+                // are counted instead
+                nodes_threshold.update(udp_server.close_nodes.read().await.iter().count());
+            }
+
+            let nodes = udp_server.get_closest(&dht_pk, u8::MAX, false).await.len();
+            metrics.dht_nodes.store(nodes as u64, Ordering::Relaxed);
+
+            let bootstrapped = nodes > 0;
+            if metrics.bootstrapped.swap(bootstrapped, Ordering::Relaxed) != bootstrapped {
+                if bootstrapped {
+                    info!("DHT node is bootstrapped, {} close nodes are known", nodes);
+                } else {
+                    warn!("DHT node lost all close nodes and is not bootstrapped anymore");
+                }
+            }
+        }
+
+        Ok(())
+    };
+
+    let log_throttle_future = log_throttle.run().map(Ok);
+
+    futures::try_join!(udp_server_future, lan_discovery_future, udp_onion_future, config_reload_future, bootstrap_file_reload_future, bootstrap_refresh_future, dht_pings_future, dht_nodes_future, nodes_cache_future, log_throttle_future)?;
 
     Ok(())
 }
 
-fn main() {
-    if crypto_init().is_err() {
-        panic!("Crypto initialization failed.");
+/// Advise when the node is bound to an address that is not reachable from
+/// the public DHT. Private addresses are expected for LAN nodes so they are
+/// not reported when LAN discovery is enabled.
+fn check_public_addr(protocol: &str, addr: SocketAddr, lan_discovery_enabled: bool) {
+    match addr_scope(addr.ip()) {
+        AddrScope::Public => { },
+        AddrScope::Loopback =>
+            warn!("{} address {} is a loopback address, the node won't be reachable from other hosts \
+                   and the public DHT", protocol, addr),
+        AddrScope::Private => if !lan_discovery_enabled {
+            info!("{} address {} is a private address, the node won't be reachable from the public DHT \
+                   unless the port is forwarded", protocol, addr)
+        },
     }
+}
 
-    let config = cli_parse();
+/// Bind UDP sockets to all UDP addresses from the config.
+fn bind_udp_sockets(config: &NodeConfig) -> Result<Vec<UdpSocket>, Error> {
+    // IPv4 packets are received via IPv4 socket if there is one and never
+    // received in IPv6 mode or when replies can't be sent via IPv6 socket
+    let only_v6 = config.ip_mode == IpMode::Ipv6 || config.no_v4_mapped || config.udp_addrs.iter().any(|udp_addr| udp_addr.is_ipv4());
+    config.udp_addrs
+        .iter()
+        .map(|&udp_addr| bind_socket(
+            udp_addr,
+            only_v6,
+            config.udp_recv_buffer,
+            config.udp_send_buffer,
+            config.lan_discovery_enabled,
+            config.lan_discovery_enabled && !config.no_multicast_loop,
+        ))
+        .collect()
+}
+
+/// Run DHT server on UDP sockets. When it fails with a network error it's
+/// restarted on new sockets up to `udp_restart_retries` times in a row with
+/// exponential backoff. Failures to bind new sockets are not retried.
+async fn run_dht_sockets(
+    config: &NodeConfig,
+    udp_server: &UdpServer,
+    mut sockets: Vec<UdpSocket>,
+    mut rx: mpsc::Receiver<(Packet, SocketAddr)>,
+    metrics: &Metrics,
+    requester: &BootstrapRequester,
+    log_throttle: &LogThrottle,
+) -> Result<(), Error> {
+    let mut backoff = Backoff::new(
+        Duration::from_secs(config.udp_restart_delay),
+        UDP_RESTART_MAX_DELAY,
+        UDP_RESTART_RESET_INTERVAL,
+        config.udp_restart_retries,
+    );
+    loop {
+        let started = Instant::now();
+        let ban_list = BanList::new(&config.ban_list);
+        let e = match dht_run_sockets(udp_server, sockets, &mut rx, config, metrics, ban_list, requester, log_throttle).await {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+
+        let (attempt, delay) = match backoff.failed(started.elapsed()) {
+            Some(next) => next,
+            None => return Err(e),
+        };
+        warn!("DHT server failed: {}. Restarting in {:?} (attempt {} of {})", e, delay, attempt, config.udp_restart_retries);
+        tokio::time::delay_for(delay).await;
 
+        info!("Binding new UDP sockets to restart DHT server");
+        sockets = bind_udp_sockets(config)?;
+        info!("Restarted DHT server on {}", config.udp_addrs.iter().format(","));
+    }
+}
+
+/// Threshold of the number of known DHT nodes. Crossing it in either
+/// direction is logged once.
+struct NodesThreshold {
+    threshold: usize,
+    exceeded: bool,
+}
+
+impl NodesThreshold {
+    fn new(threshold: usize) -> Self {
+        NodesThreshold {
+            threshold,
+            exceeded: false,
+        }
+    }
+
+    /// Update the number of known nodes. Returns whether the threshold is
+    /// exceeded if it's crossed by this update.
+    fn update(&mut self, total: usize) -> Option<bool> {
+        let exceeded = total > self.threshold;
+        if exceeded == self.exceeded {
+            return None;
+        }
+
+        self.exceeded = exceeded;
+        if exceeded {
+            warn!("DHT server knows {} nodes which exceeds the threshold of {}", total, self.threshold);
+        } else {
+            info!("DHT server knows {} nodes which is within the threshold of {} again", total, self.threshold);
+        }
+        Some(exceeded)
+    }
+}
+
+/// JSON line printed with `ready-json` when the node is ready.
+fn ready_json(config: &NodeConfig, dht_pk_hex: &str) -> String {
+    serde_json::json!({
+        "public_key": dht_pk_hex,
+        "version": env!("CARGO_PKG_VERSION"),
+        "udp_addresses": config.udp_addrs.iter().map(|addr| addr.to_string()).collect::<Vec<_>>(),
+        "tcp_addresses": config.tcp_addrs.iter().map(|addr| addr.addr.to_string()).collect::<Vec<_>>(),
+        "pid": std::process::id(),
+    }).to_string()
+}
+
+/// Refuse to start as root unless it's explicitly allowed since a bootstrap
+/// node doesn't need any privileges.
+#[cfg(unix)]
+fn check_root(allow_root: bool) -> Result<(), Error> {
+    // Safe because geteuid is always successful and has no side effects
+    if unsafe { libc::geteuid() } != 0 {
+        return Ok(());
+    }
+
+    if allow_root {
+        warn!("Running as root is not recommended, consider running as an unprivileged user");
+        Ok(())
+    } else {
+        Err(format_err!("Refusing to run as root. Run as an unprivileged user or pass --allow-root \
+                (allow-root config key) if you really need it"))
+    }
+}
+
+/// Log level chosen in the config. `quiet` overrides the default one with
+/// warnings.
+fn log_level(config: &NodeConfig) -> Option<log::LevelFilter> {
+    if config.quiet {
+        Some(log::LevelFilter::Warn)
+    } else {
+        config.log_level
+    }
+}
+
+/// Create `env_logger` builder with the layout of records chosen by
+/// `log_format`. The full format is the default one of `env_logger`.
+fn console_logger_builder(config: &NodeConfig) -> env_logger::Builder {
+    let mut builder = logger::env_logger_builder(log_level(config));
+    match config.log_format {
+        LogFormat::Compact => { builder.format(logger::format_compact); },
+        LogFormat::Full => { },
+        LogFormat::Json => { builder.format(logger::format_json); },
+    }
+    builder
+}
+
+/// Initialize the logger backend specified in the config.
+fn init_logger(config: &NodeConfig) -> Result<(), Error> {
     match config.log_type {
         LogType::Stderr => {
-            let env = env_logger::Env::default()
-                .filter_or("RUST_LOG", "info");
-            env_logger::Builder::from_env(env)
-                .init();
+            console_logger_builder(config)
+                .try_init()?;
         },
         LogType::Stdout => {
-            let env = env_logger::Env::default()
-                .filter_or("RUST_LOG", "info");
-            env_logger::Builder::from_env(env)
+            console_logger_builder(config)
                 .target(env_logger::fmt::Target::Stdout)
-                .init();
+                .try_init()?;
+        },
+        LogType::Json => {
+            logger::env_logger_builder(log_level(config))
+                .format(logger::format_json)
+                .target(env_logger::fmt::Target::Stdout)
+                .try_init()?;
         },
         #[cfg(unix)]
         LogType::Syslog => {
-            syslog::init(Facility::LOG_USER, log::LevelFilter::Info, None)
-                .expect("Failed to initialize syslog backend.");
+            let log_level = log_level(config).unwrap_or(log::LevelFilter::Info);
+            syslog::init(Facility::LOG_USER, log_level, None)
+                .map_err(|e| format_err!("{}", e))?;
+        },
+        LogType::File => {
+            let path = config.log_file.as_ref().ok_or_else(|| format_err!("Log file is not specified"))?;
+            let file = logger::RotatingFile::open(path.into(), config.log_max_size * 1024 * 1024, config.log_keep)
+                .map_err(|e| format_err!("Failed to open log file '{}': {}", path, e))?;
+            logger::FileLogger::new(file, log_level(config), config.log_format)
+                .init()?;
         },
         LogType::None => { },
     }
 
-    for key in config.unused.keys() {
-        warn!("Unused configuration key: {:?}", key);
+    Ok(())
+}
+
+fn main() {
+    if crypto_init().is_err() {
+        panic!("Crypto initialization failed.");
+    }
+
+    let config = cli_parse();
+
+    if config.print_config {
+        exit_command(print_config(&config));
+    }
+
+    if let Err(e) = init_logger(&config) {
+        console_logger_builder(&config)
+            .init();
+        warn!("Failed to initialize {:?} logger, falling back to stderr: {}", config.log_type, e);
+    }
+
+    for key in config.unused.keys().sorted() {
+        if let Some(suggestion) = suggest_config_key(key) {
+            warn!("Unused configuration key: {:?}, did you mean {:?}?", key, suggestion);
+        } else {
+            warn!("Unused configuration key: {:?}", key);
+        }
+    }
+
+    if let Err(e) = run_node(config) {
+        error!("{}", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+/// Run the node with the parsed config until it's stopped. Failures are
+/// classified so that `main` can exit with a distinct code for each class.
+fn run_node(mut config: NodeConfig) -> Result<(), NodeError> {
+    add_interfaces_addrs(&mut config);
+
+    if let Some(ref bootstrap_file) = config.bootstrap_file {
+        let nodes = load_bootstrap_file(bootstrap_file).map_err(NodeError::Config)?;
+        config.bootstrap_nodes.extend(nodes);
+    }
+
+    if config.dry_run {
+        dry_run(&config);
+    }
+
+    #[cfg(unix)]
+    check_root(config.allow_root).map_err(NodeError::Config)?;
+
+    // Checked before daemonizing so the error is printed to the terminal
+    if let Some(ref pid_file) = config.pid_file {
+        check_pid_file(Path::new(pid_file)).map_err(NodeError::Runtime)?;
     }
 
     let (dht_pk, dht_sk) = if let Some(ref sk) = config.sk {
         (sk.public_key(), sk.clone())
-    } else if let Some(ref keys_file) = config.keys_file {
-        load_or_gen_keys(keys_file)
+    } else if let Some(ref sk_file) = config.sk_file {
+        let sk = load_secret_key_file(sk_file).map_err(NodeError::Keys)?;
+        (sk.public_key(), sk)
+    } else if !config.keys_files.is_empty() {
+        select_keys(&config.keys_files, config.expected_pk, config.keys_format).map_err(NodeError::Keys)?
     } else {
-        panic!("Neither secret key nor keys file is specified")
+        return Err(NodeError::Config(format_err!("Neither secret key nor keys file is specified")))
     };
 
-    if config.tcp_addrs.is_empty() && config.udp_addr.is_none() {
-        panic!("Both TCP addresses and UDP address are not defined.")
+    if config.tcp_addrs.is_empty() && config.udp_addrs.is_empty() {
+        return Err(NodeError::Config(format_err!("Both TCP addresses and UDP addresses are not defined")))
     }
 
     if config.sk_passed_as_arg {
@@ -333,31 +1727,275 @@ fn main() {
                security reasons. Use the environment variable instead");
     }
 
-    info!("DHT public key: {}", hex::encode(dht_pk.as_ref()).to_uppercase());
+    let dht_pk_hex = hex::encode(dht_pk.as_ref()).to_uppercase();
+    info!("DHT public key: {}", dht_pk_hex);
+    for addr in config.udp_addrs.iter().chain(config.tcp_addrs.iter().map(|addr| &addr.addr)) {
+        info!("Bootstrap: {} {}", dht_pk_hex, addr);
+    }
+
+    #[cfg(unix)]
+    {
+        if config.daemonize {
+            match config.log_type {
+                LogType::Syslog | LogType::File | LogType::None => { },
+                log_type => warn!("Logs are discarded after daemonizing with {:?} log type, consider using Syslog", log_type),
+            }
+            daemon::daemonize().map_err(NodeError::Runtime)?;
+        }
+    }
+
+    // Written after daemonizing to contain PID of the detached process and
+    // removed when the node is stopped gracefully
+    let _pid_file = config.pid_file
+        .as_ref()
+        .map(|pid_file| PidFile::create(Path::new(pid_file)))
+        .transpose()
+        .map_err(NodeError::Runtime)?;
 
-    let (tcp_onion, udp_onion) = create_onion_streams();
+    let (access_log, _access_log_writer) = match config.access_log
+        .as_ref()
+        .map(|path| access_log::open(Path::new(path)))
+        .transpose()
+        .map_err(NodeError::Runtime)?
+    {
+        Some((access_log, writer)) => (Some(access_log), Some(writer)),
+        None => (None, None),
+    };
+
+    let metrics = Metrics::new();
+
+    let status = Status {
+        start_date: Local::now(),
+        dht_pk,
+        udp_addrs: config.udp_addrs.clone(),
+        tcp_addrs: config.tcp_addrs.clone(),
+        tcp_unix_path: config.tcp_unix_path.clone(),
+        metrics: metrics.clone(),
+    };
+    let status_future = run_status_dump(status);
 
-    let udp_tcp_stats = Stats::new();
-    let tcp_tcp_stats = udp_tcp_stats.clone();
+    let (tcp_onion, udp_onion, onion_forwarding) = create_onion_streams(config.onion_buffer, metrics.onion_dropped.clone());
 
     let udp_config = config.clone();
     let udp_dht_sk = dht_sk.clone();
+    let udp_metrics = metrics.clone();
+    let (udp_ready_tx, udp_ready_rx) = oneshot::channel();
     let udp_server_future = async move {
-        run_udp(&udp_config, dht_pk, &udp_dht_sk, udp_onion, udp_tcp_stats.clone()).await
+        run_udp(&udp_config, dht_pk, &udp_dht_sk, udp_onion, udp_metrics, udp_ready_tx).await
     };
 
     let tcp_config = config.clone();
     let tcp_dht_sk = dht_sk;
+    let tcp_metrics = metrics.clone();
+    let (tcp_shutdown_tx, tcp_shutdown_rx) = oneshot::channel();
+    let (tcp_ready_tx, tcp_ready_rx) = oneshot::channel();
+    // The TCP relay is restarted with a fresh state on SIGUSR2 keeping the
+    // same DHT key while the DHT server is left untouched
     let tcp_server_future = async move {
-        run_tcp(&tcp_config, tcp_dht_sk, tcp_onion, tcp_tcp_stats).await
+        let tcp_shutdown = tcp_shutdown_rx.shared();
+        let restarts = tcp_restart_signals()?.map(|()| info!(
+            "Restarting TCP relay with the same DHT key, closing {} connections",
+            tcp_metrics.tcp_connections.load(Ordering::Relaxed)
+        ));
+        // the relay can borrow only the state shared by its generations so
+        // it gets its own copy of the config
+        run_restartable("TCP relay", &mut (tcp_onion, Some(tcp_ready_tx)), |(tcp_onion, tcp_ready)| {
+            let (config, dht_sk, metrics, shutdown, access_log) =
+                (tcp_config.clone(), tcp_dht_sk.clone(), tcp_metrics.clone(), tcp_shutdown.clone(), access_log.clone());
+            async move {
+                run_tcp(&config, dht_sk, tcp_onion, metrics, shutdown, tcp_ready, access_log).await
+            }.boxed()
+        }, restarts).await
+    };
+
+    let stats_interval = config.stats_interval;
+    let stats_metrics = metrics.clone();
+    let stats_future = async move {
+        if stats_interval > 0 {
+            run_stats_logging(Duration::from_secs(stats_interval), stats_metrics).await
+        } else {
+            // If stats interval is not specified don't log stats
+            future::pending().await
+        }
+    };
+
+    let health_addr = config.health_addr;
+    let start = Instant::now();
+    let bootstrapped = metrics.bootstrapped.clone();
+    let health_future = async move {
+        if let Some(health_addr) = health_addr {
+            run_health(health_addr, start, bootstrapped).await
+        } else {
+            // If health address is not specified don't start the endpoint
+            future::pending().await
+        }
+    };
+
+    let metrics_addr = config.metrics_addr;
+    let metrics_future = async move {
+        if let Some(metrics_addr) = metrics_addr {
+            run_metrics(metrics_addr, metrics).await
+        } else {
+            // If metrics address is not specified don't start the endpoint
+            future::pending().await
+        }
+    };
+
+    // Printed once when both servers have bound their sockets. If any of
+    // them fails to bind its sender is dropped and nothing is printed.
+    let ready_line = if config.ready_json {
+        Some(ready_json(&config, &dht_pk_hex))
+    } else {
+        None
+    };
+    let ready_future = async move {
+        if let Some(ready_line) = ready_line {
+            if future::try_join(udp_ready_rx, tcp_ready_rx).await.is_ok() {
+                println!("{}", ready_line);
+            }
+        }
+        future::pending().await
+    };
+
+    let run_for = config.run_for;
+    let run_for_future = async move {
+        if let Some(run_for) = run_for {
+            tokio::time::delay_for(Duration::from_secs(run_for)).await
+        } else {
+            future::pending().await
+        }
     };
 
     let future = async move {
-        futures::select! {
+        tokio::spawn(onion_forwarding);
+
+        let mut tcp_server_future = tcp_server_future.boxed().fuse();
+        let mut shutdown = false;
+        let res = futures::select! {
             res = udp_server_future.fuse() => res,
-            res = tcp_server_future.fuse() => res,
+            res = tcp_server_future => return res,
+            res = metrics_future.fuse() => res,
+            res = stats_future.fuse() => res,
+            res = health_future.fuse() => res,
+            res = status_future.fuse() => res,
+            res = ready_future.fuse() => res,
+            res = shutdown_signal().fuse() => {
+                info!("Shutting down");
+                shutdown = true;
+                res
+            },
+            () = run_for_future.fuse() => {
+                info!("Run duration elapsed, shutting down");
+                shutdown = true;
+                Ok(())
+            },
+        };
+
+        // Other futures are dropped by now so only TCP relay keeps running
+        // while it drains connections
+        if shutdown && res.is_ok() {
+            let _ = tcp_shutdown_tx.send(());
+            tcp_server_future.await
+        } else {
+            res
         }
     };
 
-    run(future, config.threads);
+    check_threads(config.threads);
+    run(future, config.threads).map_err(NodeError::from_runtime)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn bind_tcp_with_backlog() {
+        runtime().block_on(async {
+            let mut listener = bind_tcp("127.0.0.1:0".parse().unwrap(), Some(16)).await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = TcpStream::connect(addr).await.unwrap();
+            let (_server, peer_addr) = listener.accept().await.unwrap();
+            assert_eq!(peer_addr, client.local_addr().unwrap());
+
+            // the port can be bound again right away like with the default
+            // backlog
+            drop(listener);
+            bind_tcp(addr, Some(1)).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn ready_json_fields() {
+        let mut file = tempfile::Builder::new().suffix(".yml").tempfile().unwrap();
+        write!(file, "keys-file: ./keys\nudp-address: 127.0.0.1:33445\ntcp-addresses: [127.0.0.1:33446]\nready-json: true\n").unwrap();
+        let config = try_parse_config(&[file.path().to_str().unwrap()]).unwrap();
+
+        let line = ready_json(&config, "ABCDEF");
+        assert!(!line.contains('\n'));
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["public_key"], "ABCDEF");
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(json["udp_addresses"], serde_json::json!(["127.0.0.1:33445"]));
+        assert_eq!(json["tcp_addresses"], serde_json::json!(["127.0.0.1:33446"]));
+        assert_eq!(json["pid"], std::process::id());
+    }
+
+    #[test]
+    fn broadcast_only_with_lan_discovery() {
+        runtime().block_on(async {
+            let socket = bind_socket("127.0.0.1:0".parse().unwrap(), false, None, None, false, false).unwrap();
+            assert!(!socket.broadcast().unwrap());
+            let socket = bind_socket("127.0.0.1:0".parse().unwrap(), false, None, None, true, false).unwrap();
+            assert!(socket.broadcast().unwrap());
+        });
+    }
+
+    #[test]
+    fn lan_discovery_packets_are_counted() {
+        crypto_init().unwrap();
+        let (tx, mut rx) = mpsc::channel(4096);
+        let sent = Arc::new(AtomicU64::new(0));
+        let (dht_pk, _) = gen_keypair();
+
+        runtime().block_on(async {
+            // the first round of packets is sent right away
+            let lan_discovery = run_lan_discovery(tx, dht_pk, false, 0, sent.clone());
+            let sent_round = tokio::time::delay_for(Duration::from_millis(200));
+            futures::select! {
+                res = lan_discovery.fuse() => panic!("LAN discovery stopped: {:?}", res),
+                () = sent_round.fuse() => { },
+            }
+        });
+
+        let mut received = 0;
+        while let Ok(Some((packet, _))) = rx.try_next() {
+            assert!(matches!(packet, Packet::LanDiscovery(_)));
+            received += 1;
+        }
+        assert!(received > 0);
+        assert_eq!(sent.load(Ordering::Relaxed), received);
+    }
+
+    #[test]
+    fn nodes_threshold_crossing() {
+        let mut threshold = NodesThreshold::new(10);
+        assert_eq!(threshold.update(5), None);
+        assert_eq!(threshold.update(10), None);
+        assert_eq!(threshold.update(11), Some(true));
+        // logged only once while it's exceeded
+        assert_eq!(threshold.update(20), None);
+        assert_eq!(threshold.update(10), Some(false));
+        assert_eq!(threshold.update(3), None);
+    }
 }
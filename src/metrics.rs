@@ -0,0 +1,378 @@
+//! Prometheus metrics endpoint.
+
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use failure::Error;
+use futures::StreamExt;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+
+use tox::core::stats::Stats;
+
+use crate::error::BindError;
+use crate::motd::format_uptime;
+
+/// Types of received DHT packets counted separately. Related packet kinds
+/// share a type, e.g. all three onion request layers.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PacketType {
+    PingRequest,
+    PingResponse,
+    NodesRequest,
+    NodesResponse,
+    NetCrypto,
+    DhtRequest,
+    LanDiscovery,
+    OnionRequest,
+    OnionResponse,
+    OnionAnnounceRequest,
+    OnionAnnounceResponse,
+    OnionDataRequest,
+    OnionDataResponse,
+    BootstrapInfo,
+}
+
+impl PacketType {
+    /// All packet types in the order of their counters.
+    const ALL: [PacketType; 14] = [
+        PacketType::PingRequest,
+        PacketType::PingResponse,
+        PacketType::NodesRequest,
+        PacketType::NodesResponse,
+        PacketType::NetCrypto,
+        PacketType::DhtRequest,
+        PacketType::LanDiscovery,
+        PacketType::OnionRequest,
+        PacketType::OnionResponse,
+        PacketType::OnionAnnounceRequest,
+        PacketType::OnionAnnounceResponse,
+        PacketType::OnionDataRequest,
+        PacketType::OnionDataResponse,
+        PacketType::BootstrapInfo,
+    ];
+
+    /// Value of `packet_type` label of the type.
+    fn label(self) -> &'static str {
+        match self {
+            PacketType::PingRequest => "ping_request",
+            PacketType::PingResponse => "ping_response",
+            PacketType::NodesRequest => "nodes_request",
+            PacketType::NodesResponse => "nodes_response",
+            PacketType::NetCrypto => "net_crypto",
+            PacketType::DhtRequest => "dht_request",
+            PacketType::LanDiscovery => "lan_discovery",
+            PacketType::OnionRequest => "onion_request",
+            PacketType::OnionResponse => "onion_response",
+            PacketType::OnionAnnounceRequest => "onion_announce_request",
+            PacketType::OnionAnnounceResponse => "onion_announce_response",
+            PacketType::OnionDataRequest => "onion_data_request",
+            PacketType::OnionDataResponse => "onion_data_response",
+            PacketType::BootstrapInfo => "bootstrap_info",
+        }
+    }
+}
+
+/// Counters exported via the metrics endpoint. All counters are shared so
+/// cloning is cheap and clones can be updated from any future.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    /// Packet counters of the TCP relay.
+    pub tcp: Stats,
+    /// Packet counters of the DHT server.
+    pub udp: Stats,
+    /// Number of onion requests forwarded from the TCP relay to the DHT.
+    pub onion_requests: Arc<AtomicU64>,
+    /// Number of onion responses forwarded from the DHT to the TCP relay.
+    pub onion_responses: Arc<AtomicU64>,
+    /// Number of onion packets dropped because the queue between the TCP
+    /// relay and the DHT was full.
+    pub onion_dropped: Arc<AtomicU64>,
+    /// Number of onion requests from the TCP relay that the DHT server failed
+    /// to handle.
+    pub onion_request_errors: Arc<AtomicU64>,
+    /// Number of onion responses from the DHT that the TCP relay failed to
+    /// send to the client.
+    pub onion_response_errors: Arc<AtomicU64>,
+    /// Number of onion responses from the DHT dropped because the TCP relay
+    /// is disabled.
+    pub onion_dropped_tcp_disabled: Arc<AtomicU64>,
+    /// Number of onion requests from the TCP relay dropped because the DHT
+    /// server is disabled.
+    pub onion_dropped_udp_disabled: Arc<AtomicU64>,
+    /// Number of UDP packets dropped because they exceeded the maximum
+    /// packet size.
+    pub udp_oversized: Arc<AtomicU64>,
+    /// Number of UDP packets dropped because their kind is unknown.
+    pub udp_unknown_kind: Arc<AtomicU64>,
+    /// Number of UDP packets of a known kind dropped because they can't be
+    /// parsed, e.g. have a wrong length.
+    pub udp_malformed: Arc<AtomicU64>,
+    /// Number of UDP packets not sent because they exceeded the send rate.
+    pub udp_send_dropped: Arc<AtomicU64>,
+    /// Number of received DHT packets per type indexed by `PacketType`.
+    udp_packet_types: Arc<[AtomicU64; 14]>,
+    /// Number of LAN discovery packets sent.
+    pub lan_discovery_sent: Arc<AtomicU64>,
+    /// Number of currently active TCP connections.
+    pub tcp_connections: Arc<AtomicU64>,
+    /// Number of currently active TCP connections per listener name.
+    tcp_listeners: Arc<Mutex<BTreeMap<String, Arc<AtomicU64>>>>,
+    /// Number of bootstrap nodes that failed to resolve.
+    pub bootstrap_resolve_failures: Arc<AtomicU64>,
+    /// Number of DHT nodes known by the DHT server.
+    pub dht_nodes: Arc<AtomicU64>,
+    /// Whether the DHT server has close nodes, i.e. is connected to the DHT.
+    pub bootstrapped: Arc<AtomicBool>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Count a received DHT packet of the type.
+    pub fn count_udp_packet(&self, packet_type: PacketType) {
+        self.udp_packet_types[packet_type as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counter of active TCP connections of the listener with the name. The
+    /// counter is created on the first call.
+    pub fn tcp_listener_connections(&self, name: &str) -> Arc<AtomicU64> {
+        self.tcp_listeners
+            .lock()
+            .expect("Failed to lock TCP listeners metrics")
+            .entry(name.to_owned())
+            .or_default()
+            .clone()
+    }
+
+    /// Format all counters in Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            writeln!(out, "# HELP tox_node_{} {}", name, help).unwrap();
+            writeln!(out, "# TYPE tox_node_{} {}", name, kind).unwrap();
+            writeln!(out, "tox_node_{} {}", name, value).unwrap();
+        };
+
+        metric("udp_packets_received_total", "counter", "Number of received UDP packets.",
+            self.udp.counters.incoming());
+        metric("udp_packets_sent_total", "counter", "Number of sent UDP packets.",
+            self.udp.counters.outgoing());
+        metric("udp_oversized_packets_total", "counter", "Number of UDP packets dropped because they exceeded the maximum packet size.",
+            self.udp_oversized.load(Ordering::Relaxed));
+        metric("udp_send_dropped_packets_total", "counter", "Number of UDP packets not sent because they exceeded the send rate.",
+            self.udp_send_dropped.load(Ordering::Relaxed));
+        metric("lan_discovery_packets_sent_total", "counter", "Number of sent LAN discovery packets.",
+            self.lan_discovery_sent.load(Ordering::Relaxed));
+        metric("tcp_packets_received_total", "counter", "Number of received TCP packets.",
+            self.tcp.counters.incoming());
+        metric("tcp_packets_sent_total", "counter", "Number of sent TCP packets.",
+            self.tcp.counters.outgoing());
+        metric("onion_requests_forwarded_total", "counter", "Number of onion requests forwarded from TCP to UDP.",
+            self.onion_requests.load(Ordering::Relaxed));
+        metric("onion_responses_forwarded_total", "counter", "Number of onion responses forwarded from UDP to TCP.",
+            self.onion_responses.load(Ordering::Relaxed));
+        metric("onion_packets_dropped_total", "counter", "Number of onion packets dropped because the queue between TCP and UDP was full.",
+            self.onion_dropped.load(Ordering::Relaxed));
+        metric("onion_request_errors_total", "counter", "Number of onion requests from TCP that failed to be handled.",
+            self.onion_request_errors.load(Ordering::Relaxed));
+        metric("onion_response_errors_total", "counter", "Number of onion responses from UDP that failed to be sent to the TCP client.",
+            self.onion_response_errors.load(Ordering::Relaxed));
+        metric("tcp_connections", "gauge", "Number of active TCP connections.",
+            self.tcp_connections.load(Ordering::Relaxed));
+        metric("bootstrap_resolve_failures_total", "counter", "Number of bootstrap nodes that failed to resolve.",
+            self.bootstrap_resolve_failures.load(Ordering::Relaxed));
+        metric("dht_nodes", "gauge", "Number of DHT nodes known by the DHT server.",
+            self.dht_nodes.load(Ordering::Relaxed));
+        metric("bootstrapped", "gauge", "Whether the DHT server is connected to the DHT.",
+            self.bootstrapped.load(Ordering::Relaxed) as u64);
+
+        writeln!(out, "# HELP tox_node_udp_decode_errors_total Number of UDP packets dropped because they can't be decoded.").unwrap();
+        writeln!(out, "# TYPE tox_node_udp_decode_errors_total counter").unwrap();
+        for (kind, counter) in &[("unknown_kind", &self.udp_unknown_kind), ("malformed", &self.udp_malformed)] {
+            writeln!(out, "tox_node_udp_decode_errors_total{{kind=\"{}\"}} {}", kind, counter.load(Ordering::Relaxed)).unwrap();
+        }
+
+        writeln!(out, "# HELP tox_node_onion_packets_dropped_disabled_total Number of onion packets dropped because the server they are destined to is disabled.").unwrap();
+        writeln!(out, "# TYPE tox_node_onion_packets_dropped_disabled_total counter").unwrap();
+        for (disabled, counter) in &[("tcp", &self.onion_dropped_tcp_disabled), ("udp", &self.onion_dropped_udp_disabled)] {
+            writeln!(out, "tox_node_onion_packets_dropped_disabled_total{{disabled=\"{}\"}} {}", disabled, counter.load(Ordering::Relaxed)).unwrap();
+        }
+
+        writeln!(out, "# HELP tox_node_udp_packets_received_by_type_total Number of received DHT packets by type, not counting dropped ones.").unwrap();
+        writeln!(out, "# TYPE tox_node_udp_packets_received_by_type_total counter").unwrap();
+        for &packet_type in PacketType::ALL.iter() {
+            writeln!(out, "tox_node_udp_packets_received_by_type_total{{packet_type=\"{}\"}} {}",
+                packet_type.label(), self.udp_packet_types[packet_type as usize].load(Ordering::Relaxed)).unwrap();
+        }
+
+        let tcp_listeners = self.tcp_listeners.lock().expect("Failed to lock TCP listeners metrics");
+        if !tcp_listeners.is_empty() {
+            writeln!(out, "# HELP tox_node_tcp_listener_connections Number of active TCP connections per listener.").unwrap();
+            writeln!(out, "# TYPE tox_node_tcp_listener_connections gauge").unwrap();
+        }
+        for (name, connections) in tcp_listeners.iter() {
+            writeln!(out, "tox_node_tcp_listener_connections{{listener=\"{}\"}} {}",
+                escape_label(name), connections.load(Ordering::Relaxed)).unwrap();
+        }
+
+        out
+    }
+}
+
+/// Escape a label value for Prometheus text exposition format.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Handle a single HTTP request to the metrics endpoint.
+fn handle(req: Request<Body>, metrics: &Metrics) -> Response<Body> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Body::from(metrics.render()))
+            .expect("Failed to build metrics response"),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .expect("Failed to build metrics response"),
+    }
+}
+
+/// Run HTTP server exposing `/metrics` endpoint on the address.
+pub async fn run_metrics(addr: SocketAddr, metrics: Metrics) -> Result<(), Error> {
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let response = handle(req, &metrics);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+
+    info!("Running metrics endpoint on {}", addr);
+
+    Server::try_bind(&addr)
+        .map_err(|e| BindError(format!("Failed to bind metrics endpoint to {}: {}", addr, e)))?
+        .serve(make_service)
+        .await
+        .map_err(Error::from)
+}
+
+/// Log a summary of counters every `interval`: uptime, packets received and
+/// sent since the previous summary and number of active TCP connections.
+pub async fn run_stats_logging(interval: Duration, metrics: Metrics) -> Result<(), Error> {
+    let start = Instant::now();
+    let mut wakeups = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    let mut last = [0; 4];
+
+    while wakeups.next().await.is_some() {
+        let current = [
+            metrics.udp.counters.incoming(),
+            metrics.udp.counters.outgoing(),
+            metrics.tcp.counters.incoming(),
+            metrics.tcp.counters.outgoing(),
+        ];
+        info!("Uptime: {}, UDP packets in/out: {}/{}, TCP packets in/out: {}/{}, TCP connections: {}",
+            format_uptime(chrono::Duration::seconds(start.elapsed().as_secs() as i64)),
+            current[0] - last[0],
+            current[1] - last[1],
+            current[2] - last[2],
+            current[3] - last[3],
+            metrics.tcp_connections.load(Ordering::Relaxed),
+        );
+        last = current;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::new();
+        metrics.udp.counters.increase_incoming();
+        metrics.tcp_connections.fetch_add(2, Ordering::Relaxed);
+        metrics.bootstrapped.store(true, Ordering::Relaxed);
+        metrics.lan_discovery_sent.fetch_add(4, Ordering::Relaxed);
+        metrics.udp_send_dropped.fetch_add(3, Ordering::Relaxed);
+        let text = metrics.render();
+        assert!(text.contains("# TYPE tox_node_udp_packets_received_total counter\n"));
+        assert!(text.contains("\ntox_node_udp_packets_received_total 1\n"));
+        assert!(text.contains("\ntox_node_tcp_connections 2\n"));
+        assert!(text.contains("\ntox_node_bootstrapped 1\n"));
+        assert!(text.contains("\ntox_node_lan_discovery_packets_sent_total 4\n"));
+        assert!(text.contains("\ntox_node_udp_send_dropped_packets_total 3\n"));
+        assert!(!text.contains("tox_node_tcp_listener_connections"));
+    }
+
+    #[test]
+    fn render_decode_errors() {
+        let metrics = Metrics::new();
+        metrics.udp_malformed.fetch_add(2, Ordering::Relaxed);
+        let text = metrics.render();
+        assert!(text.contains("# TYPE tox_node_udp_decode_errors_total counter\n"));
+        assert!(text.contains("\ntox_node_udp_decode_errors_total{kind=\"unknown_kind\"} 0\n"));
+        assert!(text.contains("\ntox_node_udp_decode_errors_total{kind=\"malformed\"} 2\n"));
+    }
+
+    #[test]
+    fn render_onion_dropped_disabled() {
+        let metrics = Metrics::new();
+        metrics.onion_dropped_udp_disabled.fetch_add(3, Ordering::Relaxed);
+        let text = metrics.render();
+        assert!(text.contains("# TYPE tox_node_onion_packets_dropped_disabled_total counter\n"));
+        assert!(text.contains("\ntox_node_onion_packets_dropped_disabled_total{disabled=\"tcp\"} 0\n"));
+        assert!(text.contains("\ntox_node_onion_packets_dropped_disabled_total{disabled=\"udp\"} 3\n"));
+    }
+
+    #[test]
+    fn render_packet_types() {
+        let metrics = Metrics::new();
+        // each type is counted as many times as its index to tell counters
+        // apart
+        for (index, &packet_type) in PacketType::ALL.iter().enumerate() {
+            for _ in 0..index {
+                metrics.count_udp_packet(packet_type);
+            }
+        }
+        let text = metrics.render();
+        assert!(text.contains("# TYPE tox_node_udp_packets_received_by_type_total counter\n"));
+        for (index, &packet_type) in PacketType::ALL.iter().enumerate() {
+            let line = format!("\ntox_node_udp_packets_received_by_type_total{{packet_type=\"{}\"}} {}\n", packet_type.label(), index);
+            assert!(text.contains(&line), "{} is missing", line.trim());
+        }
+        let labels = PacketType::ALL.iter().map(|packet_type| packet_type.label()).collect::<std::collections::HashSet<_>>();
+        assert_eq!(labels.len(), PacketType::ALL.len());
+    }
+
+    #[test]
+    fn packet_types_order() {
+        for (index, &packet_type) in PacketType::ALL.iter().enumerate() {
+            assert_eq!(packet_type as usize, index);
+        }
+    }
+
+    #[test]
+    fn render_tcp_listeners() {
+        let metrics = Metrics::new();
+        metrics.tcp_listener_connections("public").fetch_add(3, Ordering::Relaxed);
+        metrics.tcp_listener_connections("0.0.0.0:3389");
+        metrics.tcp_listener_connections("public").fetch_sub(1, Ordering::Relaxed);
+        let text = metrics.render();
+        assert!(text.contains("# TYPE tox_node_tcp_listener_connections gauge\n"));
+        assert!(text.contains("\ntox_node_tcp_listener_connections{listener=\"public\"} 2\n"));
+        assert!(text.contains("\ntox_node_tcp_listener_connections{listener=\"0.0.0.0:3389\"} 0\n"));
+    }
+}
@@ -1,33 +1,99 @@
-use std::borrow::Cow;
-use chrono::DateTime;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration as StdDuration, Instant};
+use chrono::{DateTime, Duration};
 use chrono::offset::Local;
-use regex::Regex;
-use std::u64;
+use itertools::Itertools;
+use regex::{Captures, Regex};
 
 use tox::core::stats::Stats;
+use tox::crypto::PublicKey;
+use tox::packet::dht::BOOSTRAP_SERVER_MAX_MOTD_LENGTH;
 
-struct RegexMatches {
-    regex: Regex,
-    matches: bool,
+/// Size of the stats header that can be prepended to MOTD: version (`u32`),
+/// uptime in seconds (`u64`) and number of known DHT nodes (`u32`), all in
+/// big-endian byte order.
+pub const STATS_HEADER_SIZE: usize = 16;
+
+/// Length of the hex encoded DHT public key substituted for `public_key`.
+const PUBLIC_KEY_HEX_LENGTH: usize = 64;
+/// Length of the version substituted for `version`.
+const VERSION_LENGTH: usize = 10;
+
+/// Regex matching template variables like `{{ uptime }}`. Names are matched
+/// case-insensitively and may be surrounded by any whitespaces.
+fn variable_regex() -> Regex {
+    Regex::new(r"\{\{\s*(\w+)\s*\}\}").expect("Failed to compile variable regex")
 }
 
-impl RegexMatches {
-    pub fn new(template: &str, regex: Regex) -> RegexMatches {
-        RegexMatches {
-            matches: regex.is_match(template),
-            regex,
-        }
-    }
+/// Variables that can be used in MOTD template.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Variable {
+    StartDate,
+    Uptime,
+    TcpPacketsIn,
+    TcpPacketsOut,
+    UdpPacketsIn,
+    UdpPacketsOut,
+    PublicKey,
+    Version,
+}
 
-    pub fn replace<'a, F: FnOnce() -> String>(&self, template: &'a str, f: F) -> Cow<'a, str> {
-        if self.matches {
-            self.regex.replace_all(template, f().as_str())
-        } else {
-            Cow::from(template)
+/// Map of lowercase variable names to variables.
+fn variables() -> HashMap<&'static str, Variable> {
+    [
+        ("start_date", Variable::StartDate),
+        ("uptime", Variable::Uptime),
+        ("tcp_packets_in", Variable::TcpPacketsIn),
+        ("tcp_packets_out", Variable::TcpPacketsOut),
+        ("udp_packets_in", Variable::UdpPacketsIn),
+        ("udp_packets_out", Variable::UdpPacketsOut),
+        ("public_key", Variable::PublicKey),
+        ("version", Variable::Version),
+    ].iter().cloned().collect()
+}
+
+/// Length of the MOTD after substitution of variables with fixed length:
+/// `public_key` and `version`. Other variables are counted as is.
+pub fn fixed_length(template: &str) -> usize {
+    let variables = variables();
+    let result = variable_regex().replace_all(template, |caps: &Captures| {
+        match variables.get(caps[1].to_lowercase().as_str()) {
+            Some(Variable::PublicKey) => "0".repeat(PUBLIC_KEY_HEX_LENGTH),
+            Some(Variable::Version) => "0".repeat(VERSION_LENGTH),
+            _ => caps[0].to_owned(),
         }
+    });
+    result.len()
+}
+
+/// Warn about variables of the template that are unknown and will be left as
+/// is.
+fn warn_unknown_variables(template: &str, regex: &Regex, variables: &HashMap<&'static str, Variable>) {
+    let unknown = regex
+        .captures_iter(template)
+        .map(|caps| caps[1].to_owned())
+        .filter(|name| !variables.contains_key(name.to_lowercase().as_str()))
+        .unique()
+        .collect::<Vec<_>>();
+    if !unknown.is_empty() {
+        warn!("Unknown MOTD variables are left as is: {}", unknown.iter().format(", "));
     }
 }
 
+/// Format uptime in the format 'XX days XX hours XX minutes'.
+pub fn format_uptime(uptime: Duration) -> String {
+    let days = uptime.num_days();
+    let hours = uptime.num_hours() - uptime.num_days() * 24;
+    let minutes = uptime.num_minutes() - uptime.num_hours() * 60;
+    format!(
+      "{:0>#2} days {:0>#2} hours {:0>#2} minutes",
+      days,
+      hours,
+      minutes
+    )
+}
+
 /// Packet counters for both tcp and udp.
 pub struct Counters {
     tcp: Stats,
@@ -43,43 +109,93 @@ impl Counters {
     }
 }
 
+/// MOTD template with its formatted value.
+struct Template {
+    text: String,
+    /// Whether the template contains variables. Otherwise it's constant and
+    /// never cached.
+    has_variables: bool,
+    /// Formatted MOTD with the time when it was formatted.
+    cache: Mutex<Option<(Instant, String)>>,
+}
+
+impl Template {
+    fn new(text: String, variable_regex: &Regex, variables: &HashMap<&'static str, Variable>) -> Template {
+        warn_unknown_variables(&text, variable_regex, variables);
+        Template {
+            has_variables: variable_regex.is_match(&text),
+            text,
+            cache: Mutex::new(None),
+        }
+    }
+}
+
 pub struct Motd {
-    start_date_regex: RegexMatches,
-    uptime_regex: RegexMatches,
-    tcp_packets_in_regex: RegexMatches,
-    tcp_packets_out_regex: RegexMatches,
-    udp_packets_in_regex: RegexMatches,
-    udp_packets_out_regex: RegexMatches,
+    variable_regex: Regex,
+    variables: HashMap<&'static str, Variable>,
     start_date: DateTime<Local>,
     counters: Counters,
-    template: String,
+    public_key: PublicKey,
+    version: u32,
+    template: Template,
+    /// Template for IPv6 clients. `template` is used for all clients if it's
+    /// not set.
+    template_ipv6: Option<Template>,
+    /// Time to reuse formatted MOTD. Zero disables caching.
+    cache_ttl: StdDuration,
 }
 
 impl Motd {
-    pub fn new(template: String, counters: Counters) -> Motd {
-        let start_date_regex = Regex::new(r"(?i)\{\{\s*start_date\s*\}\}")
-            .expect("Failed to compile start_date regex");
-        let uptime_regex = Regex::new(r"(?i)\{\{\s*uptime\s*\}\}")
-            .expect("Failed to compile uptime regex");
-        let tcp_packets_in_regex = Regex::new(r"(?i)\{\{\s*tcp_packets_in\s*\}\}")
-            .expect("Failed to compile tcp_in regex");
-        let tcp_packets_out_regex = Regex::new(r"(?i)\{\{\s*tcp_packets_out\s*\}\}")
-            .expect("Failed to compile tcp_out regex");
-        let udp_packets_in_regex = Regex::new(r"(?i)\{\{\s*udp_packets_in\s*\}\}")
-            .expect("Failed to compile udp_in regex");
-        let udp_packets_out_regex = Regex::new(r"(?i)\{\{\s*udp_packets_out\s*\}\}")
-            .expect("Failed to compile udp_out regex");
+    pub fn new(template: String, counters: Counters, public_key: PublicKey, version: u32, cache_ttl: StdDuration) -> Motd {
+        let variable_regex = variable_regex();
+        let variables = variables();
+        let template = Template::new(template, &variable_regex, &variables);
         Motd {
-            start_date_regex: RegexMatches::new(&template, start_date_regex),
-            uptime_regex: RegexMatches::new(&template, uptime_regex),
-            tcp_packets_in_regex: RegexMatches::new(&template, tcp_packets_in_regex),
-            tcp_packets_out_regex: RegexMatches::new(&template, tcp_packets_out_regex),
-            udp_packets_in_regex: RegexMatches::new(&template, udp_packets_in_regex),
-            udp_packets_out_regex: RegexMatches::new(&template, udp_packets_out_regex),
+            variable_regex,
+            variables,
             start_date: Local::now(),
             counters,
+            public_key,
+            version,
             template,
+            template_ipv6: None,
+            cache_ttl,
+        }
+    }
+
+    /// Replace the template keeping the start date and counters.
+    pub fn set_template(&mut self, template: String) {
+        self.template = Template::new(template, &self.variable_regex, &self.variables);
+    }
+
+    /// Replace the template for IPv6 clients. `None` makes them get the same
+    /// MOTD as IPv4 clients.
+    pub fn set_template_ipv6(&mut self, template: Option<String>) {
+        self.template_ipv6 = template.map(|template| Template::new(template, &self.variable_regex, &self.variables));
+    }
+
+    /// Time elapsed since the node was started.
+    pub fn uptime(&self) -> Duration {
+        Local::now() - self.start_date
+    }
+
+    /// Format MOTD for IPv6 or IPv4 clients prefixed with the stats header.
+    /// MOTD text is truncated if the result would be longer than
+    /// `BOOSTRAP_SERVER_MAX_MOTD_LENGTH`.
+    pub fn format_with_stats(&self, version: u32, nodes: u32, ipv6: bool) -> Vec<u8> {
+        let uptime = self.uptime().num_seconds().max(0) as u64;
+        let mut result = Vec::with_capacity(BOOSTRAP_SERVER_MAX_MOTD_LENGTH);
+        result.extend_from_slice(&version.to_be_bytes());
+        result.extend_from_slice(&uptime.to_be_bytes());
+        result.extend_from_slice(&nodes.to_be_bytes());
+
+        let motd = self.formatted(ipv6);
+        let mut len = motd.len().min(BOOSTRAP_SERVER_MAX_MOTD_LENGTH - STATS_HEADER_SIZE);
+        while !motd.is_char_boundary(len) {
+            len -= 1;
         }
+        result.extend_from_slice(&motd.as_bytes()[..len]);
+        result
     }
 
     fn summary(source: u64) -> String {
@@ -92,38 +208,175 @@ impl Motd {
         }
     }
 
-    pub fn format(&self) -> String {
-        let result = self.start_date_regex.replace(&self.template, ||
-            self.start_date.format("%c").to_string()
-        );
-        let result = self.uptime_regex.replace(&result, || {
-            let uptime = Local::now() - self.start_date;
-            let days = uptime.num_days();
-            let hours = uptime.num_hours() - uptime.num_days() * 24;
-            let minutes = uptime.num_minutes() - uptime.num_hours() * 60;
-            format!(
-              "{:0>#2} days {:0>#2} hours {:0>#2} minutes",
-              days,
-              hours,
-              minutes
-            )
-        });
-        let result = self.tcp_packets_in_regex.replace(&result, || {
-            let packets = self.counters.tcp.counters.incoming();
-            Self::summary(packets)
-        });
-        let result = self.tcp_packets_out_regex.replace(&result, || {
-            let packets = self.counters.tcp.counters.outgoing();
-            Self::summary(packets)
-        });
-        let result = self.udp_packets_in_regex.replace(&result, || {
-            let packets = self.counters.udp.counters.incoming();
-            Self::summary(packets)
-        });
-        let result = self.udp_packets_out_regex.replace(&result, || {
-            let packets = self.counters.udp.counters.outgoing();
-            Self::summary(packets)
-        });
-        result.into_owned()
+    /// Value of the variable.
+    fn value(&self, variable: Variable) -> String {
+        match variable {
+            Variable::StartDate => self.start_date.format("%c").to_string(),
+            Variable::Uptime => format_uptime(self.uptime()),
+            Variable::TcpPacketsIn => Self::summary(self.counters.tcp.counters.incoming()),
+            Variable::TcpPacketsOut => Self::summary(self.counters.tcp.counters.outgoing()),
+            Variable::UdpPacketsIn => Self::summary(self.counters.udp.counters.incoming()),
+            Variable::UdpPacketsOut => Self::summary(self.counters.udp.counters.outgoing()),
+            Variable::PublicKey => hex::encode(self.public_key.as_ref()).to_uppercase(),
+            Variable::Version => self.version.to_string(),
+        }
+    }
+
+    /// Template for IPv6 clients if `ipv6` is true and it's set or the
+    /// default one.
+    fn template(&self, ipv6: bool) -> &Template {
+        match self.template_ipv6 {
+            Some(ref template_ipv6) if ipv6 => template_ipv6,
+            _ => &self.template,
+        }
+    }
+
+    /// Substitute variables of the template for IPv6 or IPv4 clients.
+    /// Unknown variables are left as is.
+    pub fn format(&self, ipv6: bool) -> String {
+        self.variable_regex.replace_all(&self.template(ipv6).text, |caps: &Captures| {
+            match self.variables.get(caps[1].to_lowercase().as_str()) {
+                Some(&variable) => self.value(variable),
+                None => caps[0].to_owned(),
+            }
+        }).into_owned()
+    }
+
+    /// Substitute variables of the template for IPv6 or IPv4 clients reusing
+    /// the previous result until the cache TTL expires. Templates without
+    /// variables are returned as is.
+    pub fn formatted(&self, ipv6: bool) -> String {
+        let template = self.template(ipv6);
+        if !template.has_variables {
+            return template.text.clone()
+        }
+        if self.cache_ttl == StdDuration::from_secs(0) {
+            return self.format(ipv6)
+        }
+
+        let mut cache = template.cache.lock().expect("Failed to lock MOTD cache");
+        match *cache {
+            Some((formatted_at, ref motd)) if formatted_at.elapsed() < self.cache_ttl => motd.clone(),
+            _ => {
+                let motd = self.format(ipv6);
+                *cache = Some((Instant::now(), motd.clone()));
+                motd
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn motd(template: &str) -> Motd {
+        let pk = PublicKey::from_slice(&[42; 32]).unwrap();
+        Motd::new(template.to_owned(), Counters::new(Stats::new(), Stats::new()), pk, 3_000_001_001, StdDuration::from_secs(0))
+    }
+
+    #[test]
+    fn format_public_key_and_version() {
+        let motd = motd("{{public_key}} {{ version }}");
+        assert_eq!(motd.format(false), format!("{} 3000001001", "2A".repeat(32)));
+    }
+
+    #[test]
+    fn format_uptime_parts() {
+        let uptime = Duration::days(2) + Duration::hours(3) + Duration::minutes(4) + Duration::seconds(5);
+        assert_eq!(format_uptime(uptime), "02 days 03 hours 04 minutes");
+        assert_eq!(format_uptime(Duration::days(123)), "123 days 00 hours 00 minutes");
+    }
+
+    #[test]
+    fn format_mixed_case_and_whitespaces() {
+        let version = motd("{{  Version  }} {{VERSION}} {{\tversion\n}}");
+        assert_eq!(version.format(false), "3000001001 3000001001 3000001001");
+        let start = motd("{{ Start_Date }}|{{ UPTIME }}");
+        assert_eq!(start.format(false), format!("{}|00 days 00 hours 00 minutes", start.start_date.format("%c")));
+    }
+
+    #[test]
+    fn format_unknown_variables_verbatim() {
+        let motd = motd("{{ Version }} {{ Unknown }} {{nodes}} {version} {{ version");
+        assert_eq!(motd.format(false), "3000001001 {{ Unknown }} {{nodes}} {version} {{ version");
+    }
+
+    #[test]
+    fn set_template() {
+        let mut motd = motd("{{version}}");
+        motd.set_template("v{{ VERSION }}".to_owned());
+        assert_eq!(motd.format(false), "v3000001001");
+    }
+
+    #[test]
+    fn fixed_length_of_template() {
+        assert_eq!(fixed_length("{{public_key}} {{version}}"), 64 + 1 + 10);
+        assert_eq!(fixed_length("{{uptime}}"), 10);
+        assert_eq!(fixed_length("{{ Public_Key }} {{unknown}}"), 64 + 1 + 11);
+    }
+
+    #[test]
+    fn format_with_stats() {
+        let motd = motd("abc");
+        let result = motd.format_with_stats(3_000_001_001, 42, false);
+        assert_eq!(result.len(), STATS_HEADER_SIZE + 3);
+        assert_eq!(&result[..4], &3_000_001_001u32.to_be_bytes());
+        assert_eq!(&result[12..16], &42u32.to_be_bytes());
+        assert_eq!(&result[16..], b"abc");
+    }
+
+    #[test]
+    fn format_with_stats_truncates_motd() {
+        let motd = motd(&"ж".repeat(BOOSTRAP_SERVER_MAX_MOTD_LENGTH));
+        let result = motd.format_with_stats(0, 0, false);
+        assert!(result.len() <= BOOSTRAP_SERVER_MAX_MOTD_LENGTH);
+        assert!(std::str::from_utf8(&result[STATS_HEADER_SIZE..]).is_ok());
+    }
+
+    #[test]
+    fn formatted_is_cached_until_ttl_expires() {
+        let pk = PublicKey::from_slice(&[42; 32]).unwrap();
+        let udp = Stats::new();
+        let ttl = StdDuration::from_millis(100);
+        let motd = Motd::new("{{ udp_packets_in }}".to_owned(), Counters::new(Stats::new(), udp.clone()), pk, 0, ttl);
+        assert_eq!(motd.formatted(false), "0");
+
+        udp.counters.increase_incoming();
+        assert_eq!(motd.format(false), "1");
+        assert_eq!(motd.formatted(false), "0");
+
+        std::thread::sleep(ttl);
+        assert_eq!(motd.formatted(false), "1");
+    }
+
+    #[test]
+    fn formatted_without_variables_is_not_cached() {
+        let pk = PublicKey::from_slice(&[42; 32]).unwrap();
+        let mut motd = Motd::new("abc".to_owned(), Counters::new(Stats::new(), Stats::new()), pk, 0, StdDuration::from_secs(60));
+        assert_eq!(motd.formatted(false), "abc");
+        assert!(motd.template.cache.lock().unwrap().is_none());
+
+        motd.set_template("{{ version }}".to_owned());
+        assert_eq!(motd.formatted(false), "0");
+        assert!(motd.template.cache.lock().unwrap().is_some());
+
+        motd.set_template("def".to_owned());
+        assert_eq!(motd.formatted(false), "def");
+        assert!(motd.template.cache.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn formatted_for_ipv6() {
+        let mut motd = motd("IPv4 {{ version }}");
+        assert_eq!(motd.formatted(true), "IPv4 3000001001");
+
+        motd.set_template_ipv6(Some("IPv6 {{ version }}".to_owned()));
+        assert_eq!(motd.formatted(false), "IPv4 3000001001");
+        assert_eq!(motd.formatted(true), "IPv6 3000001001");
+        assert_eq!(&motd.format_with_stats(0, 0, true)[STATS_HEADER_SIZE..], b"IPv6 3000001001");
+
+        motd.set_template_ipv6(None);
+        assert_eq!(motd.formatted(true), "IPv4 3000001001");
     }
 }
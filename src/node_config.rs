@@ -1,18 +1,30 @@
-use std::net::{SocketAddr, ToSocketAddrs};
-use std::num::ParseIntError;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs};
 use std::str::FromStr;
 use std::path::Path;
-use std::collections::HashMap;
+use std::time::Duration;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
-use config::{Config, File as CfgFile};
-use serde::{de, Deserialize, Deserializer};
+use config::{Config, ConfigError, File as CfgFile, FileFormat, Source, Value as CfgValue};
+use get_if_addrs::Interface;
+use failure::{Error, format_err};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use serde_yaml::Value;
-use clap::{App, AppSettings, Arg, SubCommand, ArgMatches};
+use clap::{App, AppSettings, Arg, ArgGroup, SubCommand, ArgMatches};
 use hex::FromHex;
 use itertools::Itertools;
+use log::LevelFilter;
 use tox::crypto::*;
 use tox::packet::dht::packed_node::PackedNode;
 use tox::packet::dht::BOOSTRAP_SERVER_MAX_MOTD_LENGTH;
+use tox::core::dht::codec::MAX_DHT_PACKET_SIZE;
+
+use crate::bootstrapd::BootstrapdFile;
+use crate::ip_filter::Cidr;
+use crate::error::{EXIT_CONFIG, NodeError, exit_command};
+use crate::keys::{check_keys_file, try_load_keys};
+use crate::motd;
+use crate::self_test::run_self_test;
 
 /// Config for threading.
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
@@ -24,13 +36,17 @@ pub enum Threads {
 }
 
 impl FromStr for Threads {
-    type Err = ParseIntError;
+    type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if s == "auto" {
-            Ok(Threads::Auto)
-        } else {
-            u16::from_str(s).map(Threads::N)
+            return Ok(Threads::Auto)
+        }
+
+        match u16::from_str(s) {
+            Ok(0) => Err("number of threads must be greater than 0".to_owned()),
+            Ok(n) => Ok(Threads::N(n)),
+            Err(e) => Err(format!("number of threads must be 'auto' or a number: {}", e)),
         }
     }
 }
@@ -38,11 +54,13 @@ impl FromStr for Threads {
 #[cfg(unix)]
 arg_enum! {
     /// Specifies where to write logs.
-    #[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize, Serialize)]
     pub enum LogType {
         Stderr,
         Stdout,
+        Json,
         Syslog,
+        File,
         None,
     }
 }
@@ -50,25 +68,176 @@ arg_enum! {
 #[cfg(not(unix))]
 arg_enum! {
     /// Specifies where to write logs.
-    #[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize, Serialize)]
     pub enum LogType {
         Stderr,
         Stdout,
+        Json,
+        File,
         None,
     }
 }
 
+arg_enum! {
+    /// Layout of log records written to stderr, stdout or log file.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum LogFormat {
+        Compact,
+        Full,
+        Json,
+    }
+}
+
+arg_enum! {
+    /// Format of the keys file.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum KeysFormat {
+        Raw,
+        Base64,
+        Libsodium,
+    }
+}
+
+arg_enum! {
+    /// IP address family of the DHT node.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum IpMode {
+        Dual,
+        Ipv4,
+        Ipv6,
+    }
+}
+
+arg_enum! {
+    /// Decision about a TCP connection when the connection hook fails.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum HookPolicy {
+        Allow,
+        Deny,
+    }
+}
+
+arg_enum! {
+    /// What to do when an onion packet of a TCP client can't be handled.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum OnionErrorPolicy {
+        Log,
+        Count,
+        Disconnect,
+    }
+}
+
+arg_enum! {
+    /// Format of config files.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum ConfigFormat {
+        Auto,
+        Bootstrapd,
+    }
+}
+
+impl IpMode {
+    /// Check whether the address belongs to the family allowed by the mode.
+    pub fn allows(self, addr: &SocketAddr) -> bool {
+        match self {
+            IpMode::Dual => true,
+            IpMode::Ipv4 => addr.is_ipv4(),
+            IpMode::Ipv6 => addr.is_ipv6(),
+        }
+    }
+}
+
 /// Bootstrap node with generic string address which might be either IP address
 /// or DNS name.
-#[derive(Clone, PartialEq, Eq, Debug, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Debug, Serialize)]
 pub struct BootstrapNode {
     /// `PublicKey` of the node.
+    #[serde(serialize_with = "ser_to_hex")]
+    pub pk: PublicKey,
+    /// Generic string address which might be either IP address or DNS name.
+    pub addr: String,
+}
+
+/// Bootstrap node in the config as a table with `pk` and `addr` keys.
+#[derive(Deserialize)]
+struct BootstrapNodeTable {
     #[serde(deserialize_with = "de_from_hex")]
     pk: PublicKey,
-    /// Generic string address which might be either IP address or DNS name.
     addr: String,
 }
 
+/// Visitor of bootstrap nodes in the config which are either tables with `pk`
+/// and `addr` keys or strings in the compact format `<pk>@<host>:<port>`.
+struct BootstrapNodeVisitor;
+
+impl<'de> de::Visitor<'de> for BootstrapNodeVisitor {
+    type Value = BootstrapNode;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a table with pk and addr keys or a string <public key>@<host>:<port>")
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
+        parse_bootstrap_node_compact(s).map_err(E::custom)
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        let node = BootstrapNodeTable::deserialize(de::value::MapAccessDeserializer::new(map))?;
+        Ok(BootstrapNode {
+            pk: node.pk,
+            addr: node.addr,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for BootstrapNode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        deserializer.deserialize_any(BootstrapNodeVisitor)
+    }
+}
+
+/// Order of resolved addresses of bootstrap nodes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct AddrOrder {
+    /// Put IPv6 addresses before IPv4 ones keeping the order of the OS
+    /// otherwise.
+    pub prefer_ipv6: bool,
+    /// Addresses of IP families not allowed by the mode are dropped.
+    pub ip_mode: IpMode,
+}
+
+impl AddrOrder {
+    pub fn new(config: &NodeConfig) -> Self {
+        AddrOrder {
+            prefer_ipv6: config.prefer_ipv6,
+            ip_mode: config.ip_mode,
+        }
+    }
+
+    /// Filter and sort resolved addresses.
+    fn apply(self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let mut addrs = addrs
+            .into_iter()
+            .filter(|addr| self.ip_mode.allows(addr))
+            .collect::<Vec<_>>();
+        if self.prefer_ipv6 {
+            // stable sort keeps the order of addresses of the same family
+            addrs.sort_by_key(SocketAddr::is_ipv4);
+        }
+        addrs
+    }
+}
+
+/// Resolve a `host:port` address with the resolver of the OS.
+fn lookup_addr(addr: &str) -> std::io::Result<Vec<SocketAddr>> {
+    addr.to_socket_addrs().map(|addrs| addrs.collect())
+}
+
 impl BootstrapNode {
     /// Resolve string address of the node to possible multiple `SocketAddr`s.
     pub fn resolve(&self) -> impl Iterator<Item = PackedNode> {
@@ -82,67 +251,820 @@ impl BootstrapNode {
         };
         addrs.map(move |addr| PackedNode::new(addr, &pk))
     }
+
+    /// Resolve string address of the node on the blocking thread pool so that
+    /// slow DNS can't stall the runtime. Resolving is retried `retries` times
+    /// when it fails or takes longer than `timeout`, after that the node is
+    /// skipped. Resolved addresses are filtered and sorted by `order`.
+    pub async fn resolve_with_timeout(&self, timeout: Duration, retries: u32, order: AddrOrder) -> Vec<PackedNode> {
+        self.resolve_with(lookup_addr, timeout, retries, order).await
+    }
+
+    /// Resolve string address of the node with the `lookup` function.
+    async fn resolve_with(
+        &self,
+        lookup: fn(&str) -> std::io::Result<Vec<SocketAddr>>,
+        timeout: Duration,
+        retries: u32,
+        order: AddrOrder,
+    ) -> Vec<PackedNode> {
+        for attempt in 0 ..= retries {
+            let addr = self.addr.clone();
+            let resolving = tokio::task::spawn_blocking(move || lookup(&addr));
+            let error = match tokio::time::timeout(timeout, resolving).await {
+                Ok(Ok(Ok(addrs))) => return order.apply(addrs)
+                    .into_iter()
+                    .map(|addr| PackedNode::new(addr, &self.pk))
+                    .collect(),
+                Ok(Ok(Err(e))) => e.to_string(),
+                Ok(Err(e)) => e.to_string(),
+                Err(_) => format!("timed out after {:?}", timeout),
+            };
+            warn!("Failed to resolve bootstrap node address '{}' (attempt {} of {}): {}",
+                self.addr, attempt + 1, retries + 1, error);
+        }
+
+        warn!("Skipping bootstrap node '{}'", self.addr);
+        Vec::new()
+    }
+}
+
+/// Parse `PublicKey` from a hex string in any case. Surrounding whitespaces
+/// and `0x` prefix are ignored.
+pub fn pk_from_hex(s: &str) -> Result<PublicKey, String> {
+    let s = s.trim();
+    let s = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+    if s.len() != 2 * PUBLICKEYBYTES {
+        return Err(format!("public key must be {} bytes ({} hex characters) long, got {} characters", PUBLICKEYBYTES, 2 * PUBLICKEYBYTES, s.len()));
+    }
+    let pk_bytes: [u8; 32] = FromHex::from_hex(s)
+        .map_err(|e| format!("Can't make bytes from hex string {:?}", e))?;
+    PublicKey::from_slice(&pk_bytes)
+        .ok_or_else(|| "Can't make PublicKey".to_owned())
+}
+
+/// Check that the address of a bootstrap node is in the `host:port` form. The
+/// host is not validated since it might be a DNS name.
+fn validate_bootstrap_addr(addr: &str) -> Result<(), String> {
+    let (host, port) = addr.rfind(':')
+        .map(|i| (&addr[..i], &addr[i + 1..]))
+        .ok_or_else(|| format!("address '{}' must be in the form host:port", addr))?;
+    if host.is_empty() {
+        return Err(format!("address '{}' has no host", addr));
+    }
+    port.parse::<u16>()
+        .map_err(|e| format!("address '{}' has invalid port: {}", addr, e))?;
+    Ok(())
+}
+
+/// Parse bootstrap node in the compact format `<pk>@<host>:<port>`.
+fn parse_bootstrap_node_compact(s: &str) -> Result<BootstrapNode, String> {
+    let s = s.trim();
+    let (pk, addr) = s.find('@')
+        .map(|i| (&s[..i], &s[i + 1..]))
+        .ok_or_else(|| format!("bootstrap node '{}' must be in the form <public key>@<host>:<port>", s))?;
+    validate_bootstrap_addr(addr)?;
+    Ok(BootstrapNode {
+        pk: pk_from_hex(pk)?,
+        addr: addr.to_owned(),
+    })
+}
+
+/// Parse bootstrap nodes from lines in the format `<pk> <addr>`. Empty lines
+/// and comments starting with `#` are ignored. Malformed lines are skipped
+/// with a warning.
+fn parse_bootstrap_nodes(text: &str) -> Vec<BootstrapNode> {
+    text.lines()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                return None;
+            }
+
+            let node = match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                [pk, addr] => validate_bootstrap_addr(addr)
+                    .and_then(|()| pk_from_hex(pk))
+                    .map(|pk| BootstrapNode {
+                        pk,
+                        addr: (*addr).to_owned(),
+                    }),
+                _ => Err("expected '<pk> <addr>'".to_owned()),
+            };
+
+            node.map_err(|e| warn!("Skipping malformed bootstrap node on line {}: {}", i + 1, e)).ok()
+        })
+        .collect()
+}
+
+/// Load bootstrap nodes from a file with `<pk> <addr>` pairs one per line.
+pub fn load_bootstrap_file(bootstrap_file: &str) -> Result<Vec<BootstrapNode>, Error> {
+    let text = std::fs::read_to_string(bootstrap_file)
+        .map_err(|e| format_err!("Can't read bootstrap file '{}': {}", bootstrap_file, e))?;
+    Ok(parse_bootstrap_nodes(&text))
+}
+
+/// Remove resolved bootstrap nodes with the same public key and address
+/// keeping the first occurrence. The number of removed nodes is returned
+/// alongside.
+pub fn dedup_bootstrap_nodes<I: IntoIterator<Item = PackedNode>>(nodes: I) -> (Vec<PackedNode>, usize) {
+    let mut seen = HashSet::new();
+    let mut duplicates = 0;
+    let nodes = nodes
+        .into_iter()
+        .filter(|node| {
+            let unique = seen.insert((node.pk, node.saddr));
+            if !unique {
+                duplicates += 1;
+            }
+            unique
+        })
+        .collect();
+    (nodes, duplicates)
+}
+
+/// Select nodes that are not in `known` yet and add them to it. Duplicates
+/// within `nodes` are selected once.
+pub fn select_new_bootstrap_nodes(known: &mut Vec<BootstrapNode>, nodes: Vec<BootstrapNode>) -> Vec<BootstrapNode> {
+    let mut new_nodes = Vec::new();
+    for node in nodes {
+        if !known.contains(&node) {
+            known.push(node.clone());
+            new_nodes.push(node);
+        }
+    }
+    new_nodes
+}
+
+/// Bootstrap node in the JSON list fetched from `bootstrap-url`.
+#[derive(Deserialize)]
+struct JsonBootstrapNode {
+    public_key: String,
+    address: String,
+}
+
+/// Parse bootstrap nodes from a JSON array of objects with `public_key` and
+/// `address` fields. Malformed nodes are skipped with a warning.
+pub fn parse_bootstrap_json(text: &str) -> Result<Vec<BootstrapNode>, Error> {
+    let nodes: Vec<JsonBootstrapNode> = serde_json::from_str(text)
+        .map_err(|e| format_err!("Invalid JSON list of bootstrap nodes: {}", e))?;
+
+    let nodes = nodes
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, node)| {
+            let res = validate_bootstrap_addr(&node.address)
+                .and_then(|()| pk_from_hex(&node.public_key))
+                .map(|pk| BootstrapNode {
+                    pk,
+                    addr: node.address,
+                });
+
+            res.map_err(|e| warn!("Skipping malformed bootstrap node #{}: {}", i + 1, e)).ok()
+        })
+        .collect();
+
+    Ok(nodes)
+}
+
+/// TCP address to run TCP relay on with optional metadata.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+#[serde(from = "TcpAddressRepr", into = "TcpAddressRepr")]
+pub struct TcpAddress {
+    /// Address to listen on
+    pub addr: SocketAddr,
+    /// Label used in logs and metrics to distinguish listeners
+    pub label: Option<String>,
+}
+
+impl TcpAddress {
+    /// Name of the listener in metrics: the label if it's set or the address.
+    pub fn name(&self) -> String {
+        self.label.clone().unwrap_or_else(|| self.addr.to_string())
+    }
+}
+
+impl From<SocketAddr> for TcpAddress {
+    fn from(addr: SocketAddr) -> Self {
+        TcpAddress {
+            addr,
+            label: None,
+        }
+    }
+}
+
+impl fmt::Display for TcpAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.label {
+            Some(ref label) => write!(f, "{} ({})", self.addr, label),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
+
+/// Config representation of `TcpAddress`: either a bare address or a map
+/// with the address and metadata.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum TcpAddressRepr {
+    Bare(#[serde(deserialize_with = "de_socket_addr")] SocketAddr),
+    Full {
+        #[serde(deserialize_with = "de_socket_addr")]
+        address: SocketAddr,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        label: Option<String>,
+    },
+}
+
+impl From<TcpAddressRepr> for TcpAddress {
+    fn from(repr: TcpAddressRepr) -> Self {
+        match repr {
+            TcpAddressRepr::Bare(addr) => addr.into(),
+            TcpAddressRepr::Full { address, label } => TcpAddress {
+                addr: address,
+                label,
+            },
+        }
+    }
+}
+
+impl From<TcpAddress> for TcpAddressRepr {
+    fn from(addr: TcpAddress) -> Self {
+        match addr.label {
+            Some(label) => TcpAddressRepr::Full {
+                address: addr.addr,
+                label: Some(label),
+            },
+            None => TcpAddressRepr::Bare(addr.addr),
+        }
+    }
+}
+
+/// Ports to listen on a network interface.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct InterfacePorts {
+    /// UDP port to run DHT node
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub udp: Option<u16>,
+    /// TCP ports to run TCP relay
+    #[serde(default, deserialize_with = "de_one_or_many")]
+    pub tcp: Vec<u16>,
+}
+
+/// Maximum number of ports in `tcp-port-range`.
+pub const MAX_TCP_PORT_RANGE: usize = 256;
+
+/// Contiguous range of TCP ports to run TCP relay on. Ports are inclusive.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+pub struct TcpPortRange {
+    /// IP address to listen on
+    pub ip: IpAddr,
+    /// First port of the range
+    pub start: u16,
+    /// Last port of the range
+    pub end: u16,
+}
+
+impl TcpPortRange {
+    /// Make TCP addresses from the range of ports.
+    pub fn addrs(&self) -> Result<Vec<SocketAddr>, String> {
+        if self.start == 0 || self.start > self.end {
+            return Err(format!("invalid range of ports {}-{}", self.start, self.end));
+        }
+        let count = usize::from(self.end - self.start) + 1;
+        if count > MAX_TCP_PORT_RANGE {
+            return Err(format!("range of {} ports exceeds the maximum of {}", count, MAX_TCP_PORT_RANGE));
+        }
+        Ok((self.start ..= self.end).map(|port| SocketAddr::new(self.ip, port)).collect())
+    }
+}
+
+/// Make UDP and TCP addresses from current addresses of the listen
+/// interfaces. Interfaces without addresses are skipped with a warning. IPv6
+/// link-local addresses are skipped since they can't be bound without a zone.
+pub fn interfaces_addrs(listen_interfaces: &BTreeMap<String, InterfacePorts>, interfaces: &[Interface]) -> (Vec<SocketAddr>, Vec<SocketAddr>) {
+    let mut udp_addrs = Vec::new();
+    let mut tcp_addrs = Vec::new();
+
+    for (name, ports) in listen_interfaces {
+        let ips = interfaces
+            .iter()
+            .filter(|interface| interface.name == *name)
+            .map(|interface| interface.ip())
+            .filter(|ip| match ip {
+                IpAddr::V4(_) => true,
+                IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 != 0xfe80,
+            })
+            .collect::<Vec<_>>();
+
+        if ips.is_empty() {
+            warn!("Interface '{}' has no addresses, skipping it", name);
+            continue;
+        }
+
+        for ip in ips {
+            udp_addrs.extend(ports.udp.map(|port| SocketAddr::new(ip, port)));
+            tcp_addrs.extend(ports.tcp.iter().map(|&port| SocketAddr::new(ip, port)));
+        }
+    }
+
+    (udp_addrs, tcp_addrs)
+}
+
+/// Resolve IPv6 zone id to the scope id. The zone id is either an interface
+/// index or an interface name.
+fn scope_id(zone: &str) -> Result<u32, String> {
+    if let Ok(index) = zone.parse() {
+        return Ok(index);
+    }
+
+    interface_index(zone)
+}
+
+#[cfg(unix)]
+fn interface_index(name: &str) -> Result<u32, String> {
+    let c_name = std::ffi::CString::new(name)
+        .map_err(|_| format!("invalid interface name '{}'", name))?;
+    // Safe because the name is a valid nul-terminated string
+    match unsafe { libc::if_nametoindex(c_name.as_ptr()) } {
+        0 => Err(format!("unknown network interface '{}'", name)),
+        index => Ok(index),
+    }
+}
+
+#[cfg(not(unix))]
+fn interface_index(name: &str) -> Result<u32, String> {
+    Err(format!("interface names are not supported as zone ids on this platform, use the index of '{}' instead", name))
+}
+
+/// Parse socket address. IPv6 addresses may have a zone id like
+/// `[fe80::1%eth0]:33445` that is resolved to the scope id of the address.
+pub fn parse_socket_addr(s: &str) -> Result<SocketAddr, String> {
+    if let Ok(addr) = s.parse() {
+        return Ok(addr);
+    }
+
+    let invalid = || format!("'{}' is not a valid socket address", s);
+    let (host, port) = s.strip_prefix('[')
+        .and_then(|rest| rest.find("]:").map(|i| (&rest[..i], &rest[i + 2..])))
+        .ok_or_else(invalid)?;
+    let (ip, zone) = host.find('%').map(|i| (&host[..i], &host[i + 1..])).ok_or_else(invalid)?;
+    let ip: Ipv6Addr = ip.parse().map_err(|_| invalid())?;
+    let port: u16 = port.parse().map_err(|_| invalid())?;
+
+    Ok(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id(zone)?)))
+}
+
+fn de_socket_addr<'de, D>(deserializer: D) -> Result<SocketAddr, D::Error> where D: Deserializer<'de> {
+    let s = String::deserialize(deserializer)?;
+
+    parse_socket_addr(&s).map_err(de::Error::custom)
+}
+
+fn de_socket_addrs<'de, D>(deserializer: D) -> Result<Vec<SocketAddr>, D::Error> where D: Deserializer<'de> {
+    let addrs: Vec<String> = de_one_or_many(deserializer)?;
+
+    addrs.iter().map(|s| parse_socket_addr(s).map_err(de::Error::custom)).collect()
 }
 
 fn de_from_hex<'de, D>(deserializer: D) -> Result<PublicKey, D::Error> where D: Deserializer<'de> {
     let s = String::deserialize(deserializer)?;
 
-    let bootstrap_pk_bytes: [u8; 32] = FromHex::from_hex(s)
-        .map_err(|e| de::Error::custom(format!("Can't make bytes from hex string {:?}", e)))?;
-    PublicKey::from_slice(&bootstrap_pk_bytes)
-        .ok_or_else(|| de::Error::custom("Can't make PublicKey"))
+    pk_from_hex(&s).map_err(de::Error::custom)
+}
+
+fn de_opt_from_hex<'de, D>(deserializer: D) -> Result<Option<PublicKey>, D::Error> where D: Deserializer<'de> {
+    de_from_hex(deserializer).map(Some)
+}
+
+fn ser_to_hex<S>(pk: &PublicKey, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+    serializer.serialize_str(&hex::encode(pk).to_uppercase())
+}
+
+fn ser_opt_to_hex<S>(pk: &Option<PublicKey>, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+    match pk {
+        Some(pk) => ser_to_hex(pk, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn de_one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+    where D: Deserializer<'de>, T: Deserialize<'de>
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => Ok(vec![value]),
+        OneOrMany::Many(values) => Ok(values),
+    }
+}
+
+fn de_log_level<'de, D>(deserializer: D) -> Result<Option<LevelFilter>, D::Error> where D: Deserializer<'de> {
+    let s = String::deserialize(deserializer)?;
+
+    LevelFilter::from_str(&s)
+        .map(Some)
+        .map_err(|_| de::Error::custom(format!("invalid log level '{}'", s)))
 }
 
 fn de_threads<'de, D>(deserializer: D) -> Result<Threads, D::Error> where D: Deserializer<'de> {
     let s = String::deserialize(deserializer)?;
 
     Threads::from_str(&s)
-        .map_err(|e| de::Error::custom(format!("threads: {:?}", e)))
+        .map_err(de::Error::custom)
+}
+
+fn ser_threads<S>(threads: &Threads, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+    match threads {
+        Threads::Auto => serializer.serialize_str("auto"),
+        Threads::N(n) => serializer.serialize_u16(*n),
+    }
+}
+
+fn ser_log_level<S>(log_level: &Option<LevelFilter>, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+    match log_level {
+        Some(log_level) => serializer.serialize_str(&log_level.to_string().to_lowercase()),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Keys of the config file. Used to suggest the right key for unused ones.
+const CONFIG_KEYS: &[&str] = &[
+    "udp-address", "tcp-addresses", "tcp-port-range", "udp-recv-buffer", "udp-send-buffer",
+    "udp-restart-retries", "udp-restart-delay",
+    "listen-interfaces", "tcp-unix-path", "tcp-connections-limit",
+    "tcp-max-connections", "tcp-backlog", "bind-retry", "drain-timeout", "run-for", "tcp-idle-timeout", "ip-mode", "no-v4-mapped",
+    "rate-limit", "send-rate", "max-packet-size", "ban-list", "tcp-allow-list", "proxy-protocol",
+    "connection-hook", "connection-hook-timeout", "connection-hook-cache", "connection-hook-failure", "access-log", "secret-key-file", "keys-file", "expect-pk", "keys-format",
+    "bootstrap-nodes", "bootstrap-file", "bootstrap-reload-signal", "bootstrap-url", "bootstrap-refresh", "nodes-cache",
+    "dns-timeout", "dns-retries", "prefer-ipv6",
+    "dht-ping-interval", "dht-nodes-threshold", "threads", "log-type", "log-file", "log-max-size", "log-keep", "log-format", "log-level", "quiet", "log-throttle-secs", "motd",
+    "motd-file", "motd-ipv6", "motd-cache-ttl", "lan-discovery", "lan-discovery-log-interval", "no-multicast-loop", "onion-buffer", "no-onion", "onion-error-policy", "bootstrap-stats", "advertise-version",
+    "metrics-address", "health-address", "stats-interval", "allow-root",
+    "daemonize", "pid-file", "ready-json",
+];
+
+/// Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0 ..= b.len()).collect::<Vec<_>>();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &b_char) in b.iter().enumerate() {
+            let substitution = diagonal + if a_char == b_char { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Find a known config key similar to the unused one, e.g. `udp-address`
+/// for `udp_address` or `tcp-addresses` for `tcp-address`. Keys with the
+/// common prefix are preferred among equally similar ones.
+pub fn suggest_config_key(key: &str) -> Option<&'static str> {
+    let key = key.to_lowercase().replace('_', "-");
+    CONFIG_KEYS
+        .iter()
+        .map(|&known| {
+            let common_prefix = known.starts_with(&key) || key.starts_with(known);
+            (edit_distance(&key, known), !common_prefix, known)
+        })
+        .filter(|&(distance, _, _)| distance <= 3)
+        .min()
+        .map(|(_, _, known)| known)
 }
 
 /// Config parsed from command line arguments.
-#[derive(Clone, PartialEq, Eq, Debug, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
 pub struct NodeConfig {
-    /// UDP address to run DHT node
+    /// UDP addresses to run DHT node
     #[serde(rename = "udp-address")]
-    #[serde(default)]
-    pub udp_addr: Option<SocketAddr>,
+    #[serde(default, deserialize_with = "de_socket_addrs")]
+    pub udp_addrs: Vec<SocketAddr>,
     /// TCP addresses to run TCP relay
     #[serde(rename = "tcp-addresses")]
     #[serde(default)]
-    pub tcp_addrs: Vec<SocketAddr>,
+    pub tcp_addrs: Vec<TcpAddress>,
+    /// Range of ports to run TCP relay on. Its addresses are added to
+    /// `tcp_addrs` when the config is parsed so it's never serialized.
+    #[serde(rename = "tcp-port-range")]
+    #[serde(default, skip_serializing)]
+    pub tcp_port_range: Option<TcpPortRange>,
+    /// Size of receive buffer of UDP sockets in bytes. OS default is used
+    /// when not specified.
+    #[serde(rename = "udp-recv-buffer")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub udp_recv_buffer: Option<usize>,
+    /// Size of send buffer of UDP sockets in bytes. OS default is used when
+    /// not specified.
+    #[serde(rename = "udp-send-buffer")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub udp_send_buffer: Option<usize>,
+    /// Number of times in a row to restart DHT server with new UDP sockets
+    /// when it fails with a network error. 0 means the node stops.
+    #[serde(rename = "udp-restart-retries")]
+    pub udp_restart_retries: u32,
+    /// Delay in seconds before the first restart of DHT server. It's doubled
+    /// after each restart up to 30 seconds.
+    #[serde(rename = "udp-restart-delay")]
+    pub udp_restart_delay: u64,
+    /// Network interfaces to listen on with ports. Addresses of interfaces
+    /// are added to `udp_addrs` and `tcp_addrs` at startup.
+    #[serde(rename = "listen-interfaces")]
+    #[serde(default)]
+    pub listen_interfaces: BTreeMap<String, InterfacePorts>,
+    /// Path to Unix domain socket to run TCP relay on in addition to TCP
+    /// addresses.
+    #[serde(rename = "tcp-unix-path")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp_unix_path: Option<String>,
     /// Maximum number of active TCP connections relay can hold.
     #[serde(rename = "tcp-connections-limit")]
     pub tcp_connections_limit: usize,
+    /// Maximum number of active TCP connections across all TCP addresses.
+    #[serde(rename = "tcp-max-connections")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp_max_connections: Option<usize>,
+    /// Maximum length of the queue of pending TCP connections. The default of
+    /// the platform is used if not specified.
+    #[serde(rename = "tcp-backlog")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp_backlog: Option<u32>,
+    /// Number of retries to bind TCP listeners with exponential backoff.
+    #[serde(rename = "bind-retry")]
+    pub bind_retry: u32,
+    /// Time in seconds to keep serving existing TCP connections on shutdown.
+    /// 0 means the relay stops immediately.
+    #[serde(rename = "drain-timeout")]
+    pub drain_timeout: u64,
+    /// Time in seconds after which the node shuts down by itself. It runs
+    /// indefinitely if not specified.
+    #[serde(rename = "run-for")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_for: Option<u64>,
+    /// Time in seconds after which TCP connections without any traffic are
+    /// closed. 0 means disabled.
+    #[serde(rename = "tcp-idle-timeout")]
+    pub tcp_idle_timeout: u64,
+    /// IP address family of the DHT node.
+    #[serde(rename = "ip-mode")]
+    pub ip_mode: IpMode,
+    /// Don't send IPv4 packets via IPv6 sockets using IPv4-mapped addresses.
+    /// IPv6 sockets receive only IPv6 packets then.
+    #[serde(rename = "no-v4-mapped")]
+    #[serde(default)]
+    pub no_v4_mapped: bool,
+    /// Maximum number of UDP packets per second accepted from a single IP.
+    /// 0 means no limit.
+    #[serde(rename = "rate-limit")]
+    pub rate_limit: u32,
+    /// Maximum number of UDP packets per second sent in total. Excess packets
+    /// are dropped. 0 means no limit.
+    #[serde(rename = "send-rate")]
+    pub send_rate: u32,
+    /// Maximum size in bytes of UDP packets. Larger packets are dropped
+    /// before decoding.
+    #[serde(rename = "max-packet-size")]
+    pub max_packet_size: usize,
+    /// IP networks to drop UDP packets and TCP connections from
+    #[serde(rename = "ban-list")]
+    #[serde(default)]
+    pub ban_list: Vec<Cidr>,
+    /// IP networks to accept TCP connections from. Connections from all IPs
+    /// are accepted when it's empty.
+    #[serde(rename = "tcp-allow-list")]
+    #[serde(default)]
+    pub tcp_allow_list: Vec<Cidr>,
+    /// Whether TCP connections start with PROXY protocol v2 header with the
+    /// real address of the client
+    #[serde(rename = "proxy-protocol")]
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Command run with the IP of a new TCP connection as the last argument.
+    /// Non-zero exit status rejects the connection.
+    #[serde(rename = "connection-hook")]
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub connection_hook: Option<String>,
+    /// Time in seconds after which the connection hook is killed and
+    /// considered failed.
+    #[serde(rename = "connection-hook-timeout")]
+    pub connection_hook_timeout: u64,
+    /// Time in seconds to cache decisions of the connection hook per IP.
+    #[serde(rename = "connection-hook-cache")]
+    pub connection_hook_cache: u64,
+    /// Decision about a connection when the connection hook fails to run,
+    /// times out or is killed.
+    #[serde(rename = "connection-hook-failure")]
+    pub connection_hook_failure: HookPolicy,
+    /// Path to the file where closed TCP relay connections are logged.
+    #[serde(rename = "access-log")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_log: Option<String>,
     /// DHT SecretKey
-    #[serde(skip_deserializing)]
+    #[serde(skip)]
     pub sk: Option<SecretKey>,
     /// True if the SecretKey was passed as an argument instead of environment
     /// variable. Necessary to print a warning since the logger backend is not
     /// initialized when we parse arguments.
-    #[serde(skip_deserializing)]
+    #[serde(skip)]
     pub sk_passed_as_arg: bool,
-    /// Path to the file where DHT keys are stored.
+    /// Path to the file with DHT SecretKey stored as a hex string.
+    #[serde(rename = "secret-key-file")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sk_file: Option<String>,
+    /// Paths to the files where DHT keys are stored. Keys are loaded from the
+    /// first file unless `expected_pk` is set.
     /// Required with config.
     #[serde(rename = "keys-file")]
-    pub keys_file: Option<String>,
+    #[serde(default, deserialize_with = "de_one_or_many")]
+    pub keys_files: Vec<String>,
+    /// Public key that should be selected among keys files.
+    #[serde(rename = "expect-pk")]
+    #[serde(default, deserialize_with = "de_opt_from_hex", serialize_with = "ser_opt_to_hex", skip_serializing_if = "Option::is_none")]
+    pub expected_pk: Option<PublicKey>,
+    /// Format of the keys file. Binary keys files are read only in the layout
+    /// of this format, base64 keys files are recognized in any case. New
+    /// keys are generated in this format.
+    #[serde(rename = "keys-format")]
+    pub keys_format: KeysFormat,
     /// List of bootstrap nodes.
     #[serde(rename = "bootstrap-nodes")]
     #[serde(default)]
     pub bootstrap_nodes: Vec<BootstrapNode>,
+    /// Path to the file with bootstrap nodes that are added to
+    /// `bootstrap_nodes`.
+    #[serde(rename = "bootstrap-file")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bootstrap_file: Option<String>,
+    /// Number of the signal to re-read the bootstrap file on. The file is
+    /// read only at startup when not specified.
+    #[serde(rename = "bootstrap-reload-signal")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bootstrap_reload_signal: Option<i32>,
+    /// HTTP URL of a JSON list of bootstrap nodes that are added to
+    /// `bootstrap_nodes` at startup.
+    #[serde(rename = "bootstrap-url")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bootstrap_url: Option<String>,
+    /// Path to the file where known DHT nodes are saved periodically. They
+    /// are used as additional bootstrap nodes on the next start.
+    #[serde(rename = "nodes-cache")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes_cache: Option<String>,
+    /// Interval in seconds for resolving bootstrap nodes again. 0 means they
+    /// are resolved only once at startup.
+    #[serde(rename = "bootstrap-refresh")]
+    pub bootstrap_refresh: u64,
+    /// Timeout in seconds for resolving an address of a bootstrap node.
+    #[serde(rename = "dns-timeout")]
+    pub dns_timeout: u64,
+    /// Number of retries to resolve an address of a bootstrap node after a
+    /// failure or timeout.
+    #[serde(rename = "dns-retries")]
+    pub dns_retries: u32,
+    /// Try IPv6 addresses of bootstrap nodes before IPv4 ones.
+    #[serde(rename = "prefer-ipv6")]
+    #[serde(default)]
+    pub prefer_ipv6: bool,
+    /// Interval in seconds for pinging the closest DHT nodes in addition to
+    /// the pings sent by the DHT server itself. 0 means disabled.
+    #[serde(rename = "dht-ping-interval")]
+    pub dht_ping_interval: u64,
+    /// Number of DHT nodes known by the DHT server above which a warning is
+    /// logged. It's not a limit, the DHT server bounds its nodes itself.
+    #[serde(rename = "dht-nodes-threshold")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dht_nodes_threshold: Option<usize>,
     /// Number of threads for execution.
-    #[serde(deserialize_with = "de_threads")]
+    #[serde(deserialize_with = "de_threads", serialize_with = "ser_threads")]
     pub threads: Threads,
     /// Specifies where to write logs.
     #[serde(rename = "log-type")]
     pub log_type: LogType,
+    /// Path to the file to write logs to with `File` log type.
+    #[serde(rename = "log-file")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_file: Option<String>,
+    /// Size in megabytes after which the log file is rotated. 0 disables
+    /// rotation.
+    #[serde(rename = "log-max-size")]
+    pub log_max_size: u64,
+    /// Number of rotated log files to keep.
+    #[serde(rename = "log-keep")]
+    pub log_keep: usize,
+    /// Layout of log records written to stderr, stdout or log file.
+    #[serde(rename = "log-format")]
+    pub log_format: LogFormat,
+    /// Log level. `RUST_LOG` environment variable is used if not specified.
+    #[serde(rename = "log-level")]
+    #[serde(default, deserialize_with = "de_log_level", serialize_with = "ser_log_level", skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<LevelFilter>,
+    /// Log only warnings and errors. Can't be used together with `log_level`.
+    #[serde(default)]
+    pub quiet: bool,
+    /// Time in seconds to coalesce identical repeated errors of handling
+    /// packets into a single line. 0 disables throttling.
+    #[serde(rename = "log-throttle-secs")]
+    pub log_throttle_secs: u64,
     /// Message of the day
     pub motd: String,
+    /// Path to the file to read message of the day from. When set `motd` is
+    /// replaced with the file contents.
+    #[serde(rename = "motd-file")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motd_file: Option<String>,
+    /// Message of the day for IPv6 clients. `motd` is sent to all clients
+    /// when it's not set.
+    #[serde(rename = "motd-ipv6")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motd_ipv6: Option<String>,
+    /// Time in seconds to reuse formatted MOTD before substituting variables
+    /// again. 0 disables caching.
+    #[serde(rename = "motd-cache-ttl")]
+    pub motd_cache_ttl: u64,
     /// Whether LAN discovery is enabled
     #[serde(rename = "lan-discovery")]
     pub lan_discovery_enabled: bool,
+    /// Interval in seconds for logging the number of sent LAN discovery
+    /// packets. 0 means disabled.
+    #[serde(rename = "lan-discovery-log-interval")]
+    pub lan_discovery_log_interval: u64,
+    /// Don't loop IPv6 multicast LAN discovery packets back to the host.
+    /// They are never looped back when LAN discovery is disabled.
+    #[serde(rename = "no-multicast-loop")]
+    #[serde(default)]
+    pub no_multicast_loop: bool,
+    /// Maximum number of onion packets queued between TCP relay and DHT
+    /// server in each direction. Packets are dropped when it's reached.
+    #[serde(rename = "onion-buffer")]
+    pub onion_buffer: usize,
+    /// Whether onion packets forwarding between TCP relay and DHT server is
+    /// disabled
+    #[serde(rename = "no-onion")]
+    #[serde(default)]
+    pub no_onion: bool,
+    /// What to do when onion packets of a TCP client can't be handled. Errors
+    /// are counted in metrics with any policy.
+    #[serde(rename = "onion-error-policy")]
+    pub onion_error_policy: OnionErrorPolicy,
+    /// Whether to prepend stats header to the MOTD sent in `BootstrapInfo`
+    /// packets
+    #[serde(rename = "bootstrap-stats")]
+    pub bootstrap_stats: bool,
+    /// Version sent in `BootstrapInfo` packets instead of the version of the
+    /// node. Used for interoperability testing.
+    #[serde(rename = "advertise-version")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub advertise_version: Option<u32>,
+    /// Address to run Prometheus metrics endpoint
+    #[serde(rename = "metrics-address")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metrics_addr: Option<SocketAddr>,
+    /// Address to run health check endpoint
+    #[serde(rename = "health-address")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_addr: Option<SocketAddr>,
+    /// Interval in seconds for logging runtime statistics. 0 means disabled.
+    #[serde(rename = "stats-interval")]
+    pub stats_interval: u64,
+    /// Whether the node is allowed to run as root
+    #[serde(rename = "allow-root")]
+    #[serde(default)]
+    pub allow_root: bool,
+    /// Whether the node should detach from the terminal and run in background.
+    /// Supported only on unix.
+    #[serde(default)]
+    pub daemonize: bool,
+    /// Path to the file where the PID of the node is written
+    #[serde(rename = "pid-file")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid_file: Option<String>,
+    /// Whether to print a JSON line to stdout when the node is ready
+    #[serde(rename = "ready-json")]
+    #[serde(default)]
+    pub ready_json: bool,
+    /// Validate config and exit without starting the node.
+    #[serde(skip)]
+    pub dry_run: bool,
+    /// Print the effective config and exit without starting the node.
+    #[serde(skip)]
+    pub print_config: bool,
+    /// Paths to the config files the config was merged from in order. Used
+    /// to reload the config on SIGHUP.
+    #[serde(skip)]
+    pub config_paths: Vec<String>,
+    /// Format of the config files.
+    #[serde(skip, default = "default_config_format")]
+    pub config_format: ConfigFormat,
     /// Unused fields while parsing config file
-    #[serde(flatten)]
+    #[serde(flatten, skip_serializing)]
     pub unused: HashMap<String, Value>,
 }
 
@@ -160,16 +1082,60 @@ fn create_sk_arg() -> Arg<'static, 'static> {
         .hidden(true)
 }
 
+fn create_sk_file_arg() -> Arg<'static, 'static> {
+    Arg::with_name("secret-key-file")
+        .long("secret-key-file")
+        .help("Path to the file with DHT secret key stored as a hex string. \
+               Surrounding whitespaces are ignored")
+        .takes_value(true)
+        .conflicts_with_all(&["secret-key", "keys-file"])
+        .env("TOX_SECRET_KEY_FILE")
+}
+
 fn create_keys_file_arg() -> Arg<'static, 'static> {
     Arg::with_name("keys-file")
         .short("k")
         .long("keys-file")
-        .help("Path to the file where DHT keys are stored")
+        .help("Path to the file where DHT keys are stored. May be specified \
+               multiple times together with --expect-pk to select keys by \
+               the public key")
         .takes_value(true)
-        .required_unless("secret-key")
+        .multiple(true)
+        .number_of_values(1)
+        .required_unless_one(&["secret-key", "secret-key-file", "version-full", "self-test"])
         .conflicts_with("secret-key")
 }
 
+fn create_keys_format_arg() -> Arg<'static, 'static> {
+    Arg::with_name("keys-format")
+        .long("keys-format")
+        .help("Format of the keys file: raw bytes compatible with \
+               tox-bootstrapd, two lines of base64 or raw bytes with the \
+               secret key first. Binary files are read only in the layout \
+               of this format, base64 files are recognized in any case")
+        .takes_value(true)
+        .case_insensitive(true)
+        .possible_values(&["raw", "base64", "libsodium"])
+        .default_value("raw")
+}
+
+fn create_dry_run_arg() -> Arg<'static, 'static> {
+    Arg::with_name("dry-run")
+        .long("dry-run")
+        .help("Validate config, check that keys are readable, addresses can \
+               be bound and bootstrap nodes can be resolved, then exit \
+               without starting the node")
+}
+
+fn create_print_config_arg() -> Arg<'static, 'static> {
+    Arg::with_name("print-config")
+        .long("print-config")
+        .help("Print the effective config after merging defaults, config \
+               file and arguments in the config file format (YAML for \
+               arguments), then exit. The secret key is never printed")
+        .conflicts_with("dry-run")
+}
+
 fn app() -> App<'static, 'static> {
     App::new(crate_name!())
         .version(crate_version!())
@@ -179,22 +1145,128 @@ fn app() -> App<'static, 'static> {
         .subcommand(SubCommand::with_name("config")
             .arg(Arg::with_name("cfg-file")
                 .index(1)
-                .help("Load settings from saved config file. \
-                    Config file format is YAML")
-                .takes_value(true)))
+                .help("Load settings from saved config files. Several files \
+                    are merged in order, values of later files override \
+                    earlier ones. Config file format is detected by the \
+                    extension: .json for JSON, .toml for TOML and YAML \
+                    otherwise")
+                .takes_value(true)
+                .multiple(true))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .help("Format of config files. With bootstrapd the files are \
+                    read as legacy tox-bootstrapd configs")
+                .takes_value(true)
+                .case_insensitive(true)
+                .possible_values(&["auto", "bootstrapd"])
+                .default_value("auto"))
+            .arg(create_dry_run_arg())
+            .arg(create_print_config_arg()))
         .subcommand(SubCommand::with_name("derive-pk")
             .about("Derive PK from either --keys-file or from env:TOX_SECRET_KEY")
             .arg(create_sk_arg())
-            .arg(create_keys_file_arg()))
+            .arg(create_keys_file_arg())
+            .arg(create_keys_format_arg()))
+        .subcommand(SubCommand::with_name("pubkey")
+            .about("Print PK from an existing keys file")
+            .arg(Arg::with_name("path")
+                .index(1)
+                .help("Path to the keys file")
+                .required(true)
+                .takes_value(true))
+            .arg(create_keys_format_arg()))
+        .subcommand(SubCommand::with_name("check-keys")
+            .about("Check that an existing keys file is valid")
+            .arg(Arg::with_name("path")
+                .index(1)
+                .help("Path to the keys file")
+                .required(true)
+                .takes_value(true))
+            .arg(create_keys_format_arg()))
+        .subcommand(SubCommand::with_name("ping")
+            .about("Check that a DHT node responds to ping requests")
+            .arg(Arg::with_name("pk")
+                .index(1)
+                .help("Public key of the node")
+                .required(true)
+                .takes_value(true)
+                .validator(|s| pk_from_hex(&s).map(drop)))
+            .arg(Arg::with_name("addr")
+                .index(2)
+                .help("Address of the node in the form <host>:<port>")
+                .required(true)
+                .takes_value(true)
+                .validator(|s| validate_bootstrap_addr(&s)))
+            .arg(Arg::with_name("timeout")
+                .long("timeout")
+                .help("Time in seconds to wait for the response")
+                .takes_value(true)
+                .default_value("5")))
+        .subcommand(SubCommand::with_name("resolve-bootstrap")
+            .about("Print addresses the bootstrap nodes of the config resolve to")
+            .arg(Arg::with_name("cfg-file")
+                .index(1)
+                .help("Config files merged in order as for the config subcommand")
+                .required(true)
+                .takes_value(true)
+                .multiple(true)))
         // here go args without subcommands
+        .arg(Arg::with_name("version-full")
+            .long("version-full")
+            .help("Prints version information together with the numeric \
+                   version advertised to clients in bootstrap info"))
+        .arg(Arg::with_name("self-test")
+            .long("self-test")
+            .help("Check that encryption, DHT packets encoding and key pairs \
+                   generation work, report the result of each check and exit"))
         .arg(create_sk_arg())
+        .arg(create_sk_file_arg())
         .arg(create_keys_file_arg())
+        .arg(create_dry_run_arg())
+        .arg(create_print_config_arg())
+        .arg(Arg::with_name("expect-pk")
+            .long("expect-pk")
+            .help("DHT public key to select among keys files. When none of \
+                   the keys files contains it the first keys file is used")
+            .takes_value(true)
+            .requires("keys-file"))
+        .arg(create_keys_format_arg())
         .arg(Arg::with_name("udp-address")
             .short("u")
             .long("udp-address")
-            .help("UDP address to run DHT node")
+            .help("UDP address to run DHT node. May be specified multiple \
+                   times to run separate IPv4 and IPv6 sockets")
+            .multiple(true)
+            .takes_value(true)
+            .use_delimiter(true)
+            .required_unless_one(&["tcp-address", "port", "version-full", "self-test"]))
+        .arg(Arg::with_name("udp-recv-buffer")
+            .long("udp-recv-buffer")
+            .help("Size of receive buffer of UDP sockets in bytes (SO_RCVBUF). \
+                   OS may adjust it, the actual size is logged. OS default \
+                   is used when not specified")
+            .takes_value(true)
+            .requires("udp"))
+        .arg(Arg::with_name("udp-send-buffer")
+            .long("udp-send-buffer")
+            .help("Size of send buffer of UDP sockets in bytes (SO_SNDBUF). \
+                   OS may adjust it, the actual size is logged. OS default \
+                   is used when not specified")
+            .takes_value(true)
+            .requires("udp"))
+        .arg(Arg::with_name("udp-restart-retries")
+            .long("udp-restart-retries")
+            .help("Number of times in a row to restart DHT server with new \
+                   UDP sockets when it fails with a network error. 0 means \
+                   the node stops")
+            .takes_value(true)
+            .default_value("0"))
+        .arg(Arg::with_name("udp-restart-delay")
+            .long("udp-restart-delay")
+            .help("Delay in seconds before the first restart of DHT server. \
+                   It's doubled after each restart up to 30 seconds")
             .takes_value(true)
-            .required_unless("tcp-address"))
+            .default_value("1"))
         .arg(Arg::with_name("tcp-address")
             .short("t")
             .long("tcp-address")
@@ -202,15 +1274,169 @@ fn app() -> App<'static, 'static> {
             .multiple(true)
             .takes_value(true)
             .use_delimiter(true)
-            .required_unless("udp-address"))
+            .required_unless_one(&["udp-address", "port", "version-full", "self-test"]))
+        .arg(Arg::with_name("port")
+            .long("port")
+            .help("Port to run both DHT node and TCP relay on all interfaces: \
+                   [::] or 0.0.0.0 with --ip-mode ipv4. Explicit \
+                   --udp-address and --tcp-address take precedence")
+            .takes_value(true))
+        .group(ArgGroup::with_name("udp")
+            .args(&["udp-address", "port"])
+            .multiple(true))
+        .group(ArgGroup::with_name("tcp")
+            .args(&["tcp-address", "port"])
+            .multiple(true))
         .arg(Arg::with_name("tcp-connections-limit")
             .short("c")
             .long("tcp-connections-limit")
             .help("Maximum number of active TCP connections relay can hold. \
                    Defaults to 512 when tcp-address is specified")
-            .requires("tcp-address")
+            .requires("tcp")
             .takes_value(true)
             .default_value_if("tcp-address", None, "512"))
+        .arg(Arg::with_name("tcp-unix-path")
+            .long("tcp-unix-path")
+            .help("Path to Unix domain socket to run TCP relay on in addition \
+                   to TCP addresses. Stale socket file is removed on startup")
+            .takes_value(true))
+        .arg(Arg::with_name("tcp-max-connections")
+            .long("tcp-max-connections")
+            .help("Maximum number of active TCP connections across all TCP \
+                   addresses. New connections are refused when the limit \
+                   is reached. Unlimited by default")
+            .requires("tcp")
+            .takes_value(true))
+        .arg(Arg::with_name("tcp-backlog")
+            .long("tcp-backlog")
+            .help("Maximum length of the queue of pending TCP connections of \
+                   each listener. The OS may cap it, e.g. by \
+                   net.core.somaxconn on Linux")
+            .takes_value(true)
+            .requires("tcp"))
+        .arg(Arg::with_name("bind-retry")
+            .long("bind-retry")
+            .help("Number of retries to bind TCP addresses that are \
+                   temporarily unavailable, e.g. during a restart. The delay \
+                   between retries starts at 0.5 seconds and is doubled \
+                   after each attempt up to 30 seconds")
+            .takes_value(true)
+            .default_value("0"))
+        .arg(Arg::with_name("drain-timeout")
+            .long("drain-timeout")
+            .help("Time in seconds to keep serving existing TCP connections \
+                   on shutdown while new connections are not accepted. \
+                   0 means the relay stops immediately")
+            .takes_value(true)
+            .default_value("0"))
+        .arg(Arg::with_name("run-for")
+            .long("run-for")
+            .help("Time in seconds after which the node shuts down gracefully \
+                   and exits with zero code, e.g. for tests. The node runs \
+                   indefinitely if not specified")
+            .takes_value(true))
+        .arg(Arg::with_name("tcp-idle-timeout")
+            .long("tcp-idle-timeout")
+            .help("Time in seconds after which TCP connections without any \
+                   traffic are closed. 0 means disabled")
+            .takes_value(true)
+            .default_value("0"))
+        .arg(Arg::with_name("ip-mode")
+            .long("ip-mode")
+            .help("IP address family of the DHT node. In ipv4 and ipv6 modes \
+                   packets of the other family are dropped and all UDP \
+                   addresses must be of this family")
+            .takes_value(true)
+            .case_insensitive(true)
+            .possible_values(&["dual", "ipv4", "ipv6"])
+            .default_value("dual"))
+        .arg(Arg::with_name("no-v4-mapped")
+            .long("no-v4-mapped")
+            .help("Don't send packets to IPv4 nodes via IPv6 UDP sockets using \
+                   IPv4-mapped addresses. Packets to IPv4 nodes are dropped \
+                   unless there is an IPv4 UDP address")
+            .requires("udp"))
+        .arg(Arg::with_name("rate-limit")
+            .long("rate-limit")
+            .help("Maximum number of UDP packets per second accepted from a \
+                   single IP. Short bursts are allowed. 0 means no limit")
+            .takes_value(true)
+            .default_value("0"))
+        .arg(Arg::with_name("send-rate")
+            .long("send-rate")
+            .help("Maximum number of UDP packets per second sent in total. \
+                   Excess packets are dropped and counted. 0 means no limit")
+            .takes_value(true)
+            .default_value("0"))
+        .arg(Arg::with_name("max-packet-size")
+            .long("max-packet-size")
+            .help("Maximum size in bytes of UDP packets. Larger packets are \
+                   dropped before decoding and counted separately. The \
+                   default is the size of the largest DHT packets, onion \
+                   and net crypto ones")
+            .takes_value(true)
+            .validator(|s| s.parse::<usize>()
+                .map_err(|e| e.to_string())
+                .and_then(validate_max_packet_size))
+            .default_value("1400"))
+        .arg(Arg::with_name("ban-list")
+            .long("ban-list")
+            .help("Comma separated IP networks in CIDR notation to drop UDP \
+                   packets and TCP connections from, e.g. 10.0.0.0/8")
+            .multiple(true)
+            .takes_value(true)
+            .use_delimiter(true)
+            .validator(|s| s.parse::<Cidr>().map(drop)))
+        .arg(Arg::with_name("tcp-allow-list")
+            .long("tcp-allow-list")
+            .help("Comma separated IP networks in CIDR notation to accept TCP \
+                   connections from. Connections from all IPs are accepted \
+                   when not specified. --ban-list takes precedence")
+            .multiple(true)
+            .takes_value(true)
+            .use_delimiter(true)
+            .requires("tcp")
+            .validator(|s| s.parse::<Cidr>().map(drop)))
+        .arg(Arg::with_name("proxy-protocol")
+            .long("proxy-protocol")
+            .help("Expect PROXY protocol v2 header on TCP connections and use \
+                   the client address from it. Connections without a valid \
+                   header are rejected")
+            .requires("tcp"))
+        .arg(Arg::with_name("connection-hook")
+            .long("connection-hook")
+            .help("Shell command run with the IP of a new TCP connection as \
+                   the last argument. Non-zero exit status rejects the \
+                   connection. Decisions are cached per IP")
+            .takes_value(true)
+            .requires("tcp"))
+        .arg(Arg::with_name("connection-hook-timeout")
+            .long("connection-hook-timeout")
+            .help("Time in seconds after which the connection hook is killed \
+                   and considered failed")
+            .takes_value(true)
+            .default_value("2"))
+        .arg(Arg::with_name("connection-hook-cache")
+            .long("connection-hook-cache")
+            .help("Time in seconds to cache decisions of the connection hook \
+                   per IP")
+            .takes_value(true)
+            .default_value("60"))
+        .arg(Arg::with_name("connection-hook-failure")
+            .long("connection-hook-failure")
+            .help("Whether to accept or reject connections when the \
+                   connection hook fails to run, times out or is killed")
+            .takes_value(true)
+            .case_insensitive(true)
+            .possible_values(&["allow", "deny"])
+            .default_value("deny"))
+        .arg(Arg::with_name("access-log")
+            .long("access-log")
+            .help("Path to the file where a line is appended for every closed \
+                   TCP relay connection: time, client address, listener, \
+                   duration and transferred bytes")
+            .takes_value(true)
+            .requires("tcp"))
         .arg(Arg::with_name("bootstrap-node")
             .short("b")
             .long("bootstrap-node")
@@ -219,157 +1445,1013 @@ fn app() -> App<'static, 'static> {
             .takes_value(true)
             .number_of_values(2)
             .value_names(&["public key", "address"]))
+        .arg(Arg::with_name("bootstrap-nodes")
+            .long("bootstrap-nodes")
+            .visible_alias("bootstrap")
+            .help("Comma separated nodes to perform initial bootstrap in the \
+                   form <public key>@<host>:<port> as published by public \
+                   node lists. Added to nodes from --bootstrap-node")
+            .multiple(true)
+            .takes_value(true)
+            .use_delimiter(true)
+            .validator(|s| parse_bootstrap_node_compact(&s).map(drop)))
+        .arg(Arg::with_name("bootstrap-file")
+            .long("bootstrap-file")
+            .help("Path to the file with nodes to perform initial bootstrap. \
+                   Each line contains a public key and an address separated \
+                   by whitespace. Lines starting with # are ignored")
+            .takes_value(true))
+        .arg(Arg::with_name("bootstrap-reload-signal")
+            .long("bootstrap-reload-signal")
+            .help("Number of the signal to re-read the bootstrap file on and \
+                   ping new nodes from it, e.g. 35 for SIGRTMIN+1 on Linux. \
+                   Signals the node handles otherwise are rejected")
+            .takes_value(true)
+            .requires("bootstrap-file")
+            .validator(|s| s.parse().map_err(|e| format!("{}", e)).and_then(validate_reload_signal)))
+        .arg(Arg::with_name("bootstrap-url")
+            .long("bootstrap-url")
+            .help("HTTP URL of a JSON array of bootstrap nodes with \
+                   public_key and address fields. It's fetched at startup \
+                   and configured nodes are used if fetching fails")
+            .takes_value(true))
+        .arg(Arg::with_name("nodes-cache")
+            .long("nodes-cache")
+            .help("Path to the file where known DHT nodes are saved every \
+                   minute. They are used as additional bootstrap nodes on \
+                   the next start")
+            .takes_value(true)
+            .requires("udp"))
+        .arg(Arg::with_name("bootstrap-refresh")
+            .long("bootstrap-refresh")
+            .help("Interval in seconds for resolving addresses of bootstrap \
+                   nodes again to follow changes of dynamic DNS names. 0 \
+                   means they are resolved only once at startup")
+            .takes_value(true)
+            .default_value("0"))
+        .arg(Arg::with_name("dns-timeout")
+            .long("dns-timeout")
+            .help("Timeout in seconds for resolving an address of a \
+                   bootstrap node. The node is skipped when it can't be \
+                   resolved")
+            .takes_value(true)
+            .default_value("10"))
+        .arg(Arg::with_name("dns-retries")
+            .long("dns-retries")
+            .help("Number of retries to resolve an address of a bootstrap \
+                   node after a failure or timeout")
+            .takes_value(true)
+            .default_value("0"))
+        .arg(Arg::with_name("prefer-ipv6")
+            .long("prefer-ipv6")
+            .help("Add resolved IPv6 addresses of bootstrap nodes before IPv4 \
+                   ones. Addresses not matching --ip-mode are always dropped"))
+        .arg(Arg::with_name("dht-ping-interval")
+            .long("dht-ping-interval")
+            .help("Interval in seconds for pinging the closest DHT nodes in \
+                   addition to the pings sent by the DHT server itself. 0 \
+                   means disabled")
+            .takes_value(true)
+            .default_value("0"))
+        .arg(Arg::with_name("dht-nodes-threshold")
+            .long("dht-nodes-threshold")
+            .help("Log a warning when the number of DHT nodes known by the \
+                   DHT server exceeds this value. It's not enforced, the DHT \
+                   server bounds its nodes itself")
+            .takes_value(true)
+            .requires("udp"))
         .arg(Arg::with_name("threads")
             .short("j")
             .long("threads")
             .help("Number of threads to use. The value 'auto' means that the \
                    number of threads will be determined automatically by the \
-                   number of CPU cores")
+                   number of CPU cores. TOX_THREADS environment variable is \
+                   used when not specified")
             .takes_value(true)
-            .default_value("1"))
+            .default_value("1")
+            .validator(|s| s.parse::<Threads>().map(drop)))
         .arg(Arg::with_name("log-type")
             .short("l")
             .long("log-type")
-            .help("Where to write logs")
+            .help("Where to write logs. Json writes one JSON object per \
+                   record to stdout, File writes to the file specified by \
+                   --log-file")
             .takes_value(true)
             .default_value("Stderr")
-            .possible_values(&LogType::variants()))
+            .possible_values(&LogType::variants())
+            .requires_if("File", "log-file"))
+        .arg(Arg::with_name("log-file")
+            .long("log-file")
+            .help("Path to the file to write logs to with File log type")
+            .takes_value(true))
+        .arg(Arg::with_name("log-max-size")
+            .long("log-max-size")
+            .help("Size in megabytes after which the log file is rotated. \
+                   0 disables rotation")
+            .takes_value(true)
+            .default_value("10"))
+        .arg(Arg::with_name("log-keep")
+            .long("log-keep")
+            .help("Number of rotated log files to keep")
+            .takes_value(true)
+            .default_value("5"))
+        .arg(Arg::with_name("log-format")
+            .long("log-format")
+            .help("Layout of log records written to stderr, stdout or log \
+                   file. Full includes date, level and target, compact \
+                   includes only time and level, json writes one JSON object \
+                   per record. Colors are used when writing to a terminal")
+            .takes_value(true)
+            .case_insensitive(true)
+            .possible_values(&["compact", "full", "json"])
+            .default_value("full"))
+        .arg(Arg::with_name("log-level")
+            .long("log-level")
+            .help("Log level. When not specified RUST_LOG environment \
+                   variable is used with info level by default")
+            .takes_value(true)
+            .case_insensitive(true)
+            .possible_values(&["trace", "debug", "info", "warn", "error"]))
+        .arg(Arg::with_name("quiet")
+            .long("quiet")
+            .help("Log only warnings and errors. The same as --log-level warn")
+            .conflicts_with("log-level"))
+        .arg(Arg::with_name("log-throttle-secs")
+            .long("log-throttle-secs")
+            .help("Time in seconds to coalesce identical repeated errors of \
+                   handling packets into a single line. The first error is \
+                   always logged immediately. 0 disables throttling")
+            .takes_value(true)
+            .default_value("10"))
         .arg(Arg::with_name("motd")
             .short("m")
             .long("motd")
             .help("Message of the day. Must be no longer than 256 bytes. May \
                    contain next variables placed in {{ }}:\n\
                    - start_date: time when the node was started\n\
-                   - uptime: uptime in the format 'XX days XX hours XX minutes'\n")
+                   - uptime: uptime in the format 'XX days XX hours XX minutes'\n\
+                   - tcp_packets_in, tcp_packets_out, udp_packets_in, \
+                   udp_packets_out: packet counters\n\
+                   - public_key: DHT public key of the node\n\
+                   - version: version of the node\n")
             .takes_value(true)
-            .validator(|m| {
-                if m.len() > BOOSTRAP_SERVER_MAX_MOTD_LENGTH {
-                    Err(format!("Message of the day must not be longer than {} bytes", BOOSTRAP_SERVER_MAX_MOTD_LENGTH))
-                } else {
-                    Ok(())
-                }
-            })
+            .validator(|m| validate_motd(&m))
             .default_value("This is tox-rs"))
+        .arg(Arg::with_name("motd-file")
+            .long("motd-file")
+            .help("Path to the file to read message of the day from. The \
+                   same variables as for --motd are supported")
+            .takes_value(true)
+            .conflicts_with("motd"))
+        .arg(Arg::with_name("motd-ipv6")
+            .long("motd-ipv6")
+            .help("Message of the day for IPv6 clients, --motd or --motd-file \
+                   is sent to IPv4 clients. The same variables and length \
+                   limit as for --motd apply")
+            .takes_value(true)
+            .validator(|m| validate_motd(&m)))
+        .arg(Arg::with_name("motd-cache-ttl")
+            .long("motd-cache-ttl")
+            .help("Time in seconds to reuse formatted MOTD instead of \
+                   substituting variables for every bootstrap info request. \
+                   0 disables caching")
+            .takes_value(true)
+            .default_value("0"))
         .arg(Arg::with_name("lan-discovery")
             .long("lan-discovery")
             .help("Enable LAN discovery (disabled by default)"))
+        .arg(Arg::with_name("lan-discovery-log-interval")
+            .long("lan-discovery-log-interval")
+            .help("Interval in seconds for logging the number of sent LAN \
+                   discovery packets at debug level. 0 means disabled")
+            .takes_value(true)
+            .default_value("60"))
+        .arg(Arg::with_name("no-multicast-loop")
+            .long("no-multicast-loop")
+            .help("Don't loop IPv6 multicast LAN discovery packets back to \
+                   this host, e.g. when several nodes run on it. Multicast \
+                   loop is always disabled without --lan-discovery")
+            .requires("lan-discovery"))
+        .arg(Arg::with_name("onion-buffer")
+            .long("onion-buffer")
+            .help("Maximum number of onion packets queued between TCP relay \
+                   and DHT node in each direction. Packets are dropped when \
+                   the queue is full")
+            .takes_value(true)
+            .default_value("32"))
+        .arg(Arg::with_name("no-onion")
+            .long("no-onion")
+            .help("Disable forwarding of onion packets between TCP relay and \
+                   DHT node"))
+        .arg(Arg::with_name("onion-error-policy")
+            .long("onion-error-policy")
+            .help("What to do when onion packets of a TCP client can't be \
+                   handled: log a warning, only count the error in metrics \
+                   or log it and disconnect the client")
+            .takes_value(true)
+            .case_insensitive(true)
+            .possible_values(&["log", "count", "disconnect"])
+            .default_value("log"))
+        .arg(Arg::with_name("bootstrap-stats")
+            .long("bootstrap-stats")
+            .help("Prepend a binary stats header to the MOTD: version (u32), \
+                   uptime in seconds (u64) and number of known DHT nodes \
+                   (u32), all big-endian. MOTD is truncated to fit the header"))
+        .arg(Arg::with_name("advertise-version")
+            .long("advertise-version")
+            .help("Version to send in BootstrapInfo packets instead of the \
+                   version of the node. Used for interoperability testing")
+            .takes_value(true)
+            .hidden(true)
+            .validator(|s| s.parse::<u32>()
+                .map_err(|e| e.to_string())
+                .and_then(validate_advertise_version)))
+        .arg(Arg::with_name("metrics-address")
+            .long("metrics-address")
+            .help("Address to run Prometheus metrics endpoint. The endpoint \
+                   is disabled when not specified")
+            .takes_value(true))
+        .arg(Arg::with_name("health-address")
+            .long("health-address")
+            .help("TCP address to run health check endpoint. It responds \
+                   with OK and uptime to every connection. The endpoint is \
+                   disabled when not specified")
+            .takes_value(true))
+        .arg(Arg::with_name("stats-interval")
+            .long("stats-interval")
+            .help("Interval in seconds for logging a summary of runtime \
+                   statistics: uptime, packets since the previous summary \
+                   and TCP connections. 0 means disabled")
+            .takes_value(true)
+            .default_value("0"))
+        .arg(Arg::with_name("allow-root")
+            .long("allow-root")
+            .help("Allow running as root. The node refuses to start as root \
+                   without this flag"))
+        .arg(Arg::with_name("daemonize")
+            .long("daemonize")
+            .help("Detach from the terminal and run in background. Use \
+                   syslog for logging since standard streams are redirected \
+                   to /dev/null. Supported only on unix"))
+        .arg(Arg::with_name("pid-file")
+            .long("pid-file")
+            .help("Path to the file where the PID of the node is written. \
+                   The file is removed on shutdown")
+            .takes_value(true))
+        .arg(Arg::with_name("ready-json")
+            .long("ready-json")
+            .help("Print a single JSON line to stdout when UDP and TCP \
+                   addresses are bound: public key, version, addresses and \
+                   PID. Can't be used with --daemonize")
+            .conflicts_with("daemonize"))
+}
+
+/// Check that Unix domain socket for TCP relay is supported on this platform.
+fn check_tcp_unix_path(tcp_unix_path: &Option<String>) -> Result<(), Error> {
+    if tcp_unix_path.is_some() && !cfg!(unix) {
+        Err(format_err!("Unix domain sockets are not supported on this platform"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Check that daemonizing is supported on this platform.
+fn check_daemonize(daemonize: bool) -> Result<(), Error> {
+    if daemonize && !cfg!(unix) {
+        Err(format_err!("Daemonizing is not supported on this platform"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Signals the node handles for other purposes.
+#[cfg(unix)]
+const RESERVED_SIGNALS: [(i32, &str); 5] = [
+    (libc::SIGHUP, "SIGHUP"),
+    (libc::SIGINT, "SIGINT"),
+    (libc::SIGTERM, "SIGTERM"),
+    (libc::SIGUSR1, "SIGUSR1"),
+    (libc::SIGUSR2, "SIGUSR2"),
+];
+
+/// Check that the signal to reload the bootstrap file on is supported and
+/// isn't handled by the node for other purposes.
+#[cfg(unix)]
+fn validate_reload_signal(signal: i32) -> Result<(), String> {
+    if signal <= 0 {
+        return Err(format!("Invalid signal number {}", signal))
+    }
+    match RESERVED_SIGNALS.iter().find(|&&(reserved, _)| reserved == signal) {
+        Some((_, name)) => Err(format!("Signal {} is {} which is already handled by the node", signal, name)),
+        None => Ok(()),
+    }
+}
+
+/// Check that the signal to reload the bootstrap file on is supported.
+/// Signals are not supported on this platform.
+#[cfg(not(unix))]
+fn validate_reload_signal(_signal: i32) -> Result<(), String> {
+    Err("Signals are not supported on this platform".to_owned())
+}
+
+/// Check that the maximum size of UDP packets is not larger than the size
+/// accepted by the DHT codec.
+fn validate_max_packet_size(max_packet_size: usize) -> Result<(), String> {
+    if max_packet_size == 0 || max_packet_size > MAX_DHT_PACKET_SIZE {
+        Err(format!("Maximum packet size must be between 1 and {} bytes", MAX_DHT_PACKET_SIZE))
+    } else {
+        Ok(())
+    }
+}
+
+/// Check that the advertised version has 10 digits with the leading numeral
+/// of a known scheme: 1 of `tox-bootstrapd`, 2 of its old date based one or 3
+/// of this node.
+fn validate_advertise_version(version: u32) -> Result<(), String> {
+    if (1_000_000_000..4_000_000_000).contains(&version) {
+        Ok(())
+    } else {
+        Err("Advertised version must be between 1000000000 and 3999999999".to_owned())
+    }
+}
+
+/// Check that message of the day fits into `BootstrapInfo` packet.
+fn validate_motd(motd: &str) -> Result<(), String> {
+    if motd::fixed_length(motd) > BOOSTRAP_SERVER_MAX_MOTD_LENGTH {
+        Err(format!("Message of the day must not be longer than {} bytes", BOOSTRAP_SERVER_MAX_MOTD_LENGTH))
+    } else {
+        Ok(())
+    }
+}
+
+/// Read message of the day from the file. Trailing line breaks are removed.
+fn read_motd_file(motd_file: &str) -> Result<String, Error> {
+    let motd = std::fs::read_to_string(motd_file)
+        .map_err(|e| format_err!("Can't read MOTD file '{}': {}", motd_file, e))?;
+    let motd = motd.trim_end_matches(&['\r', '\n'][..]).to_owned();
+    validate_motd(&motd)
+        .map_err(|e| format_err!("Invalid MOTD file '{}': {}", motd_file, e))?;
+    Ok(motd)
+}
+
+/// Print the error of parsing arguments and exit with the exit code of
+/// config errors. Help and version are printed to stdout with code 0 as usual.
+fn exit_args_error(e: clap::Error) -> ! {
+    if !e.use_stderr() {
+        e.exit()
+    }
+    eprintln!("{}", e.message);
+    std::process::exit(EXIT_CONFIG)
 }
 
 /// Parse command line arguments.
 pub fn cli_parse() -> NodeConfig {
-    let matches = app().get_matches();
+    let matches = app().get_matches_safe().unwrap_or_else(|e| exit_args_error(e));
+
+    if matches.is_present("version-full") {
+        run_version_full();
+    }
+
+    if matches.is_present("self-test") {
+        exit_command(run_self_test());
+    }
 
     match matches.subcommand() {
-        ("derive-pk", Some(m)) => run_derive_pk(m),
+        ("derive-pk", Some(m)) => exit_command(run_derive_pk(m)),
+        ("pubkey", Some(m)) => exit_command(run_pubkey(m)),
+        ("check-keys", Some(m)) => exit_command(run_check_keys(m)),
+        ("ping", Some(m)) => exit_command(run_ping(m)),
+        ("resolve-bootstrap", Some(m)) => exit_command(run_resolve_bootstrap(m)),
         ("config", Some(m)) => run_config(m),
         _ => run_args(&matches),
     }
 }
 
-/// Parse settings from a saved file.
-fn parse_config(config_path: &str) -> NodeConfig {
+/// Detect config file format by its extension. YAML is used by default.
+fn config_format(config_path: &str) -> FileFormat {
+    let extension = Path::new(config_path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+
+    match extension.as_deref() {
+        Some("json") => FileFormat::Json,
+        Some("toml") => FileFormat::Toml,
+        _ => FileFormat::Yaml,
+    }
+}
+
+fn default_config_format() -> ConfigFormat {
+    ConfigFormat::Auto
+}
+
+/// Lookup of environment variables. Tests use their own lookup instead of
+/// changing the environment shared by all tests of the process.
+type Env<'a> = &'a dyn Fn(&str) -> Result<String, std::env::VarError>;
+
+/// Lookup of environment variables of the process.
+fn process_env(name: &str) -> Result<String, std::env::VarError> {
+    std::env::var(name)
+}
+
+/// Replace `${VAR}` references in the string with values of environment
+/// variables. A `$` not followed by `{` is kept as is.
+fn expand_env_vars(value: &str, env: Env) -> Result<String, Error> {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[.. start]);
+        let end = rest[start ..].find('}')
+            .ok_or_else(|| format_err!("Unterminated variable reference in '{}'", value))?;
+        let name = &rest[start + 2 .. start + end];
+        if name.is_empty() {
+            return Err(format_err!("Empty variable reference in '{}'", value));
+        }
+        let var = env(name)
+            .map_err(|e| format_err!("Can't expand environment variable '{}': {}", name, e))?;
+        result.push_str(&var);
+        rest = &rest[start + end + 1 ..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Expand environment variables in all strings of the config value including
+/// nested tables and arrays. `None` is returned if nothing was expanded so
+/// that untouched values keep their origin.
+fn expand_env_value(value: &CfgValue, env: Env) -> Result<Option<CfgValue>, Error> {
+    if let Ok(table) = value.clone().into_table() {
+        let mut expanded = false;
+        let mut result = HashMap::with_capacity(table.len());
+        for (key, value) in table {
+            let value = match expand_env_value(&value, env)? {
+                Some(value) => {
+                    expanded = true;
+                    value
+                },
+                None => value,
+            };
+            result.insert(key, value);
+        }
+        return Ok(if expanded { Some(CfgValue::from(result)) } else { None });
+    }
+    if let Ok(array) = value.clone().into_array() {
+        let mut expanded = false;
+        let mut result = Vec::with_capacity(array.len());
+        for value in array {
+            let value = match expand_env_value(&value, env)? {
+                Some(value) => {
+                    expanded = true;
+                    value
+                },
+                None => value,
+            };
+            result.push(value);
+        }
+        return Ok(if expanded { Some(CfgValue::from(result)) } else { None });
+    }
+    // numbers and booleans are converted to strings too but they can't
+    // contain variable references
+    match value.clone().into_str() {
+        Ok(string) if string.contains("${") => Ok(Some(CfgValue::from(expand_env_vars(&string, env)?))),
+        _ => Ok(None),
+    }
+}
+
+/// Expand environment variables in string values of the config file. It's
+/// done after merging so that values are expanded regardless of the format
+/// of the file.
+fn expand_env_config(settings: &mut Config, env: Env) -> Result<(), Error> {
+    for (key, value) in settings.collect()? {
+        if let Some(value) = expand_env_value(&value, env).map_err(|e| format_err!("Invalid '{}': {}", key, e))? {
+            settings.set(&key, value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Serialize the config to the format of the last file it was loaded from or
+/// to YAML if it was parsed from arguments or `tox-bootstrapd` configs. The
+/// secret key is never serialized.
+fn format_config(config: &NodeConfig) -> Result<String, Error> {
+    let format = match config.config_paths.last() {
+        Some(config_path) if config.config_format == ConfigFormat::Auto => config_format(config_path),
+        _ => FileFormat::Yaml,
+    };
+    let text = match format {
+        FileFormat::Json => serde_json::to_string_pretty(config)?,
+        // convert to TOML value first so that tables are placed after values
+        FileFormat::Toml => {
+            let mut value = toml::Value::try_from(config)?;
+            // TOML arrays can't mix strings and tables
+            if let Some(toml::Value::Array(tcp_addrs)) = value.get_mut("tcp-addresses") {
+                if tcp_addrs.iter().any(toml::Value::is_table) {
+                    for addr in tcp_addrs.iter_mut().filter(|addr| addr.is_str()) {
+                        let mut table = toml::value::Table::new();
+                        table.insert("address".to_owned(), addr.clone());
+                        *addr = toml::Value::Table(table);
+                    }
+                }
+            }
+            toml::to_string(&value)?
+        },
+        _ => serde_yaml::to_string(config)?,
+    };
+    Ok(text)
+}
+
+/// Print the effective config to stdout.
+pub fn print_config(config: &NodeConfig) -> Result<(), NodeError> {
+    let text = format_config(config)
+        .map_err(|e| NodeError::Config(format_err!("Failed to serialize config: {}", e)))?;
+    println!("{}", text);
+    Ok(())
+}
+
+/// Settings with default values of config keys that are not optional.
+fn default_settings() -> Config {
     let mut settings = Config::default();
 
     settings.set_default("log-type", "Stderr").expect("Can't set default value for `log-type`");
+    settings.set_default("log-max-size", "10").expect("Can't set default value for `log-max-size`");
+    settings.set_default("log-keep", "5").expect("Can't set default value for `log-keep`");
+    settings.set_default("log-format", "full").expect("Can't set default value for `log-format`");
+    settings.set_default("keys-format", "raw").expect("Can't set default value for `keys-format`");
     settings.set_default("motd", "This is tox-rs").expect("Can't set default value for `motd`");
     settings.set_default("lan-discovery", "False").expect("Can't set default value for `lan-discovery`");
+    settings.set_default("bootstrap-stats", "False").expect("Can't set default value for `bootstrap-stats`");
     settings.set_default("threads", "1").expect("Can't set default value for `threads`");
     settings.set_default("tcp-connections-limit", "512").expect("Can't set default value for `tcp-connections-limit`");
+    settings.set_default("ip-mode", "dual").expect("Can't set default value for `ip-mode`");
+    settings.set_default("dht-ping-interval", "0").expect("Can't set default value for `dht-ping-interval`");
+    settings.set_default("bootstrap-refresh", "0").expect("Can't set default value for `bootstrap-refresh`");
+    settings.set_default("dns-timeout", "10").expect("Can't set default value for `dns-timeout`");
+    settings.set_default("dns-retries", "0").expect("Can't set default value for `dns-retries`");
+    settings.set_default("motd-cache-ttl", "0").expect("Can't set default value for `motd-cache-ttl`");
+    settings.set_default("lan-discovery-log-interval", "60").expect("Can't set default value for `lan-discovery-log-interval`");
+    settings.set_default("stats-interval", "0").expect("Can't set default value for `stats-interval`");
+    settings.set_default("udp-restart-retries", "0").expect("Can't set default value for `udp-restart-retries`");
+    settings.set_default("udp-restart-delay", "1").expect("Can't set default value for `udp-restart-delay`");
+    settings.set_default("bind-retry", "0").expect("Can't set default value for `bind-retry`");
+    settings.set_default("drain-timeout", "0").expect("Can't set default value for `drain-timeout`");
+    settings.set_default("tcp-idle-timeout", "0").expect("Can't set default value for `tcp-idle-timeout`");
+    settings.set_default("onion-buffer", "32").expect("Can't set default value for `onion-buffer`");
+    settings.set_default("onion-error-policy", "log").expect("Can't set default value for `onion-error-policy`");
+    settings.set_default("connection-hook-timeout", "2").expect("Can't set default value for `connection-hook-timeout`");
+    settings.set_default("connection-hook-cache", "60").expect("Can't set default value for `connection-hook-cache`");
+    settings.set_default("connection-hook-failure", "deny").expect("Can't set default value for `connection-hook-failure`");
+    settings.set_default("log-throttle-secs", "10").expect("Can't set default value for `log-throttle-secs`");
+    settings.set_default("rate-limit", "0").expect("Can't set default value for `rate-limit`");
+    settings.set_default("send-rate", "0").expect("Can't set default value for `send-rate`");
+    settings.set_default("max-packet-size", "1400").expect("Can't set default value for `max-packet-size`");
 
-    let config_file = if !Path::new(config_path).exists() {
-        panic!("Can't find config file {}", config_path);
-    } else {
-        CfgFile::with_name(config_path)
+    settings
+}
+
+/// Find top-level keys of the config that fail deserialization. Errors of
+/// custom deserializers don't carry the key so each key is deserialized
+/// with default values of the others, several invalid keys are all found
+/// this way.
+fn failed_config_keys(settings: &Config) -> Vec<String> {
+    let values = match settings.collect() {
+        Ok(values) => values,
+        Err(_) => return Vec::new(),
     };
+    values
+        .into_iter()
+        .filter(|(key, value)| {
+            let mut probe = default_settings();
+            probe.set(key, value.clone()).is_ok() && probe.try_into::<NodeConfig>().is_err()
+        })
+        .map(|(key, _)| key)
+        .sorted()
+        .collect()
+}
+
+/// Describe the failure to deserialize the config naming the field that
+/// caused it when it's known.
+fn describe_config_error(settings: &Config, error: ConfigError) -> String {
+    match error {
+        ConfigError::Type { origin, unexpected, expected, key: Some(key) } => {
+            let origin = origin.map(|origin| format!(" in {}", origin)).unwrap_or_default();
+            format!("field `{}`: invalid type: {}, expected {}{}", key, unexpected, expected, origin)
+        },
+        error => match failed_config_keys(settings).as_slice() {
+            [] => error.to_string(),
+            [key] => format!("field `{}`: {}", key, error),
+            keys => format!("fields `{}`: {}", keys.iter().format("`, `"), error),
+        },
+    }
+}
+
+/// Parse settings from saved files merged in order so that values of later
+/// files override earlier ones. Arrays are replaced rather than concatenated.
+/// Also used to reload config of the running node.
+pub fn try_parse_config_as<P: AsRef<str>>(config_paths: &[P], format: ConfigFormat) -> Result<NodeConfig, Error> {
+    parse_config_files(config_paths, format, &process_env)
+}
 
-    settings.merge(config_file).expect("Merging config file with default values failed");
+/// The same as `try_parse_config_as` but with the format detected by file
+/// extensions.
+#[cfg(test)]
+pub fn try_parse_config<P: AsRef<str>>(config_paths: &[P]) -> Result<NodeConfig, Error> {
+    try_parse_config_as(config_paths, ConfigFormat::Auto)
+}
 
-    let config: NodeConfig = settings.try_into().expect("Can't deserialize config");
+/// The same as `try_parse_config_as` but with environment variables looked
+/// up by `env`.
+fn parse_config_files<P: AsRef<str>>(config_paths: &[P], format: ConfigFormat, env: Env) -> Result<NodeConfig, Error> {
+    let config = parse_config_settings(config_paths, format, env)?;
 
-    if config.keys_file.is_none() {
-        panic!("Can't deserialize config: 'keys-file' is not set");
+    if config.keys_files.is_empty() && config.sk_file.is_none() {
+        return Err(format_err!("Can't deserialize config: neither 'keys-file' nor 'secret-key-file' is set"));
     }
 
-    config
+    // The same as `required_unless` of CLI arguments but interfaces and Unix
+    // socket are config only ways to specify addresses
+    if config.udp_addrs.is_empty() && config.tcp_addrs.is_empty()
+        && config.listen_interfaces.is_empty() && config.tcp_unix_path.is_none() {
+        return Err(format_err!("Can't deserialize config: neither 'udp-address' nor 'tcp-addresses' is set, the node would do nothing"));
+    }
+
+    Ok(config)
+}
+
+/// The same as `parse_config_files` but without settings required only to
+/// run the node: keys and addresses to listen on.
+fn parse_config_settings<P: AsRef<str>>(config_paths: &[P], format: ConfigFormat, env: Env) -> Result<NodeConfig, Error> {
+    let mut settings = default_settings();
+
+    for config_path in config_paths {
+        let config_path = config_path.as_ref();
+        if !Path::new(config_path).exists() {
+            return Err(format_err!("Can't find config file {}", config_path));
+        }
+
+        let res = match format {
+            ConfigFormat::Auto => settings.merge(CfgFile::new(config_path, config_format(config_path))),
+            ConfigFormat::Bootstrapd => settings.merge(BootstrapdFile::new(config_path)),
+        };
+        res.map_err(|e| format_err!("Merging config file {} failed: {}", config_path, e))?;
+    }
+
+    expand_env_config(&mut settings, env)
+        .map_err(|e| format_err!("Can't expand environment variables in config: {}", e))?;
+
+    let mut config: NodeConfig = settings.clone().try_into()
+        .map_err(|e| format_err!("Can't deserialize config: {}", describe_config_error(&settings, e)))?;
+
+    if config.quiet && config.log_level.is_some() {
+        return Err(format_err!("Can't deserialize config: 'quiet' can't be used together with 'log-level'"));
+    }
+
+    if config.log_type == LogType::File && config.log_file.is_none() {
+        return Err(format_err!("Can't deserialize config: 'log-type' is File but 'log-file' is not set"));
+    }
+
+    if let Some(tcp_port_range) = config.tcp_port_range {
+        let addrs = tcp_port_range.addrs()
+            .map_err(|e| format_err!("Invalid 'tcp-port-range': {}", e))?;
+        for addr in addrs {
+            if config.tcp_addrs.iter().all(|tcp_addr| tcp_addr.addr != addr) {
+                config.tcp_addrs.push(addr.into());
+            }
+        }
+    }
+
+    for node in &config.bootstrap_nodes {
+        validate_bootstrap_addr(&node.addr)
+            .map_err(|e| format_err!("Invalid bootstrap node: {}", e))?;
+    }
+
+    if let Some(ref motd_file) = config.motd_file {
+        config.motd = read_motd_file(motd_file)?;
+    } else {
+        validate_motd(&config.motd)
+            .map_err(|e| format_err!("Invalid 'motd': {}", e))?;
+    }
+
+    if let Some(ref motd_ipv6) = config.motd_ipv6 {
+        validate_motd(motd_ipv6)
+            .map_err(|e| format_err!("Invalid 'motd-ipv6': {}", e))?;
+    }
+
+    validate_max_packet_size(config.max_packet_size)
+        .map_err(|e| format_err!("Invalid 'max-packet-size': {}", e))?;
+
+    if let Some(signal) = config.bootstrap_reload_signal {
+        validate_reload_signal(signal)
+            .map_err(|e| format_err!("Invalid 'bootstrap-reload-signal': {}", e))?;
+    }
+
+    if let Some(advertise_version) = config.advertise_version {
+        validate_advertise_version(advertise_version)
+            .map_err(|e| format_err!("Invalid 'advertise-version': {}", e))?;
+    }
+
+    check_tcp_unix_path(&config.tcp_unix_path)?;
+    check_daemonize(config.daemonize)?;
+    if config.daemonize && config.ready_json {
+        return Err(format_err!("'ready-json' can't be used with 'daemonize' since stdout of the daemon is redirected to /dev/null"));
+    }
+
+    config.config_paths = config_paths.iter().map(|config_path| config_path.as_ref().to_owned()).collect();
+    config.config_format = format;
+
+    Ok(config)
+}
+
+/// Parse the DHT secret key from hex.
+fn parse_secret_key(s: &str) -> Result<SecretKey, NodeError> {
+    let sk_bytes: [u8; 32] = FromHex::from_hex(s)
+        .map_err(|e| NodeError::Keys(format_err!("Invalid DHT secret key: {}", e)))?;
+    Ok(SecretKey::from_slice(&sk_bytes).expect("Secret key of valid length"))
+}
+
+/// Load the public key from the keys file in the format.
+fn load_public_key(keys_file: &str, format: KeysFormat) -> Result<PublicKey, NodeError> {
+    let file = std::fs::File::open(keys_file)
+        .map_err(|e| NodeError::Keys(format_err!("Failed to open the keys file '{}': {}", keys_file, e)))?;
+    let (pk, _sk) = try_load_keys(file, format)
+        .map_err(|e| NodeError::Keys(format_err!("Invalid keys file '{}': {}", keys_file, e)))?;
+    Ok(pk)
+}
+
+/// Format of the keys file the subcommand reads.
+fn keys_format(matches: &ArgMatches) -> KeysFormat {
+    value_t!(matches.value_of("keys-format"), KeysFormat).unwrap_or_else(|e| exit_args_error(e))
 }
 
-fn run_derive_pk(matches: &ArgMatches) -> ! {
+fn run_derive_pk(matches: &ArgMatches) -> Result<(), NodeError> {
     let sk_passed_as_arg = matches.occurrences_of("secret-key") > 0;
     if sk_passed_as_arg {
-        panic!("You should not pass the secret key via arguments due to \
-               security reasons. Use the environment variable instead");
+        return Err(NodeError::Config(format_err!("You should not pass the secret key via arguments due to \
+            security reasons. Use the environment variable instead")))
     }
 
-    let pk_from_arg = matches.value_of("secret-key").map(|s| {
-        let sk_bytes: [u8; 32] = FromHex::from_hex(s).expect("Invalid DHT secret key");
-        let sk = SecretKey::from_slice(&sk_bytes).expect("Invalid DHT secret key");
-        sk.public_key()
-    });
-    let pk_from_file = matches.value_of("keys-file").map(|keys_file| {
-        let mut file = std::fs::File::open(keys_file).expect("Failed to read the keys file");
-
-        let mut buf = [0; PUBLICKEYBYTES + SECRETKEYBYTES];
-        use std::io::Read;
-        file.read_exact(&mut buf).expect("Failed to read keys from the keys file");
-        let pk = PublicKey::from_slice(&buf[..PUBLICKEYBYTES]).expect("Failed to read public key from the keys file");
-        let sk = SecretKey::from_slice(&buf[PUBLICKEYBYTES..]).expect("Failed to read secret key from the keys file");
-        assert!(pk == sk.public_key(), "The loaded public key does not correspond to the loaded secret key");
-        pk
-    });
-
-    let pk = pk_from_arg.or(pk_from_file).unwrap();
+    let pk = match (matches.value_of("secret-key"), matches.value_of("keys-file")) {
+        (Some(sk), _) => parse_secret_key(sk)?.public_key(),
+        (None, Some(keys_file)) => load_public_key(keys_file, keys_format(matches))?,
+        (None, None) => unreachable!("Either secret key or keys file is required"),
+    };
 
-    println!("{}", hex::encode(&pk).to_uppercase());
+    println!("{}", hex::encode(pk).to_uppercase());
+    Ok(())
+}
 
-    // FIXME: use ExitCode::SUCCESS when stabilized
-    // https://doc.rust-lang.org/std/process/struct.ExitCode.html
+fn run_version_full() -> ! {
+    println!("{} {}", crate_name!(), crate_version!());
+    println!("Bootstrap info version: {}", crate::version());
     std::process::exit(0)
 }
 
+fn run_pubkey(matches: &ArgMatches) -> Result<(), NodeError> {
+    let keys_file = matches.value_of("path").expect("Path to the keys file is required");
+
+    let pk = load_public_key(keys_file, keys_format(matches))?;
+    println!("{}", hex::encode(pk).to_uppercase());
+    Ok(())
+}
+
+fn run_check_keys(matches: &ArgMatches) -> Result<(), NodeError> {
+    let keys_file = matches.value_of("path").expect("Path to the keys file is required");
+
+    let pk = check_keys_file(keys_file, keys_format(matches)).map_err(NodeError::Keys)?;
+    println!("Keys file '{}' is valid, public key: {}", keys_file, hex::encode(pk).to_uppercase());
+    Ok(())
+}
+
+fn run_ping(matches: &ArgMatches) -> Result<(), NodeError> {
+    let pk = pk_from_hex(matches.value_of("pk").expect("Public key is required")).expect("Validated public key");
+    let addr = matches.value_of("addr").expect("Address is required");
+    let timeout = value_t!(matches.value_of("timeout"), u64).unwrap_or_else(|e| exit_args_error(e));
+
+    let saddr = match addr.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(saddr)) => saddr,
+        Ok(None) => return Err(NodeError::Config(format_err!("Address '{}' resolved to nothing", addr))),
+        Err(e) => return Err(NodeError::Config(format_err!("Failed to resolve address '{}': {}", addr, e))),
+    };
+
+    let mut runtime = tokio::runtime::Runtime::new().expect("Failed to create runtime");
+    let rtt = runtime.block_on(crate::ping::ping_node(pk, saddr, Duration::from_secs(timeout)))
+        .map_err(|e| NodeError::Runtime(format_err!("Node {} failed to respond: {}", saddr, e)))?;
+    println!("Node {} responded in {:.1} ms", saddr, rtt.as_secs_f64() * 1000.0);
+    Ok(())
+}
+
+/// Format the public key of the bootstrap node with its address and the
+/// addresses it resolved to.
+fn format_resolved_node(node: &BootstrapNode, addrs: &[SocketAddr]) -> String {
+    let resolved = if addrs.is_empty() {
+        "unresolved".to_owned()
+    } else {
+        addrs.iter().format(", ").to_string()
+    };
+    format!("{} {}: {}", hex::encode(node.pk).to_uppercase(), node.addr, resolved)
+}
+
+fn run_resolve_bootstrap(matches: &ArgMatches) -> Result<(), NodeError> {
+    let config_paths = matches.values_of("cfg-file")
+        .map(|config_paths| config_paths.collect::<Vec<_>>())
+        .unwrap_or_else(|| exit_args_error(clap::Error::argument_not_found_auto("cfg-file")));
+
+    // the node isn't started so neither keys nor addresses are required
+    let config = parse_config_settings(&config_paths, ConfigFormat::Auto, &process_env).map_err(NodeError::Config)?;
+
+    if config.bootstrap_nodes.is_empty() {
+        return Err(NodeError::Config(format_err!("No bootstrap nodes in the config")))
+    }
+
+    let order = AddrOrder::new(&config);
+    let mut unresolved = 0;
+    for node in &config.bootstrap_nodes {
+        let addrs = order.apply(node.resolve().map(|node| node.saddr).collect());
+        if addrs.is_empty() {
+            unresolved += 1;
+        }
+        println!("{}", format_resolved_node(node, &addrs));
+    }
+
+    if unresolved > 0 {
+        return Err(NodeError::Runtime(format_err!("{} of {} bootstrap nodes are unresolved", unresolved, config.bootstrap_nodes.len())))
+    }
+    Ok(())
+}
+
 fn run_config(matches: &ArgMatches) -> NodeConfig {
-    let config_path = value_t!(matches.value_of("cfg-file"), String).unwrap_or_else(|e| e.exit());
+    let config_paths = matches.values_of("cfg-file")
+        .map(|config_paths| config_paths.collect::<Vec<_>>())
+        .unwrap_or_else(|| exit_args_error(clap::Error::argument_not_found_auto("cfg-file")));
+    let format = value_t!(matches.value_of("format"), ConfigFormat).unwrap_or_else(|e| exit_args_error(e));
 
-    parse_config(&config_path)
+    let mut config = try_parse_config_as(&config_paths, format).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(EXIT_CONFIG)
+    });
+    config.dry_run = matches.is_present("dry-run");
+    config.print_config = matches.is_present("print-config");
+    config
 }
 
 fn run_args(matches: &ArgMatches) -> NodeConfig {
-    let udp_addr = if matches.is_present("udp-address") {
-        Some(value_t!(matches.value_of("udp-address"), SocketAddr).unwrap_or_else(|e| e.exit()))
+    run_args_with_env(matches, &process_env)
+}
+
+/// The same as `run_args` but with environment variables looked up by `env`.
+fn run_args_with_env(matches: &ArgMatches, env: Env) -> NodeConfig {
+    let socket_addrs = |name: &str| matches
+        .values_of(name)
+        .into_iter()
+        .flatten()
+        .map(|addr| parse_socket_addr(addr).unwrap_or_else(|e| {
+            let description = format!("Invalid value for '--{}': {}", name, e);
+            exit_args_error(clap::Error::with_description(&description, clap::ErrorKind::InvalidValue))
+        }))
+        .collect::<Vec<_>>();
+
+    let mut udp_addrs = socket_addrs("udp-address");
+
+    let mut tcp_addrs = socket_addrs("tcp-address")
+        .into_iter()
+        .map(TcpAddress::from)
+        .collect::<Vec<_>>();
+
+    let ip_mode = value_t!(matches.value_of("ip-mode"), IpMode).unwrap_or_else(|e| exit_args_error(e));
+
+    // `--port` fills only addresses that are not specified explicitly, IPv6
+    // sockets get IPv4 packets via IPv4-mapped addresses in dual mode
+    if matches.is_present("port") {
+        let port = value_t!(matches.value_of("port"), u16).unwrap_or_else(|e| exit_args_error(e));
+        let ip = match ip_mode {
+            IpMode::Dual | IpMode::Ipv6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            IpMode::Ipv4 => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        };
+        if udp_addrs.is_empty() {
+            udp_addrs.push(SocketAddr::new(ip, port));
+        }
+        if tcp_addrs.is_empty() {
+            tcp_addrs.push(SocketAddr::new(ip, port).into());
+        }
+    }
+
+    let tcp_connections_limit = if matches.is_present("tcp-connections-limit") {
+        value_t!(matches.value_of("tcp-connections-limit"), usize).unwrap_or_else(|e| exit_args_error(e))
+    } else {
+        512
+    };
+
+    let udp_recv_buffer = if matches.is_present("udp-recv-buffer") {
+        Some(value_t!(matches.value_of("udp-recv-buffer"), usize).unwrap_or_else(|e| exit_args_error(e)))
+    } else {
+        None
+    };
+
+    let udp_send_buffer = if matches.is_present("udp-send-buffer") {
+        Some(value_t!(matches.value_of("udp-send-buffer"), usize).unwrap_or_else(|e| exit_args_error(e)))
+    } else {
+        None
+    };
+
+    let udp_restart_retries = value_t!(matches.value_of("udp-restart-retries"), u32).unwrap_or_else(|e| exit_args_error(e));
+
+    let udp_restart_delay = value_t!(matches.value_of("udp-restart-delay"), u64).unwrap_or_else(|e| exit_args_error(e));
+
+    let tcp_unix_path = value_t!(matches.value_of("tcp-unix-path"), String).ok();
+    check_tcp_unix_path(&tcp_unix_path)
+        .unwrap_or_else(|e| exit_args_error(clap::Error::with_description(&e.to_string(), clap::ErrorKind::InvalidValue)));
+
+    let tcp_max_connections = if matches.is_present("tcp-max-connections") {
+        Some(value_t!(matches.value_of("tcp-max-connections"), usize).unwrap_or_else(|e| exit_args_error(e)))
+    } else {
+        None
+    };
+
+    let tcp_backlog = if matches.is_present("tcp-backlog") {
+        Some(value_t!(matches.value_of("tcp-backlog"), u32).unwrap_or_else(|e| exit_args_error(e)))
+    } else {
+        None
+    };
+
+    let bind_retry = value_t!(matches.value_of("bind-retry"), u32).unwrap_or_else(|e| exit_args_error(e));
+
+    let drain_timeout = value_t!(matches.value_of("drain-timeout"), u64).unwrap_or_else(|e| exit_args_error(e));
+
+    let run_for = if matches.is_present("run-for") {
+        Some(value_t!(matches.value_of("run-for"), u64).unwrap_or_else(|e| exit_args_error(e)))
     } else {
         None
     };
+    let tcp_idle_timeout = value_t!(matches.value_of("tcp-idle-timeout"), u64).unwrap_or_else(|e| exit_args_error(e));
 
-    let tcp_addrs = if matches.is_present("tcp-address") {
-        values_t!(matches.values_of("tcp-address"), SocketAddr).unwrap_or_else(|e| e.exit())
+    let no_v4_mapped = matches.is_present("no-v4-mapped");
+
+    let rate_limit = value_t!(matches.value_of("rate-limit"), u32).unwrap_or_else(|e| exit_args_error(e));
+
+    let send_rate = value_t!(matches.value_of("send-rate"), u32).unwrap_or_else(|e| exit_args_error(e));
+
+    let max_packet_size = value_t!(matches.value_of("max-packet-size"), usize).unwrap_or_else(|e| exit_args_error(e));
+
+    let ban_list = if matches.is_present("ban-list") {
+        values_t!(matches.values_of("ban-list"), Cidr).unwrap_or_else(|e| exit_args_error(e))
     } else {
         Vec::new()
     };
 
-    let tcp_connections_limit = if matches.is_present("tcp-connections-limit") {
-        value_t!(matches.value_of("tcp-connections-limit"), usize).unwrap_or_else(|e| e.exit())
+    let tcp_allow_list = if matches.is_present("tcp-allow-list") {
+        values_t!(matches.values_of("tcp-allow-list"), Cidr).unwrap_or_else(|e| exit_args_error(e))
     } else {
-        512
+        Vec::new()
     };
 
-    let sk = matches.value_of("secret-key").map(|s| {
-        let sk_bytes: [u8; 32] = FromHex::from_hex(s).expect("Invalid DHT secret key");
-        SecretKey::from_slice(&sk_bytes).expect("Invalid DHT secret key")
-    });
+    let proxy_protocol = matches.is_present("proxy-protocol");
+
+    let connection_hook = matches.value_of("connection-hook").map(|s| s.to_owned());
+    let connection_hook_timeout = value_t!(matches.value_of("connection-hook-timeout"), u64).unwrap_or_else(|e| exit_args_error(e));
+    let connection_hook_cache = value_t!(matches.value_of("connection-hook-cache"), u64).unwrap_or_else(|e| exit_args_error(e));
+    let connection_hook_failure = value_t!(matches.value_of("connection-hook-failure"), HookPolicy).unwrap_or_else(|e| exit_args_error(e));
+
+    let access_log = matches.value_of("access-log").map(|s| s.to_owned());
+
+    let sk = matches.value_of("secret-key").map(|s| parse_secret_key(s).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(e.exit_code())
+    }));
 
     let sk_passed_as_arg = matches.occurrences_of("secret-key") > 0;
 
-    let keys_file = matches.value_of("keys-file").map(|s| s.to_owned());
+    let sk_file = matches.value_of("secret-key-file").map(|s| s.to_owned());
+
+    let keys_files = matches
+        .values_of("keys-file")
+        .into_iter()
+        .flatten()
+        .map(|s| s.to_owned())
+        .collect();
+
+    let keys_format = value_t!(matches.value_of("keys-format"), KeysFormat).unwrap_or_else(|e| exit_args_error(e));
 
-    let bootstrap_nodes = matches
+    let expected_pk = matches.value_of("expect-pk").map(|pk| {
+        pk_from_hex(pk).unwrap_or_else(|e| {
+            let description = format!("Invalid value for '--expect-pk <expect-pk>': {}", e);
+            exit_args_error(clap::Error::with_description(&description, clap::ErrorKind::InvalidValue))
+        })
+    });
+
+    let mut bootstrap_nodes: Vec<_> = matches
         .values_of("bootstrap-node")
         .into_iter()
         .flatten()
         .tuples()
         .map(|(pk, addr)| {
-            // get PK bytes of the bootstrap node
-            let bootstrap_pk_bytes: [u8; 32] = FromHex::from_hex(pk).expect("Invalid node key");
-            // create PK from bytes
-            let bootstrap_pk = PublicKey::from_slice(&bootstrap_pk_bytes).expect("Invalid node key");
+            if let Err(e) = validate_bootstrap_addr(addr) {
+                let description = format!("Invalid value for '--bootstrap-node <public key> <address>': {}", e);
+                exit_args_error(clap::Error::with_description(&description, clap::ErrorKind::InvalidValue))
+            }
+
+            let bootstrap_pk = pk_from_hex(pk).unwrap_or_else(|e| {
+                let description = format!("Invalid value for '--bootstrap-node <public key> <address>': {}", e);
+                exit_args_error(clap::Error::with_description(&description, clap::ErrorKind::InvalidValue))
+            });
 
             BootstrapNode {
                 pk: bootstrap_pk,
@@ -378,277 +2460,2836 @@ fn run_args(matches: &ArgMatches) -> NodeConfig {
         })
         .collect();
 
-    let threads = value_t!(matches.value_of("threads"), Threads).unwrap_or_else(|e| e.exit());
+    bootstrap_nodes.extend(
+        matches
+            .values_of("bootstrap-nodes")
+            .into_iter()
+            .flatten()
+            .map(|node| parse_bootstrap_node_compact(node).unwrap_or_else(|e| {
+                let description = format!("Invalid value for '--bootstrap-nodes <nodes>': {}", e);
+                exit_args_error(clap::Error::with_description(&description, clap::ErrorKind::InvalidValue))
+            }))
+    );
 
-    let log_type = value_t!(matches.value_of("log-type"), LogType).unwrap_or_else(|e| e.exit());
+    let bootstrap_file = matches.value_of("bootstrap-file").map(|s| s.to_owned());
 
-    let motd = value_t!(matches.value_of("motd"), String).unwrap_or_else(|e| e.exit());
+    let bootstrap_reload_signal = if matches.is_present("bootstrap-reload-signal") {
+        Some(value_t!(matches.value_of("bootstrap-reload-signal"), i32).unwrap_or_else(|e| exit_args_error(e)))
+    } else {
+        None
+    };
 
-    let lan_discovery_enabled = matches.is_present("lan-discovery");
+    let bootstrap_url = matches.value_of("bootstrap-url").map(|s| s.to_owned());
 
-    NodeConfig {
-        udp_addr,
-        tcp_addrs,
-        tcp_connections_limit,
-        sk,
-        sk_passed_as_arg,
-        keys_file,
-        bootstrap_nodes,
-        threads,
+    let nodes_cache = matches.value_of("nodes-cache").map(|s| s.to_owned());
+
+    let bootstrap_refresh = value_t!(matches.value_of("bootstrap-refresh"), u64).unwrap_or_else(|e| exit_args_error(e));
+
+    let dns_timeout = value_t!(matches.value_of("dns-timeout"), u64).unwrap_or_else(|e| exit_args_error(e));
+
+    let dns_retries = value_t!(matches.value_of("dns-retries"), u32).unwrap_or_else(|e| exit_args_error(e));
+
+    let prefer_ipv6 = matches.is_present("prefer-ipv6");
+
+    let dht_ping_interval = value_t!(matches.value_of("dht-ping-interval"), u64).unwrap_or_else(|e| exit_args_error(e));
+
+    let dht_nodes_threshold = if matches.is_present("dht-nodes-threshold") {
+        Some(value_t!(matches.value_of("dht-nodes-threshold"), usize).unwrap_or_else(|e| exit_args_error(e)))
+    } else {
+        None
+    };
+
+    let threads = match env("TOX_THREADS") {
+        Ok(threads) if matches.occurrences_of("threads") == 0 => threads.parse().unwrap_or_else(|e| {
+            let description = format!("Invalid value of TOX_THREADS environment variable: {}", e);
+            exit_args_error(clap::Error::with_description(&description, clap::ErrorKind::InvalidValue))
+        }),
+        _ => value_t!(matches.value_of("threads"), Threads).unwrap_or_else(|e| exit_args_error(e)),
+    };
+
+    let log_type = value_t!(matches.value_of("log-type"), LogType).unwrap_or_else(|e| exit_args_error(e));
+    let log_file = matches.value_of("log-file").map(|s| s.to_owned());
+    let log_max_size = value_t!(matches.value_of("log-max-size"), u64).unwrap_or_else(|e| exit_args_error(e));
+    let log_keep = value_t!(matches.value_of("log-keep"), usize).unwrap_or_else(|e| exit_args_error(e));
+
+    let log_format = value_t!(matches.value_of("log-format"), LogFormat).unwrap_or_else(|e| exit_args_error(e));
+
+    let log_level = if matches.is_present("log-level") {
+        Some(value_t!(matches.value_of("log-level"), LevelFilter).unwrap_or_else(|e| exit_args_error(e)))
+    } else {
+        None
+    };
+
+    let quiet = matches.is_present("quiet");
+
+    let log_throttle_secs = value_t!(matches.value_of("log-throttle-secs"), u64).unwrap_or_else(|e| exit_args_error(e));
+
+    let motd_file = matches.value_of("motd-file").map(|s| s.to_owned());
+
+    let motd = if let Some(ref motd_file) = motd_file {
+        read_motd_file(motd_file).unwrap_or_else(|e|
+            exit_args_error(clap::Error::with_description(&e.to_string(), clap::ErrorKind::InvalidValue))
+        )
+    } else {
+        value_t!(matches.value_of("motd"), String).unwrap_or_else(|e| exit_args_error(e))
+    };
+
+    let motd_ipv6 = matches.value_of("motd-ipv6").map(|s| s.to_owned());
+
+    let motd_cache_ttl = value_t!(matches.value_of("motd-cache-ttl"), u64).unwrap_or_else(|e| exit_args_error(e));
+
+    let lan_discovery_enabled = matches.is_present("lan-discovery");
+    let lan_discovery_log_interval = value_t!(matches.value_of("lan-discovery-log-interval"), u64).unwrap_or_else(|e| exit_args_error(e));
+    let no_multicast_loop = matches.is_present("no-multicast-loop");
+
+    let onion_buffer = value_t!(matches.value_of("onion-buffer"), usize).unwrap_or_else(|e| exit_args_error(e));
+
+    let no_onion = matches.is_present("no-onion");
+
+    let onion_error_policy = value_t!(matches.value_of("onion-error-policy"), OnionErrorPolicy).unwrap_or_else(|e| exit_args_error(e));
+
+    let bootstrap_stats = matches.is_present("bootstrap-stats");
+
+    let advertise_version = if matches.is_present("advertise-version") {
+        Some(value_t!(matches.value_of("advertise-version"), u32).unwrap_or_else(|e| exit_args_error(e)))
+    } else {
+        None
+    };
+
+    let health_addr = if matches.is_present("health-address") {
+        Some(value_t!(matches.value_of("health-address"), SocketAddr).unwrap_or_else(|e| exit_args_error(e)))
+    } else {
+        None
+    };
+
+    let stats_interval = value_t!(matches.value_of("stats-interval"), u64).unwrap_or_else(|e| exit_args_error(e));
+
+    let allow_root = matches.is_present("allow-root");
+
+    let daemonize = matches.is_present("daemonize");
+    check_daemonize(daemonize)
+        .unwrap_or_else(|e| exit_args_error(clap::Error::with_description(&e.to_string(), clap::ErrorKind::InvalidValue)));
+
+    let pid_file = value_t!(matches.value_of("pid-file"), String).ok();
+
+    let ready_json = matches.is_present("ready-json");
+
+    let dry_run = matches.is_present("dry-run");
+
+    let print_config = matches.is_present("print-config");
+
+    let metrics_addr = if matches.is_present("metrics-address") {
+        Some(value_t!(matches.value_of("metrics-address"), SocketAddr).unwrap_or_else(|e| exit_args_error(e)))
+    } else {
+        None
+    };
+
+    NodeConfig {
+        udp_addrs,
+        tcp_addrs,
+        udp_recv_buffer,
+        udp_send_buffer,
+        udp_restart_retries,
+        udp_restart_delay,
+        tcp_port_range: None,
+        listen_interfaces: BTreeMap::new(),
+        tcp_unix_path,
+        tcp_connections_limit,
+        tcp_max_connections,
+        tcp_backlog,
+        bind_retry,
+        drain_timeout,
+        run_for,
+        tcp_idle_timeout,
+        ip_mode,
+        no_v4_mapped,
+        rate_limit,
+        send_rate,
+        max_packet_size,
+        ban_list,
+        tcp_allow_list,
+        proxy_protocol,
+        connection_hook,
+        connection_hook_timeout,
+        connection_hook_cache,
+        connection_hook_failure,
+        access_log,
+        sk,
+        sk_passed_as_arg,
+        sk_file,
+        keys_files,
+        expected_pk,
+        keys_format,
+        bootstrap_nodes,
+        bootstrap_file,
+        bootstrap_reload_signal,
+        bootstrap_url,
+        nodes_cache,
+        bootstrap_refresh,
+        dns_timeout,
+        dns_retries,
+        prefer_ipv6,
+        dht_ping_interval,
+        dht_nodes_threshold,
+        threads,
         log_type,
+        log_file,
+        log_max_size,
+        log_keep,
+        log_format,
+        log_level,
+        quiet,
+        log_throttle_secs,
         motd,
+        motd_file,
+        motd_ipv6,
+        motd_cache_ttl,
         lan_discovery_enabled,
+        lan_discovery_log_interval,
+        no_multicast_loop,
+        onion_buffer,
+        no_onion,
+        onion_error_policy,
+        bootstrap_stats,
+        advertise_version,
+        metrics_addr,
+        health_addr,
+        stats_interval,
+        allow_root,
+        daemonize,
+        pid_file,
+        ready_json,
+        dry_run,
+        print_config,
+        config_paths: Vec::new(),
+        config_format: ConfigFormat::Auto,
         unused: HashMap::new(),
     }
-}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use crate::error::EXIT_KEYS;
+
+    fn config_file(extension: &str, content: &str) -> NamedTempFile {
+        let mut file = tempfile::Builder::new()
+            .suffix(extension)
+            .tempfile()
+            .unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        file
+    }
+
+    fn parse_config_file(file: &NamedTempFile) -> NodeConfig {
+        try_parse_config(&[file.path().to_str().unwrap()]).unwrap()
+    }
+
+    #[test]
+    fn config_yaml_json_toml() {
+        let yaml = config_file(".yml", r#"
+log-type: None
+keys-file: ./keys
+udp-address: 0.0.0.0:33445
+tcp-addresses:
+  - 0.0.0.0:33445
+  - 0.0.0.0:3389
+motd: "abcdef"
+bootstrap-nodes:
+  - pk: 1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F
+    addr: 198.98.51.198:33445
+threads: auto
+lan-discovery: True
+"#);
+        let json = config_file(".json", r#"{
+    "log-type": "None",
+    "keys-file": "./keys",
+    "udp-address": "0.0.0.0:33445",
+    "tcp-addresses": ["0.0.0.0:33445", "0.0.0.0:3389"],
+    "motd": "abcdef",
+    "bootstrap-nodes": [
+        {
+            "pk": "1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F",
+            "addr": "198.98.51.198:33445"
+        }
+    ],
+    "threads": "auto",
+    "lan-discovery": true
+}"#);
+        let toml = config_file(".toml", r#"
+log-type = "None"
+keys-file = "./keys"
+udp-address = "0.0.0.0:33445"
+tcp-addresses = ["0.0.0.0:33445", "0.0.0.0:3389"]
+motd = "abcdef"
+threads = "auto"
+lan-discovery = true
+
+[[bootstrap-nodes]]
+pk = "1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F"
+addr = "198.98.51.198:33445"
+"#);
+        let config = parse_config_file(&yaml);
+        assert_eq!(config.log_type, LogType::None);
+        assert_eq!(config.threads, Threads::Auto);
+        assert_eq!(config.tcp_addrs.len(), 2);
+        assert_eq!(config.bootstrap_nodes.len(), 1);
+        assert!(config.lan_discovery_enabled);
+        assert!(config.unused.is_empty());
+        let config = NodeConfig { config_paths: Vec::new(), ..config };
+        assert_eq!(NodeConfig { config_paths: Vec::new(), ..parse_config_file(&json) }, config);
+        assert_eq!(NodeConfig { config_paths: Vec::new(), ..parse_config_file(&toml) }, config);
+    }
+
+    #[test]
+    fn config_unused_keys_suggestions() {
+        let yaml = config_file(".yml", r#"
+keys-file: ./keys
+udp-address: 0.0.0.0:33445
+udp_address: 0.0.0.0:33445
+tcp-address: 0.0.0.0:33445
+bootstrap-nodse: []
+completely-unknown: 1
+"#);
+        let config = parse_config_file(&yaml);
+        assert_eq!(config.unused.len(), 4);
+        assert_eq!(suggest_config_key("udp_address"), Some("udp-address"));
+        assert_eq!(suggest_config_key("tcp-address"), Some("tcp-addresses"));
+        assert_eq!(suggest_config_key("bootstrap-nodse"), Some("bootstrap-nodes"));
+        assert_eq!(suggest_config_key("completely-unknown"), None);
+    }
+
+    #[test]
+    fn edit_distance_of_strings() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("motd", ""), 4);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("tcp-address", "tcp-addresses"), 2);
+    }
+
+    #[test]
+    fn config_malformed() {
+        let yaml = config_file(".yml", "keys-file: ./keys\nthreads: [1, 2]\n");
+        assert!(try_parse_config(&[yaml.path().to_str().unwrap()]).is_err());
+    }
+
+    #[test]
+    fn config_error_names_field() {
+        let yaml = config_file(".yml", "keys-file: ./keys\nudp-address: 0.0.0.0:33445\nthreads: many\n");
+        let error = try_parse_config(&[yaml.path().to_str().unwrap()]).unwrap_err().to_string();
+        assert!(error.contains("field `threads`: number of threads must be 'auto' or a number"), "{}", error);
+
+        let yaml = config_file(".yml", "keys-file: ./keys\nudp-address: 0.0.0.0:33445\nlan-discovery: maybe\n");
+        let error = try_parse_config(&[yaml.path().to_str().unwrap()]).unwrap_err().to_string();
+        assert!(error.contains("field `lan-discovery`: invalid type: string \"maybe\", expected a boolean"), "{}", error);
+
+        let yaml = config_file(".yml", "keys-file: ./keys\nudp-address: 0.0.0.0:33445\nlog-level: loud\n");
+        let error = try_parse_config(&[yaml.path().to_str().unwrap()]).unwrap_err().to_string();
+        assert!(error.contains("field `log-level`: invalid log level 'loud'"), "{}", error);
+
+        let yaml = config_file(".yml", "keys-file: ./keys\nudp-address: 0.0.0.0:33445\nlog-level: loud\nthreads: many\n");
+        let error = try_parse_config(&[yaml.path().to_str().unwrap()]).unwrap_err().to_string();
+        assert!(error.contains("fields `log-level`, `threads`: "), "{}", error);
+    }
+
+    #[test]
+    fn threads_zero() {
+        assert_eq!("0".parse::<Threads>().unwrap_err(), "number of threads must be greater than 0");
+        assert!("-1".parse::<Threads>().is_err());
+        assert_eq!("auto".parse::<Threads>(), Ok(Threads::Auto));
+
+        let matches = app().get_matches_from_safe(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--threads",
+            "0",
+        ]);
+        assert!(matches.is_err());
+
+        let yaml = config_file(".yml", "keys-file: ./keys
+udp-address: 0.0.0.0:33445
+threads: 0
+");
+        let error = try_parse_config(&[yaml.path().to_str().unwrap()]).unwrap_err();
+        assert!(error.to_string().contains("number of threads must be greater than 0"), "{}", error);
+    }
+
+    #[test]
+    fn config_udp_or_tcp_required() {
+        let yaml = config_file(".yml", "keys-file: ./keys\n");
+        let error = try_parse_config(&[yaml.path().to_str().unwrap()]).unwrap_err();
+        assert!(error.to_string().contains("neither 'udp-address' nor 'tcp-addresses' is set"));
+    }
+
+    #[test]
+    fn config_multiple_files() {
+        let base = config_file(".toml", r#"
+keys-file = "./keys"
+udp-address = "0.0.0.0:33445"
+motd = "base"
+lan-discovery = true
+"#);
+        let host = config_file(".yml", r#"
+motd: host
+bootstrap-nodes:
+  - pk: 1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F
+    addr: 198.98.51.198:33445
+unknown-key: 1
+"#);
+        let paths = [base.path().to_str().unwrap(), host.path().to_str().unwrap()];
+        let config = try_parse_config(&paths).unwrap();
+        assert_eq!(config.motd, "host");
+        assert!(config.lan_discovery_enabled);
+        assert_eq!(config.udp_addrs, vec!["0.0.0.0:33445".parse().unwrap()]);
+        assert_eq!(config.bootstrap_nodes.len(), 1);
+        assert_eq!(config.bootstrap_nodes[0].addr, "198.98.51.198:33445");
+        assert_eq!(config.unused.keys().collect::<Vec<_>>(), vec!["unknown-key"]);
+        assert_eq!(config.config_paths, paths);
+    }
+
+    #[test]
+    fn config_multiple_files_replace_lists() {
+        let base = config_file(".yml", r#"
+keys-file: ./keys
+udp-address: 0.0.0.0:33445
+bootstrap-nodes:
+  - pk: 1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F
+    addr: 198.98.51.198:33445
+  - pk: 8E7D0B859922EF569298B4D261A8CCB5FEA14FB91ED412A7603A585A25698832
+    addr: 85.172.30.117:33445
+"#);
+        let host = config_file(".yml", r#"
+bootstrap-nodes:
+  - pk: 3F0A45A268367C1BEA652F258C85F4A66DA76BCAA667A49E770BCC4917AB6A25
+    addr: 84.22.115.205:33445
+"#);
+        let config = try_parse_config(&[base.path().to_str().unwrap(), host.path().to_str().unwrap()]).unwrap();
+        // nodes of the second file replace nodes of the first one
+        let addrs = config.bootstrap_nodes.iter().map(|node| node.addr.as_str()).collect::<Vec<_>>();
+        assert_eq!(addrs, vec!["84.22.115.205:33445"]);
+    }
+
+    #[test]
+    fn args_config_multiple_files() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "config",
+            "./base.toml",
+            "./host.toml",
+        ]);
+        let matches = matches.subcommand_matches("config").unwrap();
+        assert_eq!(matches.values_of("cfg-file").unwrap().collect::<Vec<_>>(), vec!["./base.toml", "./host.toml"]);
+    }
+
+    #[test]
+    fn config_missing_file() {
+        assert!(try_parse_config(&["/nonexistent/tox-node.yml"]).is_err());
+    }
+
+    #[test]
+    fn config_env_vars() {
+        let home = std::env::var("HOME").unwrap();
+        let toml = config_file(".toml", r#"
+keys-file = "${HOME}/tox.keys"
+udp-address = "0.0.0.0:33445"
+motd = "$100 per ${HOME}"
+threads = 2
+"#);
+        let config = parse_config_file(&toml);
+        assert_eq!(config.keys_files, vec![format!("{}/tox.keys", home)]);
+        assert_eq!(config.motd, format!("$100 per {}", home));
+        assert_eq!(config.threads, Threads::N(2));
+    }
+
+    #[test]
+    fn config_env_vars_nested() {
+        let yaml = config_file(".yml", r#"
+keys-file: ./keys
+udp-address: 0.0.0.0:33445
+tcp-addresses:
+  - 0.0.0.0:33445
+bootstrap-nodes:
+  - pk: 1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F
+    addr: "${TOX_NODE_TEST_BOOTSTRAP_HOST}:33445"
+motd: "${TOX_NODE_TEST_MOTD}"
+"#);
+        let env = |name: &str| match name {
+            "TOX_NODE_TEST_BOOTSTRAP_HOST" => Ok("198.98.51.198".to_owned()),
+            "TOX_NODE_TEST_MOTD" => Ok("tox-node".to_owned()),
+            _ => Err(std::env::VarError::NotPresent),
+        };
+        let config = parse_config_files(&[yaml.path().to_str().unwrap()], ConfigFormat::Auto, &env).unwrap();
+        assert_eq!(config.bootstrap_nodes[0].addr, "198.98.51.198:33445");
+        assert_eq!(config.motd, "tox-node");
+    }
+
+    #[test]
+    fn config_env_vars_undefined() {
+        let yaml = config_file(".yml", "keys-file: ${TOX_NODE_TEST_UNDEFINED}/keys\nudp-address: 0.0.0.0:33445\n");
+        let error = try_parse_config(&[yaml.path().to_str().unwrap()]).unwrap_err();
+        assert!(error.to_string().contains("'TOX_NODE_TEST_UNDEFINED'"));
+        assert!(error.to_string().contains("'keys-file'"));
+    }
+
+    #[test]
+    fn expand_env_vars_malformed() {
+        assert_eq!(expand_env_vars("no variables $ here", &process_env).unwrap(), "no variables $ here");
+        assert!(expand_env_vars("${HOME", &process_env).is_err());
+        assert!(expand_env_vars("${}", &process_env).is_err());
+    }
+
+    #[test]
+    fn config_json_numeric_threads() {
+        let json = config_file(".json", r#"{
+    "keys-file": "./keys",
+    "udp-address": "0.0.0.0:33445",
+    "threads": 4
+}"#);
+        let config = parse_config_file(&json);
+        assert_eq!(config.threads, Threads::N(4));
+    }
+
+    #[test]
+    fn parse_socket_addr_with_zone() {
+        assert_eq!(parse_socket_addr("[::1]:33445").unwrap(), "[::1]:33445".parse().unwrap());
+        assert_eq!(parse_socket_addr("1.2.3.4:33445").unwrap(), "1.2.3.4:33445".parse().unwrap());
+
+        let addr = parse_socket_addr("[fe80::1%3]:33445").unwrap();
+        assert_eq!(addr, SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 33445, 0, 3)));
+
+        assert!(parse_socket_addr("fe80::1%3:33445").is_err());
+        assert!(parse_socket_addr("[fe80::1%3]").is_err());
+        assert!(parse_socket_addr("[1.2.3.4%3]:33445").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn parse_socket_addr_with_interface_zone() {
+        let index = interface_index("lo").unwrap();
+        assert_ne!(index, 0);
+        let addr = parse_socket_addr("[fe80::1%lo]:33445").unwrap();
+        assert_eq!(addr, SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 33445, 0, index)));
+
+        let error = parse_socket_addr("[fe80::1%no-such-interface]:33445").unwrap_err();
+        assert!(error.contains("unknown network interface 'no-such-interface'"));
+    }
+
+    #[test]
+    fn args_udp_and_tcp_address_with_zone() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "[fe80::1%2]:33445",
+            "--tcp-address",
+            "[fe80::1%2]:3389",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.udp_addrs, vec![SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 33445, 0, 2))]);
+        assert_eq!(config.tcp_addrs, vec![TcpAddress::from(SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 3389, 0, 2)))]);
+    }
+
+    #[test]
+    fn config_udp_and_tcp_address_with_zone() {
+        let config = parse_config_file(&config_file(".yml", r#"
+keys-file: ./keys
+udp-address: "[fe80::1%2]:33445"
+tcp-addresses:
+  - "[fe80::1%2]:33445"
+  - address: "[fe80::1%2]:3389"
+"#));
+        assert_eq!(config.udp_addrs, vec![SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 33445, 0, 2))]);
+        assert_eq!(config.tcp_addrs[1].addr, SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 3389, 0, 2)));
+    }
+
+    #[test]
+    fn config_tcp_addresses_with_labels() {
+        let config = parse_config_file(&config_file(".yml", r#"
+keys-file: ./keys
+tcp-addresses:
+  - 0.0.0.0:33445
+  - address: 0.0.0.0:3389
+    label: public
+  - address: 0.0.0.0:443
+"#));
+        assert_eq!(config.tcp_addrs, vec![
+            TcpAddress { addr: "0.0.0.0:33445".parse().unwrap(), label: None },
+            TcpAddress { addr: "0.0.0.0:3389".parse().unwrap(), label: Some("public".to_owned()) },
+            TcpAddress { addr: "0.0.0.0:443".parse().unwrap(), label: None },
+        ]);
+        assert_eq!(config.tcp_addrs[0].to_string(), "0.0.0.0:33445");
+        assert_eq!(config.tcp_addrs[1].to_string(), "0.0.0.0:3389 (public)");
+        assert_eq!(config.tcp_addrs[0].name(), "0.0.0.0:33445");
+        assert_eq!(config.tcp_addrs[1].name(), "public");
+    }
+
+    #[test]
+    fn config_listen_interfaces() {
+        let yaml = config_file(".yml", r#"
+keys-file: ./keys
+udp-address: 127.0.0.1:33445
+listen-interfaces:
+  eth0:
+    udp: 33445
+    tcp: [33445, 3389]
+  eth1:
+    tcp: 443
+"#);
+        let config = parse_config_file(&yaml);
+        assert_eq!(config.listen_interfaces["eth0"], InterfacePorts { udp: Some(33445), tcp: vec![33445, 3389] });
+        assert_eq!(config.listen_interfaces["eth1"], InterfacePorts { udp: None, tcp: vec![443] });
+    }
+
+    #[test]
+    fn config_tcp_port_range() {
+        let yaml = config_file(".yml", r#"
+keys-file: ./keys
+tcp-addresses:
+  - 0.0.0.0:3389
+  - 0.0.0.0:33446
+tcp-port-range:
+  ip: 0.0.0.0
+  start: 33445
+  end: 33447
+"#);
+        let config = parse_config_file(&yaml);
+        let addrs = config.tcp_addrs.iter().map(|addr| addr.to_string()).collect::<Vec<_>>();
+        assert_eq!(addrs, vec!["0.0.0.0:3389", "0.0.0.0:33446", "0.0.0.0:33445", "0.0.0.0:33447"]);
+        // addresses of the range are serialized as plain TCP addresses
+        assert!(!format_config(&config).unwrap().contains("tcp-port-range"));
+    }
+
+    #[test]
+    fn tcp_port_range_invalid() {
+        let range = |start, end| TcpPortRange { ip: "::".parse().unwrap(), start, end };
+        assert_eq!(range(443, 443).addrs().unwrap(), vec!["[::]:443".parse().unwrap()]);
+        assert!(range(33450, 33445).addrs().is_err());
+        assert!(range(0, 10).addrs().is_err());
+        assert!(range(1000, 1000 + MAX_TCP_PORT_RANGE as u16).addrs().is_err());
+        assert_eq!(range(1000, 999 + MAX_TCP_PORT_RANGE as u16).addrs().unwrap().len(), MAX_TCP_PORT_RANGE);
+
+        let yaml = config_file(".yml", "keys-file: ./keys
+tcp-port-range: {ip: 0.0.0.0, start: 2, end: 1}
+");
+        let error = try_parse_config(&[yaml.path().to_str().unwrap()]).unwrap_err();
+        assert!(error.to_string().contains("'tcp-port-range'"), "{}", error);
+    }
+
+    #[test]
+    fn interfaces_addrs_of_interfaces() {
+        use get_if_addrs::{IfAddr, Ifv4Addr, Ifv6Addr};
+
+        let interfaces = vec![
+            Interface {
+                name: "eth0".to_owned(),
+                addr: IfAddr::V4(Ifv4Addr {
+                    ip: "192.168.1.2".parse().unwrap(),
+                    netmask: "255.255.255.0".parse().unwrap(),
+                    broadcast: None,
+                }),
+            },
+            Interface {
+                name: "eth0".to_owned(),
+                addr: IfAddr::V6(Ifv6Addr {
+                    ip: "fe80::1".parse().unwrap(),
+                    netmask: "ffff:ffff:ffff:ffff::".parse().unwrap(),
+                    broadcast: None,
+                }),
+            },
+            Interface {
+                name: "eth0".to_owned(),
+                addr: IfAddr::V6(Ifv6Addr {
+                    ip: "2001:db8::2".parse().unwrap(),
+                    netmask: "ffff:ffff:ffff:ffff::".parse().unwrap(),
+                    broadcast: None,
+                }),
+            },
+        ];
+        let mut listen_interfaces = BTreeMap::new();
+        listen_interfaces.insert("eth0".to_owned(), InterfacePorts { udp: Some(33445), tcp: vec![3389] });
+        listen_interfaces.insert("eth1".to_owned(), InterfacePorts { udp: Some(33445), tcp: vec![3389] });
+
+        let (udp_addrs, tcp_addrs) = interfaces_addrs(&listen_interfaces, &interfaces);
+        assert_eq!(udp_addrs, vec![
+            "192.168.1.2:33445".parse().unwrap(),
+            "[2001:db8::2]:33445".parse().unwrap(),
+        ]);
+        assert_eq!(tcp_addrs, vec![
+            "192.168.1.2:3389".parse().unwrap(),
+            "[2001:db8::2]:3389".parse().unwrap(),
+        ]);
+    }
+
+    #[test]
+    fn args_udp_only() {
+        let saddr = "127.0.0.1:33445";
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            saddr,
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.keys_files, vec!["./keys"]);
+        assert_eq!(config.udp_addrs, vec![saddr.parse().unwrap()]);
+        assert!(config.tcp_addrs.is_empty());
+        assert!(!config.lan_discovery_enabled);
+    }
+
+    #[test]
+    fn args_multiple_udp_addresses() {
+        let saddr_1 = "0.0.0.0:33445";
+        let saddr_2 = "[::]:33445";
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            saddr_1,
+            "--udp-address",
+            saddr_2,
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.udp_addrs, vec![
+            saddr_1.parse().unwrap(),
+            saddr_2.parse().unwrap()
+        ]);
+    }
+
+    #[test]
+    fn config_multiple_udp_addresses() {
+        let yaml = config_file(".yml", r#"
+keys-file: ./keys
+udp-address:
+  - 0.0.0.0:33445
+  - "[::]:33445"
+"#);
+        let config = parse_config_file(&yaml);
+        assert_eq!(config.udp_addrs, vec![
+            "0.0.0.0:33445".parse().unwrap(),
+            "[::]:33445".parse().unwrap()
+        ]);
+    }
+
+    #[test]
+    fn args_tcp_only() {
+        let saddr_1 = "127.0.0.1:33445";
+        let saddr_2 = "127.0.0.1:33446";
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--tcp-address",
+            saddr_1,
+            "--tcp-address",
+            saddr_2,
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.keys_files, vec!["./keys"]);
+        assert!(config.udp_addrs.is_empty());
+        assert_eq!(config.tcp_addrs, vec![
+            TcpAddress::from(saddr_1.parse::<SocketAddr>().unwrap()),
+            TcpAddress::from(saddr_2.parse::<SocketAddr>().unwrap()),
+        ]);
+        assert!(!config.lan_discovery_enabled);
+    }
+
+    #[test]
+    fn args_udp_tcp() {
+        let saddr_1 = "127.0.0.1:33445";
+        let saddr_2 = "127.0.0.1:33446";
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            saddr_1,
+            "--tcp-address",
+            saddr_2,
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.keys_files, vec!["./keys"]);
+        assert_eq!(config.udp_addrs, vec![saddr_1.parse().unwrap()]);
+        assert_eq!(config.tcp_addrs, vec![TcpAddress::from(saddr_2.parse::<SocketAddr>().unwrap())]);
+        assert!(!config.lan_discovery_enabled);
+    }
+
+    #[test]
+    fn args_udp_tcp_with_secret_key() {
+        let saddr_1 = "127.0.0.1:33445";
+        let saddr_2 = "127.0.0.1:33446";
+        let sk = "d5ff9ceafe9e1145bc807dc94b4ee911a5878705b5f9ee68f6ccc51e498f313c";
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--secret-key",
+            sk,
+            "--udp-address",
+            saddr_1,
+            "--tcp-address",
+            saddr_2,
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.sk.unwrap(), {
+            let sk_bytes = <[u8; 32]>::from_hex(sk).unwrap();
+            SecretKey::from_slice(&sk_bytes).unwrap()
+        });
+        assert!(config.sk_passed_as_arg);
+        assert_eq!(config.udp_addrs, vec![saddr_1.parse().unwrap()]);
+        assert_eq!(config.tcp_addrs, vec![TcpAddress::from(saddr_2.parse::<SocketAddr>().unwrap())]);
+        assert!(!config.lan_discovery_enabled);
+    }
+
+    #[test]
+    fn args_secret_key_file() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--secret-key-file",
+            "./secret_key",
+            "--udp-address",
+            "127.0.0.1:33445",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.sk_file.unwrap(), "./secret_key");
+        assert!(config.keys_files.is_empty());
+        assert!(config.sk.is_none());
+    }
+
+    #[test]
+    fn args_secret_key_file_and_keys_file_conflicts() {
+        let matches = app().get_matches_from_safe(vec![
+            "tox-node",
+            "--secret-key-file",
+            "./secret_key",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+        ]);
+        assert!(matches.is_err());
+    }
+
+    #[test]
+    fn args_udp_or_tcp_required() {
+        let matches = app().get_matches_from_safe(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+        ]);
+        assert!(matches.is_err());
+    }
+
+    #[test]
+    fn args_version_full() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--version-full",
+        ]);
+        assert!(matches.is_present("version-full"));
+    }
+
+    #[test]
+    fn args_self_test() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--self-test",
+        ]);
+        assert!(matches.is_present("self-test"));
+    }
+
+    #[test]
+    fn args_keys_file_or_secret_key_required() {
+        let matches = app().get_matches_from_safe(vec![
+            "tox-node",
+            "--udp-address",
+            "127.0.0.1:33445",
+        ]);
+        assert!(matches.is_err());
+    }
+
+    #[test]
+    fn args_keys_file_and_secret_key_conflicts() {
+        let matches = app().get_matches_from_safe(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--secret-key",
+            "d5ff9ceafe9e1145bc807dc94b4ee911a5878705b5f9ee68f6ccc51e498f313c",
+            "--udp-address",
+            "127.0.0.1:33445",
+        ]);
+        assert!(matches.is_err());
+    }
+
+    #[test]
+    fn args_motd() {
+        let motd = "abcdef";
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--motd",
+            motd,
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.motd, motd);
+    }
+
+    #[test]
+    fn args_motd_too_long_with_public_key() {
+        let matches = app().get_matches_from_safe(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--motd",
+            &"{{public_key}}".repeat(5),
+        ]);
+        assert!(matches.is_err());
+    }
+
+    #[test]
+    fn args_motd_file() {
+        let file = config_file(".txt", "Line 1\nLine 2 {{public_key}}\n");
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--motd-file",
+            file.path().to_str().unwrap(),
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.motd, "Line 1\nLine 2 {{public_key}}");
+    }
+
+    #[test]
+    fn args_motd_file_conflicts_with_motd() {
+        let matches = app().get_matches_from_safe(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--motd",
+            "abc",
+            "--motd-file",
+            "./motd.txt",
+        ]);
+        assert!(matches.is_err());
+    }
+
+    #[test]
+    fn config_motd_file() {
+        let motd = config_file(".txt", "abc\n");
+        let yaml = config_file(".yml", &format!(
+            "keys-file: ./keys\nudp-address: 0.0.0.0:33445\nmotd-file: {}\n",
+            motd.path().to_str().unwrap()
+        ));
+        assert_eq!(parse_config_file(&yaml).motd, "abc");
+    }
+
+    #[test]
+    fn config_missing_motd_file() {
+        let yaml = config_file(".yml", "keys-file: ./keys\nudp-address: 0.0.0.0:33445\nmotd-file: /nonexistent/motd.txt\n");
+        assert!(try_parse_config(&[yaml.path().to_str().unwrap()]).is_err());
+    }
+
+    #[test]
+    fn args_motd_ipv6() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "[::]:33445",
+            "--motd",
+            "IPv4",
+            "--motd-ipv6",
+            "IPv6 {{ version }}",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.motd, "IPv4");
+        assert_eq!(config.motd_ipv6.as_deref(), Some("IPv6 {{ version }}"));
+
+        let too_long = "a".repeat(BOOSTRAP_SERVER_MAX_MOTD_LENGTH + 1);
+        let matches = app().get_matches_from_safe(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "[::]:33445",
+            "--motd-ipv6",
+            &too_long,
+        ]);
+        assert!(matches.is_err());
+    }
+
+    #[test]
+    fn config_motd_ipv6() {
+        let yaml = config_file(".yml", "keys-file: ./keys\nudp-address: 0.0.0.0:33445\n");
+        assert_eq!(parse_config_file(&yaml).motd_ipv6, None);
+
+        let yaml = config_file(".yml", "keys-file: ./keys\nudp-address: 0.0.0.0:33445\nmotd-ipv6: IPv6\n");
+        assert_eq!(parse_config_file(&yaml).motd_ipv6.as_deref(), Some("IPv6"));
+
+        let yaml = config_file(".yml", &format!(
+            "keys-file: ./keys\nudp-address: 0.0.0.0:33445\nmotd-ipv6: {}\n",
+            "a".repeat(BOOSTRAP_SERVER_MAX_MOTD_LENGTH + 1)
+        ));
+        let error = try_parse_config(&[yaml.path().to_str().unwrap()]).unwrap_err();
+        assert!(error.to_string().contains("Invalid 'motd-ipv6'"));
+
+        // the IPv4 MOTD has the same limit
+        let yaml = config_file(".yml", &format!(
+            "keys-file: ./keys\nudp-address: 0.0.0.0:33445\nmotd: {}\nmotd-ipv6: IPv6\n",
+            "a".repeat(BOOSTRAP_SERVER_MAX_MOTD_LENGTH + 1)
+        ));
+        let error = try_parse_config(&[yaml.path().to_str().unwrap()]).unwrap_err();
+        assert!(error.to_string().contains("Invalid 'motd'"));
+    }
+
+    #[test]
+    fn config_bootstrapd() {
+        let conf = config_file(".conf", r#"
+// tox-bootstrapd config
+port = 33446
+keys_file_path = "/var/lib/tox-bootstrapd/keys"
+pid_file_path = "/var/run/tox-bootstrapd/tox-bootstrapd.pid"
+enable_ipv6 = false
+enable_ipv4_fallback = true
+enable_lan_discovery = false
+enable_tcp_relay = true
+tcp_relay_ports = [443, 3389]
+enable_motd = true
+motd = "tox-bootstrapd"
+bootstrap_nodes = (
+  {
+    address = "198.98.51.198"
+    port = 33445
+    public_key = "1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F"
+  }
+)
+"#);
+        let config = try_parse_config_as(&[conf.path().to_str().unwrap()], ConfigFormat::Bootstrapd).unwrap();
+        assert_eq!(config.config_format, ConfigFormat::Bootstrapd);
+        assert_eq!(config.udp_addrs, vec!["0.0.0.0:33446".parse().unwrap()]);
+        assert_eq!(config.tcp_addrs.iter().map(|tcp_addr| tcp_addr.addr).collect::<Vec<_>>(), vec![
+            "0.0.0.0:443".parse().unwrap(),
+            "0.0.0.0:3389".parse().unwrap(),
+        ]);
+        assert_eq!(config.keys_files, vec!["/var/lib/tox-bootstrapd/keys".to_owned()]);
+        assert_eq!(config.pid_file.as_deref(), Some("/var/run/tox-bootstrapd/tox-bootstrapd.pid"));
+        assert!(!config.lan_discovery_enabled);
+        assert_eq!(config.motd, "tox-bootstrapd");
+        assert_eq!(config.bootstrap_nodes.len(), 1);
+        assert_eq!(config.bootstrap_nodes[0].addr, "198.98.51.198:33445");
+        assert!(config.unused.is_empty());
+        // printed configs are YAML whatever the extension of the file
+        assert!(format_config(&config).unwrap().contains("\nkeys-file:"));
+
+        // not a valid YAML file
+        assert!(try_parse_config(&[conf.path().to_str().unwrap()]).is_err());
+    }
+
+    #[test]
+    fn config_bootstrapd_unsupported_settings() {
+        let conf = config_file(".conf", r#"
+keys_file_path = "/var/lib/tox-bootstrapd/keys"
+log_backend = "syslog"
+extra = { enabled = true }
+"#);
+        let config = try_parse_config_as(&[conf.path().to_str().unwrap()], ConfigFormat::Bootstrapd).unwrap();
+        assert_eq!(config.unused.keys().sorted().collect::<Vec<_>>(), vec!["extra", "log_backend"]);
+    }
+
+    #[test]
+    fn args_config_format() {
+        let matches = app().get_matches_from(vec!["tox-node", "config", "./tox-bootstrapd.conf"]);
+        let matches = matches.subcommand_matches("config").unwrap();
+        assert_eq!(value_t!(matches.value_of("format"), ConfigFormat).unwrap(), ConfigFormat::Auto);
+
+        let matches = app().get_matches_from(vec!["tox-node", "config", "--format", "bootstrapd", "./tox-bootstrapd.conf"]);
+        let matches = matches.subcommand_matches("config").unwrap();
+        assert_eq!(value_t!(matches.value_of("format"), ConfigFormat).unwrap(), ConfigFormat::Bootstrapd);
+
+        let matches = app().get_matches_from_safe(vec!["tox-node", "config", "--format", "libconfig", "./tox-bootstrapd.conf"]);
+        assert!(matches.is_err());
+    }
+
+    #[test]
+    fn args_motd_cache_ttl() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+        ]);
+        assert_eq!(run_args(&matches).motd_cache_ttl, 0);
+
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--motd-cache-ttl",
+            "5",
+        ]);
+        assert_eq!(run_args(&matches).motd_cache_ttl, 5);
+    }
+
+    #[test]
+    fn config_motd_cache_ttl() {
+        let yaml = config_file(".yml", "keys-file: ./keys\nudp-address: 0.0.0.0:33445\nmotd-cache-ttl: 5\n");
+        assert_eq!(parse_config_file(&yaml).motd_cache_ttl, 5);
+    }
+
+    #[test]
+    fn args_lan_discovery() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--lan-discovery",
+        ]);
+        let config = run_args(&matches);
+        assert!(config.lan_discovery_enabled);
+    }
+
+    #[test]
+    fn args_bootstrap_nodes() {
+        let pk_1 = "F404ABAA1C99A9D37D61AB54898F56793E1DEF8BD46B1038B9D822E8460FAB67";
+        let addr_1 = "node.tox.biribiri.org:33445";
+        let pk_2 = "8E7D0B859922EF569298B4D261A8CCB5FEA14FB91ED412A7603A585A25698832";
+        let addr_2 = "85.172.30.117:33445";
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--bootstrap-node",
+            pk_1,
+            addr_1,
+            "--bootstrap-node",
+            pk_2,
+            addr_2,
+        ]);
+        let config = run_args(&matches);
+        let node_1 = BootstrapNode {
+            pk: {
+                let pk_bytes = <[u8; 32]>::from_hex(pk_1).unwrap();
+                PublicKey::from_slice(&pk_bytes).unwrap()
+            },
+            addr: addr_1.into(),
+        };
+        let node_2 = BootstrapNode {
+            pk: {
+                let pk_bytes = <[u8; 32]>::from_hex(pk_2).unwrap();
+                PublicKey::from_slice(&pk_bytes).unwrap()
+            },
+            addr: addr_2.into(),
+        };
+        assert_eq!(config.bootstrap_nodes, vec![node_1, node_2]);
+    }
+
+    #[test]
+    fn bootstrap_addr_validation() {
+        assert!(validate_bootstrap_addr("example.com:33445").is_ok());
+        assert!(validate_bootstrap_addr("1.2.3.4:33445").is_ok());
+        assert!(validate_bootstrap_addr("[2001:db8::1]:33445").is_ok());
+        assert!(validate_bootstrap_addr("example.com").is_err());
+        assert!(validate_bootstrap_addr("example.com:port").is_err());
+        assert!(validate_bootstrap_addr("example.com:70000").is_err());
+        assert!(validate_bootstrap_addr(":33445").is_err());
+    }
+
+    #[test]
+    fn config_invalid_bootstrap_addr() {
+        let yaml = config_file(".yml", r#"
+keys-file: ./keys
+udp-address: 0.0.0.0:33445
+bootstrap-nodes:
+  - pk: 1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F
+    addr: example.com
+"#);
+        assert!(try_parse_config(&[yaml.path().to_str().unwrap()]).is_err());
+    }
+
+    #[test]
+    fn pk_from_hex_formats() {
+        let pk = pk_from_hex("F404ABAA1C99A9D37D61AB54898F56793E1DEF8BD46B1038B9D822E8460FAB67").unwrap();
+        assert_eq!(pk_from_hex("f404abaa1c99a9d37d61ab54898f56793e1def8bd46b1038b9d822e8460fab67").unwrap(), pk);
+        assert_eq!(pk_from_hex("0xF404ABAA1C99A9D37D61AB54898F56793E1DEF8BD46B1038B9D822E8460FAB67").unwrap(), pk);
+        assert_eq!(pk_from_hex("0Xf404abaa1c99a9d37d61ab54898f56793e1def8bd46b1038b9d822e8460fab67").unwrap(), pk);
+        assert_eq!(pk_from_hex(" \tF404ABAA1C99A9D37D61AB54898F56793E1DEF8BD46B1038B9D822E8460FAB67\n").unwrap(), pk);
+    }
+
+    #[test]
+    fn pk_from_hex_wrong_length() {
+        let error = pk_from_hex("F404ABAA1C99A9D37D61AB54898F56793E1DEF8BD46B1038B9D822E8460FAB").unwrap_err();
+        assert_eq!(error, "public key must be 32 bytes (64 hex characters) long, got 62 characters");
+    }
+
+    #[test]
+    fn parse_bootstrap_node_compact_form() {
+        let node = parse_bootstrap_node_compact("1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F@node.tox.biribiri.org:33445").unwrap();
+        assert_eq!(node.pk, pk_from_hex("1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F").unwrap());
+        assert_eq!(node.addr, "node.tox.biribiri.org:33445");
+
+        let error = parse_bootstrap_node_compact("1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F 1.2.3.4:33445").unwrap_err();
+        assert!(error.contains("must be in the form <public key>@<host>:<port>"));
+        assert!(parse_bootstrap_node_compact("1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F@1.2.3.4").is_err());
+        assert!(parse_bootstrap_node_compact("1D5A@1.2.3.4:33445").is_err());
+    }
+
+    #[test]
+    fn args_bootstrap_nodes_compact() {
+        let pk_1 = "1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F";
+        let pk_2 = "8E7D0B859922EF569298B4D261A8CCB5FEA14FB91ED412A7603A585A25698832";
+        let nodes = format!("{}@198.98.51.198:33445,{}@85.172.30.117:33445", pk_1, pk_2);
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--bootstrap-node",
+            pk_1,
+            "node.tox.biribiri.org:33445",
+            "--bootstrap-nodes",
+            &nodes,
+        ]);
+        let config = run_args(&matches);
+        let addrs = config.bootstrap_nodes.iter().map(|node| node.addr.as_str()).collect::<Vec<_>>();
+        assert_eq!(addrs, vec!["node.tox.biribiri.org:33445", "198.98.51.198:33445", "85.172.30.117:33445"]);
+        assert_eq!(config.bootstrap_nodes[2].pk, pk_from_hex(pk_2).unwrap());
+    }
+
+    #[test]
+    fn args_bootstrap() {
+        let pk = "1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F";
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--bootstrap",
+            &format!("{}@[2001:db8::1]:33445", pk),
+            "--bootstrap",
+            &format!(" {}@node.tox.biribiri.org:33445 ", pk.to_lowercase()),
+        ]);
+        let config = run_args(&matches);
+        let addrs = config.bootstrap_nodes.iter().map(|node| node.addr.as_str()).collect::<Vec<_>>();
+        assert_eq!(addrs, vec!["[2001:db8::1]:33445", "node.tox.biribiri.org:33445"]);
+        assert!(config.bootstrap_nodes.iter().all(|node| node.pk == pk_from_hex(pk).unwrap()));
+    }
+
+    #[test]
+    fn args_bootstrap_malformed() {
+        let pk = "1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F";
+        for node in &[
+            pk.to_owned(),
+            format!("{}@", pk),
+            format!("{}@1.2.3.4", pk),
+            format!("{}@1.2.3.4:port", pk),
+            "@1.2.3.4:33445".to_owned(),
+            format!("{}@1.2.3.4:33445", &pk[.. 60]),
+            format!("1.2.3.4:33445|{}", pk),
+        ] {
+            let matches = app().get_matches_from_safe(vec![
+                "tox-node",
+                "--keys-file",
+                "./keys",
+                "--udp-address",
+                "127.0.0.1:33445",
+                "--bootstrap",
+                node,
+            ]);
+            assert!(matches.is_err(), "{} is accepted", node);
+        }
+    }
+
+    #[test]
+    fn config_bootstrap_nodes_compact() {
+        let config = parse_config_file(&config_file(".yml", r#"
+keys-file: ./keys
+udp-address: 0.0.0.0:33445
+bootstrap-nodes:
+  - 1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F@198.98.51.198:33445
+  - pk: 8E7D0B859922EF569298B4D261A8CCB5FEA14FB91ED412A7603A585A25698832
+    addr: 85.172.30.117:33445
+"#));
+        let addrs = config.bootstrap_nodes.iter().map(|node| node.addr.as_str()).collect::<Vec<_>>();
+        assert_eq!(addrs, vec!["198.98.51.198:33445", "85.172.30.117:33445"]);
+        assert_eq!(config.bootstrap_nodes[0].pk, pk_from_hex("1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F").unwrap());
+
+        let toml = config_file(".toml", r#"
+keys-file = "./keys"
+udp-address = "0.0.0.0:33445"
+bootstrap-nodes = ["1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F@198.98.51.198:33445"]
+"#);
+        assert_eq!(parse_config_file(&toml).bootstrap_nodes.len(), 1);
+    }
+
+    #[test]
+    fn config_bootstrap_nodes_compact_malformed() {
+        let yaml = config_file(".yml", r#"
+keys-file: ./keys
+udp-address: 0.0.0.0:33445
+bootstrap-nodes:
+  - 1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F 198.98.51.198:33445
+"#);
+        let error = try_parse_config(&[yaml.path().to_str().unwrap()]).unwrap_err();
+        assert!(error.to_string().contains("<public key>@<host>:<port>"), "{}", error);
+    }
+
+    #[test]
+    fn args_bootstrap_nodes_missing_at() {
+        let matches = app().get_matches_from_safe(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--bootstrap-nodes",
+            "1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F:198.98.51.198:33445",
+        ]);
+        assert!(matches.is_err());
+    }
+
+    #[test]
+    fn args_bootstrap_node_with_prefix() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--bootstrap-node",
+            "0x1d5a5f2f5d6233058bf0259b09622fb40b482e4fa0931eb8fd3ab8e7bf7daf6f",
+            "198.98.51.198:33445",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.bootstrap_nodes[0].pk, pk_from_hex("1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F").unwrap());
+    }
+
+    #[test]
+    fn config_bootstrap_node_with_prefix() {
+        let yaml = config_file(".yml", r#"
+keys-file: ./keys
+udp-address: 0.0.0.0:33445
+bootstrap-nodes:
+  - pk: "0x1d5a5f2f5d6233058bf0259b09622fb40b482e4fa0931eb8fd3ab8e7bf7daf6f "
+    addr: 198.98.51.198:33445
+"#);
+        let config = parse_config_file(&yaml);
+        assert_eq!(config.bootstrap_nodes[0].pk, pk_from_hex("1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F").unwrap());
+    }
+
+    #[test]
+    fn parse_bootstrap_nodes_file() {
+        let text = "
+# Public nodes
+F404ABAA1C99A9D37D61AB54898F56793E1DEF8BD46B1038B9D822E8460FAB67 node.tox.biribiri.org:33445
+
+8E7D0B859922EF569298B4D261A8CCB5FEA14FB91ED412A7603A585A25698832 85.172.30.117:33445 # comment
+malformed 1.2.3.4:33445
+8E7D0B859922EF569298B4D261A8CCB5FEA14FB91ED412A7603A585A25698832 85.172.30.117
+";
+        let nodes = parse_bootstrap_nodes(text);
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].addr, "node.tox.biribiri.org:33445");
+        assert_eq!(nodes[0].pk, pk_from_hex("F404ABAA1C99A9D37D61AB54898F56793E1DEF8BD46B1038B9D822E8460FAB67").unwrap());
+        assert_eq!(nodes[1].addr, "85.172.30.117:33445");
+    }
+
+    #[test]
+    fn dedup_bootstrap_nodes_from_overlapping_sources() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--bootstrap-node",
+            "8E7D0B859922EF569298B4D261A8CCB5FEA14FB91ED412A7603A585A25698832",
+            "85.172.30.117:33445",
+            "--bootstrap-nodes",
+            "8E7D0B859922EF569298B4D261A8CCB5FEA14FB91ED412A7603A585A25698832@85.172.30.117:33445,\
+             F404ABAA1C99A9D37D61AB54898F56793E1DEF8BD46B1038B9D822E8460FAB67@1.2.3.4:33445",
+        ]);
+        let mut nodes = run_args(&matches).bootstrap_nodes;
+        // the same key on the other address is not a duplicate
+        nodes.extend(parse_bootstrap_nodes("
+8E7D0B859922EF569298B4D261A8CCB5FEA14FB91ED412A7603A585A25698832 85.172.30.117:33445
+8E7D0B859922EF569298B4D261A8CCB5FEA14FB91ED412A7603A585A25698832 85.172.30.117:3389
+"));
+
+        let (unique, duplicates) = dedup_bootstrap_nodes(nodes.iter().flat_map(BootstrapNode::resolve));
+        assert_eq!(duplicates, 2);
+        let addrs = unique.iter().map(|node| node.saddr.to_string()).collect::<Vec<_>>();
+        assert_eq!(addrs, vec!["85.172.30.117:33445", "1.2.3.4:33445", "85.172.30.117:3389"]);
+    }
+
+    #[test]
+    fn select_new_bootstrap_nodes_skips_known() {
+        let mut known = parse_bootstrap_nodes("
+8E7D0B859922EF569298B4D261A8CCB5FEA14FB91ED412A7603A585A25698832 85.172.30.117:33445
+");
+        let nodes = parse_bootstrap_nodes("
+8E7D0B859922EF569298B4D261A8CCB5FEA14FB91ED412A7603A585A25698832 85.172.30.117:33445
+F404ABAA1C99A9D37D61AB54898F56793E1DEF8BD46B1038B9D822E8460FAB67 1.2.3.4:33445
+F404ABAA1C99A9D37D61AB54898F56793E1DEF8BD46B1038B9D822E8460FAB67 1.2.3.4:33445
+");
+        let new_nodes = select_new_bootstrap_nodes(&mut known, nodes.clone());
+        assert_eq!(new_nodes, vec![nodes[1].clone()]);
+        assert_eq!(known.len(), 2);
+        assert!(select_new_bootstrap_nodes(&mut known, nodes).is_empty());
+    }
+
+    #[test]
+    fn args_bootstrap_file() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--bootstrap-file",
+            "./nodes.txt",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.bootstrap_file.unwrap(), "./nodes.txt");
+        assert_eq!(config.bootstrap_reload_signal, None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn args_bootstrap_reload_signal() {
+        let args = |signal| vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--bootstrap-file",
+            "./nodes.txt",
+            "--bootstrap-reload-signal",
+            signal,
+        ];
+        let matches = app().get_matches_from(args("35"));
+        let config = run_args(&matches);
+        assert_eq!(config.bootstrap_reload_signal, Some(35));
+
+        // SIGHUP and SIGUSR2 are handled by the node already
+        assert!(app().get_matches_from_safe(args("1")).is_err());
+        let sigusr2 = libc::SIGUSR2.to_string();
+        assert!(app().get_matches_from_safe(args(&sigusr2)).is_err());
+        assert!(app().get_matches_from_safe(args("0")).is_err());
+        assert!(app().get_matches_from_safe(args("winch")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn config_bootstrap_reload_signal() {
+        let yaml = config_file(".yml", &format!(
+            "udp-address: 127.0.0.1:33445\nsecret-key-file: ./key\nbootstrap-file: ./nodes.txt\nbootstrap-reload-signal: {}\n",
+            libc::SIGUSR1,
+        ));
+        let error = try_parse_config(&[yaml.path().to_str().unwrap()]).unwrap_err();
+        assert!(error.to_string().contains("SIGUSR1"), "{}", error);
+    }
+
+    #[test]
+    fn parse_bootstrap_json_list() {
+        let text = r#"[
+            {"public_key": "F404ABAA1C99A9D37D61AB54898F56793E1DEF8BD46B1038B9D822E8460FAB67", "address": "node.tox.biribiri.org:33445"},
+            {"public_key": "malformed", "address": "1.2.3.4:33445"},
+            {"public_key": "8E7D0B859922EF569298B4D261A8CCB5FEA14FB91ED412A7603A585A25698832", "address": "85.172.30.117"}
+        ]"#;
+        let nodes = parse_bootstrap_json(text).unwrap();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].addr, "node.tox.biribiri.org:33445");
+        assert_eq!(nodes[0].pk, pk_from_hex("F404ABAA1C99A9D37D61AB54898F56793E1DEF8BD46B1038B9D822E8460FAB67").unwrap());
+
+        assert!(parse_bootstrap_json("{}").is_err());
+    }
+
+    #[test]
+    fn args_bootstrap_url() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--bootstrap-url",
+            "http://example.com/nodes.json",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.bootstrap_url.unwrap(), "http://example.com/nodes.json");
+    }
+
+    #[test]
+    fn args_nodes_cache() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--nodes-cache",
+            "/var/lib/tox-node/nodes",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.nodes_cache.unwrap(), "/var/lib/tox-node/nodes");
+    }
+
+    #[test]
+    fn args_bootstrap_refresh() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--bootstrap-refresh",
+            "3600",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.bootstrap_refresh, 3600);
+    }
+
+    #[test]
+    fn args_dns_timeout_and_retries() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--dns-timeout",
+            "3",
+            "--dns-retries",
+            "2",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.dns_timeout, 3);
+        assert_eq!(config.dns_retries, 2);
+    }
+
+    #[test]
+    fn resolve_with_timeout_of_ip() {
+        let node = BootstrapNode {
+            pk: pk_from_hex("1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F").unwrap(),
+            addr: "127.0.0.1:33445".to_owned(),
+        };
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let nodes = runtime.block_on(node.resolve_with_timeout(Duration::from_secs(1), 0, AddrOrder { prefer_ipv6: false, ip_mode: IpMode::Dual }));
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].saddr, "127.0.0.1:33445".parse().unwrap());
+    }
+
+    #[test]
+    fn resolve_with_timeout_of_unresolvable_name() {
+        let node = BootstrapNode {
+            pk: pk_from_hex("1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F").unwrap(),
+            addr: "tox-node.invalid:33445".to_owned(),
+        };
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let nodes = runtime.block_on(node.resolve_with_timeout(Duration::from_millis(100), 1, AddrOrder { prefer_ipv6: false, ip_mode: IpMode::Dual }));
+        assert!(nodes.is_empty());
+    }
+
+    #[test]
+    fn format_resolved_bootstrap_node() {
+        let node = BootstrapNode {
+            pk: pk_from_hex("1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F").unwrap(),
+            addr: "tox.example:33445".to_owned(),
+        };
+        assert_eq!(
+            format_resolved_node(&node, &["1.2.3.4:33445".parse().unwrap(), "[2001:db8::1]:33445".parse().unwrap()]),
+            "1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F tox.example:33445: 1.2.3.4:33445, [2001:db8::1]:33445"
+        );
+        assert_eq!(
+            format_resolved_node(&node, &[]),
+            "1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F tox.example:33445: unresolved"
+        );
+    }
+
+    #[test]
+    fn args_resolve_bootstrap() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "resolve-bootstrap",
+            "base.yml",
+            "host.yml",
+        ]);
+        let matches = matches.subcommand_matches("resolve-bootstrap").unwrap();
+        assert_eq!(matches.values_of("cfg-file").unwrap().collect::<Vec<_>>(), vec!["base.yml", "host.yml"]);
+    }
+
+    #[test]
+    fn resolve_with_addr_order() {
+        fn lookup(_addr: &str) -> std::io::Result<Vec<SocketAddr>> {
+            Ok(vec![
+                "1.2.3.4:33445".parse().unwrap(),
+                "[2001:db8::1]:33445".parse().unwrap(),
+                "5.6.7.8:33445".parse().unwrap(),
+                "[2001:db8::2]:33445".parse().unwrap(),
+            ])
+        }
+
+        let node = BootstrapNode {
+            pk: pk_from_hex("1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F").unwrap(),
+            addr: "tox.example:33445".to_owned(),
+        };
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut resolve = |prefer_ipv6, ip_mode| runtime
+            .block_on(node.resolve_with(lookup, Duration::from_secs(1), 0, AddrOrder { prefer_ipv6, ip_mode }))
+            .into_iter()
+            .map(|node| node.saddr.to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(resolve(false, IpMode::Dual), vec!["1.2.3.4:33445", "[2001:db8::1]:33445", "5.6.7.8:33445", "[2001:db8::2]:33445"]);
+        assert_eq!(resolve(true, IpMode::Dual), vec!["[2001:db8::1]:33445", "[2001:db8::2]:33445", "1.2.3.4:33445", "5.6.7.8:33445"]);
+        assert_eq!(resolve(true, IpMode::Ipv4), vec!["1.2.3.4:33445", "5.6.7.8:33445"]);
+        assert_eq!(resolve(false, IpMode::Ipv6), vec!["[2001:db8::1]:33445", "[2001:db8::2]:33445"]);
+    }
+
+    #[test]
+    fn args_prefer_ipv6() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "[::]:33445",
+            "--prefer-ipv6",
+        ]);
+        let config = run_args(&matches);
+        assert!(config.prefer_ipv6);
+        assert_eq!(AddrOrder::new(&config), AddrOrder { prefer_ipv6: true, ip_mode: IpMode::Dual });
+    }
+
+    #[test]
+    fn args_dht_ping_interval() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--dht-ping-interval",
+            "30",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.dht_ping_interval, 30);
+    }
+
+    #[test]
+    fn args_log_type() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--log-type",
+            "None"
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.log_type, LogType::None);
+    }
+
+    #[test]
+    fn args_log_file() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--log-type",
+            "File",
+            "--log-file",
+            "/var/log/tox-node.log",
+            "--log-max-size",
+            "100",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.log_type, LogType::File);
+        assert_eq!(config.log_file, Some("/var/log/tox-node.log".to_owned()));
+        assert_eq!(config.log_max_size, 100);
+        assert_eq!(config.log_keep, 5);
+
+        let matches = app().get_matches_from_safe(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--log-type",
+            "File",
+        ]);
+        assert!(matches.is_err());
+    }
+
+    #[test]
+    fn config_log_file() {
+        let yaml = config_file(".yml", "keys-file: ./keys\nudp-address: 0.0.0.0:33445\nlog-type: File\nlog-file: ./tox-node.log\nlog-keep: 2\n");
+        let config = parse_config_file(&yaml);
+        assert_eq!(config.log_file, Some("./tox-node.log".to_owned()));
+        assert_eq!(config.log_max_size, 10);
+        assert_eq!(config.log_keep, 2);
+
+        let yaml = config_file(".yml", "keys-file: ./keys\nudp-address: 0.0.0.0:33445\nlog-type: File\n");
+        let error = try_parse_config(&[yaml.path().to_str().unwrap()]).unwrap_err();
+        assert!(error.to_string().contains("'log-file' is not set"), "{}", error);
+    }
+
+    #[test]
+    fn args_log_format() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+        ]);
+        assert_eq!(run_args(&matches).log_format, LogFormat::Full);
+
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--log-type",
+            "Stdout",
+            "--log-format",
+            "compact",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.log_type, LogType::Stdout);
+        assert_eq!(config.log_format, LogFormat::Compact);
+    }
+
+    #[test]
+    fn config_log_format() {
+        let yaml = config_file(".yml", "keys-file: ./keys\nudp-address: 0.0.0.0:33445\nlog-format: json\n");
+        assert_eq!(parse_config_file(&yaml).log_format, LogFormat::Json);
+    }
+
+    #[test]
+    fn args_log_level() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--log-level",
+            "debug"
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.log_level, Some(LevelFilter::Debug));
+    }
+
+    #[test]
+    fn args_quiet() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--quiet",
+        ]);
+        let config = run_args(&matches);
+        assert!(config.quiet);
+        assert_eq!(config.log_level, None);
+    }
+
+    #[test]
+    fn args_quiet_conflicts_with_log_level() {
+        let matches = app().get_matches_from_safe(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--quiet",
+            "--log-level",
+            "debug",
+        ]);
+        assert!(matches.is_err());
+    }
+
+    #[test]
+    fn args_log_throttle_secs() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+        ]);
+        assert_eq!(run_args(&matches).log_throttle_secs, 10);
+
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--log-throttle-secs",
+            "0",
+        ]);
+        assert_eq!(run_args(&matches).log_throttle_secs, 0);
+    }
+
+    #[test]
+    fn config_log_level() {
+        let yaml = config_file(".yml", "keys-file: ./keys\nudp-address: 0.0.0.0:33445\nlog-level: warn\n");
+        assert_eq!(parse_config_file(&yaml).log_level, Some(LevelFilter::Warn));
+        let yaml = config_file(".yml", "keys-file: ./keys\nudp-address: 0.0.0.0:33445\n");
+        assert_eq!(parse_config_file(&yaml).log_level, None);
+    }
+
+    #[test]
+    fn config_quiet() {
+        let yaml = config_file(".yml", "keys-file: ./keys\nudp-address: 0.0.0.0:33445\nquiet: true\n");
+        assert!(parse_config_file(&yaml).quiet);
+        let yaml = config_file(".yml", "keys-file: ./keys\nudp-address: 0.0.0.0:33445\nquiet: true\nlog-level: debug\n");
+        let error = try_parse_config(&[yaml.path().to_str().unwrap()]).unwrap_err();
+        assert!(error.to_string().contains("'quiet'"));
+    }
+
+    #[test]
+    fn args_tcp_connections_limit() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--tcp-address",
+            "127.0.0.1:33445",
+            "--tcp-connections-limit",
+            "42"
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.tcp_connections_limit, 42);
+    }
+
+    #[test]
+    fn args_tcp_connections_limit_requires_tcp_addr() {
+        let matches = app().get_matches_from_safe(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--tcp-connections-limit",
+            "42"
+        ]);
+        assert!(matches.is_err());
+    }
+
+    #[test]
+    fn args_port() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--port",
+            "33445",
+            "--tcp-connections-limit",
+            "42",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.udp_addrs, vec!["[::]:33445".parse().unwrap()]);
+        assert_eq!(config.tcp_addrs, vec!["[::]:33445".parse::<SocketAddr>().unwrap().into()]);
+        assert_eq!(config.tcp_connections_limit, 42);
+    }
+
+    #[test]
+    fn args_port_ip_mode() {
+        let port_with_ip_mode = |ip_mode| {
+            let matches = app().get_matches_from(vec![
+                "tox-node",
+                "--keys-file",
+                "./keys",
+                "--port",
+                "33445",
+                "--ip-mode",
+                ip_mode,
+            ]);
+            run_args(&matches)
+        };
+
+        let config = port_with_ip_mode("ipv6");
+        assert_eq!(config.udp_addrs, vec!["[::]:33445".parse().unwrap()]);
+        assert_eq!(config.tcp_addrs, vec!["[::]:33445".parse::<SocketAddr>().unwrap().into()]);
+
+        let config = port_with_ip_mode("ipv4");
+        assert_eq!(config.udp_addrs, vec!["0.0.0.0:33445".parse().unwrap()]);
+        assert_eq!(config.tcp_addrs, vec!["0.0.0.0:33445".parse::<SocketAddr>().unwrap().into()]);
+    }
+
+    #[test]
+    fn args_port_with_explicit_address() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--port",
+            "33445",
+            "--udp-address",
+            "127.0.0.1:33446",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.udp_addrs, vec!["127.0.0.1:33446".parse().unwrap()]);
+        assert_eq!(config.tcp_addrs, vec!["[::]:33445".parse::<SocketAddr>().unwrap().into()]);
+    }
+
+    #[test]
+    fn args_tcp_max_connections() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--tcp-address",
+            "127.0.0.1:33445",
+            "--tcp-address",
+            "127.0.0.1:33446",
+            "--tcp-max-connections",
+            "1000"
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.tcp_max_connections, Some(1000));
+    }
+
+    #[test]
+    fn args_udp_buffers() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--udp-recv-buffer",
+            "4194304",
+            "--udp-send-buffer",
+            "1048576",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.udp_recv_buffer, Some(4_194_304));
+        assert_eq!(config.udp_send_buffer, Some(1_048_576));
+    }
+
+    #[test]
+    fn args_udp_restart() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.udp_restart_retries, 0);
+        assert_eq!(config.udp_restart_delay, 1);
+
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--udp-restart-retries",
+            "3",
+            "--udp-restart-delay",
+            "5",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.udp_restart_retries, 3);
+        assert_eq!(config.udp_restart_delay, 5);
+    }
+
+    #[test]
+    fn args_tcp_unix_path() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--tcp-address",
+            "127.0.0.1:33445",
+            "--tcp-unix-path",
+            "/run/tox-node/relay.sock",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.tcp_unix_path.unwrap(), "/run/tox-node/relay.sock");
+    }
+
+    #[test]
+    fn args_bind_retry() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--tcp-address",
+            "127.0.0.1:33445",
+            "--bind-retry",
+            "5",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.bind_retry, 5);
+    }
+
+    #[test]
+    fn args_dht_nodes_threshold() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--dht-nodes-threshold",
+            "500",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.dht_nodes_threshold, Some(500));
+    }
+
+    #[test]
+    fn args_tcp_backlog() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--tcp-address",
+            "127.0.0.1:33445",
+            "--tcp-backlog",
+            "4096",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.tcp_backlog, Some(4096));
+    }
+
+    #[test]
+    fn args_drain_timeout() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--tcp-address",
+            "127.0.0.1:33445",
+            "--drain-timeout",
+            "30",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.drain_timeout, 30);
+    }
+
+    #[test]
+    fn args_run_for() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+        ]);
+        assert_eq!(run_args(&matches).run_for, None);
+
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--run-for",
+            "60",
+        ]);
+        assert_eq!(run_args(&matches).run_for, Some(60));
+    }
+
+    #[test]
+    fn config_run_for() {
+        let yaml = config_file(".yml", "keys-file: ./keys\nudp-address: 0.0.0.0:33445\nrun-for: 60\n");
+        assert_eq!(parse_config_file(&yaml).run_for, Some(60));
+    }
+
+    #[test]
+    fn args_tcp_idle_timeout() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--tcp-address",
+            "127.0.0.1:33445",
+            "--tcp-idle-timeout",
+            "300",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.tcp_idle_timeout, 300);
+    }
+
+    #[test]
+    fn args_threads() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--threads",
+            "42"
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.threads, Threads::N(42));
+    }
+
+    #[test]
+    fn args_threads_from_env() {
+        let env = |name: &str| match name {
+            "TOX_THREADS" => Ok("auto".to_owned()),
+            _ => Err(std::env::VarError::NotPresent),
+        };
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+        ]);
+        assert_eq!(run_args_with_env(&matches, &env).threads, Threads::Auto);
+        assert_eq!(run_args_with_env(&matches, &|_| Err(std::env::VarError::NotPresent)).threads, Threads::N(1));
+
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--threads",
+            "2",
+        ]);
+        assert_eq!(run_args_with_env(&matches, &env).threads, Threads::N(2));
+    }
+
+    #[test]
+    fn args_ip_mode() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--ip-mode",
+            "ipv4",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.ip_mode, IpMode::Ipv4);
+    }
+
+    #[test]
+    fn args_no_v4_mapped() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "[::]:33445",
+            "--no-v4-mapped",
+        ]);
+        let config = run_args(&matches);
+        assert!(config.no_v4_mapped);
+        assert_eq!(config.ip_mode, IpMode::Dual);
+    }
+
+    #[test]
+    fn ip_mode_allows() {
+        let v4 = "127.0.0.1:33445".parse().unwrap();
+        let v6 = "[::1]:33445".parse().unwrap();
+        assert!(IpMode::Dual.allows(&v4) && IpMode::Dual.allows(&v6));
+        assert!(IpMode::Ipv4.allows(&v4) && !IpMode::Ipv4.allows(&v6));
+        assert!(!IpMode::Ipv6.allows(&v4) && IpMode::Ipv6.allows(&v6));
+    }
+
+    #[test]
+    fn args_rate_limit() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--rate-limit",
+            "100",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.rate_limit, 100);
+    }
+
+    #[test]
+    fn args_send_rate() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--send-rate",
+            "500",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.send_rate, 500);
+    }
+
+    #[test]
+    fn args_max_packet_size() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+        ]);
+        assert_eq!(run_args(&matches).max_packet_size, 1400);
+        let yaml = config_file(".yml", "keys-file: ./keys\nudp-address: 127.0.0.1:33445\n");
+        assert_eq!(parse_config_file(&yaml).max_packet_size, 1400);
+
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--max-packet-size",
+            "1024",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.max_packet_size, 1024);
+    }
+
+    #[test]
+    fn args_max_packet_size_too_big() {
+        let matches = app().get_matches_from_safe(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--max-packet-size",
+            "4096",
+        ]);
+        assert!(matches.is_err());
+    }
+
+    #[test]
+    fn args_advertise_version() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+        ]);
+        assert_eq!(run_args(&matches).advertise_version, None);
+
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--advertise-version",
+            "1000002018",
+        ]);
+        assert_eq!(run_args(&matches).advertise_version, Some(1_000_002_018));
+    }
+
+    #[test]
+    fn args_advertise_version_out_of_range() {
+        for version in &["999999999", "4000000000", "5000000000"] {
+            let matches = app().get_matches_from_safe(vec![
+                "tox-node",
+                "--keys-file",
+                "./keys",
+                "--udp-address",
+                "127.0.0.1:33445",
+                "--advertise-version",
+                version,
+            ]);
+            assert!(matches.is_err(), "{}", version);
+        }
+    }
+
+    #[test]
+    fn config_advertise_version_out_of_range() {
+        let yaml = config_file(".yml", "keys-file: ./keys\nudp-address: 0.0.0.0:33445\nadvertise-version: 42\n");
+        let error = try_parse_config(&[yaml.path().to_str().unwrap()]).unwrap_err();
+        assert!(error.to_string().contains("Invalid 'advertise-version'"), "{}", error);
+    }
+
+    #[test]
+    fn args_ban_list() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--ban-list",
+            "10.0.0.0/8,1.2.3.4",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.ban_list, vec!["10.0.0.0/8".parse().unwrap(), "1.2.3.4/32".parse().unwrap()]);
+    }
+
+    #[test]
+    fn args_tcp_allow_list() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--tcp-address",
+            "127.0.0.1:33445",
+            "--tcp-allow-list",
+            "192.168.0.0/16",
+            "--tcp-allow-list",
+            "::1",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.tcp_allow_list, vec!["192.168.0.0/16".parse().unwrap(), "::1/128".parse().unwrap()]);
+    }
+
+    #[test]
+    fn args_proxy_protocol() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--tcp-address",
+            "127.0.0.1:33445",
+            "--proxy-protocol",
+        ]);
+        let config = run_args(&matches);
+        assert!(config.proxy_protocol);
+    }
+
+    #[test]
+    fn args_connection_hook() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--tcp-address",
+            "127.0.0.1:33445",
+            "--connection-hook",
+            "/usr/local/bin/check-ip",
+            "--connection-hook-timeout",
+            "5",
+            "--connection-hook-failure",
+            "allow",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.connection_hook.as_deref(), Some("/usr/local/bin/check-ip"));
+        assert_eq!(config.connection_hook_timeout, 5);
+        assert_eq!(config.connection_hook_cache, 60);
+        assert_eq!(config.connection_hook_failure, HookPolicy::Allow);
+    }
+
+    #[test]
+    fn args_access_log() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--tcp-address",
+            "127.0.0.1:33445",
+            "--access-log",
+            "/var/log/tox-node/access.log",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.access_log.as_deref(), Some("/var/log/tox-node/access.log"));
+    }
+
+    #[test]
+    fn args_access_log_without_tcp() {
+        let matches = app().get_matches_from_safe(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--access-log",
+            "/var/log/tox-node/access.log",
+        ]);
+        assert!(matches.is_err());
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn config_ban_list() {
+        let config = parse_config_file(&config_file(".yml", r#"
+keys-file: ./keys
+udp-address: 0.0.0.0:33445
+ban-list:
+  - 10.0.0.0/8
+  - 2001:db8::/32
+"#));
+        assert_eq!(config.ban_list, vec!["10.0.0.0/8".parse().unwrap(), "2001:db8::/32".parse().unwrap()]);
+
+        let error = try_parse_config(&[config_file(".yml", r#"
+keys-file: ./keys
+udp-address: 0.0.0.0:33445
+ban-list:
+  - 10.0.0.0/42
+"#).path().to_str().unwrap()]).unwrap_err();
+        assert!(error.to_string().contains("not a valid prefix length"));
+    }
 
     #[test]
-    fn args_udp_only() {
-        let saddr = "127.0.0.1:33445";
+    fn args_onion_buffer() {
         let matches = app().get_matches_from(vec![
             "tox-node",
             "--keys-file",
             "./keys",
             "--udp-address",
-            saddr,
+            "127.0.0.1:33445",
+            "--onion-buffer",
+            "256",
         ]);
         let config = run_args(&matches);
-        assert_eq!(config.keys_file.unwrap(), "./keys");
-        assert_eq!(config.udp_addr.unwrap(), saddr.parse().unwrap());
-        assert!(config.tcp_addrs.is_empty());
-        assert!(!config.lan_discovery_enabled);
+        assert_eq!(config.onion_buffer, 256);
     }
 
     #[test]
-    fn args_tcp_only() {
-        let saddr_1 = "127.0.0.1:33445";
-        let saddr_2 = "127.0.0.1:33446";
+    fn args_no_onion() {
         let matches = app().get_matches_from(vec![
             "tox-node",
             "--keys-file",
             "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
             "--tcp-address",
-            saddr_1,
-            "--tcp-address",
-            saddr_2,
+            "127.0.0.1:33445",
+            "--no-onion",
         ]);
         let config = run_args(&matches);
-        assert_eq!(config.keys_file.unwrap(), "./keys");
-        assert!(config.udp_addr.is_none());
-        assert_eq!(config.tcp_addrs, vec![
-            saddr_1.parse().unwrap(),
-            saddr_2.parse().unwrap()
-        ]);
-        assert!(!config.lan_discovery_enabled);
+        assert!(config.no_onion);
     }
 
     #[test]
-    fn args_udp_tcp() {
-        let saddr_1 = "127.0.0.1:33445";
-        let saddr_2 = "127.0.0.1:33446";
+    fn args_onion_error_policy() {
         let matches = app().get_matches_from(vec![
             "tox-node",
             "--keys-file",
             "./keys",
             "--udp-address",
-            saddr_1,
-            "--tcp-address",
-            saddr_2,
+            "127.0.0.1:33445",
+        ]);
+        assert_eq!(run_args(&matches).onion_error_policy, OnionErrorPolicy::Log);
+
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--onion-error-policy",
+            "disconnect",
         ]);
         let config = run_args(&matches);
-        assert_eq!(config.keys_file.unwrap(), "./keys");
-        assert_eq!(config.udp_addr.unwrap(), saddr_1.parse().unwrap());
-        assert_eq!(config.tcp_addrs, vec![saddr_2.parse().unwrap()]);
-        assert!(!config.lan_discovery_enabled);
+        assert_eq!(config.onion_error_policy, OnionErrorPolicy::Disconnect);
     }
 
     #[test]
-    fn args_udp_tcp_with_secret_key() {
-        let saddr_1 = "127.0.0.1:33445";
-        let saddr_2 = "127.0.0.1:33446";
-        let sk = "d5ff9ceafe9e1145bc807dc94b4ee911a5878705b5f9ee68f6ccc51e498f313c";
+    fn config_onion_error_policy() {
+        let yaml = config_file(".yml", "keys-file: ./keys\nudp-address: 0.0.0.0:33445\nonion-error-policy: count\n");
+        assert_eq!(parse_config_file(&yaml).onion_error_policy, OnionErrorPolicy::Count);
+    }
+
+    #[test]
+    fn args_allow_root() {
         let matches = app().get_matches_from(vec![
             "tox-node",
-            "--secret-key",
-            sk,
+            "--keys-file",
+            "./keys",
             "--udp-address",
-            saddr_1,
-            "--tcp-address",
-            saddr_2,
+            "127.0.0.1:33445",
+            "--allow-root",
         ]);
         let config = run_args(&matches);
-        assert_eq!(config.sk.unwrap(), {
-            let sk_bytes = <[u8; 32]>::from_hex(sk).unwrap();
-            SecretKey::from_slice(&sk_bytes).unwrap()
-        });
-        assert!(config.sk_passed_as_arg);
-        assert_eq!(config.udp_addr.unwrap(), saddr_1.parse().unwrap());
-        assert_eq!(config.tcp_addrs, vec![saddr_2.parse().unwrap()]);
-        assert!(!config.lan_discovery_enabled);
+        assert!(config.allow_root);
     }
 
+    #[cfg(unix)]
     #[test]
-    fn args_udp_or_tcp_required() {
-        let matches = app().get_matches_from_safe(vec![
+    fn args_daemonize_and_pid_file() {
+        let matches = app().get_matches_from(vec![
             "tox-node",
             "--keys-file",
             "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--daemonize",
+            "--pid-file",
+            "/run/tox-node.pid",
         ]);
-        assert!(matches.is_err());
+        let config = run_args(&matches);
+        assert!(config.daemonize);
+        assert_eq!(config.pid_file.unwrap(), "/run/tox-node.pid");
     }
 
     #[test]
-    fn args_keys_file_or_secret_key_required() {
+    fn args_ready_json() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--ready-json",
+        ]);
+        let config = run_args(&matches);
+        assert!(config.ready_json);
+
         let matches = app().get_matches_from_safe(vec![
             "tox-node",
+            "--keys-file",
+            "./keys",
             "--udp-address",
             "127.0.0.1:33445",
+            "--ready-json",
+            "--daemonize",
         ]);
         assert!(matches.is_err());
     }
 
+    #[cfg(unix)]
     #[test]
-    fn args_keys_file_and_secret_key_conflicts() {
-        let matches = app().get_matches_from_safe(vec![
+    fn config_ready_json_with_daemonize() {
+        let yaml = config_file(".yml", "keys-file: ./keys\nudp-address: 127.0.0.1:33445\nready-json: true\n");
+        assert!(parse_config_file(&yaml).ready_json);
+
+        let yaml = config_file(".yml", "keys-file: ./keys\nudp-address: 127.0.0.1:33445\nready-json: true\ndaemonize: true\n");
+        let error = try_parse_config(&[yaml.path().to_str().unwrap()]).unwrap_err();
+        assert!(error.to_string().contains("'ready-json' can't be used with 'daemonize'"));
+    }
+
+    #[test]
+    fn args_bootstrap_stats() {
+        let matches = app().get_matches_from(vec![
             "tox-node",
             "--keys-file",
             "./keys",
-            "--secret-key",
-            "d5ff9ceafe9e1145bc807dc94b4ee911a5878705b5f9ee68f6ccc51e498f313c",
             "--udp-address",
             "127.0.0.1:33445",
+            "--bootstrap-stats",
         ]);
-        assert!(matches.is_err());
+        let config = run_args(&matches);
+        assert!(config.bootstrap_stats);
     }
 
     #[test]
-    fn args_motd() {
-        let motd = "abcdef";
+    fn args_multiple_keys_files() {
+        let pk = "F404ABAA1C99A9D37D61AB54898F56793E1DEF8BD46B1038B9D822E8460FAB67";
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys_1",
+            "--keys-file",
+            "./keys_2",
+            "--expect-pk",
+            pk,
+            "--udp-address",
+            "127.0.0.1:33445",
+        ]);
+        let config = run_args(&matches);
+        assert_eq!(config.keys_files, vec!["./keys_1", "./keys_2"]);
+        assert_eq!(config.expected_pk.unwrap(), {
+            let pk_bytes = <[u8; 32]>::from_hex(pk).unwrap();
+            PublicKey::from_slice(&pk_bytes).unwrap()
+        });
+    }
+
+    #[test]
+    fn config_multiple_keys_files() {
+        let yaml = config_file(".yml", r#"
+keys-file:
+  - ./keys_1
+  - ./keys_2
+expect-pk: F404ABAA1C99A9D37D61AB54898F56793E1DEF8BD46B1038B9D822E8460FAB67
+udp-address: 0.0.0.0:33445
+"#);
+        let config = parse_config_file(&yaml);
+        assert_eq!(config.keys_files, vec!["./keys_1", "./keys_2"]);
+        assert!(config.expected_pk.is_some());
+    }
+
+    #[test]
+    fn args_keys_format_libsodium() {
         let matches = app().get_matches_from(vec![
             "tox-node",
             "--keys-file",
             "./keys",
             "--udp-address",
             "127.0.0.1:33445",
-            "--motd",
-            motd,
+            "--keys-format",
+            "libsodium",
         ]);
         let config = run_args(&matches);
-        assert_eq!(config.motd, motd);
+        assert_eq!(config.keys_format, KeysFormat::Libsodium);
     }
 
     #[test]
-    fn args_lan_discovery() {
+    fn args_keys_format() {
         let matches = app().get_matches_from(vec![
             "tox-node",
             "--keys-file",
             "./keys",
+            "--keys-format",
+            "base64",
             "--udp-address",
             "127.0.0.1:33445",
-            "--lan-discovery",
         ]);
         let config = run_args(&matches);
-        assert!(config.lan_discovery_enabled);
+        assert_eq!(config.keys_format, KeysFormat::Base64);
     }
 
     #[test]
-    fn args_bootstrap_nodes() {
-        let pk_1 = "F404ABAA1C99A9D37D61AB54898F56793E1DEF8BD46B1038B9D822E8460FAB67";
-        let addr_1 = "node.tox.biribiri.org:33445";
-        let pk_2 = "8E7D0B859922EF569298B4D261A8CCB5FEA14FB91ED412A7603A585A25698832";
-        let addr_2 = "85.172.30.117:33445";
+    fn args_dry_run() {
         let matches = app().get_matches_from(vec![
             "tox-node",
             "--keys-file",
             "./keys",
             "--udp-address",
             "127.0.0.1:33445",
-            "--bootstrap-node",
-            pk_1,
-            addr_1,
-            "--bootstrap-node",
-            pk_2,
-            addr_2,
+            "--dry-run",
         ]);
         let config = run_args(&matches);
-        let node_1 = BootstrapNode {
-            pk: {
-                let pk_bytes = <[u8; 32]>::from_hex(pk_1).unwrap();
-                PublicKey::from_slice(&pk_bytes).unwrap()
-            },
-            addr: addr_1.into(),
-        };
-        let node_2 = BootstrapNode {
-            pk: {
-                let pk_bytes = <[u8; 32]>::from_hex(pk_2).unwrap();
-                PublicKey::from_slice(&pk_bytes).unwrap()
-            },
-            addr: addr_2.into(),
-        };
-        assert_eq!(config.bootstrap_nodes, vec![node_1, node_2]);
+        assert!(config.dry_run);
     }
 
     #[test]
-    fn args_log_type() {
+    fn args_print_config() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--secret-key",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "--udp-address",
+            "127.0.0.1:33445",
+            "--print-config",
+        ]);
+        let config = run_args(&matches);
+        assert!(config.print_config);
+        let text = format_config(&config).unwrap();
+        assert!(!text.contains("0000000000000000000000000000000000000000000000000000000000000000"));
+        let value: Value = serde_yaml::from_str(&text).unwrap();
+        assert_eq!(value["udp-address"][0], Value::from("127.0.0.1:33445"));
+    }
+
+    #[test]
+    fn config_print_in_file_format() {
+        let content = r#"
+keys-file: ./keys
+udp-address: 0.0.0.0:33445
+tcp-addresses:
+  - 0.0.0.0:33445
+  - address: 0.0.0.0:3389
+    label: public
+expect-pk: f404abaa1c99a9d37d61ab54898f56793e1def8bd46b1038b9d822e8460fab67
+log-level: debug
+threads: auto
+bootstrap-nodes:
+  - pk: 1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F
+    addr: 198.98.51.198:33445
+listen-interfaces:
+  eth0:
+    tcp: 3389
+"#;
+        let config = NodeConfig { config_paths: Vec::new(), ..parse_config_file(&config_file(".yml", content)) };
+        for extension in &[".yml", ".json", ".toml"] {
+            let config = NodeConfig { config_paths: vec![format!("config{}", extension)], ..config.clone() };
+            let printed = config_file(extension, &format_config(&config).unwrap());
+            assert_eq!(NodeConfig { config_paths: Vec::new(), ..parse_config_file(&printed) }, NodeConfig { config_paths: Vec::new(), ..config });
+        }
+    }
+
+    #[test]
+    fn args_config_dry_run() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "config",
+            "--dry-run",
+            "./config.yml",
+        ]);
+        let matches = matches.subcommand_matches("config").unwrap();
+        assert!(matches.is_present("dry-run"));
+    }
+
+    #[test]
+    fn args_metrics_address() {
+        let saddr = "127.0.0.1:9100";
         let matches = app().get_matches_from(vec![
             "tox-node",
             "--keys-file",
             "./keys",
             "--udp-address",
             "127.0.0.1:33445",
-            "--log-type",
-            "None"
+            "--metrics-address",
+            saddr,
         ]);
         let config = run_args(&matches);
-        assert_eq!(config.log_type, LogType::None);
+        assert_eq!(config.metrics_addr.unwrap(), saddr.parse().unwrap());
     }
 
     #[test]
-    fn args_tcp_connections_limit() {
+    fn args_health_address() {
+        let saddr = "127.0.0.1:8080";
         let matches = app().get_matches_from(vec![
             "tox-node",
             "--keys-file",
             "./keys",
-            "--tcp-address",
+            "--udp-address",
             "127.0.0.1:33445",
-            "--tcp-connections-limit",
-            "42"
+            "--health-address",
+            saddr,
         ]);
         let config = run_args(&matches);
-        assert_eq!(config.tcp_connections_limit, 42);
+        assert_eq!(config.health_addr.unwrap(), saddr.parse().unwrap());
     }
 
     #[test]
-    fn args_tcp_connections_limit_requires_tcp_addr() {
-        let matches = app().get_matches_from_safe(vec![
+    fn args_stats_interval() {
+        let matches = app().get_matches_from(vec![
             "tox-node",
             "--keys-file",
             "./keys",
             "--udp-address",
             "127.0.0.1:33445",
-            "--tcp-connections-limit",
-            "42"
+            "--stats-interval",
+            "60",
         ]);
-        assert!(matches.is_err());
+        let config = run_args(&matches);
+        assert_eq!(config.stats_interval, 60);
     }
 
     #[test]
-    fn args_threads() {
+    fn args_lan_discovery_log_interval() {
         let matches = app().get_matches_from(vec![
             "tox-node",
             "--keys-file",
             "./keys",
             "--udp-address",
             "127.0.0.1:33445",
-            "--threads",
-            "42"
+            "--lan-discovery",
+            "--lan-discovery-log-interval",
+            "30",
         ]);
         let config = run_args(&matches);
-        assert_eq!(config.threads, Threads::N(42));
+        assert!(config.lan_discovery_enabled);
+        assert_eq!(config.lan_discovery_log_interval, 30);
+    }
+
+    #[test]
+    fn args_no_multicast_loop() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "[::]:33445",
+            "--lan-discovery",
+            "--no-multicast-loop",
+        ]);
+        let config = run_args(&matches);
+        assert!(config.no_multicast_loop);
+
+        let matches = app().get_matches_from_safe(vec![
+            "tox-node",
+            "--keys-file",
+            "./keys",
+            "--udp-address",
+            "[::]:33445",
+            "--no-multicast-loop",
+        ]);
+        assert!(matches.is_err());
+    }
+
+    #[test]
+    fn args_pubkey() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "pubkey",
+            "./keys",
+        ]);
+        let matches = matches.subcommand_matches("pubkey").unwrap();
+        assert_eq!("./keys", matches.value_of("path").unwrap());
+    }
+
+    #[test]
+    fn args_pubkey_requires_path() {
+        let matches = app().get_matches_from_safe(vec![
+            "tox-node",
+            "pubkey",
+        ]);
+        assert!(matches.is_err());
+    }
+
+    #[test]
+    fn args_check_keys() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "check-keys",
+            "./keys",
+        ]);
+        let matches = matches.subcommand_matches("check-keys").unwrap();
+        assert_eq!("./keys", matches.value_of("path").unwrap());
+    }
+
+    #[test]
+    fn args_ping() {
+        let matches = app().get_matches_from(vec![
+            "tox-node",
+            "ping",
+            "1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F",
+            "node.tox.biribiri.org:33445",
+            "--timeout",
+            "2",
+        ]);
+        let matches = matches.subcommand_matches("ping").unwrap();
+        assert_eq!("node.tox.biribiri.org:33445", matches.value_of("addr").unwrap());
+        assert_eq!("2", matches.value_of("timeout").unwrap());
+    }
+
+    #[test]
+    fn args_ping_invalid() {
+        let pk = "1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F";
+        assert!(app().get_matches_from_safe(vec!["tox-node", "ping", pk]).is_err());
+        assert!(app().get_matches_from_safe(vec!["tox-node", "ping", pk, "1.2.3.4"]).is_err());
+        assert!(app().get_matches_from_safe(vec!["tox-node", "ping", &pk[.. 60], "1.2.3.4:33445"]).is_err());
     }
 
     #[test]
@@ -675,4 +5316,48 @@ mod tests {
         let matches = matches.subcommand_matches("derive-pk").unwrap();
         assert_eq!(sk_str, matches.value_of("secret-key").unwrap());
     }
+
+    #[test]
+    fn subcommand_exit_codes() {
+        let exit_code = |args: Vec<&str>| {
+            let matches = app().get_matches_from(args);
+            let result = match matches.subcommand() {
+                ("derive-pk", Some(m)) => run_derive_pk(m),
+                ("pubkey", Some(m)) => run_pubkey(m),
+                ("check-keys", Some(m)) => run_check_keys(m),
+                ("resolve-bootstrap", Some(m)) => run_resolve_bootstrap(m),
+                _ => unreachable!(),
+            };
+            result.err().map(|e| e.exit_code())
+        };
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let missing = dir.path().join("missing");
+        let missing = missing.to_str().unwrap();
+        assert_eq!(exit_code(vec!["tox-node", "pubkey", missing]), Some(EXIT_KEYS));
+        assert_eq!(exit_code(vec!["tox-node", "check-keys", missing]), Some(EXIT_KEYS));
+        assert_eq!(exit_code(vec!["tox-node", "derive-pk", "--keys-file", missing]), Some(EXIT_KEYS));
+        // the secret key must come from the environment
+        let sk_str = "d7f04a6db2c12f1eae0229c72e6bc429ca894541acc5f292da0e4d9a47827774";
+        assert_eq!(exit_code(vec!["tox-node", "derive-pk", "--secret-key", sk_str]), Some(EXIT_CONFIG));
+
+        let yaml = config_file(".yml", "udp-address: 127.0.0.1:33445\nsecret-key-file: ./key\n");
+        assert_eq!(exit_code(vec!["tox-node", "resolve-bootstrap", yaml.path().to_str().unwrap()]), Some(EXIT_CONFIG));
+        assert_eq!(exit_code(vec!["tox-node", "resolve-bootstrap", missing]), Some(EXIT_CONFIG));
+        // keys and addresses are not required to resolve bootstrap nodes
+        let yaml = config_file(".yml", r#"
+bootstrap-nodes:
+  - pk: 3F0A45A268367C1BEA652F258C85F4A66DA76BCAA667A49E770BCC4917AB6A25
+    addr: 127.0.0.1:33445
+"#);
+        assert_eq!(exit_code(vec!["tox-node", "resolve-bootstrap", yaml.path().to_str().unwrap()]), None);
+    }
+
+    #[test]
+    fn invalid_secret_key_is_keys_error() {
+        let error = parse_secret_key("abc").unwrap_err();
+        assert_eq!(error.exit_code(), EXIT_KEYS);
+        assert!(error.to_string().starts_with("Invalid DHT secret key"));
+        assert!(parse_secret_key("d7f04a6db2c12f1eae0229c72e6bc429ca894541acc5f292da0e4d9a47827774").is_ok());
+    }
 }
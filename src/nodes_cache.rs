@@ -0,0 +1,172 @@
+//! Persistent cache of known DHT nodes used as additional bootstrap nodes
+//! after restart.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use failure::{Error, format_err};
+use futures::StreamExt;
+use tox::crypto::PublicKey;
+use tox::core::dht::server::Server as UdpServer;
+use tox::packet::dht::packed_node::PackedNode;
+
+use crate::node_config::pk_from_hex;
+
+/// Interval of time for saving known DHT nodes to the cache file.
+const NODES_CACHE_INTERVAL: Duration = Duration::from_secs(60);
+/// Maximum number of nodes saved to the cache file.
+const MAX_CACHED_NODES: usize = 256;
+/// Cache files larger than this size are ignored. It's enough for
+/// `MAX_CACHED_NODES` lines with IPv6 addresses.
+const MAX_CACHE_FILE_SIZE: u64 = 64 * 1024;
+
+/// Format nodes as lines in the format `<pk> <ip:port>`, the same as the one
+/// of `bootstrap-file`.
+fn format_nodes(nodes: &[PackedNode]) -> String {
+    nodes
+        .iter()
+        .take(MAX_CACHED_NODES)
+        .map(|node| format!("{} {}\n", hex::encode_upper(node.pk.as_ref()), node.saddr))
+        .collect()
+}
+
+/// Parse nodes from lines in the format `<pk> <ip:port>`. Malformed lines are
+/// skipped, the number of them is returned alongside.
+fn parse_nodes(text: &str) -> (Vec<PackedNode>, usize) {
+    let mut malformed = 0;
+    let nodes = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| {
+            let node = match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                [pk, addr] => pk_from_hex(pk).ok().and_then(|pk|
+                    addr.parse::<SocketAddr>().ok().map(|addr| PackedNode::new(addr, &pk))
+                ),
+                _ => None,
+            };
+            if node.is_none() {
+                malformed += 1;
+            }
+            node
+        })
+        .take(MAX_CACHED_NODES)
+        .collect();
+    (nodes, malformed)
+}
+
+/// Load nodes from the cache file. A missing, too large or unreadable cache
+/// is not an error since the node can bootstrap without it.
+pub fn load_nodes_cache(path: &Path) -> Vec<PackedNode> {
+    let text = match fs::metadata(path) {
+        Ok(metadata) if metadata.len() > MAX_CACHE_FILE_SIZE => {
+            warn!("Ignoring nodes cache '{}' larger than {} bytes", path.display(), MAX_CACHE_FILE_SIZE);
+            return Vec::new();
+        },
+        Ok(_) => fs::read_to_string(path),
+        Err(e) => Err(e),
+    };
+    let text = match text {
+        Ok(text) => text,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Vec::new(),
+        Err(e) => {
+            warn!("Failed to read nodes cache '{}': {}", path.display(), e);
+            return Vec::new();
+        },
+    };
+
+    let (nodes, malformed) = parse_nodes(&text);
+    if malformed > 0 {
+        warn!("Skipped {} malformed lines of nodes cache '{}'", malformed, path.display());
+    }
+    nodes
+}
+
+/// Save nodes to the cache file. The file is replaced atomically so that it's
+/// not left truncated if the node is stopped while writing.
+fn save_nodes_cache(path: &Path, nodes: &[PackedNode]) -> Result<(), Error> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, format_nodes(nodes))
+        .and_then(|()| fs::rename(&tmp_path, path))
+        .map_err(|e| format_err!("Failed to save nodes cache '{}': {}", path.display(), e))
+}
+
+/// Save close nodes of the DHT server to the cache file periodically. Failures
+/// are logged and don't stop the node. The cache is not overwritten while no
+/// nodes are known, e.g. right after start.
+pub async fn run_nodes_cache(path: &Path, udp_server: &UdpServer, dht_pk: PublicKey) -> Result<(), Error> {
+    let mut wakeups = tokio::time::interval(NODES_CACHE_INTERVAL);
+    // the first tick completes immediately when nothing is known yet
+    wakeups.next().await;
+    while wakeups.next().await.is_some() {
+        let nodes: Vec<PackedNode> = udp_server.get_closest(&dht_pk, u8::MAX, false).await.into();
+        if nodes.is_empty() {
+            continue;
+        }
+
+        match save_nodes_cache(path, &nodes) {
+            Ok(()) => debug!("Saved {} nodes to nodes cache '{}'", nodes.len().min(MAX_CACHED_NODES), path.display()),
+            Err(e) => warn!("{}", e),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    fn node(addr: &str) -> PackedNode {
+        let pk = pk_from_hex("F404ABAA1C99A9D37D61AB54898F56793E1DEF8BD46B1038B9D822E8460FAB67").unwrap();
+        PackedNode::new(addr.parse().unwrap(), &pk)
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("nodes");
+        let nodes = vec![node("1.2.3.4:33445"), node("[2001:db8::1]:33445")];
+
+        save_nodes_cache(&path, &nodes).unwrap();
+        assert_eq!(load_nodes_cache(&path), nodes);
+    }
+
+    #[test]
+    fn load_missing_cache() {
+        let dir = TempDir::new().unwrap();
+        assert!(load_nodes_cache(&dir.path().join("nodes")).is_empty());
+    }
+
+    #[test]
+    fn load_corrupt_cache() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("nodes");
+        let mut text = format_nodes(&[node("1.2.3.4:33445")]);
+        text.push_str("garbage\nF404 1.2.3.4:33445\n\u{0}\u{1}\n");
+        fs::write(&path, text).unwrap();
+
+        assert_eq!(load_nodes_cache(&path), vec![node("1.2.3.4:33445")]);
+        assert_eq!(parse_nodes(&fs::read_to_string(&path).unwrap()).1, 3);
+    }
+
+    #[test]
+    fn load_too_large_cache() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("nodes");
+        fs::write(&path, vec![b'a'; MAX_CACHE_FILE_SIZE as usize + 1]).unwrap();
+
+        assert!(load_nodes_cache(&path).is_empty());
+    }
+
+    #[test]
+    fn format_nodes_is_bounded() {
+        let nodes = vec![node("1.2.3.4:33445"); MAX_CACHED_NODES + 10];
+        assert_eq!(format_nodes(&nodes).lines().count(), MAX_CACHED_NODES);
+    }
+}
@@ -0,0 +1,141 @@
+//! Handling of onion packets of TCP clients that can't be passed on.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use futures::channel::oneshot;
+use log::Level;
+
+use crate::logger::LogThrottle;
+use crate::node_config::OnionErrorPolicy;
+
+/// Signals to close connections of the TCP relay keyed by the address the
+/// relay knows the client by. Clones share the same connections.
+#[derive(Clone, Default)]
+pub struct TcpClients {
+    clients: Arc<Mutex<HashMap<SocketAddr, oneshot::Sender<()>>>>,
+}
+
+impl TcpClients {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Register a connection of the client with the address. The returned
+    /// receiver resolves when the client should be disconnected. Returns
+    /// `None` if there is a connection with the same address already.
+    pub fn register(&self, addr: SocketAddr) -> Option<oneshot::Receiver<()>> {
+        let mut clients = self.clients.lock().expect("Failed to lock TCP clients");
+        if clients.contains_key(&addr) {
+            return None
+        }
+        let (tx, rx) = oneshot::channel();
+        clients.insert(addr, tx);
+        Some(rx)
+    }
+
+    /// Remove a closed connection of the client with the address.
+    pub fn unregister(&self, addr: SocketAddr) {
+        self.clients.lock().expect("Failed to lock TCP clients").remove(&addr);
+    }
+
+    /// Signal the connection of the client with the address to close.
+    /// Returns `false` if there is no such connection.
+    pub fn disconnect(&self, addr: SocketAddr) -> bool {
+        let client = self.clients.lock().expect("Failed to lock TCP clients").remove(&addr);
+        client.map_or(false, |tx| tx.send(()).is_ok())
+    }
+}
+
+/// Handler of errors of onion packets of TCP clients according to the
+/// configured policy.
+#[derive(Clone)]
+pub struct OnionErrors {
+    policy: OnionErrorPolicy,
+    clients: TcpClients,
+}
+
+impl OnionErrors {
+    pub fn new(policy: OnionErrorPolicy, clients: TcpClients) -> Self {
+        OnionErrors {
+            policy,
+            clients,
+        }
+    }
+
+    /// Handle a failure to pass an onion packet of the TCP client with the
+    /// address. The error is always counted in `errors`, whether it's logged
+    /// and the client is disconnected depends on the policy.
+    pub fn handle(&self, addr: SocketAddr, message: String, errors: &AtomicU64, log_throttle: &LogThrottle) {
+        errors.fetch_add(1, Ordering::Relaxed);
+        match self.policy {
+            OnionErrorPolicy::Log => log_throttle.log(Level::Warn, message),
+            OnionErrorPolicy::Count => debug!("{}", message),
+            OnionErrorPolicy::Disconnect => if self.clients.disconnect(addr) {
+                log_throttle.log(Level::Warn, format!("{}, disconnecting the TCP client", message));
+            } else {
+                log_throttle.log(Level::Warn, message);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn disconnect_registered_client() {
+        let clients = TcpClients::new();
+        let addr = "1.2.3.4:33445".parse().unwrap();
+        let mut disconnected = clients.register(addr).unwrap();
+
+        assert!(!clients.disconnect("1.2.3.4:33446".parse().unwrap()));
+        assert_eq!(disconnected.try_recv(), Ok(None));
+        assert!(clients.disconnect(addr));
+        assert_eq!(disconnected.try_recv(), Ok(Some(())));
+        assert!(!clients.disconnect(addr));
+    }
+
+    #[test]
+    fn register_duplicate_address() {
+        let clients = TcpClients::new();
+        let addr = "1.2.3.4:33445".parse().unwrap();
+        let mut disconnected = clients.register(addr).unwrap();
+
+        assert!(clients.register(addr).is_none());
+        // the first connection is still registered
+        assert!(clients.disconnect(addr));
+        assert_eq!(disconnected.try_recv(), Ok(Some(())));
+        assert!(clients.register(addr).is_some());
+    }
+
+    #[test]
+    fn unregistered_client_is_not_disconnected() {
+        let clients = TcpClients::new();
+        let addr = "1.2.3.4:33445".parse().unwrap();
+        let _disconnected = clients.register(addr).unwrap();
+        clients.unregister(addr);
+
+        assert!(!clients.disconnect(addr));
+    }
+
+    #[test]
+    fn policies() {
+        let log_throttle = LogThrottle::new(Duration::from_secs(0));
+        let addr = "1.2.3.4:33445".parse().unwrap();
+        for &(policy, disconnects) in &[(OnionErrorPolicy::Log, false), (OnionErrorPolicy::Count, false), (OnionErrorPolicy::Disconnect, true)] {
+            let clients = TcpClients::new();
+            let mut disconnected = clients.register(addr).unwrap();
+            let errors = AtomicU64::new(0);
+
+            OnionErrors::new(policy, clients.clone()).handle(addr, "Failed".to_owned(), &errors, &log_throttle);
+            assert_eq!(errors.load(Ordering::Relaxed), 1);
+            assert_eq!(disconnected.try_recv().unwrap().is_some(), disconnects);
+        }
+    }
+}
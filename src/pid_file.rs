@@ -0,0 +1,127 @@
+//! PID file of the running node.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use failure::{Error, format_err};
+
+/// Check whether a process with the PID exists.
+#[cfg(unix)]
+fn process_is_alive(pid: u32) -> bool {
+    // Safe because signal 0 only checks that the process exists and can be
+    // signaled
+    let res = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    // EPERM means the process exists but belongs to another user
+    res == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Check whether a process with the PID exists. Not supported on this
+/// platform so all PID files are considered stale.
+#[cfg(not(unix))]
+fn process_is_alive(_pid: u32) -> bool {
+    false
+}
+
+/// Check that the PID file doesn't point to a running process. PID files
+/// left by crashed processes are reported as stale and will be overwritten.
+pub fn check_pid_file(path: &Path) -> Result<(), Error> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format_err!("Failed to read PID file '{}': {}", path.display(), e)),
+    };
+
+    match content.trim().parse::<u32>() {
+        // The same PID may be left by a previous run in a container where the
+        // node always gets the same PID
+        Ok(pid) if pid != std::process::id() && process_is_alive(pid) =>
+            Err(format_err!("PID file '{}' points to running process {}, is another node running?", path.display(), pid)),
+        Ok(pid) => {
+            warn!("Overwriting stale PID file '{}' of process {}", path.display(), pid);
+            Ok(())
+        },
+        Err(_) => {
+            warn!("Overwriting PID file '{}' with invalid content", path.display());
+            Ok(())
+        },
+    }
+}
+
+/// PID file written at startup. The file is removed when it's dropped.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Write PID of the current process to the file. The file should be
+    /// checked with `check_pid_file` first.
+    pub fn create(path: &Path) -> Result<PidFile, Error> {
+        fs::write(path, format!("{}\n", std::process::id()))
+            .map_err(|e| format_err!("Failed to write PID file '{}': {}", path.display(), e))?;
+
+        Ok(PidFile { path: path.to_owned() })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove PID file '{}': {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    #[test]
+    fn create_and_remove() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("tox-node.pid");
+
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn check_missing_pid_file() {
+        let dir = TempDir::new().unwrap();
+        assert!(check_pid_file(&dir.path().join("tox-node.pid")).is_ok());
+    }
+
+    #[test]
+    fn check_stale_pid_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("tox-node.pid");
+
+        fs::write(&path, "not a pid\n").unwrap();
+        assert!(check_pid_file(&path).is_ok());
+
+        fs::write(&path, format!("{}\n", std::process::id())).unwrap();
+        assert!(check_pid_file(&path).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_pid_file_of_running_process() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("tox-node.pid");
+
+        let mut child = std::process::Command::new("sleep").arg("10").spawn().unwrap();
+        fs::write(&path, format!("{}\n", child.id())).unwrap();
+        let res = check_pid_file(&path);
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        assert!(res.is_err());
+        // the process is reaped so the file is stale now
+        assert!(check_pid_file(&path).is_ok());
+    }
+}
@@ -0,0 +1,102 @@
+//! Checking that a DHT node responds to pings.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use failure::{Error, format_err};
+use futures::{SinkExt, StreamExt};
+use tokio_util::udp::UdpFramed;
+use tox::crypto::*;
+use tox::core::dht::codec::{DhtCodec, DecodeErrorKind};
+use tox::core::stats::Stats;
+use tox::packet::dht::{Packet, PingRequest, PingRequestPayload};
+
+use crate::bind_socket;
+
+/// Send a DHT ping request to the node from a temporary key and wait for the
+/// response. Returns the round-trip time.
+pub async fn ping_node(pk: PublicKey, addr: SocketAddr, timeout: Duration) -> Result<Duration, Error> {
+    let local_addr = match addr {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
+    let socket = bind_socket(local_addr, true, None, None, false, false)?;
+    let (mut sink, mut stream) = UdpFramed::new(socket, DhtCodec::new(Stats::new())).split();
+
+    let (temp_pk, temp_sk) = gen_keypair();
+    let precomputed_key = precompute(&pk, &temp_sk);
+    let id = random_u64();
+    let request = PingRequest::new(&precomputed_key, &temp_pk, &PingRequestPayload { id });
+
+    let started = Instant::now();
+    sink.send((Packet::PingRequest(request), addr)).await?;
+
+    let response = async {
+        while let Some(event) = stream.next().await {
+            match event {
+                Ok((Packet::PingResponse(response), from)) if from == addr && response.pk == pk => {
+                    // responses to other requests or forged ones are ignored
+                    if response.get_payload(&precomputed_key).map_or(false, |payload| payload.id == id) {
+                        return Ok(started.elapsed())
+                    }
+                },
+                Ok(_) => { },
+                Err(e) => if let DecodeErrorKind::Io = *e.kind() {
+                    return Err(Error::from(e))
+                },
+            }
+        }
+
+        Err(format_err!("Socket closed"))
+    };
+
+    tokio::time::timeout(timeout, response)
+        .await
+        .map_err(|_| format_err!("No response within {} seconds", timeout.as_secs()))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::net::UdpSocket;
+    use tox::packet::dht::{PingResponse, PingResponsePayload};
+
+    #[test]
+    fn ping_response() {
+        crypto_init().unwrap();
+        let (pk, sk) = gen_keypair();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let rtt = runtime.block_on(async {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = socket.local_addr().unwrap();
+            let (mut sink, mut stream) = UdpFramed::new(socket, DhtCodec::new(Stats::new())).split();
+            let responder = async move {
+                let (request, from) = match stream.next().await {
+                    Some(Ok((Packet::PingRequest(request), from))) => (request, from),
+                    other => panic!("Unexpected packet {:?}", other),
+                };
+                let precomputed_key = precompute(&request.pk, &sk);
+                let payload = request.get_payload(&precomputed_key).unwrap();
+                let response = PingResponse::new(&precomputed_key, &pk, &PingResponsePayload { id: payload.id });
+                sink.send((Packet::PingResponse(response), from)).await.unwrap();
+            };
+            let (rtt, ()) = futures::join!(ping_node(pk, addr, Duration::from_secs(5)), responder);
+            rtt
+        });
+        assert!(rtt.is_ok());
+    }
+
+    #[test]
+    fn ping_timeout() {
+        crypto_init().unwrap();
+        let (pk, _sk) = gen_keypair();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let error = runtime.block_on(async {
+            // the socket is kept open so that packets are not rejected
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            ping_node(pk, socket.local_addr().unwrap(), Duration::from_millis(100)).await.unwrap_err()
+        });
+        assert!(error.to_string().contains("No response"));
+    }
+}
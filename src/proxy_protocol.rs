@@ -0,0 +1,147 @@
+//! Parsing PROXY protocol v2 headers prepended to TCP connections by load
+//! balancers.
+
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use failure::{Error, format_err};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Interval of time for receiving the header, the same as for the handshake.
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Signature the header starts with.
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Length of the fixed part of the header: signature, version and command,
+/// address family and transport protocol, length of the addresses.
+const FIXED_LEN: usize = 16;
+
+/// Validate the fixed part of the header and get the length of the rest of
+/// it.
+fn parse_fixed_part(fixed: &[u8; FIXED_LEN]) -> Result<usize, Error> {
+    if fixed[.. 12] != SIGNATURE {
+        return Err(format_err!("PROXY protocol v2 signature is missing"));
+    }
+    if fixed[12] >> 4 != 2 {
+        return Err(format_err!("Unsupported PROXY protocol version {}", fixed[12] >> 4));
+    }
+
+    Ok(u16::from_be_bytes([fixed[14], fixed[15]]) as usize)
+}
+
+/// Get the source address of the client from the header. `None` means that
+/// the connection was made by the proxy itself, e.g. for health checks, or
+/// the address family is not TCP over IP so the real address of the
+/// connection should be used.
+fn parse_addresses(fixed: &[u8; FIXED_LEN], addresses: &[u8]) -> Result<Option<SocketAddr>, Error> {
+    match fixed[12] & 0x0f {
+        // LOCAL command
+        0x0 => return Ok(None),
+        // PROXY command
+        0x1 => { },
+        command => return Err(format_err!("Unknown PROXY protocol command {}", command)),
+    }
+
+    let (ip, port) = match fixed[13] {
+        // TCP over IPv4: source and destination addresses, then ports
+        0x11 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[.. 4].try_into()?;
+            (IpAddr::V4(Ipv4Addr::from(ip)), &addresses[8 .. 10])
+        },
+        // TCP over IPv6: source and destination addresses, then ports
+        0x21 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[.. 16].try_into()?;
+            (IpAddr::V6(Ipv6Addr::from(ip)), &addresses[32 .. 34])
+        },
+        0x11 | 0x21 => return Err(format_err!("PROXY protocol addresses are truncated")),
+        _ => return Ok(None),
+    };
+
+    Ok(Some(SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]]))))
+}
+
+/// Read PROXY protocol v2 header from the stream and get the source address
+/// of the client. Exactly the header is read so the stream can be passed to
+/// the relay after that.
+pub async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<SocketAddr>, Error> {
+    let mut fixed = [0; FIXED_LEN];
+    stream.read_exact(&mut fixed).await?;
+    let len = parse_fixed_part(&fixed)?;
+
+    let mut addresses = vec![0; len];
+    stream.read_exact(&mut addresses).await?;
+
+    parse_addresses(&fixed, &addresses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    fn read(header: &[u8]) -> Result<Option<SocketAddr>, Error> {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut stream = header;
+        runtime.block_on(read_header(&mut stream))
+    }
+
+    #[test]
+    fn read_ipv4_header() {
+        let addresses = [1, 2, 3, 4, 5, 6, 7, 8, 0x82, 0x35, 0x01, 0xbb];
+        let mut data = header(0x1, 0x11, &addresses);
+        data.extend_from_slice(b"handshake");
+        let mut stream = &data[..];
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let addr = runtime.block_on(read_header(&mut stream)).unwrap();
+        assert_eq!(addr, Some("1.2.3.4:33333".parse().unwrap()));
+        assert_eq!(stream, b"handshake");
+    }
+
+    #[test]
+    fn read_ipv6_header_with_tlvs() {
+        let mut addresses = vec![0x20, 0x01, 0x0d, 0xb8];
+        addresses.extend_from_slice(&[0; 11]);
+        addresses.push(1);
+        addresses.extend_from_slice(&[0; 16]);
+        addresses.extend_from_slice(&[0x82, 0x35, 0x01, 0xbb]);
+        // TLV that should be skipped
+        addresses.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+        assert_eq!(read(&header(0x1, 0x21, &addresses)).unwrap(), Some("[2001:db8::1]:33333".parse().unwrap()));
+    }
+
+    #[test]
+    fn read_local_header() {
+        assert_eq!(read(&header(0x0, 0x00, &[])).unwrap(), None);
+    }
+
+    #[test]
+    fn read_unspec_family() {
+        assert_eq!(read(&header(0x1, 0x00, &[])).unwrap(), None);
+    }
+
+    #[test]
+    fn read_malformed_header() {
+        // PROXY protocol v1
+        assert!(read(b"PROXY TCP4 1.2.3.4 5.6.7.8 33333 443\r\n").is_err());
+        // truncated addresses
+        assert!(read(&header(0x1, 0x11, &[1, 2, 3, 4])).is_err());
+        // unknown command
+        assert!(read(&header(0x2, 0x11, &[0; 12])).is_err());
+        // no header at all
+        assert!(read(&[]).is_err());
+        // wrong version
+        let mut data = header(0x1, 0x11, &[0; 12]);
+        data[12] = 0x11;
+        assert!(read(&data).is_err());
+    }
+}
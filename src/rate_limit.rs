@@ -0,0 +1,222 @@
+//! Per IP rate limiting of incoming UDP packets and limiting of the total rate
+//! of outgoing UDP packets.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Number of seconds of traffic that can be received from an IP at once. It
+/// allows short bursts of packets, e.g. during bootstrap, without drops.
+const BURST_SECS: u32 = 5;
+/// Interval of time for eviction of idle buckets. Drops are logged once per
+/// IP within this interval.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Token bucket of a single IP.
+struct Bucket {
+    /// Number of packets that can be received right now.
+    tokens: f64,
+    /// Time when tokens were refilled last time.
+    updated: Instant,
+    /// Whether a drop was already logged within the current window.
+    logged: bool,
+}
+
+/// Token bucket rate limiter keyed by IP address.
+pub struct RateLimiter {
+    /// Number of packets per second allowed from a single IP.
+    rate: u32,
+    /// Buckets of IPs that sent packets recently.
+    buckets: HashMap<IpAddr, Bucket>,
+    /// Time when idle buckets were evicted last time.
+    evicted: Instant,
+}
+
+impl RateLimiter {
+    /// Create new rate limiter allowing `rate` packets per second per IP.
+    pub fn new(rate: u32) -> Self {
+        RateLimiter {
+            rate,
+            buckets: HashMap::new(),
+            evicted: Instant::now(),
+        }
+    }
+
+    /// Maximum number of tokens in a bucket.
+    fn capacity(&self) -> f64 {
+        f64::from(self.rate) * f64::from(BURST_SECS)
+    }
+
+    /// Check whether a packet from the IP should be accepted and take a token
+    /// from its bucket if so.
+    pub fn check(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if now.duration_since(self.evicted) >= EVICTION_INTERVAL {
+            self.evict(now);
+        }
+
+        let rate = f64::from(self.rate);
+        let capacity = self.capacity();
+        let bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+            logged: false,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            if !bucket.logged {
+                bucket.logged = true;
+                debug!("Dropping UDP packets from {} exceeding rate limit of {} packets per second", ip, self.rate);
+            }
+            false
+        }
+    }
+
+    /// Remove buckets that would be full by now since they don't differ from
+    /// new ones, and allow logging drops again.
+    fn evict(&mut self, now: Instant) {
+        let rate = f64::from(self.rate);
+        let capacity = self.capacity();
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * rate < capacity
+        });
+        for bucket in self.buckets.values_mut() {
+            bucket.logged = false;
+        }
+        self.evicted = now;
+    }
+}
+
+/// Token bucket limiter of the total number of sent packets. Up to one second
+/// of traffic can be sent at once.
+pub struct SendLimiter {
+    /// Number of packets per second allowed to be sent.
+    rate: u32,
+    /// Number of packets that can be sent right now.
+    tokens: f64,
+    /// Time when tokens were refilled last time.
+    updated: Instant,
+    /// Whether packets are being dropped since the last sent packet. Only the
+    /// first drop is logged.
+    dropping: bool,
+}
+
+impl SendLimiter {
+    /// Create new limiter allowing `rate` packets per second in total.
+    pub fn new(rate: u32, now: Instant) -> Self {
+        SendLimiter {
+            rate,
+            tokens: f64::from(rate),
+            updated: now,
+            dropping: false,
+        }
+    }
+
+    /// Check whether a packet can be sent and take a token if so.
+    pub fn check(&mut self, now: Instant) -> bool {
+        let rate = f64::from(self.rate);
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            self.dropping = false;
+            true
+        } else {
+            if !self.dropping {
+                self.dropping = true;
+                debug!("Dropping UDP packets exceeding send rate of {} packets per second", self.rate);
+            }
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_burst() {
+        let mut limiter = RateLimiter::new(10);
+        let ip = "1.2.3.4".parse().unwrap();
+        let now = Instant::now();
+        for _ in 0 .. 10 * BURST_SECS {
+            assert!(limiter.check(ip, now));
+        }
+        assert!(!limiter.check(ip, now));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let mut limiter = RateLimiter::new(10);
+        let ip = "1.2.3.4".parse().unwrap();
+        let now = Instant::now();
+        for _ in 0 .. 10 * BURST_SECS {
+            limiter.check(ip, now);
+        }
+        assert!(!limiter.check(ip, now));
+        let now = now + Duration::from_millis(100);
+        assert!(limiter.check(ip, now));
+        assert!(!limiter.check(ip, now));
+    }
+
+    #[test]
+    fn ips_are_independent() {
+        let mut limiter = RateLimiter::new(1);
+        let now = Instant::now();
+        for _ in 0 .. BURST_SECS {
+            limiter.check("1.2.3.4".parse().unwrap(), now);
+        }
+        assert!(!limiter.check("1.2.3.4".parse().unwrap(), now));
+        assert!(limiter.check("5.6.7.8".parse().unwrap(), now));
+    }
+
+    #[test]
+    fn evicts_idle_buckets() {
+        let mut limiter = RateLimiter::new(10);
+        let now = Instant::now();
+        limiter.check("1.2.3.4".parse().unwrap(), now);
+        assert_eq!(limiter.buckets.len(), 1);
+        let now = now + EVICTION_INTERVAL;
+        limiter.check("5.6.7.8".parse().unwrap(), now);
+        assert_eq!(limiter.buckets.len(), 1);
+        assert!(limiter.buckets.contains_key(&"5.6.7.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn send_limiter_allows_one_second() {
+        let now = Instant::now();
+        let mut limiter = SendLimiter::new(10, now);
+        for _ in 0 .. 10 {
+            assert!(limiter.check(now));
+        }
+        assert!(!limiter.check(now));
+    }
+
+    #[test]
+    fn send_limiter_refills_over_time() {
+        let now = Instant::now();
+        let mut limiter = SendLimiter::new(10, now);
+        for _ in 0 .. 10 {
+            limiter.check(now);
+        }
+        assert!(!limiter.check(now));
+        let now = now + Duration::from_millis(100);
+        assert!(limiter.check(now));
+        assert!(!limiter.check(now));
+        let now = now + Duration::from_secs(10);
+        for _ in 0 .. 10 {
+            assert!(limiter.check(now));
+        }
+        assert!(!limiter.check(now));
+    }
+}
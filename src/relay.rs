@@ -0,0 +1,372 @@
+//! Running TCP relay connections on any stream.
+
+use std::io::Result as IoResult;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use failure::{Error, format_err};
+use futures::{Future, FutureExt, SinkExt, StreamExt, TryFutureExt, TryStreamExt};
+use futures::channel::mpsc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::Framed;
+use tox::crypto::*;
+use tox::core::relay::codec::Codec;
+use tox::core::relay::handshake::{ClientHandshakeCodec, ServerHandshakeCodec, handle_client_handshake};
+use tox::core::relay::secure::Channel;
+use tox::core::relay::server::{Client, Server as TcpServer};
+use tox::core::stats::Stats;
+
+/// Interval of time for the handshake, the same as for TCP connections.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Channel size for packets sent to a client.
+const CLIENT_CHANNEL_SIZE: usize = 64;
+
+/// Receive handshake from the client and send handshake back. The same as
+/// `make_server_handshake` of the `tox` crate that works only with TCP
+/// streams.
+async fn make_server_handshake<S>(stream: S, dht_sk: &SecretKey) -> Result<(S, Channel, PublicKey), Error>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    let mut client = Framed::new(stream, ClientHandshakeCodec);
+    let handshake = client.next().await
+        .ok_or_else(|| format_err!("Connection closed before ClientHandshake"))??;
+
+    let (channel, client_pk, server_handshake) = handle_client_handshake(dht_sk, &handshake)?;
+
+    let mut server = Framed::new(client.into_inner(), ServerHandshakeCodec);
+    server.send(server_handshake).await?;
+
+    Ok((server.into_inner(), channel, client_pk))
+}
+
+/// Run TCP relay connection on the stream. The same as `tcp_run_connection`
+/// of the `tox` crate that works only with TCP streams. The client is
+/// registered in the relay with the address that must be unique among
+/// connected clients.
+///
+/// Traffic of the stream is tracked by the activity and the connection is
+/// closed when it's idle for the activity's timeout or when `close` resolves.
+/// The client is removed from the relay however the connection ends so that
+/// its linked clients are notified right away.
+pub async fn run_connection<S, C>(
+    server: &TcpServer,
+    stream: S,
+    dht_sk: SecretKey,
+    stats: Stats,
+    addr: SocketAddr,
+    activity: Activity,
+    close: C,
+) -> Result<(), Error>
+    where S: AsyncRead + AsyncWrite + Unpin, C: Future<Output = ()>
+{
+    let stream = ActiveStream::new(stream, activity.clone());
+    let close = async {
+        futures::select! {
+            () = close.fuse() => { },
+            () = activity.idle().fuse() =>
+                debug!("Closing TCP connection of {} idle for {} seconds", addr, activity.timeout.as_secs()),
+        }
+    }.fuse();
+    futures::pin_mut!(close);
+
+    let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, make_server_handshake(stream, &dht_sk));
+    let (stream, channel, client_pk) = futures::select! {
+        res = handshake.fuse() => res.map_err(|_| format_err!("Handshake timeout"))??,
+        () = close => return Ok(()),
+    };
+
+    debug!("Handshake for TCP client {:?} is completed", client_pk);
+
+    let (mut to_client, from_client) = Framed::new(stream, Codec::new(channel, stats)).split();
+    let (to_client_tx, mut to_client_rx) = mpsc::channel(CLIENT_CHANNEL_SIZE);
+
+    let processor = from_client
+        .map_err(Error::from)
+        .try_for_each(|packet| {
+            debug!("Handle {:?} => {:?}", client_pk, packet);
+            server.handle_packet(&client_pk, packet).map_err(Error::from)
+        });
+
+    let writer = async {
+        while let Some(packet) = to_client_rx.next().await {
+            trace!("Sending TCP packet {:?} to {:?}", packet, client_pk);
+            to_client.send(packet).await?;
+        }
+
+        Ok(())
+    };
+
+    server.insert(Client::new(to_client_tx, &client_pk, addr.ip(), addr.port())).await?;
+
+    let res = futures::select! {
+        res = processor.fuse() => res,
+        res = writer.fuse() => res,
+        () = close => Ok(()),
+    };
+
+    debug!("Shutdown a client with PK {:?}", &client_pk);
+
+    server.shutdown_client(&client_pk, addr.ip(), addr.port()).await?;
+
+    res
+}
+
+/// Time of the last traffic of a connection and number of transferred bytes.
+/// Clones share the same traffic.
+#[derive(Clone)]
+pub struct Activity {
+    /// Time without traffic after which the connection is idle. Connections
+    /// are never idle if it's 0.
+    timeout: Duration,
+    started: Instant,
+    /// Milliseconds since `started` when there was traffic last time.
+    last: Arc<AtomicU64>,
+    /// Number of bytes read from the stream.
+    received: Arc<AtomicU64>,
+    /// Number of bytes written to the stream.
+    sent: Arc<AtomicU64>,
+}
+
+impl Activity {
+    pub fn new(timeout: Duration) -> Self {
+        Activity {
+            timeout,
+            started: Instant::now(),
+            last: Arc::new(AtomicU64::new(0)),
+            received: Arc::new(AtomicU64::new(0)),
+            sent: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Remember that there was traffic right now.
+    fn touch(&self) {
+        self.last.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Number of bytes received and sent so far.
+    pub fn bytes(&self) -> (u64, u64) {
+        (self.received.load(Ordering::Relaxed), self.sent.load(Ordering::Relaxed))
+    }
+
+    /// Time since the last traffic.
+    fn idle_for(&self) -> Duration {
+        self.started.elapsed().checked_sub(Duration::from_millis(self.last.load(Ordering::Relaxed))).unwrap_or_default()
+    }
+
+    /// Resolve when there is no traffic for the timeout. Never resolves if
+    /// the timeout is 0.
+    async fn idle(&self) {
+        if self.timeout == Duration::from_secs(0) {
+            return futures::future::pending().await
+        }
+
+        loop {
+            let idle_for = self.idle_for();
+            if idle_for >= self.timeout {
+                return
+            }
+            tokio::time::delay_for(self.timeout - idle_for).await;
+        }
+    }
+}
+
+/// Stream that touches the activity whenever data is read or written and
+/// counts transferred bytes.
+struct ActiveStream<S> {
+    stream: S,
+    activity: Activity,
+}
+
+impl<S> ActiveStream<S> {
+    fn new(stream: S, activity: Activity) -> Self {
+        ActiveStream {
+            stream,
+            activity,
+        }
+    }
+
+    /// Touch the activity and add to the counter if some bytes were
+    /// transferred.
+    fn track(&self, poll: Poll<IoResult<usize>>, counter: &AtomicU64) -> Poll<IoResult<usize>> {
+        if let Poll::Ready(Ok(len)) = poll {
+            if len > 0 {
+                self.activity.touch();
+                counter.fetch_add(len as u64, Ordering::Relaxed);
+            }
+        }
+        poll
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ActiveStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<IoResult<usize>> {
+        let poll = Pin::new(&mut self.stream).poll_read(cx, buf);
+        self.track(poll, &self.activity.received)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ActiveStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<IoResult<usize>> {
+        let poll = Pin::new(&mut self.stream).poll_write(cx, buf);
+        self.track(poll, &self.activity.sent)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::channel::oneshot;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tox::core::relay::handshake::make_client_handshake;
+    use tox::packet::onion::{InnerOnionResponse, OnionDataResponse};
+
+    use crate::logger::LogThrottle;
+    use crate::node_config::OnionErrorPolicy;
+    use crate::onion_errors::{OnionErrors, TcpClients};
+
+    /// Check whether a client with the address is connected to the relay by
+    /// sending an onion response to it.
+    async fn is_connected(server: &TcpServer, addr: SocketAddr) -> bool {
+        let response = InnerOnionResponse::OnionDataResponse(OnionDataResponse {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123],
+        });
+        server.handle_udp_onion_response(addr.ip(), addr.port(), response).await.is_ok()
+    }
+
+    #[test]
+    fn client_is_registered_with_address() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            crypto_init().unwrap();
+            let (server_pk, server_sk) = gen_keypair();
+            let (client_pk, client_sk) = gen_keypair();
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let (client, accepted) = futures::future::join(TcpStream::connect(listener.local_addr().unwrap()), listener.accept()).await;
+            let (stream, peer_addr) = accepted.unwrap();
+            // e.g. the address from PROXY protocol header
+            let addr = "1.2.3.4:33445".parse().unwrap();
+
+            let server = TcpServer::new();
+            let server_c = server.clone();
+            tokio::spawn(async move {
+                run_connection(&server_c, stream, server_sk, Stats::new(), addr, Activity::new(Duration::from_secs(0)), futures::future::pending()).await
+            });
+            let (_client, _channel) = make_client_handshake(client.unwrap(), &client_pk, &client_sk, &server_pk).await.unwrap();
+            while !is_connected(&server, addr).await {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+            assert!(!is_connected(&server, peer_addr).await);
+        });
+    }
+
+    /// Accept a loopback TCP connection and make the client handshake with
+    /// the relay running on it until `close` resolves. Returns the client's
+    /// stream that should be kept open, the address of the client and the
+    /// spawned connection.
+    async fn connect<C>(server: &TcpServer, activity: Activity, close: C) -> (TcpStream, SocketAddr, tokio::task::JoinHandle<Result<(), Error>>)
+        where C: Future<Output = ()> + Send + 'static
+    {
+        crypto_init().unwrap();
+        let (server_pk, server_sk) = gen_keypair();
+        let (client_pk, client_sk) = gen_keypair();
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (client, accepted) = futures::future::join(TcpStream::connect(listener.local_addr().unwrap()), listener.accept()).await;
+        let (stream, addr) = accepted.unwrap();
+
+        let server_c = server.clone();
+        let connection = tokio::spawn(async move {
+            run_connection(&server_c, stream, server_sk, Stats::new(), addr, activity, close).await
+        });
+        let (client, _channel) = make_client_handshake(client.unwrap(), &client_pk, &client_sk, &server_pk).await.unwrap();
+        while !is_connected(server, addr).await {
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+
+        (client, addr, connection)
+    }
+
+    #[test]
+    fn idle_connection_is_closed() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let server = TcpServer::new();
+            let (_client, addr, connection) = connect(&server, Activity::new(Duration::from_millis(50)), futures::future::pending()).await;
+
+            let res = tokio::time::timeout(Duration::from_secs(5), connection).await.unwrap().unwrap();
+            assert!(res.is_ok());
+            // the client is removed from the relay
+            assert!(!is_connected(&server, addr).await);
+        });
+    }
+
+    #[test]
+    fn client_disconnected_because_of_onion_errors_is_removed() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let server = TcpServer::new();
+            let clients = TcpClients::new();
+            let (close_tx, close_rx) = oneshot::channel::<oneshot::Receiver<()>>();
+            let close = async move {
+                // the connection is registered when the address is known
+                let disconnected = close_rx.await.unwrap();
+                let _ = disconnected.await;
+            };
+            let (_client, addr, connection) = connect(&server, Activity::new(Duration::from_secs(0)), close).await;
+            close_tx.send(clients.register(addr).unwrap()).unwrap();
+
+            let log_throttle = LogThrottle::new(Duration::from_secs(0));
+            let errors = AtomicU64::new(0);
+            OnionErrors::new(OnionErrorPolicy::Disconnect, clients.clone()).handle(addr, "Failed".to_owned(), &errors, &log_throttle);
+
+            let res = tokio::time::timeout(Duration::from_secs(5), connection).await.unwrap().unwrap();
+            assert!(res.is_ok());
+            assert!(!is_connected(&server, addr).await);
+        });
+    }
+
+    #[test]
+    fn traffic_resets_idle_time() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let activity = Activity::new(Duration::from_secs(1));
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+            assert!(activity.idle_for() >= Duration::from_millis(100));
+
+            let mut stream = ActiveStream::new(&b"ping"[..], activity.clone());
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert!(activity.idle_for() < Duration::from_millis(100));
+
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+            let mut stream = ActiveStream::new(Vec::new(), activity.clone());
+            stream.write_all(b"pong").await.unwrap();
+            assert!(activity.idle_for() < Duration::from_millis(100));
+            assert_eq!(activity.bytes(), (4, 4));
+        });
+    }
+
+    #[test]
+    fn connection_without_timeout_is_never_idle() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let res = runtime.block_on(async {
+            tokio::time::timeout(Duration::from_millis(100), Activity::new(Duration::from_secs(0)).idle()).await
+        });
+        assert!(res.is_err());
+    }
+}
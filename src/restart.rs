@@ -0,0 +1,165 @@
+//! Restarting servers of the node on failures and on request.
+
+use std::time::Duration;
+
+use failure::Error;
+use futures::{FutureExt, Stream, StreamExt};
+use futures::future::BoxFuture;
+
+/// Exponential back-off between restarts of a server that keeps failing.
+pub struct Backoff {
+    initial_delay: Duration,
+    max_delay: Duration,
+    reset_interval: Duration,
+    retries: u32,
+    delay: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    /// Back-off starting with `initial_delay` that is doubled up to
+    /// `max_delay` after each failure. A server that worked for
+    /// `reset_interval` before failing is restarted as if it failed for the
+    /// first time.
+    pub fn new(initial_delay: Duration, max_delay: Duration, reset_interval: Duration, retries: u32) -> Self {
+        Backoff {
+            initial_delay,
+            max_delay,
+            reset_interval,
+            retries,
+            delay: initial_delay,
+            attempt: 0,
+        }
+    }
+
+    /// Attempt number and delay before restarting the server that failed
+    /// after working for `worked`. Returns `None` when `retries` restarts in
+    /// a row are used up.
+    pub fn failed(&mut self, worked: Duration) -> Option<(u32, Duration)> {
+        // the server worked long enough so that the failure is not a part
+        // of previous failures
+        if worked >= self.reset_interval {
+            if self.attempt > 0 {
+                debug!("Server worked for {:?}, resetting restart delay", worked);
+            }
+            self.attempt = 0;
+            self.delay = self.initial_delay;
+        }
+
+        if self.attempt >= self.retries {
+            return None;
+        }
+
+        self.attempt += 1;
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(self.max_delay);
+        Some((self.attempt, delay))
+    }
+}
+
+/// Run the server created by `run` and start it again with the same `state`
+/// each time `restarts` yields. The running server is dropped before the new
+/// one is started. Completes when the server completes, errors are not
+/// retried. Each restart is logged with the generation of the server.
+pub async fn run_restartable<S, F, R>(name: &str, state: &mut S, mut run: F, mut restarts: R) -> Result<(), Error>
+    where
+        F: FnMut(&mut S) -> BoxFuture<'_, Result<(), Error>>,
+        R: Stream<Item = ()> + Unpin,
+{
+    let mut generation = 1;
+    loop {
+        debug!("Starting {} (generation {})", name, generation);
+        let mut server = run(state).fuse();
+        futures::select! {
+            res = server => return res,
+            restart = restarts.next().fuse() => if restart.is_none() {
+                // no restarts are possible anymore, let the server work
+                return server.await
+            },
+        }
+        drop(server);
+        info!("Stopped {} (generation {}) on request", name, generation);
+        generation += 1;
+        info!("Starting {} again (generation {})", name, generation);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use failure::format_err;
+    use futures::channel::mpsc;
+    use futures::future;
+
+    #[test]
+    fn backoff_delays() {
+        let second = Duration::from_secs(1);
+        let mut backoff = Backoff::new(second, 4 * second, 60 * second, 4);
+        assert_eq!(backoff.failed(Duration::from_secs(0)), Some((1, second)));
+        assert_eq!(backoff.failed(Duration::from_secs(0)), Some((2, 2 * second)));
+        assert_eq!(backoff.failed(Duration::from_secs(0)), Some((3, 4 * second)));
+        // the delay is capped
+        assert_eq!(backoff.failed(Duration::from_secs(0)), Some((4, 4 * second)));
+        assert_eq!(backoff.failed(Duration::from_secs(0)), None);
+        // a server that worked long enough starts over
+        assert_eq!(backoff.failed(Duration::from_secs(60)), Some((1, second)));
+    }
+
+    #[test]
+    fn backoff_no_retries() {
+        let second = Duration::from_secs(1);
+        let mut backoff = Backoff::new(second, second, second, 0);
+        assert_eq!(backoff.failed(Duration::from_secs(0)), None);
+        assert_eq!(backoff.failed(Duration::from_secs(10)), None);
+    }
+
+    #[test]
+    fn restart_on_request() {
+        let (restarts_tx, restarts_rx) = mpsc::unbounded();
+        let mut started = 0;
+        let server = run_restartable("test server", &mut started, |started| {
+            *started += 1;
+            let started = *started;
+            let restarts_tx = restarts_tx.clone();
+            async move {
+                match started {
+                    // requests a restart of itself and never completes
+                    1 | 2 => {
+                        restarts_tx.unbounded_send(()).unwrap();
+                        future::pending().await
+                    },
+                    _ => Err(format_err!("failed in generation {}", started)),
+                }
+            }.boxed()
+        }, restarts_rx);
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let res = runtime.block_on(server);
+        // errors are returned without restarting
+        assert_eq!(res.unwrap_err().to_string(), "failed in generation 3");
+        assert_eq!(started, 3);
+    }
+
+    #[test]
+    fn no_restarts_after_stream_end() {
+        let (restarts_tx, restarts_rx) = mpsc::unbounded::<()>();
+        drop(restarts_tx);
+        let (stop_tx, stop_rx) = futures::channel::oneshot::channel::<()>();
+        let mut stop_rx = Some(stop_rx);
+        let mut started = 0;
+        // the server keeps working after the end of restarts
+        let server = run_restartable("test server", &mut started, |started| {
+            *started += 1;
+            let stop_rx = stop_rx.take().unwrap();
+            async move {
+                stop_rx.await?;
+                Ok(())
+            }.boxed()
+        }, restarts_rx);
+        stop_tx.send(()).unwrap();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(server).unwrap();
+        assert_eq!(started, 1);
+    }
+}
@@ -0,0 +1,122 @@
+//! Startup self-test of cryptography and packet encoding.
+
+use bytes::BytesMut;
+use failure::{Error, format_err};
+use tokio_util::codec::{Decoder, Encoder};
+use tox::crypto::*;
+use tox::core::dht::codec::DhtCodec;
+use tox::core::stats::Stats;
+use tox::packet::dht::{Packet, PingRequest, PingRequestPayload};
+
+use crate::error::NodeError;
+
+/// Encrypt a sample with a precomputed key of one pair of keys and decrypt it
+/// with a precomputed key of the other pair.
+fn check_encryption() -> Result<(), Error> {
+    let (alice_pk, alice_sk) = gen_keypair();
+    let (bob_pk, bob_sk) = gen_keypair();
+    let nonce = gen_nonce();
+    let plain = b"tox-node self-test";
+
+    let encrypted = encrypt_data_symmetric(&precompute(&bob_pk, &alice_sk), &nonce, plain);
+    if encrypted[..] == plain[..] {
+        return Err(format_err!("Encrypted data is equal to the plain data"))
+    }
+    let decrypted = decrypt_data_symmetric(&precompute(&alice_pk, &bob_sk), &nonce, &encrypted)
+        .map_err(|()| format_err!("Failed to decrypt the encrypted data"))?;
+    if decrypted[..] != plain[..] {
+        return Err(format_err!("Decrypted data differs from the plain data"))
+    }
+
+    Ok(())
+}
+
+/// Encode a DHT ping request with `DhtCodec`, decode it back and check that
+/// the payload is preserved.
+fn check_dht_codec() -> Result<(), Error> {
+    let (pk, sk) = gen_keypair();
+    let (node_pk, node_sk) = gen_keypair();
+    let id = random_u64();
+    let packet = Packet::PingRequest(PingRequest::new(&precompute(&node_pk, &sk), &pk, &PingRequestPayload { id }));
+
+    let mut codec = DhtCodec::new(Stats::new());
+    let mut buf = BytesMut::new();
+    codec.encode(packet.clone(), &mut buf)?;
+    let decoded = codec.decode(&mut buf)?
+        .ok_or_else(|| format_err!("Encoded packet was not decoded"))?;
+    if decoded != packet {
+        return Err(format_err!("Decoded packet differs from the encoded one"))
+    }
+
+    let request = match decoded {
+        Packet::PingRequest(request) => request,
+        _ => unreachable!("Decoded packet is equal to a ping request"),
+    };
+    let payload = request.get_payload(&precompute(&pk, &node_sk))
+        .map_err(|e| format_err!("Failed to decrypt the payload: {}", e))?;
+    if payload.id != id {
+        return Err(format_err!("Decrypted payload has id {} instead of {}", payload.id, id))
+    }
+
+    Ok(())
+}
+
+/// Check that the public key generated together with a secret key is the one
+/// derived from it.
+fn check_keypair() -> Result<(), Error> {
+    let (pk, sk) = gen_keypair();
+    if sk.public_key() != pk {
+        return Err(format_err!("Public key derived from the secret key differs from the generated one"))
+    }
+    let (other_pk, _other_sk) = gen_keypair();
+    if other_pk == pk {
+        return Err(format_err!("Two generated key pairs have the same public key"))
+    }
+
+    Ok(())
+}
+
+/// Sub-check of the self-test.
+type Check = fn() -> Result<(), Error>;
+
+/// Sub-checks of the self-test with their names.
+const CHECKS: [(&str, Check); 3] = [
+    ("encryption", check_encryption),
+    ("DHT codec", check_dht_codec),
+    ("key pair", check_keypair),
+];
+
+/// Run all sub-checks printing the result of each one. Fails with a runtime
+/// error if any of them failed. Cryptography must be initialized before.
+pub fn run_self_test() -> Result<(), NodeError> {
+    let mut failed = 0;
+    for (name, check) in CHECKS.iter() {
+        match check() {
+            Ok(()) => println!("{}: OK", name),
+            Err(e) => {
+                println!("{}: FAILED: {}", name, e);
+                failed += 1;
+            },
+        }
+    }
+
+    if failed > 0 {
+        return Err(NodeError::Runtime(format_err!("{} of {} self-test checks failed", failed, CHECKS.len())))
+    }
+    println!("All {} self-test checks passed", CHECKS.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_pass() {
+        crypto_init().unwrap();
+        for (name, check) in CHECKS.iter() {
+            assert!(check().is_ok(), "{} check failed", name);
+        }
+        assert!(run_self_test().is_ok());
+    }
+}
@@ -0,0 +1,427 @@
+//! Running DHT server on multiple UDP sockets.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+use bytes::BytesMut;
+use failure::Error;
+use log::Level;
+use futures::{FutureExt, SinkExt, StreamExt};
+use futures::channel::mpsc::Receiver;
+use tokio::net::UdpSocket;
+use tokio_util::codec::{Decoder, Encoder};
+use tokio_util::udp::UdpFramed;
+use tox::core::dht::codec::{DhtCodec, DecodeError, DecodeErrorKind, EncodeError};
+use tox::core::dht::server::{Server as UdpServer};
+use tox::packet::dht::Packet;
+
+use crate::ip_filter::{BanList, canonical};
+use crate::logger::LogThrottle;
+use crate::metrics::{Metrics, PacketType};
+use crate::node_config::{IpMode, NodeConfig};
+use crate::rate_limit::{RateLimiter, SendLimiter};
+
+/// First bytes of DHT packets that can be decoded by `DhtCodec`.
+const KNOWN_PACKET_KINDS: &[u8] = &[
+    0x00, 0x01, 0x02, 0x04, // ping and nodes
+    0x18, 0x19, 0x1a, 0x1b, // net crypto
+    0x20, 0x21, // DHT request and LAN discovery
+    0x80, 0x81, 0x82, 0x83, 0x84, 0x85, 0x86, 0x8c, 0x8d, 0x8e, // onion
+    0xf0, // bootstrap info
+];
+
+/// Check whether the packet that failed to decode has a known kind, i.e. it's
+/// malformed rather than not a DHT packet at all.
+fn is_known_packet_kind(packet: &[u8]) -> bool {
+    packet.first().map_or(false, |kind| KNOWN_PACKET_KINDS.contains(kind))
+}
+
+/// Address of the node whose `BootstrapInfo` request is being handled. The
+/// DHT server doesn't pass it to the MOTD callback so it's stored right before
+/// the request is handled. Clones share the same address.
+#[derive(Clone, Default)]
+pub struct BootstrapRequester {
+    addr: Arc<Mutex<Option<SocketAddr>>>,
+}
+
+impl BootstrapRequester {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    fn set(&self, addr: SocketAddr) {
+        *self.addr.lock().expect("Failed to lock bootstrap requester") = Some(addr);
+    }
+
+    /// Whether the current requester is an IPv6 node. IPv4 nodes seen by
+    /// IPv6 sockets via IPv4-mapped addresses are not.
+    pub fn is_ipv6(&self) -> bool {
+        self.addr.lock().expect("Failed to lock bootstrap requester")
+            .map_or(false, |addr| canonical(addr.ip()).is_ipv6())
+    }
+}
+
+/// Type of the packet it's counted by in metrics.
+fn packet_type(packet: &Packet) -> PacketType {
+    match packet {
+        Packet::PingRequest(_) => PacketType::PingRequest,
+        Packet::PingResponse(_) => PacketType::PingResponse,
+        Packet::NodesRequest(_) => PacketType::NodesRequest,
+        Packet::NodesResponse(_) => PacketType::NodesResponse,
+        Packet::CookieRequest(_) | Packet::CookieResponse(_)
+            | Packet::CryptoHandshake(_) | Packet::CryptoData(_) => PacketType::NetCrypto,
+        Packet::DhtRequest(_) => PacketType::DhtRequest,
+        Packet::LanDiscovery(_) => PacketType::LanDiscovery,
+        Packet::OnionRequest0(_) | Packet::OnionRequest1(_) | Packet::OnionRequest2(_) => PacketType::OnionRequest,
+        Packet::OnionResponse1(_) | Packet::OnionResponse2(_) | Packet::OnionResponse3(_) => PacketType::OnionResponse,
+        Packet::OnionAnnounceRequest(_) => PacketType::OnionAnnounceRequest,
+        Packet::OnionAnnounceResponse(_) => PacketType::OnionAnnounceResponse,
+        Packet::OnionDataRequest(_) => PacketType::OnionDataRequest,
+        Packet::OnionDataResponse(_) => PacketType::OnionDataResponse,
+        Packet::BootstrapInfo(_) => PacketType::BootstrapInfo,
+    }
+}
+
+/// `DhtCodec` that rejects packets longer than `max_packet_size` bytes before
+/// parsing them.
+struct SizeLimitedCodec {
+    codec: DhtCodec,
+    max_packet_size: usize,
+}
+
+impl Decoder for SizeLimitedCodec {
+    type Item = Packet;
+    type Error = DecodeError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if buf.len() > self.max_packet_size {
+            return Err(DecodeError::from(DecodeErrorKind::TooBigPacket { len: buf.len() }))
+        }
+
+        self.codec.decode(buf)
+    }
+}
+
+impl Encoder<Packet> for SizeLimitedCodec {
+    type Error = EncodeError;
+
+    fn encode(&mut self, packet: Packet, buf: &mut BytesMut) -> Result<(), Self::Error> {
+        self.codec.encode(packet, buf)
+    }
+}
+
+/// Find a socket to send a packet to the address. Sockets of the same address
+/// family are preferred. IPv4 packets can be sent via IPv6 socket using
+/// IPv4-mapped address in dual mode if `v4_mapped` is set, IPv6 packets can't
+/// be sent via IPv4 socket.
+fn route(local_addrs: &[SocketAddr], ip_mode: IpMode, v4_mapped: bool, addr: SocketAddr) -> Option<(usize, SocketAddr)> {
+    if !ip_mode.allows(&addr) {
+        return None;
+    }
+
+    if let Some(index) = local_addrs.iter().position(|local_addr| local_addr.is_ipv4() == addr.is_ipv4()) {
+        return Some((index, addr));
+    }
+
+    match addr.ip() {
+        IpAddr::V4(ip) if ip_mode == IpMode::Dual && v4_mapped => local_addrs
+            .iter()
+            .position(|local_addr| local_addr.is_ipv6())
+            .map(|index| (index, SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()))),
+        _ => None,
+    }
+}
+
+/// Local addresses outgoing packets of each address family are sent from.
+/// Packets are sent from the same socket they are received on since DHT nodes
+/// reply to the source address of requests and share it with other nodes, so
+/// the source port is always the listen port of the socket.
+fn outgoing_addrs(local_addrs: &[SocketAddr], ip_mode: IpMode, v4_mapped: bool) -> Vec<(&'static str, SocketAddr)> {
+    let destinations = [
+        ("IPv4", SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)),
+        ("IPv6", SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)),
+    ];
+    destinations
+        .iter()
+        .filter_map(|&(family, addr)| route(local_addrs, ip_mode, v4_mapped, addr).map(|(index, _)| (family, local_addrs[index])))
+        .collect()
+}
+
+/// Run DHT server on several `UdpSocket`s. Incoming packets from all sockets
+/// are handled by the same server, outgoing packets are sent via the socket
+/// matching address family of the destination. Packets of the family not
+/// allowed by `ip_mode` are dropped. Packets from IPs exceeding `rate_limit`
+/// packets per second are dropped unless it's 0. Packets from banned IPs are
+/// dropped. The sender of each `BootstrapInfo` request is stored in
+/// `requester` before it's handled. Outgoing packets exceeding `send_rate`
+/// packets per second in total are dropped and counted unless it's 0, an
+/// empty bucket never blocks sending. Packets longer than `max_packet_size`
+/// are dropped before decoding. Packets that are not dropped are counted in
+/// `metrics` by their type, packets that can't be decoded are counted by the
+/// failure type, only IO errors stop the server. Errors of handling packets
+/// are logged via `log_throttle`.
+///
+/// Sink and stream halves of each socket come from splitting the same
+/// `UdpFramed` so there is no way to send from a port other than the one the
+/// socket listens on.
+#[allow(clippy::too_many_arguments)]
+pub async fn dht_run_sockets(
+    dht: &UdpServer,
+    sockets: Vec<UdpSocket>,
+    rx: &mut Receiver<(Packet, SocketAddr)>,
+    config: &NodeConfig,
+    metrics: &Metrics,
+    mut ban_list: BanList,
+    requester: &BootstrapRequester,
+    log_throttle: &LogThrottle,
+) -> Result<(), Error> {
+    let ip_mode = config.ip_mode;
+    let v4_mapped = !config.no_v4_mapped;
+
+    let local_addrs = sockets
+        .iter()
+        .map(|socket| socket.local_addr())
+        .collect::<Result<Vec<_>, _>>()?;
+    for (family, local_addr) in outgoing_addrs(&local_addrs, ip_mode, v4_mapped) {
+        info!("Sending {} DHT packets from {}", family, local_addr);
+    }
+
+    let (mut sinks, streams): (Vec<_>, Vec<_>) = sockets
+        .into_iter()
+        .map(|socket| {
+            let codec = SizeLimitedCodec {
+                codec: DhtCodec::new(metrics.udp.clone()),
+                max_packet_size: config.max_packet_size,
+            };
+            UdpFramed::new(socket, codec).split()
+        })
+        .unzip();
+    let mut stream = futures::stream::select_all(streams);
+
+    let mut rate_limiter = if config.rate_limit > 0 {
+        Some(RateLimiter::new(config.rate_limit))
+    } else {
+        None
+    };
+
+    let network_reader = async {
+        while let Some(event) = stream.next().await {
+            match event {
+                Ok((packet, addr)) => {
+                    if !ip_mode.allows(&addr) {
+                        trace!("Dropping packet from {:?} in {} mode", addr, ip_mode);
+                        continue
+                    }
+
+                    if ban_list.is_banned(addr.ip(), "UDP packets") {
+                        continue
+                    }
+
+                    if let Some(ref mut rate_limiter) = rate_limiter {
+                        if !rate_limiter.check(addr.ip(), Instant::now()) {
+                            continue
+                        }
+                    }
+
+                    // dropped packets are not counted by type
+                    metrics.count_udp_packet(packet_type(&packet));
+
+                    trace!("Received packet {:?}", packet);
+                    if let Packet::BootstrapInfo(_) = packet {
+                        requester.set(addr);
+                    }
+                    let res = dht.handle_packet(packet, addr).await;
+
+                    if let Err(ref err) = res {
+                        log_throttle.log(Level::Error, format!("Failed to handle packet: {:?}", err));
+                    }
+                },
+                Err(e) => {
+                    let counter = match *e.kind() {
+                        DecodeErrorKind::TooBigPacket { len } => {
+                            debug!("Dropping packet of {} bytes exceeding the maximum packet size of {} bytes", len, config.max_packet_size);
+                            &metrics.udp_oversized
+                        },
+                        DecodeErrorKind::Deserialize { ref packet, .. } if is_known_packet_kind(packet) => {
+                            debug!("Dropping malformed packet of kind 0x{:02x} and {} bytes", packet[0], packet.len());
+                            &metrics.udp_malformed
+                        },
+                        DecodeErrorKind::Deserialize { ref packet, .. } => {
+                            match packet.first() {
+                                Some(kind) => debug!("Dropping packet of unknown kind 0x{:02x}", kind),
+                                None => debug!("Dropping empty packet"),
+                            }
+                            &metrics.udp_unknown_kind
+                        },
+                        DecodeErrorKind::Io => {
+                            error!("packet receive error = {:?}", e);
+                            return Err(Error::from(e))
+                        },
+                    };
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        Ok(())
+    };
+
+    let mut send_limiter = if config.send_rate > 0 {
+        Some(SendLimiter::new(config.send_rate, Instant::now()))
+    } else {
+        None
+    };
+
+    let network_writer = async {
+        while let Some((packet, addr)) = rx.next().await {
+            if let Some(ref mut send_limiter) = send_limiter {
+                if !send_limiter.check(Instant::now()) {
+                    metrics.udp_send_dropped.fetch_add(1, Ordering::Relaxed);
+                    continue
+                }
+            }
+
+            let (index, addr) = if let Some(route) = route(&local_addrs, ip_mode, v4_mapped, addr) {
+                route
+            } else {
+                trace!("No socket to send packet to {:?}", addr);
+                continue
+            };
+
+            trace!("Sending packet {:?} to {:?}", packet, addr);
+            sinks[index].send((packet, addr)).await?;
+        }
+
+        Ok(())
+    };
+
+    futures::select! {
+        read = network_reader.fuse() => read,
+        write = network_writer.fuse() => write,
+        run = dht.run().fuse() => run.map_err(Error::from),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size_limited_codec(max_packet_size: usize) -> SizeLimitedCodec {
+        SizeLimitedCodec {
+            codec: DhtCodec::new(tox::core::stats::Stats::new()),
+            max_packet_size,
+        }
+    }
+
+    #[test]
+    fn size_limited_codec_limit() {
+        let packet = Packet::BootstrapInfo(tox::packet::dht::BootstrapInfo { version: 3, motd: vec![b'a'; 100] });
+        let mut buf = BytesMut::new();
+        size_limited_codec(1024).encode(packet.clone(), &mut buf).unwrap();
+        let len = buf.len();
+
+        assert_eq!(size_limited_codec(len + 1).decode(&mut buf.clone()).unwrap(), Some(packet.clone()));
+        assert_eq!(size_limited_codec(len).decode(&mut buf.clone()).unwrap(), Some(packet));
+        let error = size_limited_codec(len - 1).decode(&mut buf.clone()).unwrap_err();
+        assert_eq!(*error.kind(), DecodeErrorKind::TooBigPacket { len });
+    }
+
+    #[test]
+    fn size_limited_codec_below_codec_limit() {
+        // packets up to the limit are passed to `DhtCodec` and fail to
+        // decode as malformed ones
+        let mut buf = BytesMut::from(&[0xff; 1400][..]);
+        let error = size_limited_codec(1400).decode(&mut buf).unwrap_err();
+        assert!(matches!(*error.kind(), DecodeErrorKind::Deserialize { .. }));
+
+        // `DhtCodec` would try to decode this one
+        let mut buf = BytesMut::from(&[0xff; 1401][..]);
+        let error = size_limited_codec(1400).decode(&mut buf).unwrap_err();
+        assert_eq!(*error.kind(), DecodeErrorKind::TooBigPacket { len: 1401 });
+    }
+
+    #[test]
+    fn bootstrap_requester_family() {
+        let requester = BootstrapRequester::new();
+        assert!(!requester.is_ipv6());
+        requester.clone().set("[2001:db8::1]:33445".parse().unwrap());
+        assert!(requester.is_ipv6());
+        requester.set("[::ffff:1.2.3.4]:33445".parse().unwrap());
+        assert!(!requester.is_ipv6());
+        requester.set("1.2.3.4:33445".parse().unwrap());
+        assert!(!requester.is_ipv6());
+    }
+
+    #[test]
+    fn known_packet_kinds() {
+        assert!(is_known_packet_kind(&[0x00, 0x01]));
+        assert!(is_known_packet_kind(&[0x8c]));
+        assert!(is_known_packet_kind(&[0xf0]));
+        assert!(!is_known_packet_kind(&[0x03]));
+        assert!(!is_known_packet_kind(&[0xff, 0x00]));
+        assert!(!is_known_packet_kind(&[]));
+    }
+
+    #[test]
+    fn packet_types() {
+        let (pk, _sk) = tox::crypto::gen_keypair();
+        let lan_discovery = Packet::LanDiscovery(tox::packet::dht::LanDiscovery { pk });
+        assert_eq!(packet_type(&lan_discovery), PacketType::LanDiscovery);
+        let bootstrap_info = Packet::BootstrapInfo(tox::packet::dht::BootstrapInfo { version: 3, motd: Vec::new() });
+        assert_eq!(packet_type(&bootstrap_info), PacketType::BootstrapInfo);
+    }
+
+    #[test]
+    fn outgoing_addrs_of_sockets() {
+        let v4 = "0.0.0.0:33445".parse().unwrap();
+        let v6 = "[::]:33446".parse().unwrap();
+        assert_eq!(outgoing_addrs(&[v4, v6], IpMode::Dual, true), vec![("IPv4", v4), ("IPv6", v6)]);
+        assert_eq!(outgoing_addrs(&[v6], IpMode::Dual, true), vec![("IPv4", v6), ("IPv6", v6)]);
+        assert_eq!(outgoing_addrs(&[v6], IpMode::Ipv6, true), vec![("IPv6", v6)]);
+        assert_eq!(outgoing_addrs(&[v4], IpMode::Dual, true), vec![("IPv4", v4)]);
+        assert_eq!(outgoing_addrs(&[v6], IpMode::Dual, false), vec![("IPv6", v6)]);
+    }
+
+    #[test]
+    fn route_same_family() {
+        let local_addrs = ["0.0.0.0:33445".parse().unwrap(), "[::]:33445".parse().unwrap()];
+        let v4 = "1.2.3.4:33445".parse().unwrap();
+        let v6 = "[2001:db8::1]:33445".parse().unwrap();
+        assert_eq!(route(&local_addrs, IpMode::Dual, true, v4), Some((0, v4)));
+        assert_eq!(route(&local_addrs, IpMode::Dual, true, v6), Some((1, v6)));
+    }
+
+    #[test]
+    fn route_ipv4_via_ipv6_socket() {
+        let local_addrs = ["[::]:33445".parse().unwrap()];
+        let v4 = "1.2.3.4:33445".parse().unwrap();
+        assert_eq!(route(&local_addrs, IpMode::Dual, true, v4), Some((0, "[::ffff:1.2.3.4]:33445".parse().unwrap())));
+        assert_eq!(route(&local_addrs, IpMode::Ipv6, true, v4), None);
+        assert_eq!(route(&local_addrs, IpMode::Dual, false, v4), None);
+    }
+
+    #[test]
+    fn route_ipv4_without_v4_mapped() {
+        let local_addrs = ["0.0.0.0:33445".parse().unwrap(), "[::]:33446".parse().unwrap()];
+        let v4 = "1.2.3.4:33445".parse().unwrap();
+        assert_eq!(route(&local_addrs, IpMode::Dual, false, v4), Some((0, v4)));
+    }
+
+    #[test]
+    fn route_ipv6_via_ipv4_socket() {
+        let local_addrs = ["0.0.0.0:33445".parse().unwrap()];
+        let v6 = "[2001:db8::1]:33445".parse().unwrap();
+        assert_eq!(route(&local_addrs, IpMode::Dual, true, v6), None);
+    }
+
+    #[test]
+    fn route_ipv4_mode() {
+        let local_addrs = ["0.0.0.0:33445".parse().unwrap()];
+        let v4 = "1.2.3.4:33445".parse().unwrap();
+        let v6 = "[2001:db8::1]:33445".parse().unwrap();
+        assert_eq!(route(&local_addrs, IpMode::Ipv4, true, v4), Some((0, v4)));
+        assert_eq!(route(&local_addrs, IpMode::Ipv4, true, v6), None);
+    }
+}
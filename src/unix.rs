@@ -0,0 +1,163 @@
+//! Running TCP relay on a Unix domain socket.
+
+use std::collections::HashSet;
+use std::fs;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use failure::{Error, format_err};
+use futures::Future;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::UnixListener;
+use tox::crypto::*;
+use tox::core::relay::server::Server as TcpServer;
+use tox::core::stats::Stats;
+
+use crate::error::BindError;
+use crate::relay::{Activity, run_connection};
+
+/// IP address of clients connected via Unix domain socket. The relay
+/// identifies clients by IP and port so clients get the unspecified address
+/// that can't be a peer of a TCP connection and a unique port.
+pub const UNIX_CLIENT_IP: IpAddr = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+
+/// Ports of clients connected via Unix domain socket. A port is not given to
+/// another client until the client that got it disconnects.
+#[derive(Clone, Default)]
+pub struct ClientPorts {
+    /// The last given port and ports of connected clients.
+    ports: Arc<Mutex<(u16, HashSet<u16>)>>,
+}
+
+impl ClientPorts {
+    /// Take the next port that isn't used by a connected client. Returns
+    /// `None` when all ports are in use.
+    pub fn take(&self) -> Option<u16> {
+        let mut ports = self.ports.lock().expect("Failed to lock client ports");
+        let (ref mut last, ref mut used) = *ports;
+        let mut port = *last;
+        for _ in 0 ..= u16::MAX {
+            port = port.wrapping_add(1);
+            if port != 0 && used.insert(port) {
+                *last = port;
+                return Some(port)
+            }
+        }
+        None
+    }
+
+    /// Make the port of a disconnected client available again.
+    pub fn release(&self, port: u16) {
+        self.ports.lock().expect("Failed to lock client ports").1.remove(&port);
+    }
+}
+
+/// Socket file of a bound `UnixListener`. The file is removed when it's
+/// dropped.
+pub struct SocketFile {
+    path: PathBuf,
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove Unix socket file '{}': {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Bind `UnixListener` to the path. The socket file left by a previous run is
+/// removed unless another process is listening on it.
+pub fn bind_unix_listener(path: &Path) -> Result<(UnixListener, SocketFile), Error> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(format_err!("Path '{}' exists and is not a socket", path.display()));
+        }
+        if StdUnixStream::connect(path).is_ok() {
+            return Err(format_err!("Unix socket '{}' is used by another process", path.display()));
+        }
+        info!("Removing stale Unix socket file '{}'", path.display());
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)
+        .map_err(|e| BindError(format!("Failed to bind Unix socket '{}': {}", path.display(), e)))?;
+
+    Ok((listener, SocketFile { path: path.to_owned() }))
+}
+
+/// Run TCP relay connection on incoming Unix stream. The client is
+/// registered in the relay with `UNIX_CLIENT_IP` and the port that must be
+/// unique among connected Unix clients.
+pub async fn unix_run_connection<S, C>(
+    server: &TcpServer,
+    stream: S,
+    dht_sk: SecretKey,
+    stats: Stats,
+    port: u16,
+    activity: Activity,
+    close: C,
+) -> Result<(), Error>
+    where S: AsyncRead + AsyncWrite + Unpin, C: Future<Output = ()>
+{
+    debug!("A new TCP client connected via Unix socket with port {}", port);
+
+    run_connection(server, stream, dht_sk, stats, SocketAddr::new(UNIX_CLIENT_IP, port), activity, close).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    #[test]
+    fn client_ports_skip_used() {
+        let ports = ClientPorts::default();
+        assert_eq!(ports.take(), Some(1));
+        assert_eq!(ports.take(), Some(2));
+        ports.release(1);
+        assert_eq!(ports.take(), Some(3));
+
+        // after wrapping ports of connected clients are skipped
+        for port in 4 ..= u16::MAX {
+            assert_eq!(ports.take(), Some(port));
+        }
+        assert_eq!(ports.take(), Some(1));
+        assert_eq!(ports.take(), None);
+        ports.release(3);
+        assert_eq!(ports.take(), Some(3));
+    }
+
+    #[test]
+    fn bind_unix_listener_removes_stale_socket() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("tox-node.sock");
+        // a socket file without a listener is stale
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (_listener, socket_file) = bind_unix_listener(&path).unwrap();
+            drop(socket_file);
+        });
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn bind_unix_listener_rejects_regular_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("tox-node.sock");
+        fs::write(&path, "").unwrap();
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            assert!(bind_unix_listener(&path).is_err());
+        });
+        assert!(path.exists());
+    }
+}